@@ -0,0 +1,359 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use containerd_client::services::v1::container::Runtime;
+use containerd_client::services::v1::containers_client::ContainersClient;
+use containerd_client::services::v1::content_client::ContentClient;
+use containerd_client::services::v1::images_client::ImagesClient;
+use containerd_client::services::v1::leases_client::LeasesClient;
+use containerd_client::services::v1::{
+    Container, CreateContainerRequest, CreateImageRequest, CreateRequest, DeleteContainerRequest,
+    DeleteImageRequest, DeleteRequest, Image, UpdateImageRequest, WriteAction, WriteContentRequest,
+};
+use containerd_client::tonic::transport::Channel;
+use containerd_client::tonic::{Code, Request, Status};
+use containerd_client::types::Descriptor;
+use containerd_shim_wasm::container::{Engine, RuntimeContext, Stdio};
+use containerd_shim_wasm::sandbox::containerd::Client;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use prost_types::FieldMask;
+use tempfile::{tempdir, TempDir};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::runtime::Runtime as TokioRuntime;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/*
+    Benchmarks reading the layers of an image from a content store that is slow to answer,
+    one layer at a time and all of them at once, see `AsyncClient::with_read_concurrency`.
+
+    The benchmark needs a running containerd. The image is written to its content store,
+    and the shim reads it through a proxy that delays everything sent over the socket
+    by half of LATENCY in each direction, like a remote content store would.
+
+    The container runs the image under one of two manifests in turn, so that
+    each load misses the modules that the shim caches per container.
+*/
+
+const CONTAINERD_SOCKET: &str = "/run/containerd/containerd.sock";
+const NAMESPACE: &str = "runwasi-bench";
+const IMAGE_NAME: &str = "localhost/runwasi-bench/layer-reads:latest";
+const CONTAINER_ID: &str = "runwasi-bench-layer-reads";
+const LEASE_ID: &str = "runwasi-bench-layer-reads";
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const WASM_LAYER_MEDIA_TYPE: &str = "application/vnd.bytecodealliance.wasm.module.layer.v0+wasm";
+
+const LAYERS: usize = 8;
+const LAYER_SIZE: usize = 256 * 1024;
+const LATENCY: Duration = Duration::from_millis(10);
+
+#[derive(Clone)]
+struct BenchEngine;
+
+impl Engine for BenchEngine {
+    fn name() -> &'static str {
+        "bench"
+    }
+
+    fn run_wasi(&self, _ctx: &impl RuntimeContext, _stdio: Stdio) -> anyhow::Result<i32> {
+        Ok(0)
+    }
+}
+
+// The image and the container that runs it in containerd, which are removed when dropped.
+struct Fixture {
+    rt: TokioRuntime,
+    channel: Channel,
+    manifests: Vec<Descriptor>,
+    loads: usize,
+    proxy: PathBuf,
+    _dir: TempDir,
+}
+
+fn request<T>(message: T, lease: Option<&str>) -> Request<T> {
+    let mut req = Request::new(message);
+    let md = req.metadata_mut();
+    md.insert("containerd-namespace", NAMESPACE.parse().unwrap());
+    if let Some(lease) = lease {
+        md.insert("containerd-lease", lease.parse().unwrap());
+    }
+    req
+}
+
+// a wasm module with a custom section that pads it to `size` bytes
+fn wasm_layer(name: &str, size: usize) -> Vec<u8> {
+    fn leb128(mut n: usize, out: &mut Vec<u8>) {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    let mut payload = vec![];
+    leb128(name.len(), &mut payload);
+    payload.extend_from_slice(name.as_bytes());
+    payload.resize(payload.len() + size, 0);
+
+    let mut module = b"\0asm\x01\0\0\0\0".to_vec();
+    leb128(payload.len(), &mut module);
+    module.extend(payload);
+    module
+}
+
+// forwards what is read from `from` to `to`, each chunk `latency` after it was read
+async fn forward_delayed(mut from: OwnedReadHalf, mut to: OwnedWriteHalf, latency: Duration) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 64 * 1024];
+        while let Ok(n @ 1..) = from.read(&mut buf).await {
+            if tx
+                .send((Instant::now() + latency, buf[..n].to_vec()))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    while let Some((due, chunk)) = rx.recv().await {
+        tokio::time::sleep_until(due).await;
+        if to.write_all(&chunk).await.is_err() {
+            break;
+        }
+    }
+}
+
+// serves a socket at `path` that is forwarded to containerd with `latency` added to every round trip
+fn spawn_proxy(rt: &TokioRuntime, path: &Path, latency: Duration) {
+    let listener = rt.block_on(async { UnixListener::bind(path) }).unwrap();
+    rt.spawn(async move {
+        while let Ok((client, _)) = listener.accept().await {
+            let Ok(containerd) = UnixStream::connect(CONTAINERD_SOCKET).await else {
+                continue;
+            };
+            let (client_read, client_write) = client.into_split();
+            let (containerd_read, containerd_write) = containerd.into_split();
+            tokio::spawn(forward_delayed(client_read, containerd_write, latency / 2));
+            tokio::spawn(forward_delayed(containerd_read, client_write, latency / 2));
+        }
+    });
+}
+
+impl Fixture {
+    fn new() -> anyhow::Result<Self> {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let channel = rt.block_on(containerd_client::connect(CONTAINERD_SOCKET))?;
+        let dir = tempdir()?;
+        let mut fixture = Fixture {
+            rt,
+            channel,
+            manifests: vec![],
+            loads: 0,
+            proxy: dir.path().join("content.sock"),
+            _dir: dir,
+        };
+        // the last run may have been interrupted before it removed them
+        fixture.remove();
+
+        // the content is only referenced by the lease, so it is collected once the lease is deleted
+        fixture
+            .rt
+            .block_on(LeasesClient::new(fixture.channel.clone()).create(request(
+                CreateRequest {
+                    id: LEASE_ID.to_string(),
+                    ..Default::default()
+                },
+                None,
+            )))?;
+
+        let config = fixture.write_content(br#"{"architecture":"wasm","os":"wasip1"}"#.to_vec())?;
+        let layers = (0..LAYERS)
+            .map(|i| fixture.write_content(wasm_layer(&format!("layer-{i}"), LAYER_SIZE)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        fixture.manifests = (0..2)
+            .map(|i| fixture.write_manifest(i, &config, &layers))
+            .collect::<anyhow::Result<_>>()?;
+
+        let image = Image {
+            name: IMAGE_NAME.to_string(),
+            target: Some(fixture.manifests[0].clone()),
+            ..Default::default()
+        };
+        let container = Container {
+            id: CONTAINER_ID.to_string(),
+            image: IMAGE_NAME.to_string(),
+            runtime: Some(Runtime {
+                name: "io.containerd.wasmtime.v1".to_string(),
+                options: None,
+            }),
+            spec: Some(prost_types::Any {
+                type_url: "types.containerd.io/opencontainers/runtime-spec/1/Spec".to_string(),
+                value: b"{}".to_vec(),
+            }),
+            ..Default::default()
+        };
+        fixture.rt.block_on(async {
+            ImagesClient::new(fixture.channel.clone())
+                .create(request(CreateImageRequest { image: Some(image) }, None))
+                .await?;
+            ContainersClient::new(fixture.channel.clone())
+                .create(request(
+                    CreateContainerRequest {
+                        container: Some(container),
+                    },
+                    None,
+                ))
+                .await?;
+            anyhow::Ok(())
+        })?;
+
+        spawn_proxy(&fixture.rt, &fixture.proxy, LATENCY);
+        Ok(fixture)
+    }
+
+    // writes the content to the content store, and returns its digest and size
+    fn write_content(&self, data: Vec<u8>) -> anyhow::Result<(String, usize)> {
+        let digest = format!("sha256:{}", sha256::digest(data.as_slice()));
+        let size = data.len();
+        let req = WriteContentRequest {
+            action: WriteAction::Commit as i32,
+            r#ref: format!("runwasi-bench-{digest}"),
+            total: size as i64,
+            expected: digest.clone(),
+            data,
+            ..Default::default()
+        };
+        let written = self.rt.block_on(async {
+            let mut responses = ContentClient::new(self.channel.clone())
+                .write(request(tokio_stream::iter([req]), Some(LEASE_ID)))
+                .await?
+                .into_inner();
+            while responses.message().await?.is_some() {}
+            Ok::<_, Status>(())
+        });
+        match written {
+            Err(status) if status.code() != Code::AlreadyExists => Err(status.into()),
+            _ => Ok((digest, size)),
+        }
+    }
+
+    // writes a manifest of the layers, which is told apart from the other manifests by its `index`
+    fn write_manifest(
+        &self,
+        index: usize,
+        config: &(String, usize),
+        layers: &[(String, usize)],
+    ) -> anyhow::Result<Descriptor> {
+        let descriptor = |media_type: &str, (digest, size): &(String, usize)| serde_json::json!({ "mediaType": media_type, "digest": digest, "size": size });
+        let layers: Vec<_> = layers
+            .iter()
+            .map(|layer| descriptor(WASM_LAYER_MEDIA_TYPE, layer))
+            .collect();
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": MANIFEST_MEDIA_TYPE,
+            "config": descriptor("application/vnd.oci.image.config.v1+json", config),
+            "layers": layers,
+            "annotations": { "runwasi.io/bench-manifest": index.to_string() },
+        });
+        let (digest, size) = self.write_content(serde_json::to_vec(&manifest)?)?;
+        Ok(Descriptor {
+            media_type: MANIFEST_MEDIA_TYPE.to_string(),
+            digest,
+            size: size as i64,
+            ..Default::default()
+        })
+    }
+
+    // points the image at the other manifest, so that the shim doesn't have the modules of the container cached
+    fn switch_manifest(&mut self) -> anyhow::Result<()> {
+        self.loads += 1;
+        let image = Image {
+            name: IMAGE_NAME.to_string(),
+            target: Some(self.manifests[self.loads % 2].clone()),
+            ..Default::default()
+        };
+        let req = UpdateImageRequest {
+            image: Some(image),
+            update_mask: Some(FieldMask {
+                paths: vec!["target".to_string()],
+            }),
+        };
+        self.rt
+            .block_on(ImagesClient::new(self.channel.clone()).update(request(req, None)))?;
+        Ok(())
+    }
+
+    // removes the container, the image and the lease on its content, as far as they exist
+    fn remove(&self) {
+        let channel = self.channel.clone();
+        self.rt.block_on(async move {
+            let req = DeleteContainerRequest {
+                id: CONTAINER_ID.to_string(),
+            };
+            let _ = ContainersClient::new(channel.clone())
+                .delete(request(req, None))
+                .await;
+            let req = DeleteImageRequest {
+                name: IMAGE_NAME.to_string(),
+                sync: true,
+            };
+            let _ = ImagesClient::new(channel.clone())
+                .delete(request(req, None))
+                .await;
+            let req = DeleteRequest {
+                id: LEASE_ID.to_string(),
+                sync: true,
+            };
+            let _ = LeasesClient::new(channel).delete(request(req, None)).await;
+        });
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+fn bench_layer_reads(c: &mut Criterion) {
+    let mut fixture = Fixture::new().expect("the benchmark needs a running containerd");
+
+    let mut group = c.benchmark_group(format!("read {LAYERS} layers with {LATENCY:?} latency"));
+    for concurrency in [1, LAYERS] {
+        let client = Client::connect(CONTAINERD_SOCKET, NAMESPACE)
+            .and_then(|client| client.with_content_address(fixture.proxy.display()))
+            .unwrap()
+            .with_read_concurrency(concurrency);
+        group.bench_function(BenchmarkId::new("concurrency", concurrency), |b| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    fixture.switch_manifest().unwrap();
+                    let start = std::time::Instant::now();
+                    let (layers, _) = client.load_modules(CONTAINER_ID, &BenchEngine).unwrap();
+                    elapsed += start.elapsed();
+                    assert_eq!(layers.len(), LAYERS);
+                }
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_layer_reads
+}
+
+criterion_main!(benches);
@@ -0,0 +1,5 @@
+use std::fs::write;
+
+fn main() {
+    write("/created.txt", "created by the guest\n").unwrap();
+}
@@ -0,0 +1,8 @@
+use std::io::{stdout, Write};
+
+// Writes lines with CRLF line endings, as a guest built for windows would.
+fn main() {
+    let mut stdout = stdout().lock();
+    stdout.write_all(b"hello\r\nworld\r\n").unwrap();
+    stdout.flush().unwrap();
+}
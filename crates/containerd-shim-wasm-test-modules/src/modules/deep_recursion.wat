@@ -0,0 +1,18 @@
+(module
+    ;; Recurse a fixed number of times without tail calls, so that the
+    ;; amount of wasm stack used grows with the recursion depth.
+    (func $recurse (param $n i32) (result i32)
+        (if (result i32) (i32.eqz (local.get $n))
+            (then (i32.const 0))
+            (else
+                (i32.add
+                    (call $recurse (i32.sub (local.get $n) (i32.const 1)))
+                    (i32.const 1)
+                )
+            )
+        )
+    )
+    (func $main (export "_start")
+        (drop (call $recurse (i32.const 1000)))
+    )
+)
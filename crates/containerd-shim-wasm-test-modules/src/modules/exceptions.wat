@@ -0,0 +1,18 @@
+(module
+    ;; Exits with the payload of a caught exception, so that it can only run on engines with exception handling enabled.
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+    (tag $exit (param i32))
+    (memory 1)
+    (export "memory" (memory 0))
+    (func $main (export "_start")
+        (block $caught (result i32)
+            (try_table (catch $exit $caught)
+                i32.const 5
+                throw $exit
+            )
+            unreachable
+        )
+        call $proc_exit
+        unreachable
+    )
+)
@@ -0,0 +1,17 @@
+use std::fs::File;
+use std::io::Write;
+
+// Writes to /data until a write fails, and prints the error,
+// so that a size quota on /data shows up in the output.
+fn main() {
+    let mut file = File::create("/data/fill").unwrap();
+    let chunk = vec![0u8; 64 * 1024];
+    for _ in 0..256 {
+        if let Err(err) = file.write_all(&chunk) {
+            println!("write failed: {err}");
+            return;
+        }
+    }
+    println!("wrote 16MiB without an error");
+    std::process::exit(1);
+}
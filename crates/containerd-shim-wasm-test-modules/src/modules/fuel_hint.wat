@@ -0,0 +1,11 @@
+(module
+    ;; Suggests a fuel limit in its hints, so that its loop traps once the fuel runs out
+    (@custom "runwasi" "{\"fuel\": 100000}")
+
+    ;; Spins forever, unless the engine limits its fuel
+    (func $main (export "_start")
+        (loop $forever
+            (br $forever)
+        )
+    )
+)
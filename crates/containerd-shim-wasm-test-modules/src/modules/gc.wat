@@ -0,0 +1,13 @@
+(module
+    ;; Exits with a field of a GC struct, so that it can only run on engines with the GC proposal enabled.
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+    (type $point (struct (field i32) (field i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+    (func $main (export "_start")
+        (call $proc_exit
+            (struct.get $point 1
+                (struct.new $point (i32.const 3) (i32.const 7))))
+        unreachable
+    )
+)
@@ -0,0 +1,20 @@
+use std::fs::read_to_string;
+use std::thread::sleep;
+use std::time::Duration;
+
+fn main() {
+    println!("ready");
+
+    // wait until the shim forwards SIGUSR1 or SIGTERM
+    loop {
+        let signals = read_to_string("/run/runwasi/signals").unwrap_or_default();
+        if let Some(signal) = signals
+            .lines()
+            .find(|signal| ["SIGUSR1", "SIGTERM"].contains(signal))
+        {
+            println!("received {signal}");
+            return;
+        }
+        sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,11 @@
+(module
+    ;; Import a function that the engine links besides WASI, and exit with what it returns.
+    (import "host" "answer" (func $answer (result i32)))
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+    (func $main (export "_start")
+        (call $proc_exit (call $answer))
+        unreachable
+    )
+)
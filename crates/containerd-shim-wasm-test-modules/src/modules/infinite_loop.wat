@@ -0,0 +1,8 @@
+(module
+    ;; Spins forever, so that the guest only stops when it is killed
+    (func $main (export "_start")
+        (loop $forever
+            (br $forever)
+        )
+    )
+)
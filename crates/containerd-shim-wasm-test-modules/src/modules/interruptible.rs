@@ -0,0 +1,16 @@
+use std::hint::black_box;
+use std::io::{stdout, Write};
+
+// Writes its output, then spins until it is interrupted,
+// so that the output shows whether anything was lost when the guest stopped.
+fn main() {
+    let mut stdout = stdout().lock();
+    for i in 0..10_000 {
+        writeln!(stdout, "line {i:05} written before the interrupt").unwrap();
+    }
+    writeln!(stdout, "ready").unwrap();
+    stdout.flush().unwrap();
+    loop {
+        black_box(());
+    }
+}
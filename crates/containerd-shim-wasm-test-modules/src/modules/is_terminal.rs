@@ -0,0 +1,9 @@
+use std::io::{stdin, stdout, IsTerminal};
+
+fn main() {
+    println!(
+        "stdin: {}, stdout: {}",
+        stdin().is_terminal(),
+        stdout().is_terminal()
+    );
+}
@@ -0,0 +1,11 @@
+(module
+    ;; Exits with a value stored in a 64-bit memory, so that it can only run on engines with memory64 enabled.
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+    (memory i64 1)
+    (export "memory" (memory 0))
+    (func $main (export "_start")
+        (i32.store (i64.const 8) (i32.const 64))
+        (call $proc_exit (i32.load (i64.const 8)))
+        unreachable
+    )
+)
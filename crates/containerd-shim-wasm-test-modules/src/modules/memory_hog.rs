@@ -0,0 +1,9 @@
+// Keeps allocating, and touching, memory until the guest is killed,
+// so that it runs into the memory limit of its cgroup.
+fn main() {
+    let mut chunks = vec![];
+    for _ in 0..1024 {
+        chunks.push(vec![1u8; 1024 * 1024]);
+    }
+    println!("allocated {} MiB", chunks.len());
+}
@@ -0,0 +1,6 @@
+// Prints the arguments of the guest, one per line.
+fn main() {
+    for arg in std::env::args() {
+        println!("{arg}");
+    }
+}
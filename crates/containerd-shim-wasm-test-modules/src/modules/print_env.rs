@@ -0,0 +1,8 @@
+// Prints the environment variables of the guest, one per line, sorted by name.
+fn main() {
+    let mut vars: Vec<_> = std::env::vars().collect();
+    vars.sort();
+    for (key, value) in vars {
+        println!("{key}={value}");
+    }
+}
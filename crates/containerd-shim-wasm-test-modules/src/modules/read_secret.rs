@@ -0,0 +1,6 @@
+use std::fs::read_to_string;
+
+fn main() {
+    let secret = read_to_string("/secrets/token").expect("failed to read secret");
+    print!("{secret}");
+}
@@ -0,0 +1,15 @@
+(module
+    ;; Exits with a code computed with SIMD instructions, so that it can only run on engines with SIMD enabled.
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+    (func $main (export "_start")
+        ;; lane 2 of [1, 2, 3, 4] + [5, 6, 7, 8] is 10
+        (call $proc_exit
+            (i32x4.extract_lane 2
+                (i32x4.add
+                    (v128.const i32x4 1 2 3 4)
+                    (v128.const i32x4 5 6 7 8))))
+        unreachable
+    )
+)
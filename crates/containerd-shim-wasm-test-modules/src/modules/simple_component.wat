@@ -2,6 +2,11 @@
   (core module $m
       (func (export "thunk"))
       (func (export "thunk-trap") unreachable)
+      (func (export "add") (param i32 i32) (result i32)
+          local.get 0
+          local.get 1
+          i32.add
+      )
   )
   (core instance $i (instantiate $m))
   (func (export "thunk")
@@ -10,4 +15,7 @@
   (func (export "thunk-trap")
       (canon lift (core func $i "thunk-trap"))
   )
+  (func (export "add") (param "a" u32) (param "b" u32) (result u32)
+      (canon lift (core func $i "add"))
+  )
 )
\ No newline at end of file
@@ -0,0 +1,11 @@
+use std::io::{stdout, Write};
+
+// Writes enough output to fill the pipe and file buffers many times over,
+// so that any dropped or short write shows up in the captured output.
+fn main() {
+    let mut stdout = stdout().lock();
+    for i in 0..200_000 {
+        writeln!(stdout, "line {i:06} of the output written by the guest").unwrap();
+    }
+    stdout.flush().unwrap();
+}
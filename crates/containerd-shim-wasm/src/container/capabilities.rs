@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+
+use super::Engine;
+
+/// What the engine of a shim supports, e.g., so that a build tool can tell how to package images for a node.
+/// The `capabilities` subcommand of the shim prints it as JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// The name of the engine, see [`Engine::name`].
+    pub engine: String,
+    /// The version of the engine, see [`Engine::version`].
+    pub engine_version: String,
+    /// The media types of the layers that the engine runs, see [`Engine::supported_layers_types`].
+    pub layer_types: Vec<String>,
+    /// The platform features that the engine supports, or None if it doesn't check the features of images,
+    /// see [`Engine::supported_features`].
+    pub features: Option<Vec<String>>,
+    /// The wasm features that the engine can't run on this host, see [`Engine::disabled_wasm_features`].
+    pub disabled_wasm_features: Vec<String>,
+    /// The wasm features that a container can enable, see [`Engine::optional_wasm_features`].
+    pub optional_wasm_features: Vec<String>,
+    /// The label that points an image at the module that the engine precompiled from it,
+    /// or None if the engine doesn't precompile modules.
+    pub precompile_label: Option<String>,
+}
+
+impl Capabilities {
+    /// Returns the capabilities that `engine` declares.
+    pub fn of<E: Engine>(engine: &E) -> Self {
+        let to_strings = |items: &[&str]| items.iter().map(ToString::to_string).collect();
+        #[cfg(unix)]
+        let precompile_label = engine
+            .can_precompile()
+            .map(|version| crate::sandbox::containerd::engine_precompile_label(engine, &version));
+        #[cfg(not(unix))]
+        let precompile_label = None;
+        Self {
+            engine: E::name().to_string(),
+            engine_version: E::version(),
+            layer_types: to_strings(E::supported_layers_types()),
+            features: E::supported_features().map(to_strings),
+            disabled_wasm_features: engine
+                .disabled_wasm_features()
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            optional_wasm_features: engine
+                .optional_wasm_features()
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            precompile_label,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container::{RuntimeContext, Stdio, WasmFeature};
+
+    #[derive(Clone)]
+    struct DeclaringEngine;
+
+    impl Engine for DeclaringEngine {
+        fn name() -> &'static str {
+            "declaring"
+        }
+        fn version() -> String {
+            "1.2.3".to_string()
+        }
+        fn run_wasi(&self, _ctx: &impl RuntimeContext, _stdio: Stdio) -> anyhow::Result<i32> {
+            Ok(0)
+        }
+        fn supported_layers_types() -> &'static [&'static str] {
+            &["application/vnd.example.layer"]
+        }
+        fn supported_features() -> Option<&'static [&'static str]> {
+            Some(&["wasi:cli/run"])
+        }
+        fn disabled_wasm_features(&self) -> Vec<WasmFeature> {
+            vec![WasmFeature::Threads, WasmFeature::Gc]
+        }
+        fn optional_wasm_features(&self) -> Vec<WasmFeature> {
+            vec![WasmFeature::Gc]
+        }
+        fn can_precompile(&self) -> Option<String> {
+            Some(Self::version())
+        }
+    }
+
+    #[test]
+    fn test_capabilities() -> anyhow::Result<()> {
+        let capabilities = Capabilities::of(&DeclaringEngine);
+        assert_eq!(
+            capabilities,
+            Capabilities {
+                engine: "declaring".to_string(),
+                engine_version: "1.2.3".to_string(),
+                layer_types: vec!["application/vnd.example.layer".to_string()],
+                features: Some(vec!["wasi:cli/run".to_string()]),
+                disabled_wasm_features: vec!["threads".to_string(), "gc".to_string()],
+                optional_wasm_features: vec!["gc".to_string()],
+                #[cfg(unix)]
+                precompile_label: Some("runwasi.io/precompiled/declaring/1.2.3".to_string()),
+                #[cfg(not(unix))]
+                precompile_label: None,
+            }
+        );
+
+        // the JSON that the `capabilities` subcommand prints
+        let json = serde_json::to_value(&capabilities)?;
+        assert_eq!(json["engine"], "declaring");
+        assert_eq!(json["layer_types"][0], "application/vnd.example.layer");
+        assert_eq!(json["optional_wasm_features"][0], "gc");
+
+        Ok(())
+    }
+}
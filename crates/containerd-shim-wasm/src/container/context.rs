@@ -1,11 +1,19 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::{bail, Context};
 use oci_spec::image::Platform;
 use oci_spec::runtime::Spec;
 
+use crate::container::engine::parse_interrupt_timeout;
 use crate::container::path::PathResolve;
+use crate::container::wasm::{parse_wasm_features, platform_wasm_features};
+use crate::container::{
+    WasiVersion, WasmBinaryType, WasmFeature, INTERRUPT_TIMEOUT_ANNOTATION,
+    WASM_FEATURES_ANNOTATION,
+};
 use crate::sandbox::oci::WasmLayer;
 
 pub trait RuntimeContext {
@@ -22,7 +30,7 @@ pub trait RuntimeContext {
     //     If the image contains custom OCI Wasm layers, the source is provided as an array of `WasmLayer` structs.
     //
     // The first argument in the OCI spec for entrypoint is specified as `path#func` where `func` is optional
-    // and defaults to _start, e.g.:
+    // and defaults to _start, see `parse_entrypoint` for the details, e.g.:
     //   "/app/app.wasm#entry" -> { source: File("/app/app.wasm"), func: "entry", name: "Some(app)", arg0: "/app/app.wasm#entry" }
     //   "my_module.wat" -> { source: File("my_module.wat"), func: "_start", name: "Some(my_module)", arg0: "my_module.wat" }
     //   "#init" -> { source: File(""), func: "init", name: None, arg0: "#init" }
@@ -31,6 +39,39 @@ pub trait RuntimeContext {
     // the platform for the container using the struct defined on the OCI spec definition
     // https://github.com/opencontainers/image-spec/blob/v1.1.0-rc5/image-index.md
     fn platform(&self) -> &Platform;
+
+    // ctx.annotations() returns the annotations from the runtime spec, if any.
+    // Engines can use these for per-container configuration.
+    // Contexts that aren't backed by a runtime spec have none.
+    fn annotations(&self) -> Option<&HashMap<String, String>> {
+        None
+    }
+
+    // ctx.annotation(key) returns the value of a single annotation from the runtime spec.
+    fn annotation(&self, key: &str) -> Option<&str> {
+        self.annotations()?.get(key).map(String::as_str)
+    }
+
+    // ctx.wasm_features() returns the wasm features that the container enables with the
+    // `WASM_FEATURES_ANNOTATION` annotation, which the engine must run the guest with.
+    // The shim only creates the container if they are in `Engine::optional_wasm_features`.
+    // The memory64 feature is enabled for the images whose platform is `wasm64` too.
+    fn wasm_features(&self) -> Vec<WasmFeature> {
+        let features = self
+            .annotation(WASM_FEATURES_ANNOTATION)
+            .and_then(|features| parse_wasm_features(features).ok())
+            .unwrap_or_default();
+        platform_wasm_features(&features, self.platform())
+    }
+
+    // ctx.interrupt_timeout() returns how long the engine has to return after the guest is interrupted,
+    // from the `INTERRUPT_TIMEOUT_ANNOTATION` annotation, or None if the guest isn't interrupted when
+    // the container is stopped, in which case the engine doesn't need to make the guest interruptible.
+    fn interrupt_timeout(&self) -> anyhow::Result<Option<Duration>> {
+        self.annotation(INTERRUPT_TIMEOUT_ANNOTATION)
+            .map(parse_interrupt_timeout)
+            .transpose()
+    }
 }
 
 /// The source for a WASI module / components.
@@ -64,6 +105,33 @@ impl<'a> Source<'a> {
             }
         }
     }
+
+    /// Returns whether the source is a wasm module or a component, as tagged by its OCI layer media type.
+    /// Returns None for file sources, and for layers whose type couldn't be determined from the image.
+    pub fn binary_type(&self) -> Option<WasmBinaryType> {
+        match self {
+            Source::Oci([module]) => module.binary_type,
+            _ => None,
+        }
+    }
+
+    /// Returns the version of WASI the source targets, as tagged by its OCI layer.
+    /// Returns None for file sources, and for layers whose type couldn't be determined from the image.
+    pub fn wasi_version(&self) -> Option<WasiVersion> {
+        match self {
+            Source::Oci([module]) => module.wasi_version,
+            _ => None,
+        }
+    }
+
+    /// Returns the digest of a precompiled module, which it was warmed with, see [`Engine::warm`](super::Engine::warm).
+    /// Returns None for file sources, and for wasm modules and components.
+    pub fn digest(&self) -> Option<&str> {
+        match self {
+            Source::Oci([module]) if module.binary_type.is_none() => Some(module.config.digest()),
+            _ => None,
+        }
+    }
 }
 
 /// The entrypoint for a WASI module / component.
@@ -75,6 +143,63 @@ pub struct Entrypoint<'a> {
     pub source: Source<'a>,
 }
 
+/// Splits the entrypoint argument `path#func` into the path of the module and the name of the exported function.
+///
+/// The argument is split on the last `#`, so that the path can contain a `#` itself.
+/// A `#` followed by a `/` is part of the path, since function names can't contain a `/`.
+/// The function is None when there is no `#`, or nothing after it, and the default function should be used.
+pub fn parse_entrypoint(arg: &str) -> (&str, Option<&str>) {
+    match arg.rsplit_once('#') {
+        Some((_, func)) if func.contains('/') => (arg, None),
+        Some((path, "")) => (path, None),
+        Some((path, func)) => (path, Some(func)),
+        None => (arg, None),
+    }
+}
+
+/// Sets the function that the entrypoint of the spec names, keeping the path of the module.
+#[cfg_attr(windows, allow(dead_code))]
+pub(crate) fn set_start_function(spec: &mut Spec, func: &str) {
+    let Some(mut process) = spec.process().clone() else {
+        return;
+    };
+    let Some(mut args) = process.args().clone() else {
+        return;
+    };
+    if let Some(arg0) = args.first_mut() {
+        let (path, _) = parse_entrypoint(arg0);
+        *arg0 = format!("{path}#{func}");
+        process.set_args(Some(args));
+        spec.set_process(Some(process));
+    }
+}
+
+/// Returns the function that the container runs from the [`ENTRYPOINT_ANNOTATION`](crate::sandbox::oci::ENTRYPOINT_ANNOTATION)
+/// of the manifest of its image, unless the runtime spec names one itself.
+///
+/// The entrypoint of the runtime spec is the one of the image config, unless the runtime overrides it,
+/// so a function in an entrypoint equal to `config_arg0` was named by the image config,
+/// and the manifest takes precedence over it.
+#[cfg_attr(windows, allow(dead_code))]
+pub(crate) fn manifest_start_function(
+    spec: &Spec,
+    manifest_function: Option<String>,
+    config_arg0: Option<&str>,
+) -> Option<String> {
+    let manifest_function = manifest_function.filter(|func| !func.is_empty())?;
+    let arg0 = spec
+        .process()
+        .as_ref()
+        .and_then(|process| process.args().as_ref())
+        .and_then(|args| args.first());
+    match arg0 {
+        Some(arg0) if parse_entrypoint(arg0).1.is_some() && config_arg0 != Some(arg0.as_str()) => {
+            None
+        }
+        _ => Some(manifest_function),
+    }
+}
+
 pub(crate) struct WasiContext<'a> {
     pub spec: &'a Spec,
     pub wasm_layers: &'a [WasmLayer],
@@ -95,9 +220,8 @@ impl RuntimeContext for WasiContext<'_> {
         let arg0 = self.args().first();
 
         let entry_point = arg0.map(String::as_str).unwrap_or("");
-        let (path, func) = entry_point
-            .split_once('#')
-            .unwrap_or((entry_point, "_start"));
+        let (path, func) = parse_entrypoint(entry_point);
+        let func = func.unwrap_or("_start");
 
         let source = if self.wasm_layers.is_empty() {
             Source::File(PathBuf::from(path))
@@ -120,6 +244,10 @@ impl RuntimeContext for WasiContext<'_> {
     fn platform(&self) -> &Platform {
         self.platform
     }
+
+    fn annotations(&self) -> Option<&HashMap<String, String>> {
+        self.spec.annotations().as_ref()
+    }
 }
 
 #[cfg(test)]
@@ -130,6 +258,55 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_manifest_start_function() -> Result<()> {
+        let spec = |arg0: &str| -> Result<Spec> {
+            Ok(SpecBuilder::default()
+                .process(
+                    ProcessBuilder::default()
+                        .args(vec![arg0.to_string()])
+                        .build()?,
+                )
+                .build()?)
+        };
+        let manifest = || Some("manifest".to_string());
+
+        // the runtime spec names the function
+        let func = manifest_start_function(
+            &spec("app.wasm#runtime")?,
+            manifest(),
+            Some("app.wasm#config"),
+        );
+        assert_eq!(func, None);
+
+        // the manifest takes precedence over the image config
+        let func = manifest_start_function(
+            &spec("app.wasm#config")?,
+            manifest(),
+            Some("app.wasm#config"),
+        );
+        assert_eq!(func.as_deref(), Some("manifest"));
+        let func = manifest_start_function(&spec("app.wasm")?, manifest(), None);
+        assert_eq!(func.as_deref(), Some("manifest"));
+
+        // the function of the image config is used without the annotation
+        let func =
+            manifest_start_function(&spec("app.wasm#config")?, None, Some("app.wasm#config"));
+        assert_eq!(func, None);
+
+        let mut spec = spec("app.wasm#config")?;
+        set_start_function(&mut spec, "manifest");
+        let ctx = WasiContext {
+            spec: &spec,
+            wasm_layers: &[],
+            platform: &Platform::default(),
+        };
+        assert_eq!(ctx.entrypoint().func, "manifest");
+        assert_eq!(ctx.args(), ["app.wasm#manifest"]);
+
+        Ok(())
+    }
+
     #[test]
     fn test_get_args() -> Result<()> {
         let spec = SpecBuilder::default()
@@ -359,6 +536,8 @@ mod tests {
             wasm_layers: &[WasmLayer {
                 layer: vec![],
                 config: Descriptor::new(oci_spec::image::MediaType::Other("".to_string()), 10, ""),
+                binary_type: None,
+                wasi_version: None,
             }],
             platform: &Platform::default(),
         };
@@ -367,4 +546,86 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_get_annotations() -> Result<()> {
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .process(ProcessBuilder::default().cwd("/").args(vec![]).build()?)
+            .annotations(HashMap::from([(
+                "runwasi.io/max-wasm-stack".to_string(),
+                "65536".to_string(),
+            )]))
+            .build()?;
+
+        let ctx = WasiContext {
+            spec: &spec,
+            wasm_layers: &[],
+            platform: &Platform::default(),
+        };
+
+        assert_eq!(ctx.annotation("runwasi.io/max-wasm-stack"), Some("65536"));
+        assert_eq!(ctx.annotation("runwasi.io/missing"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_entrypoint() {
+        assert_eq!(parse_entrypoint(""), ("", None));
+        assert_eq!(parse_entrypoint("hello.wasm"), ("hello.wasm", None));
+        assert_eq!(
+            parse_entrypoint("hello.wasm#foo"),
+            ("hello.wasm", Some("foo"))
+        );
+        assert_eq!(parse_entrypoint("#init"), ("", Some("init")));
+
+        // an empty function means the default one
+        assert_eq!(parse_entrypoint("hello.wasm#"), ("hello.wasm", None));
+
+        // paths can contain a `#`
+        assert_eq!(
+            parse_entrypoint("/app#1/hello.wasm#foo"),
+            ("/app#1/hello.wasm", Some("foo"))
+        );
+        assert_eq!(
+            parse_entrypoint("/app#1/hello.wasm"),
+            ("/app#1/hello.wasm", None)
+        );
+        assert_eq!(
+            parse_entrypoint("/app/hello#1.wasm#"),
+            ("/app/hello#1.wasm", None)
+        );
+    }
+
+    #[test]
+    fn test_get_module_with_hash_in_path() -> Result<()> {
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .process(
+                ProcessBuilder::default()
+                    .cwd("/")
+                    .args(vec!["/app#1/hello.wasm#".to_string()])
+                    .build()?,
+            )
+            .build()?;
+
+        let ctx = WasiContext {
+            spec: &spec,
+            wasm_layers: &[],
+            platform: &Platform::default(),
+        };
+
+        let Entrypoint {
+            name, func, source, ..
+        } = ctx.entrypoint();
+        assert_eq!(name, Some("hello".to_string()));
+        assert_eq!(func, "_start");
+        assert!(matches!(
+            source,
+            Source::File(p) if p == Path::new("/app#1/hello.wasm")
+        ));
+
+        Ok(())
+    }
 }
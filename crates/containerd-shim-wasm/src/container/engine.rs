@@ -1,19 +1,63 @@
 use std::fs::File;
 use std::io::Read;
+use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
+use oci_spec::image::ImageManifest;
 
-use super::Source;
+use super::{Source, WasmFeature};
 use crate::container::{PathResolve, RuntimeContext};
+use crate::sandbox::oci::{WASM_COMPONENT_LAYER_MEDIA_TYPE, WASM_MODULE_LAYER_MEDIA_TYPE};
 use crate::sandbox::Stdio;
 
+/// Annotation to interrupt the guest with [`Engine::interrupt`] when its container is stopped with `SIGTERM` or `SIGINT`,
+/// with how long the engine then has to return before the container exits anyway, in seconds, e.g., `5`.
+/// Without it, the signal terminates the container, and the engine doesn't have to make the guest interruptible,
+/// which slows the guest down.
+pub const INTERRUPT_TIMEOUT_ANNOTATION: &str = "runwasi.io/interrupt-timeout";
+
+/// An update on a precompile in progress, see [`Engine::precompile_with_progress`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrecompileProgress {
+    /// The precompile started.
+    Started,
+    /// The engine entered a phase of the precompile, e.g., `compiling functions`,
+    /// and is done with the given percentage of the whole precompile, if it knows.
+    Phase { name: String, percent: Option<u8> },
+    /// The precompile finished.
+    Finished,
+}
+
 pub trait Engine: Clone + Send + Sync + 'static {
     /// The name to use for this engine
     fn name() -> &'static str;
 
+    /// The version of this engine, e.g., the version of the wasm runtime it embeds.
+    /// This is reported by the shim's `--version` flag.
+    /// Engines that support precompilation should include it in the string returned by `can_precompile`,
+    /// so that upgrading the engine invalidates the cached modules.
+    /// The default implementation returns "unknown".
+    fn version() -> String {
+        "unknown".to_string()
+    }
+
     /// Run a WebAssembly container
     fn run_wasi(&self, ctx: &impl RuntimeContext, stdio: Stdio) -> Result<i32>;
 
+    /// Rewrites the arguments that the guest is run with, e.g., to prepend a flag without changing the image.
+    /// It is given the arguments after the entrypoint, which selects the module to run and is kept as it is.
+    /// The default implementation returns the arguments unchanged.
+    fn transform_args(&self, args: Vec<String>) -> Vec<String> {
+        args
+    }
+
+    /// Interrupts the guest that [`Engine::run_wasi`] runs, when its container is stopped with `SIGTERM` or `SIGINT`
+    /// and sets the [`INTERRUPT_TIMEOUT_ANNOTATION`], e.g., by making the engine trap at the next safe point,
+    /// so that `run_wasi` returns and the output of the guest is flushed before the container exits.
+    /// This is called from another thread than `run_wasi`.
+    /// The default implementation does nothing, and the container exits once the timeout passes.
+    fn interrupt(&self) {}
+
     /// Check that the runtime can run the container.
     /// This checks runs after the container creation and before the container starts.
     /// By it checks that the wasi_entrypoint is either:
@@ -43,14 +87,64 @@ pub trait Engine: Clone + Send + Sync + 'static {
         Ok(())
     }
 
+    /// Hook that runs after the container has been created and before it is started.
+    /// Shims can override this to run their own setup for the container,
+    /// e.g., to configure its network or to inject devices.
+    /// If this returns an error, the container is deleted and its creation fails.
+    /// The default implementation does nothing.
+    #[cfg(unix)]
+    fn post_create(&self, _container: &super::Container) -> Result<()> {
+        Ok(())
+    }
+
     /// Return the supported OCI layer types
     /// This is used to filter only layers that are supported by the runtime.
-    /// The default implementation returns the OCI layer type 'application/vnd.bytecodealliance.wasm.component.layer.v0+wasm'
-    /// for WASM modules which can be contain with wasip1 or wasip2 components.
+    /// The default implementation returns the OCI layer types 'application/vnd.bytecodealliance.wasm.component.layer.v0+wasm'
+    /// for WASM components and 'application/vnd.bytecodealliance.wasm.module.layer.v0+wasm' for WASM modules.
+    /// Each layer is tagged as a module or a component based on its media type, see `WasmLayer::binary_type`.
     /// Runtimes can override this to support other layer types
     /// such as lays that contain runtime specific configuration
     fn supported_layers_types() -> &'static [&'static str] {
-        &["application/vnd.bytecodealliance.wasm.component.layer.v0+wasm"]
+        &[
+            WASM_COMPONENT_LAYER_MEDIA_TYPE,
+            WASM_MODULE_LAYER_MEDIA_TYPE,
+        ]
+    }
+
+    /// Return the platform features that the runtime supports.
+    /// Images can declare the host features they require in the `os.features` field of their config,
+    /// for example the wasi worlds they target.
+    /// When this returns Some, the shim refuses to run images that require a feature that isn't in the list.
+    /// The default implementation returns None, and the features of the image are not validated.
+    fn supported_features() -> Option<&'static [&'static str]> {
+        None
+    }
+
+    /// Verify_image checks the manifest of the image of a container before its modules are loaded,
+    /// e.g., that the image is signed, or has the annotations that the operator requires.
+    /// If it returns an error the container is not created, and fails with `Error::VerificationFailed`.
+    /// The default implementation accepts every image.
+    fn verify_image(&self, _manifest: &ImageManifest) -> Result<()> {
+        Ok(())
+    }
+
+    /// Return the wasm features that the engine can't run on this host,
+    /// e.g., because they are disabled in its configuration, or the CPU lacks the instructions they need.
+    /// Before the container is created, its modules are checked for these features, and a module that
+    /// requires one of them fails with `Error::UnsupportedFeature` rather than crashing when it runs.
+    /// The default implementation returns an empty list, and the modules are not checked.
+    fn disabled_wasm_features(&self) -> Vec<WasmFeature> {
+        vec![]
+    }
+
+    /// Return the wasm features that the engine disables by default, but can enable for a container
+    /// that requests them with the `WASM_FEATURES_ANNOTATION` annotation, see [`RuntimeContext::wasm_features`].
+    /// A container that requests any other feature fails with `Error::UnsupportedFeature`.
+    /// The modules of a container that enables features are not precompiled, as they are precompiled
+    /// with the default configuration of the engine.
+    /// The default implementation returns an empty list.
+    fn optional_wasm_features(&self) -> Vec<WasmFeature> {
+        vec![]
     }
 
     /// Precompiles a module that is in the WASM OCI layer format
@@ -61,6 +155,46 @@ pub trait Engine: Clone + Send + Sync + 'static {
         bail!("precompilation not supported for this runtime")
     }
 
+    /// Like `precompile`, but reports the progress of the precompile to `progress`,
+    /// so that a slow precompile of a large module can be told apart from one that hangs.
+    /// The shim logs the updates.
+    /// The default implementation calls `precompile`, and only reports when it starts and finishes.
+    fn precompile_with_progress(
+        &self,
+        layers: &[Vec<u8>],
+        progress: &dyn Fn(PrecompileProgress),
+    ) -> Result<Vec<u8>> {
+        progress(PrecompileProgress::Started);
+        let precompiled = self.precompile(layers)?;
+        progress(PrecompileProgress::Finished);
+        Ok(precompiled)
+    }
+
+    /// Warm prepares a precompiled module ahead of the first container that runs it,
+    /// e.g., by deserializing it into an in-memory cache of the engine, so that the container starts faster.
+    /// It is called for the precompiled modules of an image when the container is created,
+    /// if the `warm_precompiled` option of the shim is set.  The default implementation does nothing.
+    ///
+    /// The containers that run the same precompiled module warm it with the same `digest`,
+    /// so that the engine can share a single deserialized module between them.
+    /// Each call is matched by a call to `release` when the container is deleted.
+    fn warm(&self, _digest: &str, _precompiled: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Release tells the engine that a container that warmed the precompiled module with `digest` was deleted,
+    /// so that the engine can drop the module once no container uses it anymore.
+    /// The default implementation does nothing.
+    fn release(&self, _digest: &str) {}
+
+    /// Validate_precompiled checks that the output of `precompile` can be loaded by the runtime.
+    /// It is called before the precompiled module is cached in the containerd content store.
+    /// If it returns an error the output is not cached, and the module in the OCI layers is used instead.
+    /// Empty output is always rejected.  The default implementation accepts any other output.
+    fn validate_precompiled(&self, _precompiled: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
     /// Can_precompile lets the shim know if the runtime supports precompilation.
     /// When it returns Some(unique_string) the `unique_string` will be used as a cache key for the precompiled module.
     ///
@@ -75,4 +209,34 @@ pub trait Engine: Clone + Send + Sync + 'static {
     fn can_precompile(&self) -> Option<String> {
         None
     }
+
+    /// Precompile_config_hash returns a hash of the effective configuration of the engine,
+    /// covering any setting that affects the precompiled modules.
+    /// When it returns Some(config_hash) the hash is added to the cache key of the precompiled module:
+    /// "runwasi.io/precompiled/<Engine.name()>/<unique_string>/<config_hash>"
+    ///
+    /// This way, changing the configuration of the engine invalidates the cached modules, and they are recompiled,
+    /// even if the version of the shim is unchanged.
+    ///
+    /// When it returns None only the `unique_string` from `can_precompile` is used.  This is the default value.
+    fn precompile_config_hash(&self) -> Option<String> {
+        None
+    }
+
+    /// Precompiled_media_type returns the media type of the precompiled modules.
+    /// It is stored in the "runwasi.io/media-type" label of the cached content, so that tools
+    /// that export the content store can recognize the precompiled artifacts.
+    ///
+    /// The default value is "application/vnd.wasm.precompiled.<Engine.name()>".
+    fn precompiled_media_type(&self) -> String {
+        format!("application/vnd.wasm.precompiled.{}", Self::name())
+    }
+}
+
+/// Parses the value of the [`INTERRUPT_TIMEOUT_ANNOTATION`].
+pub(crate) fn parse_interrupt_timeout(timeout: &str) -> Result<Duration> {
+    let secs = timeout.parse().with_context(|| {
+        format!("invalid {INTERRUPT_TIMEOUT_ANNOTATION} annotation {timeout:?}")
+    })?;
+    Ok(Duration::from_secs(secs))
 }
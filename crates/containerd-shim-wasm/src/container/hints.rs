@@ -0,0 +1,176 @@
+//! Modules can ship defaults for how they run, in a `runwasi` custom section with a JSON object of hints,
+//! e.g., `{"fuel": 1000000, "entrypoint": "main"}`.
+//!
+//! When the `module_hints` option of the shim is set, the hints that the shim recognizes are applied
+//! to the spec of the container, unless the spec already sets them:
+//! * `fuel`: the fuel that the guest may consume, as the [`FUEL_ANNOTATION`]
+//! * `entrypoint`: the function to run, when the entrypoint of the container doesn't name one
+//!
+//! Other keys are ignored.
+
+use oci_spec::runtime::Spec;
+use serde::Deserialize;
+use wasmparser::{Parser, Payload};
+
+use super::{parse_entrypoint, set_start_function, RuntimeContext, WasiContext, WasmBinaryType};
+
+/// Annotation to limit the fuel that the guest may consume before it traps, for engines that meter fuel.
+pub const FUEL_ANNOTATION: &str = "runwasi.io/fuel";
+
+const HINTS_SECTION: &str = "runwasi";
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+struct ModuleHints {
+    fuel: Option<u64>,
+    entrypoint: Option<String>,
+}
+
+/// Returns the hints in the `runwasi` custom section of a wasm module or component,
+/// or None if it has no such section, or the bytes aren't a wasm binary, e.g., because they are a precompiled module.
+#[cfg_attr(windows, allow(dead_code))]
+fn module_hints(bytes: &[u8]) -> Option<ModuleHints> {
+    WasmBinaryType::from_bytes(bytes)?;
+    // the sections of a nested module or component follow its section, up to its own `End`
+    let mut depth = 0;
+    for payload in Parser::new(0).parse_all(bytes) {
+        match payload.ok()? {
+            Payload::ModuleSection { .. } | Payload::ComponentSection { .. } => depth += 1,
+            Payload::End(_) => depth -= 1,
+            Payload::CustomSection(reader) if depth == 0 && reader.name() == HINTS_SECTION => {
+                return match serde_json::from_slice(reader.data()) {
+                    Ok(hints) => Some(hints),
+                    Err(err) => {
+                        log::warn!("ignoring invalid {HINTS_SECTION} custom section: {err}");
+                        None
+                    }
+                };
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns whether a wasm module or component has a fuel hint, which makes its container meter fuel.
+#[cfg_attr(windows, allow(dead_code))]
+pub(crate) fn has_fuel_hint(bytes: &[u8]) -> bool {
+    module_hints(bytes).is_some_and(|hints| hints.fuel.is_some())
+}
+
+/// Returns the spec of the container, with the hints of the module it runs applied as defaults.
+/// The spec is returned unchanged if the module can't be read, or has no hints.
+#[cfg_attr(windows, allow(dead_code))]
+pub(crate) fn apply_module_hints(ctx: &WasiContext) -> Spec {
+    let mut spec = ctx.spec.clone();
+    let hints = ctx
+        .entrypoint()
+        .source
+        .as_bytes()
+        .ok()
+        .and_then(|bytes| module_hints(&bytes));
+    let Some(hints) = hints else {
+        return spec;
+    };
+    log::info!("applying the hints of the module: {hints:?}");
+
+    if let Some(fuel) = hints.fuel {
+        let mut annotations = spec.annotations().clone().unwrap_or_default();
+        annotations
+            .entry(FUEL_ANNOTATION.to_string())
+            .or_insert_with(|| fuel.to_string());
+        spec.set_annotations(Some(annotations));
+    }
+    if let Some(func) = hints.entrypoint {
+        let names_function = ctx
+            .args()
+            .first()
+            .is_some_and(|arg0| parse_entrypoint(arg0).1.is_some());
+        if !names_function {
+            set_start_function(&mut spec, &func);
+        }
+    }
+    spec
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use oci_spec::image::Platform;
+    use oci_spec::runtime::{ProcessBuilder, SpecBuilder};
+
+    use super::*;
+    use crate::sandbox::oci::WasmLayer;
+
+    fn module_with_hints(hints: &str) -> Vec<u8> {
+        let hints = hints.replace('\\', "\\\\").replace('"', "\\\"");
+        wat::parse_str(format!(
+            r#"(module (@custom "runwasi" "{hints}") (func (export "main")))"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_module_hints() {
+        let module = module_with_hints(r#"{"fuel": 1000, "entrypoint": "main", "unknown": true}"#);
+        assert_eq!(
+            module_hints(&module),
+            Some(ModuleHints {
+                fuel: Some(1000),
+                entrypoint: Some("main".to_string()),
+            })
+        );
+
+        let module = module_with_hints("not json");
+        assert_eq!(module_hints(&module), None);
+
+        let module = wat::parse_str("(module)").unwrap();
+        assert_eq!(module_hints(&module), None);
+    }
+
+    #[test]
+    fn test_apply_module_hints() {
+        let layers = [WasmLayer::from_module(module_with_hints(
+            r#"{"fuel": 1000, "entrypoint": "main"}"#,
+        ))];
+        let platform = Platform::default();
+        let spec = |arg0: &str, annotations: HashMap<String, String>| {
+            SpecBuilder::default()
+                .process(
+                    ProcessBuilder::default()
+                        .args(vec![arg0.to_string()])
+                        .build()
+                        .unwrap(),
+                )
+                .annotations(annotations)
+                .build()
+                .unwrap()
+        };
+
+        let original = spec("module.wasm", HashMap::new());
+        let ctx = WasiContext {
+            spec: &original,
+            wasm_layers: &layers,
+            platform: &platform,
+        };
+        let applied = apply_module_hints(&ctx);
+        assert_eq!(
+            applied.annotations().as_ref().unwrap()[FUEL_ANNOTATION],
+            "1000"
+        );
+        assert_eq!(
+            applied.process().as_ref().unwrap().args().as_ref().unwrap(),
+            &["module.wasm#main"]
+        );
+
+        // the spec of the container overrides the hints
+        let annotations = HashMap::from([(FUEL_ANNOTATION.to_string(), "42".to_string())]);
+        let original = spec("module.wasm#other", annotations);
+        let ctx = WasiContext {
+            spec: &original,
+            wasm_layers: &layers,
+            platform: &platform,
+        };
+        assert_eq!(apply_module_hints(&ctx), original);
+    }
+}
@@ -0,0 +1,224 @@
+//! The layers of an image are run in the order of its manifest by default.
+//! Images with several components whose manifest doesn't list them in the order of their dependencies
+//! can set the [`LAYER_ORDER_ANNOTATION`], and the engine gets their layers in that order instead:
+//!
+//! * `dependencies` sorts the layers so that each component follows the components that export
+//!   the names that it imports, e.g., `example:lib/math`. Layers without dependencies between them
+//!   keep the order of the manifest.
+//! * a comma separated list of the indices of the layers in the manifest, e.g., `1,0`, declares the order.
+//!
+//! The layers are ordered before they are handed to the executor, so an image whose layers are ordered
+//! isn't precompiled, as the precompiled module would be compiled from the layers in the order of the manifest.
+
+use oci_spec::runtime::Spec;
+use wasmparser::{Parser, Payload};
+
+use super::WasmBinaryType;
+use crate::sandbox::{Error, WasmLayer};
+
+/// Annotation with the order in which the layers of the image are run: `manifest`, the default,
+/// `dependencies`, or the comma separated indices of the layers in the manifest.
+pub const LAYER_ORDER_ANNOTATION: &str = "runwasi.io/layer-order";
+
+/// Returns whether the spec runs the layers of the image in an order other than the one of the manifest.
+#[cfg_attr(windows, allow(dead_code))]
+pub(crate) fn reorders_layers(spec: &Spec) -> bool {
+    layer_order(spec).is_some_and(|order| order != "manifest")
+}
+
+/// Returns the layers in the order that the [`LAYER_ORDER_ANNOTATION`] of the spec asks for.
+/// Fails if the annotation is invalid, or the layers depend on each other in a cycle.
+#[cfg_attr(windows, allow(dead_code))]
+pub(crate) fn order_layers(spec: &Spec, layers: Vec<WasmLayer>) -> Result<Vec<WasmLayer>, Error> {
+    let order = match layer_order(spec) {
+        None | Some("manifest") => return Ok(layers),
+        Some("dependencies") => dependency_order(&layers)?,
+        Some(order) => declared_order(order, layers.len())?,
+    };
+    log::info!("running the layers of the image in the order {order:?}");
+
+    let mut layers: Vec<_> = layers.into_iter().map(Some).collect();
+    Ok(order
+        .into_iter()
+        .filter_map(|index| layers[index].take())
+        .collect())
+}
+
+fn layer_order(spec: &Spec) -> Option<&str> {
+    spec.annotations()
+        .as_ref()
+        .and_then(|a| a.get(LAYER_ORDER_ANNOTATION))
+        .map(|order| order.trim())
+}
+
+// Parses an order declared as the indices of the layers, which must name every layer once.
+fn declared_order(order: &str, len: usize) -> Result<Vec<usize>, Error> {
+    let invalid = || {
+        Error::InvalidArgument(format!(
+            "invalid layer order {order:?}, expected \"manifest\", \"dependencies\", or a permutation of the indices of the {len} layers"
+        ))
+    };
+    let indices = order
+        .split(',')
+        .map(|index| index.trim().parse::<usize>().map_err(|_| invalid()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut sorted = indices.clone();
+    sorted.sort_unstable();
+    if !sorted.into_iter().eq(0..len) {
+        return Err(invalid());
+    }
+    Ok(indices)
+}
+
+// Sorts the layers topologically, taking the first layer of the manifest whose dependencies
+// are all sorted at each step, so that the manifest order is kept wherever the dependencies allow it.
+fn dependency_order(layers: &[WasmLayer]) -> Result<Vec<usize>, Error> {
+    let names: Vec<_> = layers.iter().map(component_names).collect();
+    let dependencies: Vec<Vec<usize>> = names
+        .iter()
+        .enumerate()
+        .map(|(index, layer)| {
+            let Some((imports, _)) = layer else {
+                return vec![];
+            };
+            names
+                .iter()
+                .enumerate()
+                .filter(|(other, candidate)| {
+                    *other != index
+                        && candidate.as_ref().is_some_and(|(_, exports)| {
+                            exports.iter().any(|name| imports.contains(name))
+                        })
+                })
+                .map(|(other, _)| other)
+                .collect()
+        })
+        .collect();
+
+    let mut sorted = vec![false; layers.len()];
+    let mut order = Vec::with_capacity(layers.len());
+    while order.len() < layers.len() {
+        let next = (0..layers.len())
+            .find(|&i| !sorted[i] && dependencies[i].iter().all(|&dep| sorted[dep]))
+            .ok_or_else(|| {
+                let cycle: Vec<_> = (0..layers.len())
+                    .filter(|&i| !sorted[i])
+                    .map(|i| i.to_string())
+                    .collect();
+                Error::InvalidArgument(format!(
+                    "the layers [{}] of the image depend on each other",
+                    cycle.join(", ")
+                ))
+            })?;
+        sorted[next] = true;
+        order.push(next);
+    }
+    Ok(order)
+}
+
+// Returns the names that a component layer imports and exports,
+// or None if the layer isn't a component or can't be parsed.
+fn component_names(layer: &WasmLayer) -> Option<(Vec<String>, Vec<String>)> {
+    if layer.binary_type != Some(WasmBinaryType::Component) {
+        return None;
+    }
+    let (mut imports, mut exports) = (vec![], vec![]);
+    // the payloads of a nested module or component follow its section, up to its own `End`
+    let mut depth = 0;
+    for payload in Parser::new(0).parse_all(&layer.layer) {
+        match payload.ok()? {
+            Payload::ModuleSection { .. } | Payload::ComponentSection { .. } => depth += 1,
+            Payload::End(_) => depth -= 1,
+            Payload::ComponentImportSection(reader) if depth == 0 => {
+                for import in reader {
+                    imports.push(import.ok()?.name.0.to_string());
+                }
+            }
+            Payload::ComponentExportSection(reader) if depth == 0 => {
+                for export in reader {
+                    exports.push(export.ok()?.name.0.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    Some((imports, exports))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use oci_spec::runtime::SpecBuilder;
+
+    use super::*;
+
+    fn spec(order: &str) -> Spec {
+        SpecBuilder::default()
+            .annotations(HashMap::from([(
+                LAYER_ORDER_ANNOTATION.to_string(),
+                order.to_string(),
+            )]))
+            .build()
+            .unwrap()
+    }
+
+    fn layers(wats: &[&str]) -> Vec<WasmLayer> {
+        wats.iter()
+            .map(|wat| WasmLayer::from_module(wat::parse_str(wat).unwrap()))
+            .collect()
+    }
+
+    // the layers are told apart by their bytes, as they share the descriptor of the image config
+    fn positions(original: &[WasmLayer], ordered: &[WasmLayer]) -> Vec<usize> {
+        ordered
+            .iter()
+            .map(|l| original.iter().position(|o| o.layer == l.layer).unwrap())
+            .collect()
+    }
+
+    const APP: &str = r#"(component (import "example:lib/math" (instance)))"#;
+    const LIB: &str =
+        r#"(component (instance $math) (export "example:lib/math" (instance $math)))"#;
+    const OTHER: &str = r#"(component (import "example:other/io" (instance)))"#;
+
+    #[test]
+    fn test_order_by_imports() {
+        let original = layers(&[APP, OTHER, LIB]);
+
+        let ordered = order_layers(&Spec::default(), original.clone()).unwrap();
+        assert_eq!(positions(&original, &ordered), [0, 1, 2]);
+        assert!(!reorders_layers(&Spec::default()));
+        let ordered = order_layers(&spec("manifest"), original.clone()).unwrap();
+        assert_eq!(positions(&original, &ordered), [0, 1, 2]);
+        assert!(!reorders_layers(&spec("manifest")));
+
+        // the layer without dependencies keeps its place before the app
+        let ordered = order_layers(&spec("dependencies"), original.clone()).unwrap();
+        assert_eq!(positions(&original, &ordered), [1, 2, 0]);
+        assert!(reorders_layers(&spec("dependencies")));
+    }
+
+    #[test]
+    fn test_declared_order() {
+        let original = layers(&[APP, OTHER, LIB]);
+        let ordered = order_layers(&spec("2, 0, 1"), original.clone()).unwrap();
+        assert_eq!(positions(&original, &ordered), [2, 0, 1]);
+
+        for order in ["2,0", "0,1,2,3", "0,0,1", "1,two,0", "alphabetical"] {
+            let err = order_layers(&spec(order), original.clone()).unwrap_err();
+            assert!(matches!(err, Error::InvalidArgument(_)), "{order}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_order_cycle() {
+        let a = r#"(component (import "example:b/api" (instance)) (instance $a) (export "example:a/api" (instance $a)))"#;
+        let b = r#"(component (import "example:a/api" (instance)) (instance $b) (export "example:b/api" (instance $b)))"#;
+        let err = order_layers(&spec("dependencies"), layers(&[LIB, a, b])).unwrap_err();
+        assert!(
+            matches!(&err, Error::InvalidArgument(msg) if msg.contains("[1, 2]")),
+            "{err}"
+        );
+    }
+}
@@ -10,20 +10,61 @@
 //! * Less customizable
 //! * Currently only works on Linux
 
+mod capabilities;
 mod context;
 mod engine;
+mod hints;
+mod layer_order;
 mod path;
 mod wasm;
 
-pub(crate) use context::WasiContext;
-pub use context::{Entrypoint, RuntimeContext, Source};
-pub use engine::Engine;
+pub use capabilities::Capabilities;
+#[cfg(unix)]
+pub(crate) use context::manifest_start_function;
+pub use context::{parse_entrypoint, Entrypoint, RuntimeContext, Source};
+pub(crate) use context::{set_start_function, WasiContext};
+#[cfg(unix)]
+pub(crate) use engine::parse_interrupt_timeout;
+pub use engine::{Engine, PrecompileProgress, INTERRUPT_TIMEOUT_ANNOTATION};
+pub use hints::FUEL_ANNOTATION;
+#[cfg(unix)]
+pub(crate) use hints::{apply_module_hints, has_fuel_hint};
 pub use instance::Instance;
+pub use layer_order::LAYER_ORDER_ANNOTATION;
+#[cfg(unix)]
+pub(crate) use layer_order::{order_layers, reorders_layers};
+#[cfg(unix)]
+pub use libcontainer::container::Container;
 pub use path::PathResolve;
-pub use wasm::WasmBinaryType;
+#[cfg(unix)]
+pub(crate) use wasm::{
+    check_start_function, check_wasm_features, enabled_wasm_features, platform_wasm_features,
+};
+pub use wasm::{WasiVersion, WasmBinaryType, WasmFeature, WASM_FEATURES_ANNOTATION};
 
-pub use crate::sandbox::stdio::Stdio;
+pub use crate::sandbox::instance::TrapReason;
+pub use crate::sandbox::oci::ENTRYPOINT_ANNOTATION;
+pub use crate::sandbox::shim::logger::LOG_LEVEL_ANNOTATION;
+pub use crate::sandbox::stdio::{Stdio, NORMALIZE_LINE_ENDINGS_ANNOTATION};
+#[cfg(unix)]
+pub use crate::sys::container::cpuset::CPUSET_ANNOTATION;
+#[cfg(unix)]
+pub use crate::sys::container::executor::ExecutorKind;
+#[cfg(unix)]
+pub use crate::sys::container::fs_quota::FS_QUOTA_ANNOTATION;
+#[cfg(unix)]
+pub use crate::sys::container::guest_signals::{GUEST_SIGNALS_ANNOTATION, GUEST_SIGNALS_FILE};
 use crate::sys::container::instance;
+#[cfg(unix)]
+pub use crate::sys::container::instance::STOP_SIGNAL_ANNOTATION;
+#[cfg(unix)]
+pub use crate::sys::container::metrics::InstanceMetrics;
+#[cfg(unix)]
+pub use crate::sys::container::name_resolution::{DNS_ANNOTATION, HOSTS_ANNOTATION};
+#[cfg(unix)]
+pub use crate::sys::container::secrets::SECRET_MOUNTS_ANNOTATION;
+#[cfg(unix)]
+pub use crate::sys::container::timings::StartupTimings;
 
 #[cfg(test)]
 mod tests;
@@ -21,6 +21,9 @@ impl Engine for EngineFailingValidation {
 
 type InstanceFailingValidation = Instance<EngineFailingValidation>;
 
+#[cfg(unix)] // not yet implemented on Windows
+type TestInstance = Instance<crate::testing::TestEngine>;
+
 #[test]
 #[cfg(unix)] // not yet implemented on Windows
 fn test_validation_error() -> anyhow::Result<()> {
@@ -36,3 +39,612 @@ fn test_validation_error() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+mod precompile_progress {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::container::PrecompileProgress;
+
+    // an engine that reports a phase for each layer it compiles
+    #[derive(Clone, Default)]
+    struct EngineWithProgress;
+
+    impl Engine for EngineWithProgress {
+        fn name() -> &'static str {
+            "progress"
+        }
+        fn run_wasi(&self, _ctx: &impl RuntimeContext, _stdio: Stdio) -> anyhow::Result<i32> {
+            Ok(0)
+        }
+        fn precompile(&self, layers: &[Vec<u8>]) -> anyhow::Result<Vec<u8>> {
+            Ok(layers.concat())
+        }
+        fn precompile_with_progress(
+            &self,
+            layers: &[Vec<u8>],
+            progress: &dyn Fn(PrecompileProgress),
+        ) -> anyhow::Result<Vec<u8>> {
+            progress(PrecompileProgress::Started);
+            for (index, _) in layers.iter().enumerate() {
+                progress(PrecompileProgress::Phase {
+                    name: format!("compiling layer {index}"),
+                    percent: Some((100 * index / layers.len()) as u8),
+                });
+            }
+            let precompiled = self.precompile(layers)?;
+            progress(PrecompileProgress::Finished);
+            Ok(precompiled)
+        }
+    }
+
+    #[test]
+    fn test_precompile_progress() -> anyhow::Result<()> {
+        let updates = Mutex::new(vec![]);
+        let layers = [b"first".to_vec(), b"second".to_vec()];
+        let precompiled = EngineWithProgress
+            .precompile_with_progress(&layers, &|p| updates.lock().unwrap().push(p))?;
+
+        assert_eq!(precompiled, b"firstsecond");
+        assert_eq!(
+            updates.into_inner().unwrap(),
+            vec![
+                PrecompileProgress::Started,
+                PrecompileProgress::Phase {
+                    name: "compiling layer 0".to_string(),
+                    percent: Some(0)
+                },
+                PrecompileProgress::Phase {
+                    name: "compiling layer 1".to_string(),
+                    percent: Some(50)
+                },
+                PrecompileProgress::Finished,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_precompile_progress() {
+        let updates = Mutex::new(vec![]);
+        // the precompile fails, so it never finishes
+        EngineFailingValidation
+            .precompile_with_progress(&[b"module".to_vec()], &|p| updates.lock().unwrap().push(p))
+            .unwrap_err();
+        assert_eq!(
+            updates.into_inner().unwrap(),
+            vec![PrecompileProgress::Started]
+        );
+    }
+}
+
+#[cfg(unix)] // not yet implemented on Windows
+mod post_create {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use libcontainer::container::ContainerStatus;
+    use serial_test::serial;
+
+    use super::*;
+    use crate::container::Container;
+    use crate::testing::modules::HELLO_WORLD;
+
+    // records the id and status of every container the hook is invoked with
+    static POST_CREATE_CALLS: Mutex<Vec<(String, ContainerStatus)>> = Mutex::new(vec![]);
+
+    #[derive(Clone, Default)]
+    struct EngineWithPostCreate;
+
+    impl Engine for EngineWithPostCreate {
+        fn name() -> &'static str {
+            "wasi_instance"
+        }
+        fn run_wasi(&self, _ctx: &impl RuntimeContext, _stdio: Stdio) -> anyhow::Result<i32> {
+            Ok(0)
+        }
+        fn post_create(&self, container: &Container) -> anyhow::Result<()> {
+            POST_CREATE_CALLS
+                .lock()
+                .unwrap()
+                .push((container.id().to_string(), container.status()));
+            Ok(())
+        }
+    }
+
+    type InstanceWithPostCreate = Instance<EngineWithPostCreate>;
+
+    #[test]
+    #[serial]
+    fn test_post_create_hook() -> anyhow::Result<()> {
+        let test = WasiTest::<InstanceWithPostCreate>::builder()?
+            .with_wasm(HELLO_WORLD)?
+            .build()?;
+
+        // the hook runs when the container is created, before it is started
+        assert_eq!(
+            *POST_CREATE_CALLS.lock().unwrap(),
+            vec![("test".to_string(), ContainerStatus::Created)]
+        );
+
+        let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+        assert_eq!(exit_code, 0);
+        assert_eq!(POST_CREATE_CALLS.lock().unwrap().len(), 1);
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)] // not yet implemented on Windows
+mod which_executor {
+    use std::time::Duration;
+
+    use serial_test::serial;
+
+    use super::*;
+    use crate::container::ExecutorKind;
+    use crate::sandbox::Error;
+    use crate::testing::modules::HELLO_WORLD;
+
+    #[test]
+    #[serial]
+    fn test_wasm_executor() -> anyhow::Result<()> {
+        let test = WasiTest::<TestInstance>::builder()?
+            .with_wasm(HELLO_WORLD)?
+            .build()?;
+        assert_eq!(test.instance().which_executor(), None);
+
+        let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+        assert_eq!(exit_code, 0);
+        assert_eq!(test.instance().which_executor(), Some(ExecutorKind::Wasm));
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_linux_executor() -> anyhow::Result<()> {
+        // there is no shell in the container, so the script fails to run,
+        // but it is still handled by the linux executor
+        let test = WasiTest::<TestInstance>::builder()?
+            .with_native("#!/bin/sh\necho hello\n")?
+            .build()?;
+
+        let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+        assert_ne!(exit_code, 0);
+        assert_eq!(test.instance().which_executor(), Some(ExecutorKind::Linux));
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_native_fallback_disabled() -> anyhow::Result<()> {
+        let result = WasiTest::<TestInstance>::builder()?
+            .with_native("#!/bin/sh\necho hello\n")?
+            .without_native_fallback()?
+            .build();
+
+        let Err(err) = result else {
+            panic!("a native entrypoint should be rejected without the native fallback");
+        };
+        match err.downcast_ref::<Error>() {
+            Some(Error::NotWasm(_)) => {}
+            _ => panic!("unexpected error: {err}"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_native_fallback_disabled_sandbox() -> anyhow::Result<()> {
+        // the pause executable of the sandbox container of a pod is native
+        let test = WasiTest::<TestInstance>::builder()?
+            .with_native("#!/bin/sh\necho hello\n")?
+            .with_annotation("io.kubernetes.cri.container-type", "sandbox")?
+            .without_native_fallback()?
+            .build()?;
+
+        let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+        assert_ne!(exit_code, 0);
+        assert_eq!(test.instance().which_executor(), Some(ExecutorKind::Linux));
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)] // not yet implemented on Windows
+mod log_level {
+    use std::time::Duration;
+
+    use serial_test::serial;
+
+    use super::*;
+    use crate::container::LOG_LEVEL_ANNOTATION;
+    use crate::testing::modules::HELLO_WORLD;
+    use crate::testing::TestEngine;
+
+    fn reporting_log_level() -> TestEngine {
+        TestEngine::printing(|_| Ok(log::max_level().to_string()))
+    }
+
+    #[test]
+    #[serial]
+    fn test_log_level_annotation() -> anyhow::Result<()> {
+        let debugged = WasiTest::<TestInstance>::builder()?
+            .with_engine(reporting_log_level())
+            .with_wasm(HELLO_WORLD)?
+            .with_annotation(LOG_LEVEL_ANNOTATION, "debug")?
+            .build()?;
+        let other = WasiTest::<TestInstance>::builder()?
+            .with_engine(reporting_log_level())
+            .with_wasm(HELLO_WORLD)?
+            .build()?;
+
+        let (exit_code, stdout, _) = debugged.start()?.wait(Duration::from_secs(10))?;
+        assert_eq!(exit_code, 0);
+        assert_eq!(stdout, "DEBUG");
+
+        // the level of the other container is still the one of the shim
+        let (exit_code, stdout, _) = other.start()?.wait(Duration::from_secs(10))?;
+        assert_eq!(exit_code, 0);
+        assert_eq!(stdout, log::max_level().to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_invalid_log_level_annotation() -> anyhow::Result<()> {
+        let result = WasiTest::<TestInstance>::builder()?
+            .with_wasm(HELLO_WORLD)?
+            .with_annotation(LOG_LEVEL_ANNOTATION, "verbose")?
+            .build();
+        assert!(result.is_err());
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)] // not yet implemented on Windows
+mod cpuset {
+    use std::time::Duration;
+
+    use nix::sched::{sched_getaffinity, CpuSet};
+    use nix::unistd::Pid;
+    use serial_test::serial;
+
+    use super::*;
+    use crate::container::CPUSET_ANNOTATION;
+    use crate::testing::modules::HELLO_WORLD;
+    use crate::testing::TestEngine;
+
+    fn allowed_cpus() -> anyhow::Result<Vec<usize>> {
+        let cpuset = sched_getaffinity(Pid::from_raw(0))?;
+        Ok((0..CpuSet::count())
+            .filter(|cpu| cpuset.is_set(*cpu).unwrap_or(false))
+            .collect())
+    }
+
+    #[test]
+    #[serial]
+    fn test_cpuset_annotation() -> anyhow::Result<()> {
+        // pin the guest to the last CPU available to the tests
+        let cpu = *allowed_cpus()?.last().unwrap();
+        let test = WasiTest::<TestInstance>::builder()?
+            .with_engine(TestEngine::printing(|_| {
+                Ok(format!("{:?}", allowed_cpus()?))
+            }))
+            .with_wasm(HELLO_WORLD)?
+            .with_annotation(CPUSET_ANNOTATION, cpu.to_string())?
+            .build()?;
+
+        let (exit_code, stdout, _) = test.start()?.wait(Duration::from_secs(10))?;
+        assert_eq!(exit_code, 0);
+        assert_eq!(stdout, format!("{:?}", [cpu]));
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)] // not yet implemented on Windows
+mod pod_sandbox {
+    use std::fs::read_link;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use oci_spec::runtime::LinuxNamespaceType;
+    use serial_test::serial;
+
+    use super::*;
+    use crate::sandbox::Instance as _;
+    use crate::sys::signals::SIGKILL;
+    use crate::testing::TestEngine;
+
+    // runs until it is killed, like the pause container of a pod
+    fn sleeping() -> TestEngine {
+        TestEngine::new(|_| loop {
+            sleep(Duration::from_secs(1));
+        })
+    }
+
+    #[test]
+    #[serial]
+    fn test_containers_share_sandbox_namespaces() -> anyhow::Result<()> {
+        assert!(TestInstance::supports_pod_sandbox());
+
+        let sandbox = WasiTest::<TestInstance>::builder()?
+            .with_engine(sleeping())
+            .with_container_name("test-sandbox")?
+            .build()?;
+        let sandbox_pid = sandbox.instance().start()?;
+
+        let namespace = |pid: u32, typ: &str| format!("/proc/{pid}/ns/{typ}");
+
+        let mut containers = vec![];
+        for name in ["test-container-1", "test-container-2"] {
+            let container = WasiTest::<TestInstance>::builder()?
+                .with_engine(sleeping())
+                .with_container_name(name)?
+                .with_namespace_path(LinuxNamespaceType::Ipc, namespace(sandbox_pid, "ipc"))?
+                .with_namespace_path(LinuxNamespaceType::Uts, namespace(sandbox_pid, "uts"))?
+                .build()?;
+            let pid = container.instance().start()?;
+            containers.push((container, pid));
+        }
+
+        for typ in ["ipc", "uts"] {
+            let sandbox_ns = read_link(namespace(sandbox_pid, typ))?;
+            assert_ne!(sandbox_ns, read_link(format!("/proc/self/ns/{typ}"))?);
+            for (_, pid) in &containers {
+                assert_eq!(read_link(namespace(*pid, typ))?, sandbox_ns);
+            }
+        }
+
+        for (container, _) in &containers {
+            container.instance().kill(SIGKILL as u32)?;
+            container.wait(Duration::from_secs(10))?;
+        }
+        sandbox.instance().kill(SIGKILL as u32)?;
+        sandbox.wait(Duration::from_secs(10))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)] // not yet implemented on Windows
+mod name_resolution {
+    use std::net::{IpAddr, ToSocketAddrs};
+    use std::time::Duration;
+
+    use serial_test::serial;
+
+    use super::*;
+    use crate::container::HOSTS_ANNOTATION;
+    use crate::testing::modules::HELLO_WORLD;
+    use crate::testing::TestEngine;
+
+    #[test]
+    #[serial]
+    fn test_hosts_annotation() -> anyhow::Result<()> {
+        // the engine resolves names from inside the container on behalf of the guest
+        let resolving = TestEngine::new(|_| {
+            let expected: IpAddr = "10.1.2.3".parse()?;
+            let resolved = ("db.internal", 0)
+                .to_socket_addrs()?
+                .any(|addr| addr.ip() == expected);
+            Ok(if resolved { 0 } else { 1 })
+        });
+        let test = WasiTest::<TestInstance>::builder()?
+            .with_engine(resolving)
+            .with_wasm(HELLO_WORLD)?
+            .with_annotation(HOSTS_ANNOTATION, "db.internal=10.1.2.3")?
+            .build()?;
+
+        let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+        assert_eq!(exit_code, 0);
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)] // not yet implemented on Windows
+mod wasm_features {
+    use std::time::Duration;
+
+    use oci_spec::image::Platform;
+    use serial_test::serial;
+
+    use super::*;
+    use crate::container::{WasmFeature, WASM_FEATURES_ANNOTATION};
+    use crate::sandbox::{Error, ResolvedImage, WasmLayer};
+    use crate::testing::modules::{EXCEPTIONS, GC};
+    use crate::testing::TestEngine;
+
+    // reports the features that the container enables
+    fn with_optional_gc() -> TestEngine {
+        TestEngine::printing(|ctx| {
+            let features: Vec<_> = ctx
+                .wasm_features()
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            Ok(features.join(","))
+        })
+        .with_wasm_features(
+            vec![WasmFeature::Gc, WasmFeature::Exceptions],
+            vec![WasmFeature::Gc],
+        )
+    }
+
+    fn resolved(module: &[u8]) -> ResolvedImage {
+        ResolvedImage::from((
+            vec![WasmLayer::from_module(module.to_vec())],
+            Platform::default(),
+        ))
+    }
+
+    #[test]
+    #[serial]
+    fn test_enable_optional_feature() -> anyhow::Result<()> {
+        let (exit_code, stdout, _) = WasiTest::<TestInstance>::builder()?
+            .with_engine(with_optional_gc())
+            .with_resolved_image(resolved(GC.bytes))
+            .with_annotation(WASM_FEATURES_ANNOTATION, "gc")?
+            .build()?
+            .start()?
+            .wait(Duration::from_secs(10))?;
+
+        assert_eq!(exit_code, 0);
+        assert_eq!(stdout, "gc");
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_disabled_features() -> anyhow::Result<()> {
+        // the module requires a feature that the container doesn't enable
+        let err = WasiTest::<TestInstance>::builder()?
+            .with_engine(with_optional_gc())
+            .with_resolved_image(resolved(GC.bytes))
+            .build()
+            .err()
+            .expect("a module that requires gc should be rejected");
+        let err = err.downcast::<Error>()?;
+        assert!(
+            matches!(&err, Error::UnsupportedFeature(msg) if msg.contains("[gc]")),
+            "{err}"
+        );
+
+        // the engine can't enable the feature
+        let err = WasiTest::<TestInstance>::builder()?
+            .with_engine(with_optional_gc())
+            .with_resolved_image(resolved(EXCEPTIONS.bytes))
+            .with_annotation(WASM_FEATURES_ANNOTATION, "exceptions")?
+            .build()
+            .err()
+            .expect("the engine can't enable exceptions");
+        let err = err.downcast::<Error>()?;
+        assert!(
+            matches!(&err, Error::UnsupportedFeature(msg) if msg.contains("can't enable")),
+            "{err}"
+        );
+
+        let err = WasiTest::<TestInstance>::builder()?
+            .with_engine(with_optional_gc())
+            .with_annotation(WASM_FEATURES_ANNOTATION, "gc,tail-call")?
+            .build()
+            .err()
+            .expect("an unknown feature should be rejected");
+        let err = err.downcast::<Error>()?;
+        assert!(matches!(&err, Error::InvalidArgument(_)), "{err}");
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)] // not yet implemented on Windows
+mod namespace_annotations {
+    use std::time::Duration;
+
+    use serial_test::serial;
+
+    use super::*;
+    use crate::container::FUEL_ANNOTATION;
+    use crate::testing::modules::HELLO_WORLD;
+    use crate::testing::TestEngine;
+
+    fn run_in(namespace: &str, fuel: Option<&str>) -> anyhow::Result<String> {
+        let reporting_fuel = TestEngine::printing(|ctx| {
+            Ok(ctx
+                .annotation(FUEL_ANNOTATION)
+                .unwrap_or("none")
+                .to_string())
+        });
+        let mut builder = WasiTest::<TestInstance>::builder()?
+            .with_engine(reporting_fuel)
+            .with_wasm(HELLO_WORLD)?
+            .with_namespace(namespace)?
+            .with_namespace_annotation("tenant-a", FUEL_ANNOTATION, "1000")?
+            .with_namespace_annotation("tenant-b", FUEL_ANNOTATION, "5000")?;
+        if let Some(fuel) = fuel {
+            builder = builder.with_annotation(FUEL_ANNOTATION, fuel)?;
+        }
+        let (exit_code, stdout, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+        assert_eq!(exit_code, 0);
+        Ok(stdout)
+    }
+
+    #[test]
+    #[serial]
+    fn test_namespace_annotations() -> anyhow::Result<()> {
+        assert_eq!(run_in("tenant-a", None)?, "1000");
+        assert_eq!(run_in("tenant-b", None)?, "5000");
+        assert_eq!(run_in("tenant-c", None)?, "none");
+
+        // the annotations of the container take precedence
+        assert_eq!(run_in("tenant-a", Some("42"))?, "42");
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)] // not yet implemented on Windows
+mod layer_order {
+    use std::time::Duration;
+
+    use oci_spec::image::Platform;
+    use serial_test::serial;
+
+    use super::*;
+    use crate::container::{Source, LAYER_ORDER_ANNOTATION};
+    use crate::sandbox::{ResolvedImage, WasmLayer};
+    use crate::testing::TestEngine;
+
+    fn run(image: &ResolvedImage, order: Option<&str>) -> anyhow::Result<String> {
+        let reporting_layers = TestEngine::printing(|ctx| {
+            let Source::Oci(layers) = ctx.entrypoint().source else {
+                bail!("expected the layers of an image");
+            };
+            // the layers share the descriptor of the image config, so they are told apart by their size
+            let sizes: Vec<_> = layers.iter().map(|l| l.layer.len().to_string()).collect();
+            Ok(sizes.join(","))
+        });
+        let mut builder = WasiTest::<TestInstance>::builder()?
+            .with_engine(reporting_layers)
+            .with_resolved_image(image.clone());
+        if let Some(order) = order {
+            builder = builder.with_annotation(LAYER_ORDER_ANNOTATION, order)?;
+        }
+        let (exit_code, stdout, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+        assert_eq!(exit_code, 0);
+        Ok(stdout)
+    }
+
+    #[test]
+    #[serial]
+    fn test_layers_in_order_of_dependencies() -> anyhow::Result<()> {
+        // the manifest lists the app before the library that it imports
+        let app = wat::parse_str(r#"(component (import "example:lib/math" (instance)))"#)?;
+        let lib = wat::parse_str(
+            r#"(component (instance $math) (export "example:lib/math" (instance $math)))"#,
+        )?;
+        let (app_size, lib_size) = (app.len(), lib.len());
+        assert_ne!(app_size, lib_size);
+        let image = ResolvedImage::from((
+            vec![WasmLayer::from_module(app), WasmLayer::from_module(lib)],
+            Platform::default(),
+        ));
+
+        assert_eq!(run(&image, None)?, format!("{app_size},{lib_size}"));
+        assert_eq!(
+            run(&image, Some("dependencies"))?,
+            format!("{lib_size},{app_size}")
+        );
+        assert_eq!(run(&image, Some("1,0"))?, format!("{lib_size},{app_size}"));
+
+        Ok(())
+    }
+}
@@ -1,6 +1,18 @@
-use wasmparser::Parser;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use anyhow::bail;
+use oci_spec::image::Platform;
+use serde::{Deserialize, Serialize};
+use wasmparser::{ComponentExternalKind, ExternalKind, Parser, Payload, Validator, WasmFeatures};
+
+use super::{Engine, RuntimeContext};
+use crate::sandbox::oci::is_wasm64;
+use crate::sandbox::Error;
 
 /// The type of a wasm binary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum WasmBinaryType {
     /// A wasm module.
     Module,
@@ -8,6 +20,39 @@ pub enum WasmBinaryType {
     Component,
 }
 
+/// The version of WASI that a wasm binary targets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WasiVersion {
+    /// WASI preview 1.
+    Preview1,
+    /// WASI preview 2.
+    Preview2,
+}
+
+impl WasiVersion {
+    /// Returns the version of WASI that a binary of the given type targets, unless told otherwise.
+    /// Modules target WASI preview 1, and components target WASI preview 2.
+    pub fn default_for(binary_type: WasmBinaryType) -> Self {
+        match binary_type {
+            WasmBinaryType::Module => Self::Preview1,
+            WasmBinaryType::Component => Self::Preview2,
+        }
+    }
+}
+
+impl FromStr for WasiVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preview1" | "wasip1" => Ok(Self::Preview1),
+            "preview2" | "wasip2" => Ok(Self::Preview2),
+            _ => bail!("unknown WASI version {s:?}"),
+        }
+    }
+}
+
 impl WasmBinaryType {
     /// Returns the type of the wasm binary.
     pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
@@ -20,3 +65,262 @@ impl WasmBinaryType {
         }
     }
 }
+
+/// A wasm proposal that a binary can require, and that an engine may not support on every host,
+/// e.g., because the CPU of the host lacks the instructions it needs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WasmFeature {
+    /// The fixed-width SIMD proposal.
+    Simd,
+    /// The relaxed SIMD proposal.
+    RelaxedSimd,
+    /// The threads proposal.
+    Threads,
+    /// The garbage collection proposal, with the function references proposal that it builds on.
+    Gc,
+    /// The exception handling proposal.
+    Exceptions,
+    /// The memory64 proposal, for 64-bit memories.
+    /// It is enabled for the images whose platform is `wasm64`, besides the containers that request it.
+    Memory64,
+}
+
+impl WasmFeature {
+    const ALL: [Self; 6] = [
+        Self::Simd,
+        Self::RelaxedSimd,
+        Self::Threads,
+        Self::Gc,
+        Self::Exceptions,
+        Self::Memory64,
+    ];
+
+    fn set(self, features: &mut WasmFeatures, enabled: bool) {
+        match self {
+            Self::Simd => features.simd = enabled,
+            Self::RelaxedSimd => features.relaxed_simd = enabled,
+            Self::Threads => features.threads = enabled,
+            Self::Gc => {
+                features.gc = enabled;
+                features.function_references = enabled;
+            }
+            Self::Exceptions => features.exceptions = enabled,
+            Self::Memory64 => features.memory64 = enabled,
+        }
+    }
+}
+
+impl FromStr for WasmFeature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Self::ALL
+            .into_iter()
+            .find(|feature| feature.to_string() == s)
+        {
+            Some(feature) => Ok(feature),
+            None => bail!("unknown wasm feature {s:?}"),
+        }
+    }
+}
+
+impl Display for WasmFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Simd => f.write_str("simd"),
+            Self::RelaxedSimd => f.write_str("relaxed-simd"),
+            Self::Threads => f.write_str("threads"),
+            Self::Gc => f.write_str("gc"),
+            Self::Exceptions => f.write_str("exceptions"),
+            Self::Memory64 => f.write_str("memory64"),
+        }
+    }
+}
+
+/// Annotation with a comma separated list of wasm features that the engine disables by default,
+/// to enable for the container, e.g., `gc,exceptions`.
+/// The engine must support enabling them, see [`Engine::optional_wasm_features`].
+pub const WASM_FEATURES_ANNOTATION: &str = "runwasi.io/wasm-features";
+
+pub(crate) fn parse_wasm_features(features: &str) -> anyhow::Result<Vec<WasmFeature>> {
+    features
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Returns the wasm features that the spec enables with the [`WASM_FEATURES_ANNOTATION`] annotation.
+/// Fails if the annotation is invalid, or names a feature that the engine can't enable.
+#[cfg_attr(windows, allow(dead_code))]
+pub(crate) fn enabled_wasm_features<E: Engine>(
+    engine: &E,
+    spec: &oci_spec::runtime::Spec,
+) -> crate::sandbox::Result<Vec<WasmFeature>> {
+    let Some(features) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(WASM_FEATURES_ANNOTATION))
+    else {
+        return Ok(vec![]);
+    };
+    let features = parse_wasm_features(features).map_err(|err| {
+        Error::InvalidArgument(format!(
+            "invalid {WASM_FEATURES_ANNOTATION} annotation: {err}"
+        ))
+    })?;
+    let optional = engine.optional_wasm_features();
+    let unsupported: Vec<_> = features
+        .iter()
+        .filter(|feature| !optional.contains(feature))
+        .map(ToString::to_string)
+        .collect();
+    if !unsupported.is_empty() {
+        return Err(Error::UnsupportedFeature(format!(
+            "the {} engine can't enable the wasm features [{}]",
+            E::name(),
+            unsupported.join(", ")
+        )));
+    }
+    Ok(features)
+}
+
+/// Returns the `enabled` features, with the ones that the platform of the image implies,
+/// i.e., memory64 for the images whose platform is `wasm64`.
+pub(crate) fn platform_wasm_features(
+    enabled: &[WasmFeature],
+    platform: &Platform,
+) -> Vec<WasmFeature> {
+    let mut features = enabled.to_vec();
+    if is_wasm64(platform.architecture()) && !features.contains(&WasmFeature::Memory64) {
+        features.push(WasmFeature::Memory64);
+    }
+    features
+}
+
+/// Fails if one of the wasm binaries needs a feature that the engine can't run on this host,
+/// rather than letting the guest crash when it runs, e.g., with an illegal instruction.
+/// The `enabled` features are the ones that the container enables, see [`enabled_wasm_features`].
+/// Binaries that aren't wasm, e.g., precompiled modules, are never rejected.
+#[cfg_attr(windows, allow(dead_code))]
+pub(crate) fn check_wasm_features<'a, E: Engine>(
+    engine: &E,
+    enabled: &[WasmFeature],
+    binaries: impl IntoIterator<Item = &'a [u8]>,
+) -> crate::sandbox::Result<()> {
+    let mut disabled = engine.disabled_wasm_features();
+    disabled.retain(|feature| !enabled.contains(feature));
+    if disabled.is_empty() {
+        return Ok(());
+    }
+    for binary in binaries {
+        let required = required_features(binary, &disabled);
+        if !required.is_empty() {
+            let required: Vec<_> = required.iter().map(ToString::to_string).collect();
+            return Err(Error::UnsupportedFeature(format!(
+                "the module requires the wasm features [{}], which the {} engine doesn't support on this host",
+                required.join(", "),
+                E::name()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the features in `disabled` that the wasm binary needs.
+///
+/// The binary is validated without the disabled features, and then with each of them enabled, to find
+/// the ones it uses. A binary that is invalid for any other reason needs none of them,
+/// so that the engine reports the actual error when it loads the binary.
+fn required_features(bytes: &[u8], disabled: &[WasmFeature]) -> Vec<WasmFeature> {
+    let validates = |enabled: &[WasmFeature]| {
+        let mut features = WasmFeatures {
+            component_model: true,
+            ..Default::default()
+        };
+        for feature in WasmFeature::ALL {
+            let enabled = !disabled.contains(&feature) || enabled.contains(&feature);
+            feature.set(&mut features, enabled);
+        }
+        Validator::new_with_features(features)
+            .validate_all(bytes)
+            .is_ok()
+    };
+
+    if disabled.is_empty() || validates(&[]) {
+        return vec![];
+    }
+    let required: Vec<_> = disabled
+        .iter()
+        .copied()
+        .filter(|feature| validates(&[*feature]))
+        .collect();
+    if !required.is_empty() {
+        return required;
+    }
+    // e.g., relaxed SIMD also needs SIMD
+    if validates(disabled) {
+        disabled.to_vec()
+    } else {
+        vec![]
+    }
+}
+
+/// Returns the names of the functions that a wasm module or component exports,
+/// or None if the bytes aren't a wasm binary, e.g., because they are a precompiled module.
+#[cfg_attr(windows, allow(dead_code))]
+fn function_exports(bytes: &[u8]) -> Option<Vec<String>> {
+    WasmBinaryType::from_bytes(bytes)?;
+    let mut exports = vec![];
+    // the payloads of a nested module or component follow its section, up to its own `End`
+    let mut depth = 0;
+    for payload in Parser::new(0).parse_all(bytes) {
+        match payload.ok()? {
+            Payload::ModuleSection { .. } | Payload::ComponentSection { .. } => depth += 1,
+            Payload::End(_) => depth -= 1,
+            Payload::ExportSection(reader) if depth == 0 => {
+                for export in reader {
+                    let export = export.ok()?;
+                    if export.kind == ExternalKind::Func {
+                        exports.push(export.name.to_string());
+                    }
+                }
+            }
+            Payload::ComponentExportSection(reader) if depth == 0 => {
+                for export in reader {
+                    let export = export.ok()?;
+                    if export.kind == ComponentExternalKind::Func {
+                        exports.push(export.name.0.to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(exports)
+}
+
+/// Fails if the wasm binary of the entrypoint doesn't export the function that the entrypoint names,
+/// listing the functions it does export, rather than letting the engine fail with an opaque error.
+///
+/// Sources that can't be read or aren't wasm binaries, e.g., precompiled modules, are never rejected,
+/// and neither is `_start` for components, which the engines run through their `wasi:cli/run` export.
+#[cfg_attr(windows, allow(dead_code))]
+pub(crate) fn check_start_function(ctx: &impl RuntimeContext) -> crate::sandbox::Result<()> {
+    let entrypoint = ctx.entrypoint();
+    let Ok(bytes) = entrypoint.source.as_bytes() else {
+        return Ok(());
+    };
+    let name = entrypoint.func;
+    if name == "_start" && WasmBinaryType::from_bytes(&bytes) == Some(WasmBinaryType::Component) {
+        return Ok(());
+    }
+    let Some(available) = function_exports(&bytes) else {
+        return Ok(());
+    };
+    if available.contains(&name) {
+        return Ok(());
+    }
+    Err(Error::StartFunctionNotFound { name, available })
+}
@@ -47,6 +47,14 @@ pub fn shim_main<'a, I>(
     I::Engine: Default,
 {
     let os_args: Vec<_> = std::env::args_os().collect();
+    if os_args.get(1).is_some_and(|arg| arg == "capabilities") {
+        let Some(capabilities) = I::capabilities(&I::Engine::default()) else {
+            eprintln!("error: the {name} shim doesn't run an engine");
+            std::process::exit(1);
+        };
+        println!("{}", serde_json::to_string_pretty(&capabilities).unwrap());
+        std::process::exit(0);
+    }
     let flags = parse(&os_args[1..]).unwrap();
     let argv0 = PathBuf::from(&os_args[0]);
     let argv0 = argv0.file_stem().unwrap_or_default().to_string_lossy();
@@ -56,6 +64,9 @@ pub fn shim_main<'a, I>(
         println!("  Runtime: {name}");
         println!("  Version: {version}");
         println!("  Revision: {}", revision.into().unwrap_or("<none>"));
+        if let Some(engine_version) = I::engine_version() {
+            println!("  Engine: {engine_version}");
+        }
         println!();
 
         std::process::exit(0);
@@ -0,0 +1,185 @@
+//! Precompiled modules can be kept in a directory of the host rather than in the content store of containerd,
+//! e.g., on a disk that is faster or larger, see [`AsyncClient::with_precompile_cache_dir`](super::AsyncClient::with_precompile_cache_dir).
+//!
+//! Each module is stored in a file named after the digest of its image and the precompile label of the engine.
+//! The first line of the file is the digest of the module, which is checked when the module is loaded,
+//! so that a corrupted module is precompiled again rather than run.
+
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use nix::unistd::geteuid;
+use sha256::digest;
+
+use crate::sandbox::error::{Error as ShimError, Result};
+
+/// A directory of the host with precompiled modules.
+#[derive(Debug, Clone)]
+pub(crate) struct PrecompileCacheDir {
+    dir: PathBuf,
+}
+
+impl PrecompileCacheDir {
+    /// Opens the directory, creating it if it doesn't exist.
+    /// The modules in it are run as native code, so it must not be writable by other users than the shim.
+    pub(crate) fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .map_err(|err| {
+                ShimError::InvalidArgument(format!(
+                    "failed to create precompile cache directory {dir:?}: {err}"
+                ))
+            })?;
+        let metadata = fs::metadata(dir)?;
+        if metadata.uid() != geteuid().as_raw() || metadata.mode() & 0o022 != 0 {
+            return Err(ShimError::FailedPrecondition(format!(
+                "precompile cache directory {dir:?} must be owned by the shim and not writable by others"
+            )));
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    fn module_path(&self, image_digest: &str, precompile_id: &str) -> PathBuf {
+        let key = digest(format!("{precompile_id}\n{image_digest}"));
+        self.dir.join(format!("{key}.precompiled"))
+    }
+
+    /// Returns the digest and the precompiled module of the image, or None if it isn't in the directory.
+    /// A corrupted module is removed, and None returned, so that the image is precompiled again.
+    pub(crate) fn load(
+        &self,
+        image_digest: &str,
+        precompile_id: &str,
+    ) -> Option<(String, Vec<u8>)> {
+        let path = self.module_path(image_digest, precompile_id);
+        let file = match fs::read(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return None,
+            Err(err) => {
+                log::warn!("failed to read precompiled module {path:?}: {err}");
+                return None;
+            }
+        };
+        match verified_module(&file) {
+            Some((digest, precompiled)) => Some((digest.to_string(), precompiled.to_vec())),
+            None => {
+                log::warn!(
+                    "precompiled module {path:?} is corrupted, it will be precompiled again"
+                );
+                if let Err(err) = fs::remove_file(&path) {
+                    log::warn!("failed to remove corrupted precompiled module {path:?}: {err}");
+                }
+                None
+            }
+        }
+    }
+
+    /// Stores the precompiled module of the image, replacing any previous one.
+    /// Returns the digest of the module.
+    pub(crate) fn store(
+        &self,
+        image_digest: &str,
+        precompile_id: &str,
+        precompiled: &[u8],
+    ) -> Result<String> {
+        let path = self.module_path(image_digest, precompile_id);
+        let module_digest = format!("sha256:{}", digest(precompiled));
+        // written next to its final path and then renamed, so that a partial write is never loaded
+        let partial = path.with_extension(format!("partial-{}", std::process::id()));
+        let written = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&partial)
+            .and_then(|mut file| {
+                writeln!(file, "{module_digest}")?;
+                file.write_all(precompiled)?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&partial, &path));
+        if let Err(err) = written {
+            let _ = fs::remove_file(&partial);
+            return Err(err.into());
+        }
+        log::info!("stored precompiled module in {path:?}");
+        Ok(module_digest)
+    }
+}
+
+// Returns the digest on the first line of the file and the module, if the module matches the digest.
+fn verified_module(file: &[u8]) -> Option<(&str, &[u8])> {
+    let newline = file.iter().position(|b| *b == b'\n')?;
+    let (header, precompiled) = (&file[..newline], &file[newline + 1..]);
+    let header = std::str::from_utf8(header).ok()?;
+    let expected = header.strip_prefix("sha256:")?;
+    (digest(precompiled) == expected).then_some((header, precompiled))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn test_store_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PrecompileCacheDir::open(dir.path().join("cache")).unwrap();
+
+        assert_eq!(cache.load("sha256:image", "engine/v1"), None);
+        let stored = cache
+            .store("sha256:image", "engine/v1", b"precompiled\nmodule")
+            .unwrap();
+        let (digest, precompiled) = cache.load("sha256:image", "engine/v1").unwrap();
+        assert_eq!(precompiled, b"precompiled\nmodule");
+        assert_eq!(digest, stored);
+        assert_eq!(
+            digest,
+            format!("sha256:{}", super::digest(precompiled.as_slice()))
+        );
+        // modules are keyed by both the image and the engine
+        assert_eq!(cache.load("sha256:image", "engine/v2"), None);
+        assert_eq!(cache.load("sha256:other", "engine/v1"), None);
+
+        let mode = fs::metadata(cache.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[test]
+    fn test_load_corrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PrecompileCacheDir::open(dir.path()).unwrap();
+        cache
+            .store("sha256:image", "engine/v1", b"precompiled")
+            .unwrap();
+
+        let path = cache.module_path("sha256:image", "engine/v1");
+        let mut file = fs::read(&path).unwrap();
+        *file.last_mut().unwrap() ^= 1;
+        fs::write(&path, file).unwrap();
+
+        assert_eq!(cache.load("sha256:image", "engine/v1"), None);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_open_writable_by_others() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777)).unwrap();
+
+        let err = PrecompileCacheDir::open(dir.path()).unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)), "{err}");
+    }
+}
@@ -1,229 +1,862 @@
 #![cfg(unix)]
 
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use containerd_client;
 use containerd_client::services::v1::containers_client::ContainersClient;
 use containerd_client::services::v1::content_client::ContentClient;
 use containerd_client::services::v1::images_client::ImagesClient;
 use containerd_client::services::v1::leases_client::LeasesClient;
+use containerd_client::services::v1::tasks_client::TasksClient;
 use containerd_client::services::v1::{
-    Container, DeleteContentRequest, GetContainerRequest, GetImageRequest, Image, Info,
-    InfoRequest, ReadContentRequest, UpdateImageRequest, UpdateRequest, WriteAction,
-    WriteContentRequest,
+    AbortRequest, Container, DeleteContentRequest, GetContainerRequest, GetImageRequest, Image,
+    Info, InfoRequest, ListContentRequest, ListImagesRequest, ListTasksRequest, ReadContentRequest,
+    UpdateImageRequest, UpdateRequest, WriteAction, WriteContentRequest, WriteContentResponse,
 };
 use containerd_client::tonic::transport::Channel;
+use containerd_client::types::v1::Status;
 use containerd_client::{tonic, with_namespace};
-use futures::TryStreamExt;
-use oci_spec::image::{Arch, ImageManifest, MediaType, Platform};
+use futures::{stream, StreamExt, TryStreamExt};
+use oci_spec::image::{ImageConfiguration, ImageManifest, MediaType, Platform};
 use prost_types::FieldMask;
+use serde::Deserialize;
 use sha256::digest;
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Code, Request};
 
+use super::cache_dir::PrecompileCacheDir;
+use super::connect::connect;
 use super::lease::LeaseGuard;
-use crate::container::Engine;
+use super::retry::RetryPolicy;
+use super::trace::timed_span;
+use crate::container::{
+    check_wasm_features, has_fuel_hint, platform_wasm_features, Engine, PrecompileProgress,
+    WasmFeature,
+};
 use crate::sandbox::error::{Error as ShimError, Result};
+use crate::sandbox::instance_utils::ContainerdTlsOptions;
 use crate::sandbox::oci::{self, WasmLayer};
+use crate::sandbox::sync::WaitableCell;
 use crate::with_lease;
 
 static PRECOMPILE_PREFIX: &str = "runwasi.io/precompiled";
+static PRECOMPILE_GC_REF: &str = "containerd.io/gc.ref.content.precompile";
+static LAST_USED_LABEL: &str = "runwasi.io/last-used";
+static MEDIA_TYPE_LABEL: &str = "runwasi.io/media-type";
+static IMAGE_REFERENCE_LABEL: &str = "runwasi.io/image-reference";
+static LOGS_PREFIX: &str = "runwasi.io/logs";
+static GC_ROOT_LABEL: &str = "containerd.io/gc.root";
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_STAT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_LAST_USED_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_READ_CONCURRENCY: usize = 4;
+const DEFAULT_SPACE_FACTOR: f64 = 2.0;
+const DEFAULT_PRECOMPILE_WAIT: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_IMAGE_TARGET_WAIT: Duration = Duration::from_secs(5);
+const IMAGE_TARGET_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// the frame magic number of zstd, which precompiled content stored compressed starts with
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const ZSTD_MEDIA_TYPE_SUFFIX: &str = "+zstd";
+const ZSTD_LEVEL: i32 = 3;
+// how many times the labels of content are set before giving up on concurrent updates dropping them
+const LABEL_UPDATE_ATTEMPTS: u32 = 5;
+// the content store of containerd with its default root
+const DEFAULT_CONTENT_STORE_ROOT: &str = "/var/lib/containerd/io.containerd.content.v1.content";
 
-pub struct Client {
-    inner: Channel,
-    rt: Runtime,
+/// A client for the containerd services used by the shim, for callers in an async context.
+pub struct AsyncClient {
+    channel: Channel,
     namespace: String,
     address: String,
+    content_channel: Channel,
+    content_address: String,
+    tls: Option<ContainerdTlsOptions>,
+    write_timeout: Duration,
+    stat_timeout: Duration,
+    max_cache_size: Option<u64>,
+    precompile_cache_dir: Option<PrecompileCacheDir>,
+    max_module_bytes: Option<u64>,
+    last_used_interval: Duration,
+    read_concurrency: usize,
+    read_retries: u32,
+    read_retry_delay: Duration,
+    space_factor: f64,
+    content_store_root: PathBuf,
+    precompile_wait: Duration,
+    image_target_wait: Duration,
+    keep_existing_precompiled: bool,
+    compress_precompiled: bool,
+    available_space: fn(&Path) -> Result<u64>,
+    min_precompile_memory: Option<u64>,
+    available_memory: fn() -> Result<u64>,
+    load_timings: Mutex<LoadTimings>,
+    verifier: Option<Box<ImageVerifier>>,
+    wasm_features: Vec<WasmFeature>,
+    precompile: bool,
+    module_hints: bool,
+    retry_policy: RetryPolicy,
+}
+
+/// A blocking client for the containerd services used by the shim.
+///
+/// It runs the operations of [`AsyncClient`] to completion on a runtime of its own,
+/// so it can't be used from within an async context, where `AsyncClient` should be used instead.
+// sync wrapper implementation from https://tokio.rs/tokio/topics/bridging
+pub struct Client {
+    inner: AsyncClient,
+    rt: Runtime,
+}
+
+/// A check of the manifest of an image, that rejects the image by returning an error.
+pub type ImageVerifier = dyn Fn(&ImageManifest) -> anyhow::Result<()> + Send + Sync;
+
+/// The digests of the output of a container in the content store, see [`AsyncClient::export_logs`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedLogs {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Whether an image has precompiled content for an engine, see [`AsyncClient::precompile_status`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PrecompileState {
+    /// The image has precompiled content for the current version and settings of the engine.
+    Present { digest: String },
+    /// The image has no precompiled content for the engine.
+    Absent,
+    /// The image only has precompiled content for other versions or settings of the engine,
+    /// or its precompile label points at content that is no longer in the content store.
+    /// The image is precompiled again the next time it runs.
+    Stale,
+}
+
+/// When the phases of the last `load_modules` call finished.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct LoadTimings {
+    /// When the modules were read from the content store or the caches.
+    pub content_loaded: Option<Instant>,
+    /// When the modules were precompiled and cached, if they weren't in the cache already.
+    pub precompiled: Option<Instant>,
 }
 
 #[derive(Debug)]
 pub(crate) struct WriteContent {
-    _lease: LeaseGuard,
+    lease: LeaseGuard,
     pub digest: String,
 }
 
-// sync wrapper implementation from https://tokio.rs/tokio/topics/bridging
-impl Client {
+impl WriteContent {
+    // releases the lease of the content once something else keeps it from being garbage collected,
+    // and returns its digest; the lease expires on its own if it can't be released.
+    // Callers release it on their error paths too, rather than dropping it.
+    async fn release(self) -> String {
+        if let Err(err) = self.lease.release().await {
+            log::warn!("{err}");
+        }
+        self.digest
+    }
+}
+
+// Fails if `fut` doesn't complete within `timeout`, so that a stalled write to containerd
+// results in an error rather than hanging forever.
+async fn stall_timeout<T>(
+    timeout: Duration,
+    step: &str,
+    fut: impl Future<Output = T>,
+) -> Result<T> {
+    tokio::time::timeout(timeout, fut)
+        .await
+        .map_err(|_| ShimError::Timeout {
+            operation: format!("{step} of a content write"),
+            after: timeout,
+        })
+}
+
+// Gets the image with `get` until it has a target, for up to `wait`,
+// as the record of an image that is still being pulled may not have one yet.
+async fn wait_for_target<Fut>(wait: Duration, mut get: impl FnMut() -> Fut) -> Result<Image>
+where
+    Fut: Future<Output = Result<Image>>,
+{
+    let polls = (wait.as_millis() / IMAGE_TARGET_POLL_INTERVAL.as_millis()) as u32;
+    RetryPolicy::new(polls + 1, IMAGE_TARGET_POLL_INTERVAL)
+        .with_max_delay(IMAGE_TARGET_POLL_INTERVAL)
+        .with_retryable(|err| matches!(err, ShimError::ImageNotReady(_)))
+        .run("image resolution", || {
+            let image = get();
+            async move {
+                let image = image.await?;
+                match image.target {
+                    Some(_) => Ok(image),
+                    None => Err(ShimError::ImageNotReady(format!(
+                        "image {} has no target after {wait:?}, it may still be pulled",
+                        image.name
+                    ))),
+                }
+            }
+        })
+        .await
+}
+
+// The outcome of the stat of a content write.
+enum Stat {
+    // the content is already in the content store
+    Exists,
+    // the write started, and the first `offset` bytes of the content are already in the content store
+    Started {
+        requests: mpsc::Sender<WriteContentRequest>,
+        responses: tonic::Streaming<WriteContentResponse>,
+        offset: i64,
+    },
+}
+
+impl AsyncClient {
     // wrapper around connection that will establish a connection and create a client
-    pub fn connect(
+    // the address is either the path of the unix socket of containerd, or `tcp://host:port`
+    pub async fn connect(
         address: impl AsRef<Path> + ToString,
         namespace: impl ToString,
-    ) -> Result<Client> {
-        let rt = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?;
+    ) -> Result<AsyncClient> {
+        Self::connect_with_tls(address, namespace, None).await
+    }
 
-        let inner = rt
-            .block_on(containerd_client::connect(address.as_ref()))
-            .map_err(|err| ShimError::Containerd(err.to_string()))?;
+    /// Like [`AsyncClient::connect`], but connects to a `tcp://` address over TLS when `tls` is set.
+    pub async fn connect_with_tls(
+        address: impl AsRef<Path> + ToString,
+        namespace: impl ToString,
+        tls: Option<ContainerdTlsOptions>,
+    ) -> Result<AsyncClient> {
+        let address = address.to_string();
+        let channel = connect(&address, tls.as_ref()).await?;
 
-        Ok(Client {
-            inner,
-            rt,
+        Ok(AsyncClient {
+            content_channel: channel.clone(),
+            content_address: address.clone(),
+            channel,
             namespace: namespace.to_string(),
-            address: address.to_string(),
+            address,
+            tls,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            stat_timeout: DEFAULT_STAT_TIMEOUT,
+            max_cache_size: None,
+            precompile_cache_dir: None,
+            max_module_bytes: None,
+            last_used_interval: DEFAULT_LAST_USED_INTERVAL,
+            read_concurrency: DEFAULT_READ_CONCURRENCY,
+            read_retries: 0,
+            read_retry_delay: Duration::ZERO,
+            space_factor: DEFAULT_SPACE_FACTOR,
+            content_store_root: PathBuf::from(DEFAULT_CONTENT_STORE_ROOT),
+            precompile_wait: DEFAULT_PRECOMPILE_WAIT,
+            image_target_wait: DEFAULT_IMAGE_TARGET_WAIT,
+            keep_existing_precompiled: false,
+            compress_precompiled: false,
+            available_space,
+            min_precompile_memory: None,
+            available_memory,
+            load_timings: Mutex::default(),
+            verifier: None,
+            wasm_features: vec![],
+            precompile: true,
+            module_hints: false,
+            retry_policy: RetryPolicy::none(),
         })
     }
 
+    /// Connects to the content store, and the leases that protect its content, at `address`
+    /// rather than at the address of the client, for setups where they are served by another endpoint.
+    /// By default the content store is reached at the same address as the other services.
+    pub async fn with_content_address(mut self, address: impl ToString) -> Result<Self> {
+        let address = address.to_string();
+        if address != self.content_address {
+            self.content_channel = connect(&address, self.tls.as_ref()).await?;
+            self.content_address = address;
+        }
+        Ok(self)
+    }
+
+    /// Sets how long a content write may go without progress before it is aborted.
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Sets how long the stat of a content write, which checks whether the content is already there,
+    /// may take before the write fails with a timeout, which the retry policy retries.
+    pub fn with_stat_timeout(mut self, timeout: Duration) -> Self {
+        self.stat_timeout = timeout;
+        self
+    }
+
+    /// Retries a content read up to `retries` times while the content isn't found,
+    /// e.g., because it was committed by another client right before, and isn't visible yet.
+    /// The first retry waits `delay`, and each next one twice as long.
+    /// The reads run with the retry policy, extended to content that isn't found, so that both share the attempts.
+    /// By default reads of missing content fail right away.
+    pub fn with_read_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.read_retries = retries;
+        self.read_retry_delay = delay;
+        self
+    }
+
+    /// Sets how the content reads and writes, the leases and the image operations are retried when they fail,
+    /// e.g., while containerd restarts. By default they aren't retried.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Caps the total size in bytes of the precompiled content in the content store.
+    /// The least recently used precompiled content is evicted before new content is saved
+    /// that would go over this size.
+    pub fn with_max_cache_size(mut self, max_cache_size: u64) -> Self {
+        self.max_cache_size = Some(max_cache_size);
+        self
+    }
+
+    /// Keeps the precompiled modules in `dir`, keyed by the digest of their image and the engine,
+    /// rather than in the content store, e.g., to keep them on a faster or larger disk.
+    /// The directory is created if it doesn't exist, and must not be writable by other users.
+    /// By default precompiled modules are kept in the content store.
+    pub fn with_precompile_cache_dir(mut self, dir: impl AsRef<Path>) -> Result<Self> {
+        self.precompile_cache_dir = Some(PrecompileCacheDir::open(dir)?);
+        Ok(self)
+    }
+
+    /// Sets how long to wait for the image of a container to have a target, before failing with `Error::ImageNotReady`.
+    /// The record of an image that is still being pulled may not have one yet.
+    pub fn with_image_target_wait(mut self, wait: Duration) -> Self {
+        self.image_target_wait = wait;
+        self
+    }
+
+    /// Caps the size in bytes of each wasm layer of an image.
+    /// Larger layers are rejected with `Error::ModuleTooLarge` before they are read from the content store.
+    /// By default there is no limit.
+    pub fn with_max_module_bytes(mut self, max_module_bytes: u64) -> Self {
+        self.max_module_bytes = Some(max_module_bytes);
+        self
+    }
+
+    /// Sets how often the last-used label of precompiled content is updated on a cache hit.
+    /// Updates are throttled to this interval to avoid writing to the content store on every run.
+    pub fn with_last_used_interval(mut self, interval: Duration) -> Self {
+        self.last_used_interval = interval;
+        self
+    }
+
+    /// Sets how many layers are read from the content store at the same time.
+    pub fn with_read_concurrency(mut self, read_concurrency: usize) -> Self {
+        self.read_concurrency = read_concurrency.max(1);
+        self
+    }
+
+    /// Sets how many times the size of the layers must be free in the content store before precompiling.
+    pub fn with_space_factor(mut self, space_factor: f64) -> Self {
+        self.space_factor = space_factor;
+        self
+    }
+
+    /// Sets the directory of the content store of containerd, whose free space is checked before precompiling.
+    /// Defaults to the content store of containerd with its default root, `/var/lib/containerd`.
+    pub fn with_content_store_root(mut self, root: impl AsRef<Path>) -> Self {
+        self.content_store_root = root.as_ref().to_path_buf();
+        self
+    }
+
+    /// Skips precompiling when less than `min_precompile_memory` bytes of memory are available,
+    /// and runs the modules from the wasm layers instead, so that a large precompile doesn't run the shim out of memory.
+    /// By default images are always precompiled.
+    pub fn with_min_precompile_memory(mut self, min_precompile_memory: u64) -> Self {
+        self.min_precompile_memory = Some(min_precompile_memory);
+        self
+    }
+
+    /// Sets how long to wait for the precompile of an image that another container of the image started.
+    /// If it doesn't finish in time, the image is precompiled again.
+    pub fn with_precompile_wait(mut self, precompile_wait: Duration) -> Self {
+        self.precompile_wait = precompile_wait;
+        self
+    }
+
+    /// Sets whether to keep the precompiled content that an image already has when precompiling it again
+    /// produces different output, e.g., because the engine doesn't compile deterministically.
+    /// By default the label of the image is pointed at the new output.
+    pub fn with_keep_existing_precompiled(mut self, keep_existing_precompiled: bool) -> Self {
+        self.keep_existing_precompiled = keep_existing_precompiled;
+        self
+    }
+
+    /// Sets whether to compress the precompiled content that is saved to the content store with zstd,
+    /// which saves disk at the cost of decompressing it each time it is loaded.
+    /// The media type label of the content gets a `+zstd` suffix, and its digest is that of the compressed bytes.
+    /// Compressed content is decompressed when it is loaded whether this is set or not.
+    /// By default precompiled content is saved uncompressed.
+    pub fn with_compressed_precompiled(mut self, compress_precompiled: bool) -> Self {
+        self.compress_precompiled = compress_precompiled;
+        self
+    }
+
+    /// Sets a check of the manifest of the images, run before their modules are loaded.
+    /// Images that it rejects fail to load with `Error::VerificationFailed`.
+    /// By default every image is accepted.
+    pub fn with_verifier(
+        mut self,
+        verifier: impl Fn(&ImageManifest) -> anyhow::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.verifier = Some(Box::new(verifier));
+        self
+    }
+
+    /// Sets the wasm features that the container enables, see [`Engine::optional_wasm_features`].
+    /// The modules aren't rejected for requiring them, and aren't precompiled when any is set.
+    pub fn with_wasm_features(mut self, wasm_features: Vec<WasmFeature>) -> Self {
+        self.wasm_features = wasm_features;
+        self
+    }
+
+    /// Sets whether the modules are precompiled, when the engine can precompile them.
+    /// The modules of a container that the engine runs with other settings than it precompiles with,
+    /// e.g., with fuel metering, must run from the wasm layers, as the precompiled modules can't be loaded.
+    /// By default the modules are precompiled.
+    pub fn with_precompile(mut self, precompile: bool) -> Self {
+        self.precompile = precompile;
+        self
+    }
+
+    /// Sets whether the hints of the modules are applied to the container, see the `module_hints` option of the shim.
+    /// Modules with a fuel hint run with fuel metering, so they aren't precompiled, see [`AsyncClient::with_precompile`].
+    pub fn with_module_hints(mut self, module_hints: bool) -> Self {
+        self.module_hints = module_hints;
+        self
+    }
+
+    fn verify_manifest(&self, manifest: &ImageManifest, image_name: &str) -> Result<()> {
+        let Some(verifier) = &self.verifier else {
+            return Ok(());
+        };
+        verifier(manifest)
+            .map_err(|err| ShimError::VerificationFailed(format!("image {image_name}: {err:#}")))
+    }
+
+    // Whether there is enough memory available to precompile, see `with_min_precompile_memory`.
+    fn has_memory_to_precompile(&self) -> bool {
+        let Some(min_memory) = self.min_precompile_memory else {
+            return true;
+        };
+        match (self.available_memory)() {
+            Ok(available) if available < min_memory => {
+                log::warn!(
+                    "not precompiling: {available} bytes of memory are available, less than the {min_memory} bytes required, using the module from the OCI layers"
+                );
+                false
+            }
+            Ok(_) => true,
+            Err(err) => {
+                log::warn!("failed to get the available memory: {err}");
+                true
+            }
+        }
+    }
+
+    // fails early if the content store doesn't have room for the precompiled output of `input_size` bytes,
+    // rather than with a confusing error when the write is committed
+    fn check_free_space(&self, input_size: u64) -> Result<()> {
+        let required = (input_size as f64 * self.space_factor).ceil() as u64;
+        let (root, store) = match &self.precompile_cache_dir {
+            Some(cache_dir) => (cache_dir.path(), "the precompile cache directory"),
+            None => (self.content_store_root.as_path(), "the content store"),
+        };
+        let available = match (self.available_space)(root) {
+            Ok(available) => available,
+            Err(err) => {
+                // precompiling goes ahead, so a full disk fails late, when the content is committed
+                log::error!(
+                    "failed to get the free space of {store} at {root:?}, it isn't checked before precompiling: {err}; if containerd doesn't run with its default root, set the content_store_root option of the shim"
+                );
+                return Ok(());
+            }
+        };
+        if available < required {
+            return Err(ShimError::InsufficientSpace(format!(
+                "precompiling needs about {required} bytes, but {store} has {available} bytes free"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns when the phases of the last `load_modules` call finished.
+    pub(crate) fn load_timings(&self) -> LoadTimings {
+        *self.load_timings.lock().unwrap()
+    }
+
+    fn record_content_loaded(&self) {
+        self.load_timings.lock().unwrap().content_loaded = Some(Instant::now());
+    }
+
     // wrapper around read that will read the entire content file
-    fn read_content(&self, digest: impl ToString) -> Result<Vec<u8>> {
-        self.rt.block_on(async {
-            let req = ReadContentRequest {
-                digest: digest.to_string(),
-                ..Default::default()
-            };
-            let req = with_namespace!(req, self.namespace);
-            ContentClient::new(self.inner.clone())
-                .read(req)
-                .await
-                .map_err(|err| ShimError::Containerd(err.to_string()))?
-                .into_inner()
-                .map_ok(|msg| msg.data)
-                .try_concat()
-                .await
-                .map_err(|err| ShimError::Containerd(err.to_string()))
-        })
+    async fn read_content(&self, digest: impl ToString) -> Result<Vec<u8>> {
+        let digest = digest.to_string();
+        timed_span!("read_content", digest = digest.clone())
+            .instrument(
+                self.read_policy()
+                    .run("content read", || self.read_content_once(&digest)),
+            )
+            .await
     }
 
-    // used in tests to clean up content
-    #[allow(dead_code)]
-    fn delete_content(&self, digest: impl ToString) -> Result<()> {
-        self.rt.block_on(async {
-            let req = DeleteContentRequest {
-                digest: digest.to_string(),
-            };
-            let req = with_namespace!(req, self.namespace);
-            ContentClient::new(self.inner.clone())
-                .delete(req)
-                .await
-                .map_err(|err| ShimError::Containerd(err.to_string()))?;
-            Ok(())
-        })
+    // the retry policy of the content reads, see `with_read_retries`
+    fn read_policy(&self) -> RetryPolicy {
+        match self.read_retries {
+            0 => self.retry_policy.clone(),
+            retries => self
+                .retry_policy
+                .also_retrying(retries + 1, self.read_retry_delay, |err| {
+                    matches!(err, ShimError::NotFound(_))
+                }),
+        }
     }
 
-    // wrapper around lease that will create a lease and return a guard that will delete the lease when dropped
-    fn lease(&self, reference: String) -> Result<LeaseGuard> {
-        self.rt.block_on(async {
-            let mut lease_labels = HashMap::new();
-            let expire = chrono::Utc::now() + chrono::Duration::hours(24);
-            lease_labels.insert("containerd.io/gc.expire".to_string(), expire.to_rfc3339());
-            let lease_request = containerd_client::services::v1::CreateRequest {
-                id: reference.clone(),
-                labels: lease_labels,
-            };
+    // reads a precompiled module, or returns None if it is gone from the content store,
+    // e.g., because it was removed manually, so that the image is precompiled again.
+    // Any other failure, e.g., an unreachable content store, is returned, as precompiling wouldn't fix it.
+    async fn read_precompiled(&self, digest: &str) -> Result<Option<Vec<u8>>> {
+        match self.read_content(digest).await {
+            Ok(precompiled) => match decompress_precompiled(precompiled) {
+                Ok(precompiled) => Ok(Some(precompiled)),
+                Err(err) => {
+                    log::warn!("failed to decompress precompiled module {digest}: {err}, will attempt to recompile");
+                    Ok(None)
+                }
+            },
+            Err(ShimError::NotFound(err)) => {
+                log::warn!("precompiled module {digest} not found: {err}. Content may have been removed manually, will attempt to recompile");
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn read_content_once(&self, digest: &str) -> Result<Vec<u8>> {
+        let req = ReadContentRequest {
+            digest: digest.to_string(),
+            ..Default::default()
+        };
+        let req = with_namespace!(req, self.namespace);
+        let map_err = |err: tonic::Status| match err.code() {
+            Code::NotFound => ShimError::NotFound(err.message().to_string()),
+            _ => ShimError::Containerd(err.to_string()),
+        };
+        ContentClient::new(self.content_channel.clone())
+            .read(req)
+            .await
+            .map_err(map_err)?
+            .into_inner()
+            .map_ok(|msg| msg.data)
+            .try_concat()
+            .await
+            .map_err(map_err)
+    }
 
-            let mut leases_client = LeasesClient::new(self.inner.clone());
+    /// Streams the content of `digest` into `writer`, without holding all of it in memory.
+    /// Returns the number of bytes written.
+    pub async fn copy_content(
+        &self,
+        digest: impl ToString,
+        writer: &mut impl Write,
+    ) -> Result<u64> {
+        let digest = digest.to_string();
+        timed_span!("copy_content", digest = digest.clone())
+            .instrument(async {
+                // only the read that opens the stream is retried, as the bytes already written can't be taken back
+                let mut stream = self
+                    .read_policy()
+                    .run("content read", || {
+                        let req = ReadContentRequest {
+                            digest: digest.clone(),
+                            ..Default::default()
+                        };
+                        let req = with_namespace!(req, self.namespace);
+                        let mut content_client = ContentClient::new(self.content_channel.clone());
+                        async move {
+                            content_client
+                                .read(req)
+                                .await
+                                .map_err(|err| match err.code() {
+                                    Code::NotFound => {
+                                        ShimError::NotFound(err.message().to_string())
+                                    }
+                                    _ => ShimError::Containerd(err.to_string()),
+                                })
+                        }
+                    })
+                    .await?
+                    .into_inner();
+                let mut written = 0;
+                while let Some(msg) = stream
+                    .message()
+                    .await
+                    .map_err(|err| ShimError::Containerd(err.to_string()))?
+                {
+                    writer.write_all(&msg.data)?;
+                    written += msg.data.len() as u64;
+                }
+                Ok(written)
+            })
+            .await
+    }
 
-            let lease = leases_client
-                .create(with_namespace!(lease_request, self.namespace))
-                .await
-                .map_err(|e| ShimError::Containerd(e.to_string()))?
-                .into_inner()
-                .lease
-                .ok_or_else(|| {
-                    ShimError::Containerd(format!("unable to create lease for  {}", reference))
-                })?;
-
-            Ok(LeaseGuard {
-                lease_id: lease.id,
-                address: self.address.clone(),
-                namespace: self.namespace.clone(),
+    // reads the content of every digest, up to `read_concurrency` at a time.
+    // The content is returned in the same order as the digests, and the first error aborts the other reads.
+    async fn read_contents(&self, digests: Vec<String>) -> Result<Vec<Vec<u8>>> {
+        timed_span!("read_contents", count = digests.len())
+            .instrument(
+                stream::iter(digests)
+                    .map(|digest| self.read_content(digest))
+                    .buffered(self.read_concurrency)
+                    .try_collect(),
+            )
+            .await
+    }
+
+    async fn delete_content(&self, digest: impl ToString) -> Result<()> {
+        let req = DeleteContentRequest {
+            digest: digest.to_string(),
+        };
+        let req = with_namespace!(req, self.namespace);
+        ContentClient::new(self.content_channel.clone())
+            .delete(req)
+            .await
+            .map_err(|err| match err.code() {
+                Code::NotFound => ShimError::NotFound(err.message().to_string()),
+                _ => ShimError::Containerd(err.to_string()),
+            })?;
+        Ok(())
+    }
+
+    // best effort to abort an in-progress write, so that the ref can be written again
+    async fn abort_write(&self, reference: &str) {
+        let req = AbortRequest {
+            r#ref: reference.to_string(),
+        };
+        let req = with_namespace!(req, self.namespace);
+        match ContentClient::new(self.content_channel.clone())
+            .abort(req)
+            .await
+        {
+            Ok(_) => log::debug!("aborted write of {reference}"),
+            Err(err) if err.code() == Code::NotFound => {}
+            Err(err) => log::warn!("failed to abort write of {reference}: {err}"),
+        }
+    }
+
+    // wrapper around lease that will create a lease and return a guard that deletes the lease when released,
+    // or on a best effort when dropped
+    async fn lease(&self, reference: String) -> Result<LeaseGuard> {
+        let mut lease_labels = HashMap::new();
+        let expire = chrono::Utc::now() + chrono::Duration::hours(24);
+        lease_labels.insert("containerd.io/gc.expire".to_string(), expire.to_rfc3339());
+        let lease_request = containerd_client::services::v1::CreateRequest {
+            id: reference.clone(),
+            labels: lease_labels,
+        };
+
+        let lease = self
+            .retry_policy
+            .run("lease creation", || {
+                let req = lease_request.clone();
+                let req = with_namespace!(req, self.namespace);
+                let mut leases_client = LeasesClient::new(self.content_channel.clone());
+                async move {
+                    leases_client
+                        .create(req)
+                        .await
+                        .map_err(|e| ShimError::Containerd(e.to_string()))
+                }
             })
+            .await?
+            .into_inner()
+            .lease
+            .ok_or_else(|| {
+                ShimError::Containerd(format!("unable to create lease for  {}", reference))
+            })?;
+
+        Ok(LeaseGuard {
+            lease_id: lease.id,
+            namespace: self.namespace.clone(),
+            channel: self.content_channel.clone(),
+            handle: tokio::runtime::Handle::current(),
+            released: false,
         })
     }
 
-    fn save_content(
+    // saves precompiled content, labeled with the digest it was precompiled from, and with the name of the image
+    // when it is set, so that tooling can map it back to the image by name, not just by digest
+    async fn save_image_content(
         &self,
         data: Vec<u8>,
+        image_name: Option<&str>,
         original_digest: String,
         label: &str,
+        media_type: Option<&str>,
+    ) -> Result<WriteContent> {
+        let mut labels = HashMap::new();
+        labels.insert(label.to_string(), original_digest);
+        labels.insert(LAST_USED_LABEL.to_string(), unix_now().to_string());
+        if let Some(media_type) = media_type {
+            labels.insert(MEDIA_TYPE_LABEL.to_string(), media_type.to_string());
+        }
+        if let Some(image_name) = image_name {
+            labels.insert(IMAGE_REFERENCE_LABEL.to_string(), image_name.to_string());
+        }
+        self.write_content(data, format!("precompile-{}", label), labels)
+            .await
+    }
+
+    // Sends write request with Stat action to containerd to let it know that we are going to write content.
+    // If the content is already there, it returns early with AlreadyExists.
+    async fn stat_content(
+        &self,
+        reference: &str,
+        total: i64,
+        expected: &str,
+        lease_id: &str,
+    ) -> Result<Stat> {
+        // create a channel to feed the stream; only sending one message at a time so we can set this to one
+        let (tx, rx) = mpsc::channel(1);
+        let req = WriteContentRequest {
+            r#ref: reference.to_string(),
+            action: WriteAction::Stat.into(),
+            total,
+            expected: expected.to_string(),
+            ..Default::default()
+        };
+        tx.send(req)
+            .await
+            .map_err(|err| ShimError::Containerd(err.to_string()))?;
+        let request_stream = ReceiverStream::new(rx);
+        let request_stream = with_lease!(request_stream, self.namespace, lease_id);
+        let mut responses = match ContentClient::new(self.content_channel.clone())
+            .write(request_stream)
+            .await
+        {
+            Ok(responses) => responses.into_inner(),
+            Err(e) if e.code() == Code::AlreadyExists => return Ok(Stat::Exists),
+            Err(e) => return Err(ShimError::Containerd(e.to_string())),
+        };
+        let response = responses
+            .message()
+            .await
+            .map_err(|e| ShimError::Containerd(e.to_string()))?
+            .ok_or_else(|| {
+                ShimError::Containerd(format!(
+                    "no response received after write request for {}",
+                    expected
+                ))
+            })?;
+        Ok(Stat::Started {
+            requests: tx,
+            responses,
+            offset: response.offset,
+        })
+    }
+
+    // writes `data` to the content store with `labels`, under a lease that lives as long as the returned value.
+    // Concurrent writes with the same `reference` conflict, so it should be unique to what is written.
+    async fn write_content(
+        &self,
+        data: Vec<u8>,
+        reference: String,
+        labels: HashMap<String, String>,
     ) -> Result<WriteContent> {
-        let expected = format!("sha256:{}", digest(data.clone()));
-        let reference = format!("precompile-{}", label);
-        let lease = self.lease(reference.clone())?;
+        timed_span!("write_content", reference = reference)
+            .instrument(self.retry_policy.run("content write", || {
+                self.write_content_once(&data, &reference, &labels)
+            }))
+            .await
+    }
 
-        let digest = self.rt.block_on(async {
-            // create a channel to feed the stream; only sending one message at a time so we can set this to one
-            let (tx, rx) = mpsc::channel(1);
+    async fn write_content_once(
+        &self,
+        data: &[u8],
+        reference: &str,
+        labels: &HashMap<String, String>,
+    ) -> Result<WriteContent> {
+        let expected = format!("sha256:{}", digest(data));
+        let lease = self.lease(reference.to_string()).await?;
 
+        let result: Result<String> = async {
             let len = data.len() as i64;
             log::debug!("Writing {} bytes to content store", len);
-            let mut client = ContentClient::new(self.inner.clone());
 
-            // Send write request with Stat action to containerd to let it know that we are going to write content
-            // if the content is already there, it will return early with AlreadyExists
+            // a stat that stalls fails the attempt, so that a slow content server doesn't stall the write
             log::debug!("Sending stat request to containerd");
-            let req = WriteContentRequest {
-                r#ref: reference.clone(),
-                action: WriteAction::Stat.into(),
-                total: len,
-                expected: expected.clone(),
-                ..Default::default()
+            let stat = self.stat_content(reference, len, &expected, &lease.lease_id);
+            let stat = match stall_timeout(self.stat_timeout, "stat", stat).await {
+                Ok(stat) => stat?,
+                Err(err) => {
+                    // the stalled stat may still hold the ref, which the next attempt needs
+                    self.abort_write(reference).await;
+                    return Err(err);
+                }
             };
-            tx.send(req)
-                .await
-                .map_err(|err| ShimError::Containerd(err.to_string()))?;
-            let request_stream = ReceiverStream::new(rx);
-            let request_stream =
-                with_lease!(request_stream, self.namespace, lease.lease_id.clone());
-            let mut response_stream = match client.write(request_stream).await {
-                Ok(response_stream) => response_stream.into_inner(),
-                Err(e) if e.code() == Code::AlreadyExists => {
-                    log::info!("content already exists {}", expected.clone().to_string());
+            let (tx, mut response_stream, offset) = match stat {
+                Stat::Exists => {
+                    log::info!("content already exists {}", expected);
                     return Ok(expected);
                 }
-                Err(e) => return Err(ShimError::Containerd(e.to_string())),
+                Stat::Started {
+                    requests,
+                    responses,
+                    offset,
+                } => (requests, responses, offset),
             };
-            let response = response_stream
-                .message()
-                .await
-                .map_err(|e| ShimError::Containerd(e.to_string()))?
-                .ok_or_else(|| {
-                    ShimError::Containerd(format!(
-                        "no response received after write request for {}",
-                        expected
-                    ))
-                })?;
 
             // There is a scenario where the content might have been removed manually
             // but the content isn't removed from the containerd file system yet.
             // In this case if we re-add it at before its removed from file system
             // we don't need to copy the content again.  Container tells us it found the blob
             // by returning the offset of the content that was found.
-            let data_to_write = data[response.offset as usize..].to_vec();
+            let data_to_write = data[offset as usize..].to_vec();
 
             // Write and commit at same time
-            let mut labels = HashMap::new();
-            labels.insert(label.to_string(), original_digest.clone());
             let commit_request = WriteContentRequest {
                 action: WriteAction::Commit.into(),
                 total: len,
-                offset: response.offset,
+                offset,
                 expected: expected.clone(),
-                labels,
+                labels: labels.clone(),
                 data: data_to_write,
                 ..Default::default()
             };
-            log::debug!(
-                "Sending commit request to containerd with response: {:?}",
-                response
-            );
-            tx.send(commit_request)
-                .await
-                .map_err(|err| ShimError::Containerd(format!("commit request error: {}", err)))?;
-            let response = response_stream
-                .message()
-                .await
-                .map_err(|err| ShimError::Containerd(format!("response stream error: {}", err)))?
-                .ok_or_else(|| {
-                    ShimError::Containerd(format!(
-                        "no response received after write request for {}",
-                        expected.clone()
-                    ))
-                })?;
+            log::debug!("Sending commit request to containerd at offset {offset}");
+            stall_timeout(
+                self.write_timeout,
+                "commit request",
+                tx.send(commit_request),
+            )
+            .await?
+            .map_err(|err| ShimError::Containerd(format!("commit request error: {}", err)))?;
+            let response = stall_timeout(
+                self.write_timeout,
+                "commit response",
+                response_stream.message(),
+            )
+            .await?
+            .map_err(|err| ShimError::Containerd(format!("response stream error: {}", err)))?
+            .ok_or_else(|| {
+                ShimError::Containerd(format!(
+                    "no response received after write request for {}",
+                    expected.clone()
+                ))
+            })?;
 
             log::debug!("Validating response");
             // client should validate that all bytes were written and that the digest matches
@@ -240,239 +873,933 @@ impl Client {
                 )));
             }
             Ok(response.digest)
-        })?;
+        }
+        .await;
 
-        Ok(WriteContent {
-            _lease: lease,
-            digest: digest.clone(),
-        })
+        // abort the ingest so that a stalled or failed write doesn't keep the ref locked
+        let digest = match result {
+            Ok(digest) => digest,
+            Err(err) => {
+                self.abort_write(reference).await;
+                if let Err(err) = lease.release().await {
+                    log::warn!("{err}");
+                }
+                return Err(err);
+            }
+        };
+
+        Ok(WriteContent { lease, digest })
     }
 
-    fn get_info(&self, content_digest: String) -> Result<Info> {
-        self.rt.block_on(async {
-            let req = InfoRequest {
-                digest: content_digest.clone(),
-            };
-            let req = with_namespace!(req, self.namespace);
-            let info = ContentClient::new(self.inner.clone())
-                .info(req)
-                .await
-                .map_err(|err| ShimError::Containerd(err.to_string()))?
-                .into_inner()
-                .info
-                .ok_or_else(|| {
-                    ShimError::Containerd(format!(
-                        "failed to get info for content {}",
-                        content_digest
-                    ))
-                })?;
-            Ok(info)
-        })
+    async fn get_info(&self, content_digest: String) -> Result<Info> {
+        let req = InfoRequest {
+            digest: content_digest.clone(),
+        };
+        let req = with_namespace!(req, self.namespace);
+        let info = ContentClient::new(self.content_channel.clone())
+            .info(req)
+            .await
+            .map_err(|err| match err.code() {
+                Code::NotFound => ShimError::NotFound(err.message().to_string()),
+                _ => ShimError::Containerd(err.to_string()),
+            })?
+            .into_inner()
+            .info
+            .ok_or_else(|| {
+                ShimError::Containerd(format!("failed to get info for content {}", content_digest))
+            })?;
+        Ok(info)
     }
 
-    fn update_info(&self, info: Info) -> Result<Info> {
-        self.rt.block_on(async {
-            let req = UpdateRequest {
-                info: Some(info.clone()),
-                update_mask: Some(FieldMask {
-                    paths: vec!["labels".to_string()],
-                }),
+    // replaces all the labels of the content, which drops the concurrent updates of its labels,
+    // so only the tests use it, to set the labels up; see `update_info_labels`
+    #[cfg(test)]
+    async fn update_info(&self, info: Info) -> Result<Info> {
+        self.update_info_fields(info, vec!["labels".to_string()])
+            .await
+    }
+
+    // Sets the `labels` of the content, where an empty value removes the label, and keeps its other labels.
+    // Only these labels are updated, so that the concurrent updates of other labels, e.g., the gc refs of
+    // other precompiled content of the same image, are kept. A concurrent update of all the labels can still
+    // drop them, so they are read back, and set again while they are missing.
+    async fn update_info_labels(
+        &self,
+        digest: impl ToString,
+        labels: HashMap<String, String>,
+    ) -> Result<Info> {
+        let digest = digest.to_string();
+        let paths: Vec<_> = labels.keys().map(|key| format!("labels.{key}")).collect();
+        for attempt in 1..=LABEL_UPDATE_ATTEMPTS {
+            let info = Info {
+                digest: digest.clone(),
+                labels: labels.clone(),
+                ..Default::default()
             };
-            let req = with_namespace!(req, self.namespace);
-            let info = ContentClient::new(self.inner.clone())
-                .update(req)
-                .await
-                .map_err(|err| ShimError::Containerd(err.to_string()))?
-                .into_inner()
-                .info
-                .ok_or_else(|| {
-                    ShimError::Containerd(format!(
-                        "failed to update info for content {}",
-                        info.digest
-                    ))
-                })?;
-            Ok(info)
-        })
+            self.update_info_fields(info, paths.clone()).await?;
+
+            let info = self.get_info(digest.clone()).await?;
+            let missing: Vec<_> = labels
+                .iter()
+                .filter(|(key, value)| !value.is_empty() && !info.labels.contains_key(*key))
+                .map(|(key, _)| key.as_str())
+                .collect();
+            if missing.is_empty() {
+                return Ok(info);
+            }
+            log::debug!(
+                "labels {missing:?} of content {digest} were dropped by a concurrent update ({attempt}/{LABEL_UPDATE_ATTEMPTS})"
+            );
+        }
+        Err(ShimError::Containerd(format!(
+            "failed to update the labels of content {digest}, concurrent updates kept dropping them"
+        )))
     }
 
-    fn get_image(&self, image_name: impl ToString) -> Result<Image> {
-        self.rt.block_on(async {
-            let name = image_name.to_string();
-            let req = GetImageRequest { name };
-            let req = with_namespace!(req, self.namespace);
-            let image = ImagesClient::new(self.inner.clone())
-                .get(req)
-                .await
-                .map_err(|err| ShimError::Containerd(err.to_string()))?
-                .into_inner()
-                .image
-                .ok_or_else(|| {
-                    ShimError::Containerd(format!(
-                        "failed to get image for image {}",
-                        image_name.to_string()
-                    ))
-                })?;
-            Ok(image)
-        })
+    // updates the fields of the content in `paths`, e.g., `labels.<key>` for a single label
+    async fn update_info_fields(&self, info: Info, paths: Vec<String>) -> Result<Info> {
+        let req = UpdateRequest {
+            info: Some(info.clone()),
+            update_mask: Some(FieldMask { paths }),
+        };
+        let req = with_namespace!(req, self.namespace);
+        let info = ContentClient::new(self.content_channel.clone())
+            .update(req)
+            .await
+            .map_err(|err| ShimError::Containerd(err.to_string()))?
+            .into_inner()
+            .info
+            .ok_or_else(|| {
+                ShimError::Containerd(format!("failed to update info for content {}", info.digest))
+            })?;
+        Ok(info)
     }
 
-    fn update_image(&self, image: Image) -> Result<Image> {
-        self.rt.block_on(async {
-            let req = UpdateImageRequest {
-                image: Some(image.clone()),
-                update_mask: Some(FieldMask {
-                    paths: vec!["labels".to_string()],
-                }),
-            };
+    async fn get_image(&self, image_name: impl ToString) -> Result<Image> {
+        timed_span!("get_image", image = image_name.to_string())
+            .instrument(async {
+                let name = image_name.to_string();
+                let image = self
+                    .retry_policy
+                    .run("image get", || {
+                        let req = GetImageRequest { name: name.clone() };
+                        let req = with_namespace!(req, self.namespace);
+                        let mut images_client = ImagesClient::new(self.channel.clone());
+                        async move {
+                            images_client
+                                .get(req)
+                                .await
+                                .map_err(|err| match err.code() {
+                                    Code::NotFound => {
+                                        ShimError::NotFound(err.message().to_string())
+                                    }
+                                    _ => ShimError::Containerd(err.to_string()),
+                                })
+                        }
+                    })
+                    .await?
+                    .into_inner()
+                    .image
+                    .ok_or_else(|| {
+                        ShimError::Containerd(format!(
+                            "failed to get image for image {}",
+                            image_name.to_string()
+                        ))
+                    })?;
+                Ok(image)
+            })
+            .await
+    }
 
-            let req = with_namespace!(req, self.namespace);
-            let image = ImagesClient::new(self.inner.clone())
-                .update(req)
-                .await
-                .map_err(|err| ShimError::Containerd(err.to_string()))?
-                .into_inner()
-                .image
-                .ok_or_else(|| {
-                    ShimError::Containerd(format!("failed to update image {}", image.name))
-                })?;
-            Ok(image)
-        })
+    // Looks up the image of a container, see `find_image`, waiting for the image to have a target,
+    // e.g., when the container runs right after the pull of the image started.
+    async fn resolve_image(&self, reference: impl ToString) -> Result<Image> {
+        let reference = reference.to_string();
+        wait_for_target(self.image_target_wait, || self.find_image(&reference)).await
+    }
+
+    // Looks up an image, tolerating differences in how its reference is written,
+    // e.g., `foo` for an image stored as `docker.io/library/foo:latest`.
+    async fn find_image(&self, reference: impl ToString) -> Result<Image> {
+        let reference = reference.to_string();
+        match self.get_image(&reference).await {
+            Err(ShimError::NotFound(_)) => {}
+            res => return res,
+        }
+
+        let normalized = normalize_reference(&reference);
+        if normalized != reference {
+            log::debug!("image {reference} not found, trying {normalized}");
+            match self.get_image(&normalized).await {
+                Err(ShimError::NotFound(_)) => {}
+                res => return res,
+            }
+        }
+
+        // the image may be stored under another form of the reference, or be referenced by digest
+        let digest = reference.split_once('@').map(|(_, digest)| digest);
+        self.list_images(vec![])
+            .await?
+            .into_iter()
+            .find(|image| {
+                normalize_reference(&image.name) == normalized
+                    || digest.is_some_and(|d| image.target.as_ref().is_some_and(|t| t.digest == d))
+            })
+            .ok_or_else(|| ShimError::NotFound(format!("image {reference}")))
+    }
+
+    async fn list_images(&self, filters: Vec<String>) -> Result<Vec<Image>> {
+        let req = ListImagesRequest { filters };
+        let req = with_namespace!(req, self.namespace);
+        let images = ImagesClient::new(self.channel.clone())
+            .list(req)
+            .await
+            .map_err(|err| ShimError::Containerd(err.to_string()))?
+            .into_inner()
+            .images;
+        Ok(images)
+    }
+
+    async fn list_content(&self, filters: Vec<String>) -> Result<Vec<Info>> {
+        let req = ListContentRequest { filters };
+        let req = with_namespace!(req, self.namespace);
+        ContentClient::new(self.content_channel.clone())
+            .list(req)
+            .await
+            .map_err(|err| ShimError::Containerd(err.to_string()))?
+            .into_inner()
+            .map_ok(|msg| msg.info)
+            .try_concat()
+            .await
+            .map_err(|err| ShimError::Containerd(err.to_string()))
+    }
+
+    // updates the fields of the image in `paths`, e.g., `labels.<key>` for a single label
+    async fn update_image_fields(&self, image: Image, paths: Vec<String>) -> Result<Image> {
+        let req = UpdateImageRequest {
+            image: Some(image.clone()),
+            update_mask: Some(FieldMask { paths }),
+        };
+
+        let image = self
+            .retry_policy
+            .run("image update", || {
+                let req = req.clone();
+                let req = with_namespace!(req, self.namespace);
+                let mut images_client = ImagesClient::new(self.channel.clone());
+                async move {
+                    images_client
+                        .update(req)
+                        .await
+                        .map_err(|err| ShimError::Containerd(err.to_string()))
+                }
+            })
+            .await?
+            .into_inner()
+            .image
+            .ok_or_else(|| {
+                ShimError::Containerd(format!("failed to update image {}", image.name))
+            })?;
+        Ok(image)
     }
 
     fn extract_image_content_sha(&self, image: &Image) -> Result<String> {
         let digest = image
             .target
             .as_ref()
-            .ok_or_else(|| {
-                ShimError::Containerd(format!(
-                    "failed to get image content sha for image {}",
-                    image.name
-                ))
-            })?
+            .ok_or_else(|| ShimError::ImageNotReady(format!("image {} has no target", image.name)))?
             .digest
             .clone();
         Ok(digest)
     }
 
-    fn get_container(&self, container_name: impl ToString) -> Result<Container> {
-        self.rt.block_on(async {
-            let id = container_name.to_string();
-            let req = GetContainerRequest { id };
-            let req = with_namespace!(req, self.namespace);
-            let container = ContainersClient::new(self.inner.clone())
-                .get(req)
-                .await
-                .map_err(|err| ShimError::Containerd(err.to_string()))?
-                .into_inner()
-                .container
-                .ok_or_else(|| {
-                    ShimError::Containerd(format!(
-                        "failed to get image for container {}",
-                        container_name.to_string()
-                    ))
-                })?;
-            Ok(container)
-        })
+    async fn get_container(&self, container_name: impl ToString) -> Result<Container> {
+        timed_span!("get_container", container = container_name.to_string())
+            .instrument(async {
+                let id = container_name.to_string();
+                let req = GetContainerRequest { id };
+                let req = with_namespace!(req, self.namespace);
+                let container = ContainersClient::new(self.channel.clone())
+                    .get(req)
+                    .await
+                    .map_err(|err| ShimError::Containerd(err.to_string()))?
+                    .into_inner()
+                    .container
+                    .ok_or_else(|| {
+                        ShimError::Containerd(format!(
+                            "failed to get image for container {}",
+                            container_name.to_string()
+                        ))
+                    })?;
+                Ok(container)
+            })
+            .await
     }
 
-    // load module will query the containerd store to find an image that has an OS of type 'wasm'
-    // If found it continues to parse the manifest and return the layers that contains the WASM modules
-    // and possibly other configuration layers.
-    pub fn load_modules<T: Engine>(
-        &self,
-        containerd_id: impl ToString,
-        engine: &T,
-    ) -> Result<(Vec<oci::WasmLayer>, Platform)> {
-        let container = self.get_container(containerd_id.to_string())?;
-        let mut image = self.get_image(container.image)?;
-        let image_digest = self.extract_image_content_sha(&image)?;
-        let manifest = self.read_content(image_digest.clone())?;
-        let manifest = manifest.as_slice();
-        let manifest = ImageManifest::from_reader(manifest)?;
+    // whether the precompile label of an image references the precompiled content
+    async fn image_references(&self, digest: &str) -> Result<bool> {
+        let in_use = self.list_images(vec![]).await?.into_iter().any(|image| {
+            image
+                .labels
+                .iter()
+                .any(|(k, v)| k.starts_with(PRECOMPILE_PREFIX) && v == digest)
+        });
+        Ok(in_use)
+    }
 
-        let image_config_descriptor = manifest.config();
-        let image_config = self.read_content(image_config_descriptor.digest())?;
-        let image_config = image_config.as_slice();
+    /// Removes the precompiled content with the given digest from the content store.
+    ///
+    /// The garbage collection refs pointing at the content are dropped and the blob is deleted.
+    /// Content that is still referenced by the precompile label of an image is left untouched.
+    /// This is checked again once the refs are dropped, so that content that a concurrent precompile
+    /// referenced in between is kept, and gets its refs back.
+    /// Returns `true` if the blob was deleted, and `false` if it is still in use or doesn't exist.
+    pub async fn delete_precompiled_blob(&self, digest: impl ToString) -> Result<bool> {
+        let digest = digest.to_string();
 
-        // the only part we care about here is the platform values
-        let platform: Platform = serde_json::from_slice(image_config)?;
-        let Arch::Wasm = platform.architecture() else {
-            log::info!("manifest is not in WASM OCI image format");
-            return Ok((vec![], platform));
-        };
+        if self.image_references(&digest).await? {
+            log::info!("precompiled content {digest} is still referenced by an image");
+            return Ok(false);
+        }
+        self.delete_unreferenced_blob(digest).await
+    }
 
-        log::info!("found manifest with WASM OCI image format.");
-        // This label is unique across runtimes and version of the shim running
-        // a precompiled component/module will not work across different runtimes or versions
-        let (can_precompile, precompile_id) = match engine.can_precompile() {
-            Some(precompile_id) => (true, precompile_label(T::name(), &precompile_id)),
-            None => (false, "".to_string()),
-        };
-
-        match image.labels.get(&precompile_id) {
-            Some(precompile_digest) if can_precompile => {
-                log::info!("found precompiled label: {} ", &precompile_id);
-                match self.read_content(precompile_digest) {
-                    Ok(precompiled) => {
-                        log::info!("found precompiled module in cache: {} ", &precompile_digest);
-                        return Ok((
-                            vec![WasmLayer {
-                                config: image_config_descriptor.clone(),
-                                layer: precompiled,
-                            }],
-                            platform,
-                        ));
-                    }
-                    Err(e) => {
-                        // log and continue
-                        log::warn!("failed to read precompiled module from cache: {}. Content may have been removed manually, will attempt to recompile", e);
-                    }
+    // deletes precompiled content that no image referenced when it was last checked,
+    // see `AsyncClient::delete_precompiled_blob`
+    async fn delete_unreferenced_blob(&self, digest: String) -> Result<bool> {
+        // the gc refs are named after the precompile labels of the content, or are the single ref of older shims
+        let mut gc_refs = vec![PRECOMPILE_GC_REF.to_string()];
+        match self.get_info(digest.clone()).await {
+            Ok(info) => gc_refs.extend(
+                info.labels
+                    .keys()
+                    .filter(|k| k.starts_with(PRECOMPILE_PREFIX))
+                    .map(|k| precompile_gc_ref(k)),
+            ),
+            Err(ShimError::NotFound(_)) => {}
+            Err(err) => return Err(err),
+        }
+        let gc_ref_filter = |gc_ref: &str| format!("labels.\"{gc_ref}\"==\"{digest}\"");
+        let mut removed = vec![];
+        for gc_ref in &gc_refs {
+            for info in self.list_content(vec![gc_ref_filter(gc_ref)]).await? {
+                log::debug!("removing precompile gc ref from content {}", info.digest);
+                self.update_info_labels(
+                    &info.digest,
+                    HashMap::from([(gc_ref.clone(), String::new())]),
+                )
+                .await?;
+                removed.push((info.digest, gc_ref.clone()));
+            }
+        }
+
+        // a precompile labels the image before it adds its gc ref,
+        // so a precompile of the content since the first check shows up in either of them
+        let mut referenced = self.image_references(&digest).await?;
+        for gc_ref in &gc_refs {
+            if referenced {
+                break;
+            }
+            referenced = !self
+                .list_content(vec![gc_ref_filter(gc_ref)])
+                .await?
+                .is_empty();
+        }
+        if referenced {
+            log::info!(
+                "precompiled content {digest} was referenced again while it was being deleted"
+            );
+            for (content, gc_ref) in removed {
+                self.update_info_labels(&content, HashMap::from([(gc_ref, digest.clone())]))
+                    .await?;
+            }
+            return Ok(false);
+        }
+
+        match self.delete_content(&digest).await {
+            Ok(()) => Ok(true),
+            Err(ShimError::NotFound(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reclaims the precompiled content of `engine` for an image that was removed, rather than
+    /// leaving it to the garbage collector of containerd.
+    ///
+    /// The content precompiled for the image, by any version or configuration of the engine, is found by
+    /// the name of the image it was precompiled from, and removed with [`AsyncClient::delete_precompiled_blob`].
+    /// Content that another image still references, e.g., one with the same manifest under another name, is kept.
+    /// Returns the number of precompiled blobs that were deleted.
+    pub async fn on_image_removed<T: Engine>(
+        &self,
+        image_name: impl ToString,
+        engine: &T,
+    ) -> Result<usize> {
+        if engine.can_precompile().is_none() {
+            return Ok(0);
+        }
+        let image_name = normalize_reference(&image_name.to_string());
+        // the labels of every version and configuration of the engine
+        let engine_prefix = precompile_label(T::name(), "");
+
+        let mut deleted = 0;
+        for info in self.list_precompiled().await? {
+            let precompiled_from = info
+                .labels
+                .get(IMAGE_REFERENCE_LABEL)
+                .is_some_and(|name| normalize_reference(name) == image_name);
+            let for_engine = info.labels.keys().any(|k| k.starts_with(&engine_prefix));
+            if precompiled_from && for_engine && self.delete_precompiled_blob(&info.digest).await? {
+                log::info!(
+                    "reclaimed precompiled content {} of removed image {image_name}",
+                    info.digest
+                );
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    // lists the content in the content store that has a precompile label
+    async fn list_precompiled(&self) -> Result<Vec<Info>> {
+        let precompiled = self
+            .list_content(vec![])
+            .await?
+            .into_iter()
+            .filter(|info| info.labels.keys().any(|k| k.starts_with(PRECOMPILE_PREFIX)))
+            .collect();
+        Ok(precompiled)
+    }
+
+    // records that the precompiled content was used, so that it is evicted last
+    // returns false if the label was updated less than `last_used_interval` ago, and was left as is
+    async fn touch_precompiled(&self, digest: impl ToString) -> Result<bool> {
+        let digest = digest.to_string();
+        let info = self.get_info(digest.clone()).await?;
+        let now = unix_now();
+        if now.saturating_sub(last_used(&info)) < self.last_used_interval.as_secs() {
+            return Ok(false);
+        }
+        // only the last-used label, so that the labels updated since the read are kept
+        let labels = HashMap::from([(LAST_USED_LABEL.to_string(), now.to_string())]);
+        self.update_info_labels(digest, labels).await?;
+        Ok(true)
+    }
+
+    // returns the digests of the precompiled content used by the image of a container with a task that hasn't stopped
+    async fn precompiled_in_use(&self) -> Result<HashSet<String>> {
+        let req = ListTasksRequest::default();
+        let req = with_namespace!(req, self.namespace);
+        let tasks = TasksClient::new(self.channel.clone())
+            .list(req)
+            .await
+            .map_err(|err| ShimError::Containerd(err.to_string()))?
+            .into_inner()
+            .tasks;
+
+        let mut in_use = HashSet::new();
+        for task in tasks {
+            if task.status == Status::Stopped as i32 {
+                continue;
+            }
+            let container = self.get_container(&task.container_id).await?;
+            // only the labels of the image are needed
+            let image = self.find_image(container.image).await?;
+            in_use.extend(
+                image
+                    .labels
+                    .into_iter()
+                    .filter(|(k, _)| k.starts_with(PRECOMPILE_PREFIX))
+                    .map(|(_, v)| v),
+            );
+        }
+        Ok(in_use)
+    }
+
+    /// Evicts the least recently used precompiled content until `incoming` more bytes fit within
+    /// the max cache size.  Content used by a running container is never evicted.
+    /// Returns the number of precompiled blobs that were evicted.
+    async fn evict_precompiled(&self, incoming: u64) -> Result<usize> {
+        let Some(max_cache_size) = self.max_cache_size else {
+            return Ok(0);
+        };
+
+        let mut precompiled = self.list_precompiled().await?;
+        let mut total = incoming + precompiled.iter().map(|info| info.size as u64).sum::<u64>();
+        if total <= max_cache_size {
+            return Ok(0);
+        }
+
+        let in_use = self.precompiled_in_use().await?;
+        precompiled.sort_by_key(last_used);
+        let mut images = self.list_images(vec![]).await?;
+
+        let mut evicted = 0;
+        for info in precompiled {
+            if total <= max_cache_size {
+                break;
+            }
+            if in_use.contains(&info.digest) {
+                log::debug!("not evicting precompiled content {} in use", info.digest);
+                continue;
+            }
+
+            log::info!("evicting precompiled content {}", info.digest);
+            // a blob that fails to be evicted is skipped, the next one may free enough space
+            match self.evict_blob(&mut images, &info.digest).await {
+                Ok(true) => {
+                    total = total.saturating_sub(info.size as u64);
+                    evicted += 1;
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    log::warn!("failed to evict precompiled content {}: {err}", info.digest)
                 }
             }
-            _ => {}
         }
 
-        let layers = manifest
+        if total > max_cache_size {
+            log::warn!("precompiled content uses {total} bytes, over the max cache size of {max_cache_size} bytes");
+        }
+        Ok(evicted)
+    }
+
+    // drops the precompile labels of `images` that reference the precompiled content, and deletes it
+    async fn evict_blob(&self, images: &mut [Image], digest: &str) -> Result<bool> {
+        for image in images.iter_mut() {
+            let removed: HashMap<_, _> = image
+                .labels
+                .iter()
+                .filter(|(k, v)| k.starts_with(PRECOMPILE_PREFIX) && *v == digest)
+                .map(|(k, _)| (k.clone(), String::new()))
+                .collect();
+            if removed.is_empty() {
+                continue;
+            }
+            image.labels.retain(|k, _| !removed.contains_key(k));
+            // only the removed labels are updated, as the images are listed once for every blob evicted,
+            // and the labels that concurrent precompiles set meanwhile must be kept
+            let paths = removed.keys().map(|key| format!("labels.{key}")).collect();
+            let update = Image {
+                labels: removed,
+                ..image.clone()
+            };
+            self.update_image_fields(update, paths).await?;
+        }
+        self.delete_unreferenced_blob(digest.to_string()).await
+    }
+
+    /// Copies the precompiled content of an image from one namespace to another.
+    ///
+    /// The image must exist in both namespaces.  For every precompile label of the image in `from_ns`,
+    /// the precompiled blob is written to the content store of `to_ns`, and the label and the
+    /// garbage collection ref are re-applied to the image there, so it doesn't need to be recompiled.
+    /// Content that was already migrated is skipped, so this can be called repeatedly.
+    /// Returns the number of precompiled blobs that were migrated.
+    pub async fn migrate_precompiled(
+        &self,
+        from_ns: impl ToString,
+        to_ns: impl ToString,
+        image_name: impl ToString,
+    ) -> Result<usize> {
+        let from = AsyncClient::connect_with_tls(self.address.as_str(), from_ns, self.tls.clone())
+            .await?
+            .with_content_address(self.content_address.as_str())
+            .await?;
+        let to = AsyncClient::connect_with_tls(self.address.as_str(), to_ns, self.tls.clone())
+            .await?
+            .with_content_address(self.content_address.as_str())
+            .await?;
+        let image_name = image_name.to_string();
+
+        let source_image = from.get_image(&image_name).await?;
+        let target_image = to.get_image(&image_name).await?;
+        let image_digest = to.extract_image_content_sha(&target_image)?;
+
+        let mut migrated = 0;
+        for (label, digest) in source_image.labels {
+            if !label.starts_with(PRECOMPILE_PREFIX) {
+                continue;
+            }
+            if target_image.labels.get(&label) == Some(&digest)
+                && to.get_info(digest.clone()).await.is_ok()
+            {
+                log::debug!(
+                    "precompiled content {digest} already in namespace {}",
+                    to.namespace
+                );
+                continue;
+            }
+
+            log::info!(
+                "migrating precompiled content {digest} from namespace {} to {}",
+                from.namespace,
+                to.namespace
+            );
+            // content precompiled before the media type label was added has none to carry over
+            let media_type = from
+                .get_info(digest.clone())
+                .await?
+                .labels
+                .get(MEDIA_TYPE_LABEL)
+                .cloned();
+            let data = from.read_content(&digest).await?;
+            let content = to
+                .save_image_content(
+                    data,
+                    Some(&image_name),
+                    image_digest.clone(),
+                    &label,
+                    media_type.as_deref(),
+                )
+                .await?;
+
+            let gc_labels = precompile_gc_labels(&label, &content.digest);
+            // only the label being migrated, so that the labels set meanwhile in the namespace are kept
+            let paths = vec![format!("labels.{label}")];
+            let update = Image {
+                labels: HashMap::from([(label, content.digest.clone())]),
+                ..target_image.clone()
+            };
+            let result = async {
+                to.update_image_fields(update, paths).await?;
+                // keep the content around after the lease is dropped, as in load_modules
+                to.update_info_labels(&image_digest, gc_labels).await
+            }
+            .await;
+            // released when the labels fail to be updated too, as when precompiling
+            content.release().await;
+            result?;
+
+            migrated += 1;
+        }
+
+        Ok(migrated)
+    }
+
+    /// Writes the output of a container to the content store, and returns the digests of the blobs.
+    ///
+    /// Each blob is labeled with `runwasi.io/logs.stdout` or `runwasi.io/logs.stderr` set to the id of the container,
+    /// and is a root of the garbage collection of containerd, so it is kept until it is deleted.
+    pub async fn export_logs(
+        &self,
+        container_id: impl ToString,
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+    ) -> Result<ExportedLogs> {
+        let container_id = container_id.to_string();
+        timed_span!("export_logs", container = container_id)
+            .instrument(async {
+                Ok(ExportedLogs {
+                    stdout: self.export_log(&container_id, "stdout", stdout).await?,
+                    stderr: self.export_log(&container_id, "stderr", stderr).await?,
+                })
+            })
+            .await
+    }
+
+    async fn export_log(&self, container_id: &str, stream: &str, data: Vec<u8>) -> Result<String> {
+        let mut labels = HashMap::new();
+        labels.insert(format!("{LOGS_PREFIX}.{stream}"), container_id.to_string());
+        labels.insert(GC_ROOT_LABEL.to_string(), unix_now().to_string());
+        let reference = format!("logs-{container_id}-{stream}");
+        // the lease keeps the blob until it is a gc root
+        let content = self.write_content(data, reference, labels.clone()).await?;
+        let result = async {
+            // the same output of another container is already there, with the labels of that container
+            let info = self.get_info(content.digest.clone()).await?;
+            if labels.iter().any(|(k, v)| info.labels.get(k) != Some(v)) {
+                self.update_info_labels(&content.digest, labels).await?;
+            }
+            Ok::<_, ShimError>(())
+        }
+        .await;
+        let digest = content.release().await;
+        result?;
+        log::info!("exported {stream} of container {container_id} to {digest}");
+        Ok(digest)
+    }
+
+    /// Returns the asset layers in the image of the container.
+    /// Their content isn't read, see [`AsyncClient::copy_content`].
+    pub async fn load_assets(&self, containerd_id: impl ToString) -> Result<Vec<oci::AssetLayer>> {
+        let image_digest = self.image_digest(containerd_id).await?;
+        let manifest = self.read_content(image_digest).await?;
+        let manifest = ImageManifest::from_reader(manifest.as_slice())?;
+        manifest
             .layers()
             .iter()
-            .filter(|x| is_wasm_layer(x.media_type(), T::supported_layers_types()))
-            .map(|config| self.read_content(config.digest()))
-            .collect::<Result<Vec<_>>>()?;
+            .filter_map(|descriptor| oci::AssetLayer::from_descriptor(descriptor).transpose())
+            .collect()
+    }
 
-        if layers.is_empty() {
-            log::info!("no WASM modules found in OCI layers");
+    /// Returns the `StopSignal` from the config of the image of the container, e.g., `SIGTERM`,
+    /// or None if the image doesn't set one.
+    pub async fn stop_signal(&self, containerd_id: impl ToString) -> Result<Option<String>> {
+        let image_digest = self.image_digest(containerd_id).await?;
+        let image_config = self.read_image_config(image_digest).await?;
+        parse_stop_signal(&image_config)
+    }
+
+    /// Returns the config of an image, e.g., with the entrypoint, environment, labels and stop signal of the image.
+    pub async fn image_config(&self, image_name: impl ToString) -> Result<ImageConfiguration> {
+        let image = self.resolve_image(image_name).await?;
+        let image_digest = self.extract_image_content_sha(&image)?;
+        let image_config = self.read_image_config(image_digest).await?;
+        Ok(ImageConfiguration::from_reader(image_config.as_slice())?)
+    }
+
+    /// Returns the labels of an image, e.g., its build metadata, or the precompiled content of the engines.
+    pub async fn image_labels(&self, image_name: impl ToString) -> Result<HashMap<String, String>> {
+        Ok(self.find_image(image_name).await?.labels)
+    }
+
+    /// Sets `labels` on an image, keeping its other labels, and returns the labels of the updated image.
+    /// A label with an empty value is removed.
+    pub async fn set_image_labels(
+        &self,
+        image_name: impl ToString,
+        labels: HashMap<String, String>,
+    ) -> Result<HashMap<String, String>> {
+        let mut image = self.find_image(image_name).await?;
+        // only the given labels are updated, so that concurrent updates of other labels aren't lost
+        let paths = labels.keys().map(|key| format!("labels.{key}")).collect();
+        image.labels = labels;
+        Ok(self.update_image_fields(image, paths).await?.labels)
+    }
+
+    /// Returns the annotations of the manifest of the image of the container,
+    /// e.g., the function its containers run, in the [`ENTRYPOINT_ANNOTATION`](crate::container::ENTRYPOINT_ANNOTATION).
+    pub async fn manifest_annotations(
+        &self,
+        containerd_id: impl ToString,
+    ) -> Result<HashMap<String, String>> {
+        let image_digest = self.image_digest(containerd_id).await?;
+        let manifest = self.read_content(image_digest).await?;
+        let manifest = ImageManifest::from_reader(manifest.as_slice())?;
+        Ok(manifest.annotations().clone().unwrap_or_default())
+    }
+
+    /// Returns the entrypoint in the config of the image of the container, if it sets one.
+    pub async fn config_entrypoint(
+        &self,
+        containerd_id: impl ToString,
+    ) -> Result<Option<Vec<String>>> {
+        let image_digest = self.image_digest(containerd_id).await?;
+        let image_config = self.read_image_config(image_digest).await?;
+        let image_config = ImageConfiguration::from_reader(image_config.as_slice())?;
+        Ok(image_config
+            .config()
+            .as_ref()
+            .and_then(|config| config.entrypoint().clone()))
+    }
+
+    // Reads the config of the image with the given manifest digest, unparsed.
+    async fn read_image_config(&self, image_digest: String) -> Result<Vec<u8>> {
+        let manifest = self.read_content(image_digest).await?;
+        let manifest = ImageManifest::from_reader(manifest.as_slice())?;
+        self.read_content(manifest.config().digest()).await
+    }
+
+    /// Returns the digest of the image manifest of the container.
+    pub async fn image_digest(&self, containerd_id: impl ToString) -> Result<String> {
+        let container = self.get_container(containerd_id.to_string()).await?;
+        let image = self.resolve_image(container.image).await?;
+        self.extract_image_content_sha(&image)
+    }
+
+    // load module will query the containerd store to find an image that has an OS of type 'wasm'
+    // If found it continues to parse the manifest and return the layers that contains the WASM modules
+    // and possibly other configuration layers.
+    // The result is cached per container, and reused as long as the image digest and the wasm features don't change,
+    // so that restarting a container doesn't need to read and parse the image again.
+    // The layers are shared with the cache, rather than copied out of it.
+    pub async fn load_modules<T: Engine>(
+        &self,
+        containerd_id: impl ToString,
+        engine: &T,
+    ) -> Result<(Arc<Vec<oci::WasmLayer>>, Platform)> {
+        timed_span!("load_modules", container = containerd_id.to_string())
+            .instrument(async {
+                let containerd_id = containerd_id.to_string();
+                let container = self.get_container(&containerd_id).await?;
+                let image = self.resolve_image(container.image).await?;
+                let image_digest = self.extract_image_content_sha(&image)?;
+
+                let key = (T::name(), containerd_id);
+                if let Some((manifest, modules)) =
+                    cached_modules(&key, &image_digest, &self.wasm_features)
+                {
+                    log::info!("using cached modules for image {image_digest}");
+                    // only the reads are saved, the image is checked on every load
+                    self.verify_manifest(&manifest, &image.name)?;
+                    if oci::is_wasm_arch(modules.1.architecture()) {
+                        self.check_image(&image.name, &manifest, &modules.1, engine)?;
+                    }
+                    self.record_content_loaded();
+                    return Ok(modules);
+                }
+
+                let manifest = self.read_content(image_digest.clone()).await?;
+                let manifest = ImageManifest::from_reader(manifest.as_slice())?;
+                let (layers, platform) = self
+                    .load_manifest_modules(image, image_digest.clone(), &manifest, engine)
+                    .await?;
+                let modules = (Arc::new(layers), platform);
+                cache_modules(
+                    key,
+                    image_digest,
+                    self.wasm_features.clone(),
+                    manifest,
+                    modules.clone(),
+                );
+                Ok(modules)
+            })
+            .await
+    }
+
+    // loads the modules of an image whose record is already resolved, which only the tests need
+    #[cfg(test)]
+    async fn load_image_modules<T: Engine>(
+        &self,
+        image: Image,
+        image_digest: String,
+        engine: &T,
+    ) -> Result<(Vec<oci::WasmLayer>, Platform)> {
+        let manifest = self.read_content(image_digest.clone()).await?;
+        let manifest = ImageManifest::from_reader(manifest.as_slice())?;
+        self.load_manifest_modules(image, image_digest, &manifest, engine)
+            .await
+    }
+
+    async fn load_manifest_modules<T: Engine>(
+        &self,
+        mut image: Image,
+        image_digest: String,
+        manifest: &ImageManifest,
+        engine: &T,
+    ) -> Result<(Vec<oci::WasmLayer>, Platform)> {
+        self.verify_manifest(manifest, &image.name)?;
+
+        let image_config_descriptor = manifest.config();
+        let image_config = self.read_content(image_config_descriptor.digest()).await?;
+        let image_config = image_config.as_slice();
+
+        // the only part we care about here is the platform values
+        let platform = parse_platform(image_config)?;
+        // wasm32 and wasm64 tell 32-bit images from 64-bit images
+        if !oci::is_wasm_arch(platform.architecture()) {
+            log::info!("manifest is not in WASM OCI image format");
             return Ok((vec![], platform));
         }
 
+        log::info!("found manifest with WASM OCI image format.");
+        let (wasm_features, precompile_id) =
+            self.check_image(&image.name, manifest, &platform, engine)?;
+        let can_precompile = precompile_id.is_some();
+        let precompile_id = precompile_id.unwrap_or_default();
+
         if can_precompile {
-            log::info!("precompiling module");
-            let precompiled = engine.precompile(layers.as_slice())?;
-            log::info!("precompiling module: {}", image_digest.clone());
-            let precompiled_content =
-                self.save_content(precompiled.clone(), image_digest.clone(), &precompile_id)?;
+            if let Some((digest, precompiled)) = self
+                .load_precompiled(&image, &image_digest, &precompile_id)
+                .await?
+            {
+                return Ok((
+                    vec![precompiled_layer(engine, &digest, precompiled)],
+                    platform,
+                ));
+            }
+        }
 
-            log::debug!("updating image with compiled content digest");
-            image
-                .labels
-                .insert(precompile_id, precompiled_content.digest.clone());
-            self.update_image(image)?;
+        // the containers of an image that start at the same time share a single precompile
+        let mut leader = None;
+        if can_precompile {
+            match self.join_precompile(&precompile_id, &image_digest).await {
+                Flight::Leader(flight) => {
+                    // a precompile that finished after the lookup above has labeled the image by now
+                    if self.precompile_cache_dir.is_none() {
+                        match self.get_image(&image.name).await {
+                            Ok(latest) => image = latest,
+                            Err(err) => {
+                                log::warn!("failed to read the labels of the image again: {err}")
+                            }
+                        }
+                    }
+                    if let Some((digest, precompiled)) = self
+                        .load_precompiled(&image, &image_digest, &precompile_id)
+                        .await?
+                    {
+                        flight.finish(digest.clone(), precompiled.clone());
+                        return Ok((
+                            vec![precompiled_layer(engine, &digest, precompiled)],
+                            platform,
+                        ));
+                    }
+                    leader = Some(flight);
+                }
+                Flight::Done((digest, precompiled)) => {
+                    self.record_content_loaded();
+                    return Ok((
+                        vec![precompiled_layer(engine, &digest, precompiled)],
+                        platform,
+                    ));
+                }
+                Flight::Failed => {}
+            }
+        }
 
-            // The original image is considered a root object, by adding a ref to the new compiled content
-            // We tell containerd to not garbage collect the new content until this image is removed from the system
-            // this ensures that we keep the content around after the lease is dropped
-            log::debug!("updating content with precompile digest to avoid garbage collection");
-            let mut image_content = self.get_info(image_digest.clone())?;
-            image_content.labels.insert(
-                "containerd.io/gc.ref.content.precompile".to_string(),
-                precompiled_content.digest.clone(),
-            );
-            self.update_info(image_content)?;
+        let (descriptors, layers) = self.read_wasm_layers::<T>(manifest, &image_digest).await?;
+        self.record_content_loaded();
+        // before precompiling, which would fail with a less helpful error
+        check_wasm_features(engine, &wasm_features, layers.iter().map(Vec::as_slice))?;
+
+        // the hints are applied when the container runs, so a fuel hint only shows up in the layers
+        let fuel_hinted = self.module_hints && layers.iter().any(|layer| has_fuel_hint(layer));
+        if can_precompile && fuel_hinted {
+            log::info!("not precompiling: a module hints at fuel, which the engine doesn't precompile with");
+        }
+        let precompiled = if can_precompile && !fuel_hinted && self.has_memory_to_precompile() {
+            self.check_free_space(layers.iter().map(|l| l.len() as u64).sum())?;
+            log::info!("precompiling module");
+            let precompiled = timed_span!("precompile", engine = T::name())
+                .in_scope(|| precompile_logged(engine, &layers, &image_digest))?;
+            // don't cache output that would be a cache hit on garbage on the next run
+            match validate_precompiled(engine, &precompiled) {
+                Ok(()) => Some(precompiled),
+                Err(err) => {
+                    log::warn!(
+                        "engine returned an invalid precompiled module, it won't be cached: {err}"
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
 
+        if let Some(precompiled) = precompiled {
+            // the digest is returned by the store, so that the containers that run the module
+            // don't have to hash it, see `Source::digest`
+            let digest = match &self.precompile_cache_dir {
+                Some(cache_dir) => cache_dir.store(&image_digest, &precompile_id, &precompiled)?,
+                None => {
+                    self.store_precompiled(
+                        image,
+                        &image_digest,
+                        precompile_id,
+                        &precompiled,
+                        engine,
+                        self.keep_existing_precompiled,
+                    )
+                    .await?
+                }
+            };
+            self.load_timings.lock().unwrap().precompiled = Some(Instant::now());
+            if let Some(leader) = leader {
+                leader.finish(digest.clone(), precompiled.clone());
+            }
             return Ok((
-                vec![WasmLayer {
-                    config: image_config_descriptor.clone(),
-                    layer: precompiled,
-                }],
+                vec![precompiled_layer(engine, &digest, precompiled)],
                 platform,
             ));
         }
@@ -480,66 +1807,3335 @@ impl Client {
         log::info!("using module from OCI layers");
         let layers = layers
             .into_iter()
-            .map(|module| WasmLayer {
-                config: image_config_descriptor.clone(),
-                layer: module,
+            .zip(descriptors)
+            .map(|(module, descriptor)| {
+                let binary_type = WasmLayer::classify(descriptor.media_type(), &module);
+                Ok(WasmLayer {
+                    binary_type,
+                    wasi_version: WasmLayer::wasi_version(descriptor, binary_type)?,
+                    config: image_config_descriptor.clone(),
+                    layer: module,
+                })
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>>>()?;
         Ok((layers, platform))
     }
-}
 
-fn precompile_label(name: &str, version: &str) -> String {
-    format!("{}/{}/{}", PRECOMPILE_PREFIX, name, version)
-}
+    // reads the module that the engine precompiled for the image from the precompile cache directory,
+    // or from the content store if the image is labeled with it,
+    // returning its digest along with the module, or None if it wasn't precompiled yet
+    async fn load_precompiled(
+        &self,
+        image: &Image,
+        image_digest: &str,
+        precompile_id: &str,
+    ) -> Result<Option<PrecompileOutput>> {
+        if let Some(cache_dir) = &self.precompile_cache_dir {
+            // with a cache directory, precompiled modules aren't read from the content store
+            let precompiled = cache_dir.load(image_digest, precompile_id);
+            if precompiled.is_some() {
+                log::info!("found precompiled module in {:?}", cache_dir.path());
+                self.record_content_loaded();
+            }
+            return Ok(precompiled);
+        }
 
-fn is_wasm_layer(media_type: &MediaType, supported_layer_types: &[&str]) -> bool {
-    supported_layer_types.contains(&media_type.to_string().as_str())
-}
+        let Some(precompile_digest) = image.labels.get(precompile_id) else {
+            return Ok(None);
+        };
+        log::info!("found precompiled label: {} ", precompile_id);
+        let Some(precompiled) = self.read_precompiled(precompile_digest).await? else {
+            return Ok(None);
+        };
+        log::info!("found precompiled module in cache: {} ", precompile_digest);
+        self.record_content_loaded();
+        if let Err(err) = self.touch_precompiled(precompile_digest).await {
+            log::warn!("failed to update last use of precompiled module: {err}");
+        }
+        Ok(Some((precompile_digest.clone(), precompiled)))
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::path::PathBuf;
+    // checks that the engine can run the wasm image with the wasm features of the container.
+    // Returns the wasm features that the modules run with, and the precompile label of the engine
+    // if it can precompile them.
+    fn check_image<T: Engine>(
+        &self,
+        image_name: &str,
+        manifest: &ImageManifest,
+        platform: &Platform,
+        engine: &T,
+    ) -> Result<(Vec<WasmFeature>, Option<String>)> {
+        if let Some(supported_features) = T::supported_features() {
+            check_os_features(platform, supported_features)?;
+        }
 
-    use super::*;
+        // the modules of 64-bit images run with memory64, as the executor enables it for them
+        let wasm_features = platform_wasm_features(&self.wasm_features, platform);
+        let memory64 = WasmFeature::Memory64;
+        if wasm_features.contains(&memory64)
+            && !self.wasm_features.contains(&memory64)
+            && engine.disabled_wasm_features().contains(&memory64)
+            && !engine.optional_wasm_features().contains(&memory64)
+        {
+            return Err(ShimError::UnsupportedFeature(format!(
+                "the {} engine can't run wasm64 images, as it can't enable the wasm feature {memory64}",
+                T::name()
+            )));
+        }
 
-    #[test]
-    fn test_save_content() {
-        let path = PathBuf::from("/run/containerd/containerd.sock");
-        let path = path.to_str().unwrap();
-        let client = Client::connect(path, "test-ns").unwrap();
-        let data = b"hello world".to_vec();
+        // This label is unique across runtimes, version of the shim running and engine configuration
+        // a precompiled component/module will not work across different runtimes, versions or configurations
+        let precompile_id = match engine.can_precompile() {
+            // the engine precompiles with its default configuration, without the enabled features
+            Some(precompile_id) if wasm_features.is_empty() && self.precompile => {
+                Some(engine_precompile_label(engine, &precompile_id))
+            }
+            _ => None,
+        };
+        let require_precompile = manifest
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(oci::REQUIRE_PRECOMPILE_ANNOTATION))
+            .is_some_and(|v| v == "true");
+        if require_precompile && precompile_id.is_none() {
+            let reason = match engine.can_precompile() {
+                Some(_) if !self.precompile => {
+                    "doesn't precompile with the settings that the container runs with"
+                }
+                Some(_) => "doesn't precompile with the wasm features that the container runs with",
+                None => "can't precompile",
+            };
+            return Err(ShimError::PrecompileRequired(format!(
+                "image {image_name} must run precompiled, but the {} engine {reason}",
+                T::name()
+            )));
+        }
+        Ok((wasm_features, precompile_id))
+    }
 
-        let expected = digest(data.clone());
-        let expected = format!("sha256:{}", expected);
+    // joins the precompile of the image in progress in this process, if any, or starts one
+    async fn join_precompile(&self, precompile_id: &str, image_digest: &str) -> Flight {
+        let key = (precompile_id.to_string(), image_digest.to_string());
+        let cell = {
+            let mut precompiles = PRECOMPILES.lock().unwrap();
+            match precompiles.get(&key) {
+                Some(cell) => cell.clone(),
+                None => {
+                    let cell = WaitableCell::new();
+                    precompiles.insert(key.clone(), cell.clone());
+                    return Flight::Leader(PrecompileLeader { key, cell });
+                }
+            }
+        };
 
-        let label = precompile_label("test", "hasdfh");
-        let returned = client
-            .save_content(data, "original".to_string(), &label)
-            .unwrap();
-        assert_eq!(expected, returned.digest.clone());
+        log::info!("waiting for the precompile of image {image_digest} in progress");
+        // the wait blocks, so it runs off the threads of the async runtime
+        let precompile_wait = self.precompile_wait;
+        let outcome =
+            tokio::task::spawn_blocking(move || cell.wait_timeout(precompile_wait).cloned())
+                .await
+                .ok()
+                .flatten();
+        match outcome {
+            Some(Some(precompiled)) => Flight::Done(precompiled),
+            Some(None) => {
+                log::warn!("the precompile of image {image_digest} in progress failed");
+                Flight::Failed
+            }
+            None => {
+                log::warn!(
+                    "timed out waiting {:?} for the precompile of image {image_digest} in progress",
+                    self.precompile_wait
+                );
+                Flight::Failed
+            }
+        }
+    }
 
-        let data = client.read_content(returned.digest.clone()).unwrap();
-        assert_eq!(data, b"hello world");
+    // reads the layers of the image that the engine can run, along with their descriptors
+    async fn read_wasm_layers<'a, T: Engine>(
+        &self,
+        manifest: &'a ImageManifest,
+        image_digest: &str,
+    ) -> Result<(Vec<&'a oci_spec::image::Descriptor>, Vec<Vec<u8>>)> {
+        let descriptors: Vec<_> = manifest
+            .layers()
+            .iter()
+            .filter(|x| is_wasm_layer(x.media_type(), T::supported_layers_types()))
+            .collect();
+        // before reading anything, so that a hostile image can't exhaust the memory of the shim
+        for descriptor in &descriptors {
+            check_module_limit(descriptor.size().max(0) as u64, self.max_module_bytes)?;
+        }
+        let digests = descriptors.iter().map(|d| d.digest().clone()).collect();
+        let layers = self.read_contents(digests).await?;
+        for (descriptor, layer) in descriptors.iter().zip(&layers) {
+            // the content may be larger than the descriptor claims
+            check_module_limit(layer.len() as u64, self.max_module_bytes)?;
+            check_module_size(descriptor, layer)?;
+        }
 
-        client
-            .save_content(data.clone(), "original".to_string(), &label)
-            .expect_err("Should not be able to save when lease is open");
+        if layers.is_empty() {
+            let media_types: Vec<_> = manifest
+                .layers()
+                .iter()
+                .map(|d| d.media_type().to_string())
+                .collect();
+            return Err(ShimError::NoRunnableContent(format!(
+                "image {image_digest} has no layers that {} can run, found {media_types:?}, expected one of {:?}",
+                T::name(),
+                T::supported_layers_types()
+            )));
+        }
+        Ok((descriptors, layers))
+    }
 
-        // need to drop the lease to be able to create a second one
-        // a second call should be successful since it already exists
-        drop(returned);
+    // saves the precompiled module and points the precompile label and gc ref of the image at it,
+    // returning the digest of the saved content.
+    // With `keep_existing`, content that the label points at already is kept, even if it differs from `precompiled`,
+    // and its digest is returned instead.
+    async fn store_precompiled<T: Engine>(
+        &self,
+        mut image: Image,
+        image_digest: &str,
+        precompile_id: String,
+        precompiled: &[u8],
+        engine: &T,
+        keep_existing: bool,
+    ) -> Result<String> {
+        let (content, media_type) = if self.compress_precompiled {
+            let compressed = zstd::encode_all(precompiled, ZSTD_LEVEL)?;
+            log::debug!(
+                "compressed precompiled module from {} to {} bytes",
+                precompiled.len(),
+                compressed.len()
+            );
+            let media_type = engine.precompiled_media_type() + ZSTD_MEDIA_TYPE_SUFFIX;
+            (compressed, media_type)
+        } else {
+            (precompiled.to_vec(), engine.precompiled_media_type())
+        };
 
-        // a second call should be successful since it already exists
-        let returned = client
-            .save_content(data, "original".to_string(), &label)
-            .unwrap();
-        assert_eq!(expected, returned.digest);
+        // the label covers the engine version and configuration, so the same modules should compile
+        // to the same output, otherwise nodes can't share the precompiled content of an image
+        let expected = format!("sha256:{}", digest(content.as_slice()));
+        if let Some(existing) = image.labels.get(&precompile_id).filter(|d| **d != expected) {
+            log::warn!(
+                "nondeterministic compile: precompiling image {image_digest} produced {expected}, but its {precompile_id} label points at {existing}"
+            );
+            if keep_existing && self.get_info(existing.clone()).await.is_ok() {
+                log::info!("keeping the existing precompiled content {existing}");
+                return Ok(existing.clone());
+            }
+        }
 
-        client.delete_content(expected.clone()).unwrap();
+        log::info!("precompiling module: {image_digest}");
+        if let Err(err) = self.evict_precompiled(content.len() as u64).await {
+            log::warn!("failed to evict precompiled content: {err}");
+        }
+        let precompiled_content = self
+            .save_image_content(
+                content,
+                Some(&image.name),
+                image_digest.to_string(),
+                &precompile_id,
+                Some(&media_type),
+            )
+            .await?;
 
-        client
-            .read_content(expected)
-            .expect_err("content should not exist");
+        log::debug!("updating image with compiled content digest");
+        // only the label of the engine, so that the labels that other precompiles set meanwhile are kept
+        let gc_labels = precompile_gc_labels(&precompile_id, &precompiled_content.digest);
+        let paths = vec![format!("labels.{precompile_id}")];
+        image
+            .labels
+            .insert(precompile_id, precompiled_content.digest.clone());
+        let result = async {
+            self.update_image_fields(image, paths).await?;
+
+            // The original image is considered a root object, by adding a ref to the new compiled content
+            // We tell containerd to not garbage collect the new content until this image is removed from the system
+            // this ensures that we keep the content around after the lease is dropped
+            log::debug!("updating content with precompile digest to avoid garbage collection");
+            // each engine and configuration has its own ref, so that the content precompiled for another one is kept too
+            self.update_info_labels(image_digest, gc_labels).await
+        }
+        .await;
+        // released when the image fails to be labeled too, so that the blocking client doesn't leave the delete
+        // of the lease to a task that its runtime may never run
+        let digest = precompiled_content.release().await;
+        result.map(|_| digest)
+    }
+
+    /// Recompiles the modules of an image with the engine, ignoring any precompiled content in the cache,
+    /// and replaces the precompiled content of the image with the output.
+    ///
+    /// This repairs a corrupted cache entry without clearing the cache of other images.
+    /// The precompile label and the garbage collection ref of the image are pointed at the new content,
+    /// so that the old content is no longer referenced and can be garbage collected.
+    /// Returns the digest of the new precompiled content.
+    pub async fn force_precompile<T: Engine>(
+        &self,
+        image: impl ToString,
+        engine: &T,
+    ) -> Result<String> {
+        let image = self.resolve_image(image).await?;
+        let image_digest = self.extract_image_content_sha(&image)?;
+        let precompile_id = match engine.can_precompile() {
+            Some(precompile_id) => engine_precompile_label(engine, &precompile_id),
+            None => {
+                return Err(ShimError::FailedPrecondition(format!(
+                    "engine {} doesn't support precompilation",
+                    T::name()
+                )))
+            }
+        };
+
+        let manifest = self.read_content(image_digest.clone()).await?;
+        let manifest = ImageManifest::from_reader(manifest.as_slice())?;
+        let (_, layers) = self.read_wasm_layers::<T>(&manifest, &image_digest).await?;
+
+        self.check_free_space(layers.iter().map(|l| l.len() as u64).sum())?;
+        log::info!("force precompiling image {}", image.name);
+        let precompiled = timed_span!("precompile", engine = T::name())
+            .in_scope(|| precompile_logged(engine, &layers, &image_digest))?;
+        validate_precompiled(engine, &precompiled)?;
+
+        let old_digest = image.labels.get(&precompile_id).cloned();
+        // the point is to replace the existing content, even if it differs from the new output
+        let digest = self
+            .store_precompiled(
+                image,
+                &image_digest,
+                precompile_id,
+                &precompiled,
+                engine,
+                false,
+            )
+            .await?;
+        if let Some(old_digest) = old_digest.filter(|old_digest| *old_digest != digest) {
+            log::info!("replaced precompiled content {old_digest} with {digest}");
+        }
+
+        // running shims must not keep using modules loaded from the old content
+        MODULES_CACHE
+            .lock()
+            .unwrap()
+            .retain(|_, cached| cached.image_digest != image_digest);
+        Ok(digest)
+    }
+
+    /// Reports, for each of `images`, whether it has precompiled content for `engine`.
+    ///
+    /// The images and the content store are listed once, however many images are queried,
+    /// and the references are resolved the way the image of a container is.
+    /// Images that don't exist are reported as [`PrecompileState::Absent`].
+    pub async fn precompile_status<T: Engine>(
+        &self,
+        images: &[String],
+        engine: &T,
+    ) -> Result<Vec<(String, PrecompileState)>> {
+        let precompile_id = match engine.can_precompile() {
+            Some(precompile_id) => engine_precompile_label(engine, &precompile_id),
+            None => {
+                return Err(ShimError::FailedPrecondition(format!(
+                    "engine {} doesn't support precompilation",
+                    T::name()
+                )))
+            }
+        };
+        // the labels of every version and configuration of the engine
+        let engine_prefix = precompile_label(T::name(), "");
+
+        let stored = self.list_images(vec![]).await?;
+        let content: HashSet<String> = self
+            .list_content(vec![])
+            .await?
+            .into_iter()
+            .map(|info| info.digest)
+            .collect();
+
+        let status = images
+            .iter()
+            .map(|reference| {
+                let state = match find_image(&stored, reference) {
+                    None => PrecompileState::Absent,
+                    Some(image) => match image.labels.get(&precompile_id) {
+                        Some(digest) if content.contains(digest) => PrecompileState::Present {
+                            digest: digest.clone(),
+                        },
+                        Some(_) => PrecompileState::Stale,
+                        None if image.labels.keys().any(|k| k.starts_with(&engine_prefix)) => {
+                            PrecompileState::Stale
+                        }
+                        None => PrecompileState::Absent,
+                    },
+                };
+                (reference.clone(), state)
+            })
+            .collect();
+        Ok(status)
+    }
+}
+
+// Builder methods of the blocking client that configure its async client.
+macro_rules! with_async_client {
+    ($($name:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            #[doc = concat!("See [`AsyncClient::", stringify!($name), "`].")]
+            pub fn $name(mut self, $($arg: $ty),*) -> Self {
+                self.inner = self.inner.$name($($arg),*);
+                self
+            }
+        )*
+    };
+}
+
+impl Client {
+    // wrapper around connection that will establish a connection and create a client
+    pub fn connect(
+        address: impl AsRef<Path> + ToString,
+        namespace: impl ToString,
+    ) -> Result<Client> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let inner = rt.block_on(AsyncClient::connect(address, namespace))?;
+        Ok(Client { inner, rt })
+    }
+
+    /// Blocking version of [`AsyncClient::connect_with_tls`].
+    pub fn connect_with_tls(
+        address: impl AsRef<Path> + ToString,
+        namespace: impl ToString,
+        tls: Option<ContainerdTlsOptions>,
+    ) -> Result<Client> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let inner = rt.block_on(AsyncClient::connect_with_tls(address, namespace, tls))?;
+        Ok(Client { inner, rt })
+    }
+
+    /// Blocking version of [`AsyncClient::with_content_address`].
+    pub fn with_content_address(mut self, address: impl ToString) -> Result<Self> {
+        self.inner = self.rt.block_on(self.inner.with_content_address(address))?;
+        Ok(self)
+    }
+
+    /// See [`AsyncClient::with_precompile_cache_dir`].
+    pub fn with_precompile_cache_dir(mut self, dir: impl AsRef<Path>) -> Result<Self> {
+        self.inner = self.inner.with_precompile_cache_dir(dir)?;
+        Ok(self)
+    }
+
+    with_async_client! {
+        with_write_timeout(timeout: Duration);
+        with_stat_timeout(timeout: Duration);
+        with_read_retries(retries: u32, delay: Duration);
+        with_retry_policy(retry_policy: RetryPolicy);
+        with_image_target_wait(wait: Duration);
+        with_max_module_bytes(max_module_bytes: u64);
+        with_max_cache_size(max_cache_size: u64);
+        with_last_used_interval(interval: Duration);
+        with_read_concurrency(read_concurrency: usize);
+        with_min_precompile_memory(min_precompile_memory: u64);
+        with_space_factor(space_factor: f64);
+        with_content_store_root(root: impl AsRef<Path>);
+        with_precompile_wait(precompile_wait: Duration);
+        with_keep_existing_precompiled(keep_existing_precompiled: bool);
+        with_compressed_precompiled(compress_precompiled: bool);
+        with_verifier(verifier: impl Fn(&ImageManifest) -> anyhow::Result<()> + Send + Sync + 'static);
+        with_wasm_features(wasm_features: Vec<WasmFeature>);
+        with_precompile(precompile: bool);
+        with_module_hints(module_hints: bool);
+    }
+
+    /// Returns when the phases of the last `load_modules` call finished.
+    pub(crate) fn load_timings(&self) -> LoadTimings {
+        self.inner.load_timings()
+    }
+
+    /// Blocking version of [`AsyncClient::copy_content`].
+    pub fn copy_content(&self, digest: impl ToString, writer: &mut impl Write) -> Result<u64> {
+        self.rt.block_on(self.inner.copy_content(digest, writer))
+    }
+
+    /// Blocking version of [`AsyncClient::delete_precompiled_blob`].
+    pub fn delete_precompiled_blob(&self, digest: impl ToString) -> Result<bool> {
+        self.rt.block_on(self.inner.delete_precompiled_blob(digest))
+    }
+
+    /// Blocking version of [`AsyncClient::on_image_removed`].
+    pub fn on_image_removed<T: Engine>(
+        &self,
+        image_name: impl ToString,
+        engine: &T,
+    ) -> Result<usize> {
+        self.rt
+            .block_on(self.inner.on_image_removed(image_name, engine))
+    }
+
+    /// Blocking version of [`AsyncClient::migrate_precompiled`].
+    pub fn migrate_precompiled(
+        &self,
+        from_ns: impl ToString,
+        to_ns: impl ToString,
+        image_name: impl ToString,
+    ) -> Result<usize> {
+        self.rt
+            .block_on(self.inner.migrate_precompiled(from_ns, to_ns, image_name))
+    }
+
+    /// Blocking version of [`AsyncClient::load_assets`].
+    pub fn load_assets(&self, containerd_id: impl ToString) -> Result<Vec<oci::AssetLayer>> {
+        self.rt.block_on(self.inner.load_assets(containerd_id))
+    }
+
+    /// Blocking version of [`AsyncClient::stop_signal`].
+    pub fn stop_signal(&self, containerd_id: impl ToString) -> Result<Option<String>> {
+        self.rt.block_on(self.inner.stop_signal(containerd_id))
+    }
+
+    /// Blocking version of [`AsyncClient::image_config`].
+    pub fn image_config(&self, image_name: impl ToString) -> Result<ImageConfiguration> {
+        self.rt.block_on(self.inner.image_config(image_name))
+    }
+
+    /// Blocking version of [`AsyncClient::image_labels`].
+    pub fn image_labels(&self, image_name: impl ToString) -> Result<HashMap<String, String>> {
+        self.rt.block_on(self.inner.image_labels(image_name))
+    }
+
+    /// Blocking version of [`AsyncClient::set_image_labels`].
+    pub fn set_image_labels(
+        &self,
+        image_name: impl ToString,
+        labels: HashMap<String, String>,
+    ) -> Result<HashMap<String, String>> {
+        self.rt
+            .block_on(self.inner.set_image_labels(image_name, labels))
+    }
+
+    /// Blocking version of [`AsyncClient::manifest_annotations`].
+    pub fn manifest_annotations(
+        &self,
+        containerd_id: impl ToString,
+    ) -> Result<HashMap<String, String>> {
+        self.rt
+            .block_on(self.inner.manifest_annotations(containerd_id))
+    }
+
+    /// Blocking version of [`AsyncClient::config_entrypoint`].
+    pub fn config_entrypoint(&self, containerd_id: impl ToString) -> Result<Option<Vec<String>>> {
+        self.rt
+            .block_on(self.inner.config_entrypoint(containerd_id))
+    }
+
+    /// Blocking version of [`AsyncClient::export_logs`].
+    pub fn export_logs(
+        &self,
+        container_id: impl ToString,
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+    ) -> Result<ExportedLogs> {
+        self.rt
+            .block_on(self.inner.export_logs(container_id, stdout, stderr))
+    }
+
+    /// Blocking version of [`AsyncClient::image_digest`].
+    pub fn image_digest(&self, containerd_id: impl ToString) -> Result<String> {
+        self.rt.block_on(self.inner.image_digest(containerd_id))
+    }
+
+    /// Blocking version of [`AsyncClient::load_modules`].
+    pub fn load_modules<T: Engine>(
+        &self,
+        containerd_id: impl ToString,
+        engine: &T,
+    ) -> Result<(Arc<Vec<oci::WasmLayer>>, Platform)> {
+        self.rt
+            .block_on(self.inner.load_modules(containerd_id, engine))
+    }
+
+    /// Blocking version of [`AsyncClient::force_precompile`].
+    pub fn force_precompile<T: Engine>(&self, image: impl ToString, engine: &T) -> Result<String> {
+        self.rt.block_on(self.inner.force_precompile(image, engine))
+    }
+
+    /// Blocking version of [`AsyncClient::precompile_status`].
+    pub fn precompile_status<T: Engine>(
+        &self,
+        images: &[String],
+        engine: &T,
+    ) -> Result<Vec<(String, PrecompileState)>> {
+        self.rt
+            .block_on(self.inner.precompile_status(images, engine))
+    }
+}
+
+type ModulesCacheKey = (&'static str, String);
+
+// the layers of an image that the engine runs, which are shared with the modules cache, and its platform
+type LoadedModules = (Arc<Vec<WasmLayer>>, Platform);
+
+// the precompile label and the digest of the image
+type PrecompileKey = (String, String);
+
+// the digest of a precompiled module, and the module
+type PrecompileOutput = (String, Vec<u8>);
+
+// the precompiles in progress in this process, with the cell where each publishes its output
+static PRECOMPILES: Mutex<BTreeMap<PrecompileKey, WaitableCell<Option<PrecompileOutput>>>> =
+    Mutex::new(BTreeMap::new());
+
+enum Flight {
+    // no precompile of the image was in progress, so the caller runs it
+    Leader(PrecompileLeader),
+    // the output of the precompile that was in progress
+    Done(PrecompileOutput),
+    // the precompile that was in progress failed or took too long, so the caller compiles on its own
+    Failed,
+}
+
+// removes the precompile from the ones in progress when dropped,
+// and lets the callers waiting for it know that it failed, unless it finished
+struct PrecompileLeader {
+    key: PrecompileKey,
+    cell: WaitableCell<Option<PrecompileOutput>>,
+}
+
+impl PrecompileLeader {
+    fn finish(self, digest: String, precompiled: Vec<u8>) {
+        let _ = self.cell.set(Some((digest, precompiled)));
+    }
+}
+
+impl Drop for PrecompileLeader {
+    fn drop(&mut self) {
+        let _ = self.cell.set(None);
+        PRECOMPILES.lock().unwrap().remove(&self.key);
+    }
+}
+
+struct CachedModules {
+    image_digest: String,
+    // the modules depend on the wasm features, e.g., they aren't precompiled with any enabled
+    wasm_features: Vec<WasmFeature>,
+    // kept to check the image again on every load
+    manifest: ImageManifest,
+    modules: LoadedModules,
+}
+
+// the modules last loaded for each engine and container id
+static MODULES_CACHE: Mutex<BTreeMap<ModulesCacheKey, CachedModules>> = Mutex::new(BTreeMap::new());
+
+// returns the manifest and the cached modules for the container,
+// unless its image digest or wasm features have changed since they were loaded
+fn cached_modules(
+    key: &ModulesCacheKey,
+    image_digest: &str,
+    wasm_features: &[WasmFeature],
+) -> Option<(ImageManifest, LoadedModules)> {
+    let cache = MODULES_CACHE.lock().unwrap();
+    let cached = cache.get(key)?;
+    (cached.image_digest == image_digest && cached.wasm_features == wasm_features)
+        .then(|| (cached.manifest.clone(), cached.modules.clone()))
+}
+
+/// Drops the modules cached for a container, once the container is deleted or fails to be created.
+pub(crate) fn forget_modules(engine_name: &'static str, containerd_id: &str) {
+    let key = (engine_name, containerd_id.to_string());
+    MODULES_CACHE.lock().unwrap().remove(&key);
+}
+
+fn cache_modules(
+    key: ModulesCacheKey,
+    image_digest: String,
+    wasm_features: Vec<WasmFeature>,
+    manifest: ImageManifest,
+    modules: LoadedModules,
+) {
+    let cached = CachedModules {
+        image_digest,
+        wasm_features,
+        manifest,
+        modules,
+    };
+    MODULES_CACHE.lock().unwrap().insert(key, cached);
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// content without a last-used label is treated as the least recently used
+fn last_used(info: &Info) -> u64 {
+    info.labels
+        .get(LAST_USED_LABEL)
+        .and_then(|t| t.parse().ok())
+        .unwrap_or_default()
+}
+
+// returns the space on the filesystem of `path` that is available to unprivileged users
+#[cfg(unix)]
+fn available_space(path: &Path) -> Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+#[cfg(windows)]
+fn available_space(_path: &Path) -> Result<u64> {
+    Ok(u64::MAX)
+}
+
+// returns the memory that is available for new allocations without swapping, from `MemAvailable` in /proc/meminfo
+#[cfg(unix)]
+fn available_memory() -> Result<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .and_then(|kb| kb.trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .ok_or_else(|| ShimError::Others("no MemAvailable in /proc/meminfo".to_string()))
+}
+
+#[cfg(windows)]
+fn available_memory() -> Result<u64> {
+    Ok(u64::MAX)
+}
+
+// The layer of a precompiled module, described by the digest of its content rather than by the image config,
+// so that the engine can tell the precompiled modules apart without hashing them, see `Source::digest`.
+fn precompiled_layer<T: Engine>(engine: &T, digest: &str, precompiled: Vec<u8>) -> WasmLayer {
+    WasmLayer {
+        config: oci_spec::image::Descriptor::new(
+            MediaType::Other(engine.precompiled_media_type()),
+            precompiled.len() as i64,
+            digest,
+        ),
+        layer: precompiled,
+        binary_type: None,
+        wasi_version: None,
+    }
+}
+
+// precompiles the layers of the image, and logs the progress the engine reports
+fn precompile_logged<T: Engine>(
+    engine: &T,
+    layers: &[Vec<u8>],
+    image_digest: &str,
+) -> anyhow::Result<Vec<u8>> {
+    let start = Instant::now();
+    engine.precompile_with_progress(layers, &|progress| match progress {
+        PrecompileProgress::Started => log::info!("precompile of {image_digest} started"),
+        PrecompileProgress::Phase {
+            name,
+            percent: Some(percent),
+        } => log::info!(
+            "precompile of {image_digest}: {name} ({percent}%) after {:?}",
+            start.elapsed()
+        ),
+        PrecompileProgress::Phase {
+            name,
+            percent: None,
+        } => log::info!(
+            "precompile of {image_digest}: {name} after {:?}",
+            start.elapsed()
+        ),
+        PrecompileProgress::Finished => log::info!(
+            "precompile of {image_digest} finished after {:?}",
+            start.elapsed()
+        ),
+    })
+}
+
+// Decompresses precompiled content that was stored compressed with zstd, and returns other content as is.
+fn decompress_precompiled(content: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    if !content.starts_with(&ZSTD_MAGIC) {
+        return Ok(content);
+    }
+    zstd::decode_all(content.as_slice())
+}
+
+fn validate_precompiled<T: Engine>(engine: &T, precompiled: &[u8]) -> anyhow::Result<()> {
+    anyhow::ensure!(!precompiled.is_empty(), "the precompiled module is empty");
+    engine.validate_precompiled(precompiled)
+}
+
+fn precompile_label(name: &str, version: &str) -> String {
+    format!("{}/{}/{}", PRECOMPILE_PREFIX, name, version)
+}
+
+// The gc ref from the content of an image to the content precompiled from it for the precompile `label`,
+// e.g., `containerd.io/gc.ref.content.precompile.wasmtime/17.0.0` for `runwasi.io/precompiled/wasmtime/17.0.0`.
+fn precompile_gc_ref(label: &str) -> String {
+    let engine = label
+        .strip_prefix(PRECOMPILE_PREFIX)
+        .unwrap_or(label)
+        .trim_start_matches('/');
+    format!("{PRECOMPILE_GC_REF}.{engine}")
+}
+
+// The labels of the content of an image that point it at the content precompiled from it for the precompile `label`.
+// They remove the single gc ref that all the engines shared before each got its own,
+// so that it doesn't keep the content it points at from being garbage collected forever.
+fn precompile_gc_labels(label: &str, precompiled: &str) -> HashMap<String, String> {
+    HashMap::from([
+        (precompile_gc_ref(label), precompiled.to_string()),
+        (PRECOMPILE_GC_REF.to_string(), String::new()),
+    ])
+}
+
+pub(crate) fn engine_precompile_label<T: Engine>(engine: &T, version: &str) -> String {
+    match engine.precompile_config_hash() {
+        Some(config_hash) => precompile_label(T::name(), &format!("{version}/{config_hash}")),
+        None => precompile_label(T::name(), version),
+    }
+}
+
+// Finds the image with the given reference among `images`, the way `resolve_image` looks it up in containerd.
+fn find_image<'a>(images: &'a [Image], reference: &str) -> Option<&'a Image> {
+    let normalized = normalize_reference(reference);
+    let digest = reference.split_once('@').map(|(_, digest)| digest);
+    images
+        .iter()
+        .find(|image| image.name == reference)
+        .or_else(|| {
+            images.iter().find(|image| {
+                normalize_reference(&image.name) == normalized
+                    || digest.is_some_and(|d| image.target.as_ref().is_some_and(|t| t.digest == d))
+            })
+        })
+}
+
+// Expands an image reference to its fully qualified form, the way docker and ctr do,
+// e.g., `foo` to `docker.io/library/foo:latest`.
+fn normalize_reference(reference: &str) -> String {
+    let (name, digest) = match reference.split_once('@') {
+        Some((name, digest)) => (name, Some(digest)),
+        None => (reference, None),
+    };
+    // a colon followed by a path is the port of the registry, e.g., `localhost:5000/foo`
+    let (name, tag) = match name.rsplit_once(':') {
+        Some((name, tag)) if !tag.contains('/') => (name, Some(tag)),
+        _ => (name, None),
+    };
+    let (domain, path) = match name.split_once('/') {
+        Some((domain, path))
+            if domain.contains('.') || domain.contains(':') || domain == "localhost" =>
+        {
+            (domain, path)
+        }
+        _ => ("docker.io", name),
+    };
+    let domain = match domain {
+        "index.docker.io" => "docker.io",
+        domain => domain,
+    };
+
+    let mut normalized = match (domain, path.contains('/')) {
+        ("docker.io", false) => format!("{domain}/library/{path}"),
+        _ => format!("{domain}/{path}"),
+    };
+    match (tag, digest) {
+        (Some(tag), _) => normalized.push_str(&format!(":{tag}")),
+        (None, None) => normalized.push_str(":latest"),
+        (None, Some(_)) => {}
+    }
+    if let Some(digest) = digest {
+        normalized.push_str(&format!("@{digest}"));
+    }
+    normalized
+}
+
+// Rejects wasm layers that are too small to hold anything to run, before they reach the engine,
+// which would fail on them with a less helpful error.
+fn check_module_size(descriptor: &oci_spec::image::Descriptor, layer: &[u8]) -> Result<()> {
+    if WasmLayer::classify(descriptor.media_type(), layer).is_none() {
+        return Ok(());
+    }
+    let reason = match layer.len() {
+        0 => "empty module",
+        // the header is the magic number and the version, followed by the sections
+        1..=7 => "truncated wasm header",
+        8 => "module has nothing after the wasm header",
+        _ => return Ok(()),
+    };
+    Err(ShimError::InvalidModule {
+        digest: descriptor.digest().to_string(),
+        reason: reason.to_string(),
+    })
+}
+
+fn check_module_limit(size: u64, limit: Option<u64>) -> Result<()> {
+    match limit {
+        Some(limit) if size > limit => Err(ShimError::ModuleTooLarge { size, limit }),
+        _ => Ok(()),
+    }
+}
+
+fn is_wasm_layer(media_type: &MediaType, supported_layer_types: &[&str]) -> bool {
+    supported_layer_types.contains(&media_type.to_string().as_str())
+}
+
+// The image config stores the platform features under `os.features`,
+// which the `Platform` struct from oci_spec doesn't deserialize on its own.
+#[derive(Deserialize)]
+struct ImageConfigPlatform {
+    #[serde(flatten)]
+    platform: Platform,
+    #[serde(rename = "os.features", default)]
+    os_features: Option<Vec<String>>,
+}
+
+// The `StopSignal` of the image config, without requiring the rest of the config to be valid.
+#[derive(Deserialize)]
+struct ImageConfigStopSignal {
+    #[serde(default)]
+    config: Option<StopSignalConfig>,
+}
+
+#[derive(Deserialize)]
+struct StopSignalConfig {
+    #[serde(rename = "StopSignal", default)]
+    stop_signal: Option<String>,
+}
+
+fn parse_stop_signal(image_config: &[u8]) -> Result<Option<String>> {
+    let ImageConfigStopSignal { config } = serde_json::from_slice(image_config)?;
+    Ok(config
+        .and_then(|config| config.stop_signal)
+        .filter(|signal| !signal.is_empty()))
+}
+
+fn parse_platform(image_config: &[u8]) -> Result<Platform> {
+    let ImageConfigPlatform {
+        mut platform,
+        os_features,
+    } = serde_json::from_slice(image_config)?;
+    if os_features.is_some() {
+        platform.set_os_features(os_features);
+    }
+    Ok(platform)
+}
+
+fn check_os_features(platform: &Platform, supported_features: &[&str]) -> Result<()> {
+    let unsupported = platform
+        .os_features()
+        .iter()
+        .flatten()
+        .filter(|feature| !supported_features.contains(&feature.as_str()))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !unsupported.is_empty() {
+        return Err(ShimError::FailedPrecondition(format!(
+            "image requires os features not supported by this runtime: {}",
+            unsupported.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use containerd_client::services::v1::container::Runtime as ContainerRuntime;
+    use containerd_client::services::v1::{
+        CreateContainerRequest, CreateImageRequest, DeleteContainerRequest, DeleteImageRequest,
+    };
+    use containerd_client::tonic::transport::Endpoint;
+    use containerd_client::types::Descriptor;
+    use oci_spec::image::Arch;
+
+    use super::*;
+    use crate::container::RuntimeContext;
+    use crate::sandbox::Stdio;
+
+    impl Client {
+        // runs an operation of the async client to completion
+        fn block_on<T>(&self, fut: impl Future<Output = T>) -> T {
+            self.rt.block_on(fut)
+        }
+
+        // blocking versions of the operations of the async client that the tests use
+        fn read_content(&self, digest: impl ToString) -> Result<Vec<u8>> {
+            self.rt.block_on(self.inner.read_content(digest))
+        }
+
+        fn read_contents(&self, digests: Vec<String>) -> Result<Vec<Vec<u8>>> {
+            self.rt.block_on(self.inner.read_contents(digests))
+        }
+
+        fn read_precompiled(&self, digest: &str) -> Result<Option<Vec<u8>>> {
+            self.rt.block_on(self.inner.read_precompiled(digest))
+        }
+
+        fn save_content(
+            &self,
+            data: Vec<u8>,
+            original_digest: String,
+            label: &str,
+            media_type: Option<&str>,
+        ) -> Result<WriteContent> {
+            self.save_image_content(data, None, original_digest, label, media_type)
+        }
+
+        fn save_image_content(
+            &self,
+            data: Vec<u8>,
+            image_name: Option<&str>,
+            original_digest: String,
+            label: &str,
+            media_type: Option<&str>,
+        ) -> Result<WriteContent> {
+            self.rt.block_on(self.inner.save_image_content(
+                data,
+                image_name,
+                original_digest,
+                label,
+                media_type,
+            ))
+        }
+
+        fn release(&self, content: WriteContent) -> String {
+            self.rt.block_on(content.release())
+        }
+
+        fn delete_content(&self, digest: impl ToString) -> Result<()> {
+            self.rt.block_on(self.inner.delete_content(digest))
+        }
+
+        fn list_content(&self, filters: Vec<String>) -> Result<Vec<Info>> {
+            self.rt.block_on(self.inner.list_content(filters))
+        }
+
+        fn get_info(&self, content_digest: String) -> Result<Info> {
+            self.rt.block_on(self.inner.get_info(content_digest))
+        }
+
+        fn update_info(&self, info: Info) -> Result<Info> {
+            self.rt.block_on(self.inner.update_info(info))
+        }
+
+        fn get_image(&self, image_name: impl ToString) -> Result<Image> {
+            self.rt.block_on(self.inner.get_image(image_name))
+        }
+
+        fn resolve_image(&self, reference: impl ToString) -> Result<Image> {
+            self.rt.block_on(self.inner.resolve_image(reference))
+        }
+
+        fn load_image_modules<T: Engine>(
+            &self,
+            image: Image,
+            image_digest: String,
+            engine: &T,
+        ) -> Result<(Vec<oci::WasmLayer>, Platform)> {
+            self.rt
+                .block_on(self.inner.load_image_modules(image, image_digest, engine))
+        }
+
+        fn store_precompiled<T: Engine>(
+            &self,
+            image: Image,
+            image_digest: &str,
+            precompile_id: String,
+            precompiled: &[u8],
+            engine: &T,
+            keep_existing: bool,
+        ) -> Result<String> {
+            self.rt.block_on(self.inner.store_precompiled(
+                image,
+                image_digest,
+                precompile_id,
+                precompiled,
+                engine,
+                keep_existing,
+            ))
+        }
+
+        fn touch_precompiled(&self, digest: impl ToString) -> Result<bool> {
+            self.rt.block_on(self.inner.touch_precompiled(digest))
+        }
+
+        fn evict_precompiled(&self, incoming: u64) -> Result<usize> {
+            self.rt.block_on(self.inner.evict_precompiled(incoming))
+        }
+
+        fn create_image(&self, name: &str, target: &str, labels: HashMap<String, String>) {
+            self.rt.block_on(async {
+                let image = Image {
+                    name: name.to_string(),
+                    labels,
+                    target: Some(Descriptor {
+                        media_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
+                        digest: target.to_string(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                };
+                let req = CreateImageRequest { image: Some(image) };
+                let req = with_namespace!(req, self.inner.namespace);
+                ImagesClient::new(self.inner.channel.clone())
+                    .create(req)
+                    .await
+                    .unwrap();
+            })
+        }
+
+        fn delete_image(&self, name: &str) {
+            self.rt.block_on(async {
+                let req = DeleteImageRequest {
+                    name: name.to_string(),
+                    sync: false,
+                };
+                let req = with_namespace!(req, self.inner.namespace);
+                ImagesClient::new(self.inner.channel.clone())
+                    .delete(req)
+                    .await
+                    .unwrap();
+            })
+        }
+
+        fn create_container(&self, id: &str, image: &str) {
+            self.rt.block_on(async {
+                let container = Container {
+                    id: id.to_string(),
+                    image: image.to_string(),
+                    runtime: Some(ContainerRuntime {
+                        name: "io.containerd.wasmtime.v1".to_string(),
+                        options: None,
+                    }),
+                    spec: Some(prost_types::Any {
+                        type_url: "types.containerd.io/opencontainers/runtime-spec/1/Spec"
+                            .to_string(),
+                        value: b"{}".to_vec(),
+                    }),
+                    ..Default::default()
+                };
+                let req = CreateContainerRequest {
+                    container: Some(container),
+                };
+                let req = with_namespace!(req, self.inner.namespace);
+                ContainersClient::new(self.inner.channel.clone())
+                    .create(req)
+                    .await
+                    .unwrap();
+            })
+        }
+
+        fn delete_container(&self, id: &str) {
+            self.rt.block_on(async {
+                let req = DeleteContainerRequest { id: id.to_string() };
+                let req = with_namespace!(req, self.inner.namespace);
+                ContainersClient::new(self.inner.channel.clone())
+                    .delete(req)
+                    .await
+                    .unwrap();
+            })
+        }
+    }
+
+    #[test]
+    fn test_normalize_reference() {
+        for reference in [
+            "foo",
+            "foo:latest",
+            "library/foo",
+            "docker.io/foo",
+            "docker.io/library/foo",
+            "index.docker.io/library/foo:latest",
+        ] {
+            assert_eq!(
+                normalize_reference(reference),
+                "docker.io/library/foo:latest",
+                "{reference}"
+            );
+        }
+        assert_eq!(normalize_reference("user/foo:v1"), "docker.io/user/foo:v1");
+        assert_eq!(
+            normalize_reference("localhost:5000/foo"),
+            "localhost:5000/foo:latest"
+        );
+        assert_eq!(
+            normalize_reference("ghcr.io/containerd/foo:v1"),
+            "ghcr.io/containerd/foo:v1"
+        );
+        assert_eq!(
+            normalize_reference("foo@sha256:1234"),
+            "docker.io/library/foo@sha256:1234"
+        );
+    }
+
+    #[test]
+    fn test_resolve_image() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        let image_name = "docker.io/library/test-resolve-image:latest";
+        let manifest = client
+            .save_content(
+                b"resolve-manifest".to_vec(),
+                "original".to_string(),
+                &precompile_label("test", "resolve"),
+                None,
+            )
+            .unwrap();
+        client.create_image(image_name, &manifest.digest, HashMap::new());
+
+        for reference in [
+            image_name.to_string(),
+            "test-resolve-image".to_string(),
+            "test-resolve-image:latest".to_string(),
+            "library/test-resolve-image".to_string(),
+            format!("test-resolve-image@{}", manifest.digest),
+        ] {
+            let image = client.resolve_image(&reference).unwrap();
+            assert_eq!(image.name, image_name, "{reference}");
+        }
+
+        let err = client.resolve_image("test-resolve-image:v2").unwrap_err();
+        assert!(matches!(err, ShimError::NotFound(_)));
+
+        client.delete_image(image_name);
+        let digest = manifest.digest.clone();
+        drop(manifest);
+        client.delete_content(digest).unwrap();
+    }
+
+    #[test]
+    fn test_save_content() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+        let data = b"hello world".to_vec();
+
+        let expected = digest(data.clone());
+        let expected = format!("sha256:{}", expected);
+
+        let label = precompile_label("test", "hasdfh");
+        let returned = client
+            .save_content(data, "original".to_string(), &label, None)
+            .unwrap();
+        assert_eq!(expected, returned.digest.clone());
+
+        let data = client.read_content(returned.digest.clone()).unwrap();
+        assert_eq!(data, b"hello world");
+
+        client
+            .save_content(data.clone(), "original".to_string(), &label, None)
+            .expect_err("Should not be able to save when lease is open");
+
+        // need to release the lease to be able to create a second one
+        client.release(returned);
+
+        // a second call should be successful since it already exists
+        let returned = client
+            .save_content(data, "original".to_string(), &label, None)
+            .unwrap();
+        assert_eq!(expected, returned.digest);
+
+        client.delete_content(expected.clone()).unwrap();
+
+        client
+            .read_content(expected)
+            .expect_err("content should not exist");
+    }
+
+    #[test]
+    fn test_save_content_media_type() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        let label = precompile_label("test", "media-type");
+        let media_type = "application/vnd.wasm.precompiled.test";
+        let returned = client
+            .save_content(
+                b"typed".to_vec(),
+                "original".to_string(),
+                &label,
+                Some(media_type),
+            )
+            .unwrap();
+        let info = client.get_info(returned.digest.clone()).unwrap();
+        assert_eq!(
+            info.labels.get(MEDIA_TYPE_LABEL).map(String::as_str),
+            Some(media_type)
+        );
+        client.release(returned);
+        client.delete_content(info.digest).unwrap();
+
+        // content saved without a media type is labelled as before
+        let returned = client
+            .save_content(b"untyped".to_vec(), "original".to_string(), &label, None)
+            .unwrap();
+        let info = client.get_info(returned.digest.clone()).unwrap();
+        assert!(!info.labels.contains_key(MEDIA_TYPE_LABEL));
+        assert_eq!(
+            info.labels.get(&label).map(String::as_str),
+            Some("original")
+        );
+        drop(returned);
+        client.delete_content(info.digest).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_precompiled() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let from = Client::connect(path, "test-ns").unwrap();
+        let to = Client::connect(path, "test-ns-migrate").unwrap();
+        let image_name = "localhost/test-migrate-precompiled:latest";
+
+        // the image exists in both namespaces
+        let manifest_label = precompile_label("test", "migrate-manifest");
+        let from_manifest = from
+            .save_content(
+                b"manifest".to_vec(),
+                "original".to_string(),
+                &manifest_label,
+                None,
+            )
+            .unwrap();
+        let to_manifest = to
+            .save_content(
+                b"manifest".to_vec(),
+                "original".to_string(),
+                &manifest_label,
+                None,
+            )
+            .unwrap();
+
+        // but is only precompiled in the first one
+        let label = precompile_label("test", "migrate");
+        let precompiled = from
+            .save_content(
+                b"precompiled".to_vec(),
+                from_manifest.digest.clone(),
+                &label,
+                None,
+            )
+            .unwrap();
+        from.create_image(
+            image_name,
+            &from_manifest.digest,
+            HashMap::from([(label.clone(), precompiled.digest.clone())]),
+        );
+        let other = precompile_label("test", "migrate-other");
+        to.create_image(
+            image_name,
+            &to_manifest.digest,
+            HashMap::from([(other.clone(), "sha256:other".to_string())]),
+        );
+
+        let migrated = from
+            .migrate_precompiled("test-ns", "test-ns-migrate", image_name)
+            .unwrap();
+        assert_eq!(migrated, 1);
+
+        // the precompile labels the image already had in the namespace are kept
+        let image = to.get_image(image_name).unwrap();
+        assert_eq!(image.labels.get(&label), Some(&precompiled.digest));
+        assert_eq!(image.labels.get(&other).unwrap(), "sha256:other");
+        let precompiled_info = to.get_info(precompiled.digest.clone()).unwrap();
+        assert_eq!(
+            precompiled_info.labels.get(&label),
+            Some(&to_manifest.digest)
+        );
+        assert_eq!(
+            precompiled_info
+                .labels
+                .get(IMAGE_REFERENCE_LABEL)
+                .map(String::as_str),
+            Some(image_name)
+        );
+        assert_eq!(
+            to.read_content(&precompiled.digest).unwrap(),
+            b"precompiled"
+        );
+        let manifest_info = to.get_info(to_manifest.digest.clone()).unwrap();
+        assert_eq!(
+            manifest_info.labels.get(&precompile_gc_ref(&label)),
+            Some(&precompiled.digest)
+        );
+
+        // migrating again is a no-op
+        let migrated = from
+            .migrate_precompiled("test-ns", "test-ns-migrate", image_name)
+            .unwrap();
+        assert_eq!(migrated, 0);
+
+        from.delete_image(image_name);
+        to.delete_image(image_name);
+        from.delete_precompiled_blob(&precompiled.digest).unwrap();
+        to.delete_precompiled_blob(&precompiled.digest).unwrap();
+    }
+
+    #[derive(Clone)]
+    struct ConfigurableEngine {
+        config_hash: Option<&'static str>,
+    }
+
+    impl Engine for ConfigurableEngine {
+        fn name() -> &'static str {
+            "configurable"
+        }
+        fn run_wasi(&self, _ctx: &impl RuntimeContext, _stdio: Stdio) -> anyhow::Result<i32> {
+            Ok(0)
+        }
+        fn can_precompile(&self) -> Option<String> {
+            Some("v1".to_string())
+        }
+        fn precompile_config_hash(&self) -> Option<String> {
+            self.config_hash.map(String::from)
+        }
+    }
+
+    #[test]
+    fn test_precompile_label_includes_config_hash() {
+        let engine = ConfigurableEngine { config_hash: None };
+        assert_eq!(
+            engine_precompile_label(&engine, "v1"),
+            "runwasi.io/precompiled/configurable/v1"
+        );
+
+        let engine = ConfigurableEngine {
+            config_hash: Some("1234"),
+        };
+        let label = engine_precompile_label(&engine, "v1");
+        assert_eq!(label, "runwasi.io/precompiled/configurable/v1/1234");
+
+        // changing the configuration changes the label, so the module is recompiled
+        let engine = ConfigurableEngine {
+            config_hash: Some("5678"),
+        };
+        assert_ne!(engine_precompile_label(&engine, "v1"), label);
+    }
+
+    #[test]
+    fn test_parse_platform_os_features() {
+        let config = br#"{
+            "architecture": "wasm",
+            "os": "wasip1",
+            "os.features": ["wasi:http/proxy"],
+            "rootfs": { "type": "layers", "diff_ids": [] }
+        }"#;
+        let platform = parse_platform(config).unwrap();
+        assert_eq!(platform.architecture(), &Arch::Wasm);
+        assert_eq!(
+            platform.os_features(),
+            &Some(vec!["wasi:http/proxy".to_string()])
+        );
+        check_os_features(&platform, &["wasi:http/proxy", "wasi:cli/command"]).unwrap();
+
+        let platform = parse_platform(br#"{"architecture": "wasm", "os": "wasip1"}"#).unwrap();
+        assert_eq!(platform.os_features(), &None);
+        check_os_features(&platform, &[]).unwrap();
+    }
+
+    #[test]
+    fn test_check_unsupported_os_features() {
+        let config = br#"{
+            "architecture": "wasm",
+            "os": "wasip1",
+            "os.features": ["wasi:cli/command", "wasi:http/proxy"]
+        }"#;
+        let platform = parse_platform(config).unwrap();
+        let err = check_os_features(&platform, &["wasi:cli/command"]).unwrap_err();
+        assert!(
+            matches!(err, ShimError::FailedPrecondition(msg) if msg.ends_with(": wasi:http/proxy"))
+        );
+    }
+
+    #[test]
+    fn test_parse_stop_signal() {
+        let config = br#"{
+            "architecture": "wasm",
+            "os": "wasip1",
+            "config": { "Entrypoint": ["_start"], "StopSignal": "SIGTERM" }
+        }"#;
+        assert_eq!(
+            parse_stop_signal(config).unwrap(),
+            Some("SIGTERM".to_string())
+        );
+
+        for config in [
+            r#"{"architecture": "wasm", "os": "wasip1"}"#,
+            r#"{"config": {"Entrypoint": ["_start"]}}"#,
+            r#"{"config": {"StopSignal": ""}}"#,
+        ] {
+            assert_eq!(parse_stop_signal(config.as_bytes()).unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_retries() {
+        let client = AsyncClient::connect("/run/containerd/containerd.sock", "test-ns")
+            .await
+            .unwrap();
+        let data = format!("delayed content {}", unix_now()).into_bytes();
+        let digest = format!("sha256:{}", sha256::digest(data.as_slice()));
+
+        // missing content fails right away by default
+        let err = client.read_content(&digest).await.unwrap_err();
+        assert!(matches!(err, ShimError::NotFound(_)), "{err}");
+
+        // the content only becomes visible after the first reads
+        let client = client.with_read_retries(50, Duration::from_millis(20));
+        let label = precompile_label("test", "read-retries");
+        let save = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            client
+                .save_image_content(data.clone(), None, "original".to_string(), &label, None)
+                .await
+        };
+        let (read, saved) = tokio::join!(client.read_content(&digest), save);
+        let saved = saved.unwrap();
+        assert_eq!(saved.digest, digest);
+        assert_eq!(read.unwrap(), data);
+
+        client.delete_content(&digest).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_precompiled() {
+        let mut client = AsyncClient::connect("/run/containerd/containerd.sock", "test-ns")
+            .await
+            .unwrap();
+        let digest = format!(
+            "sha256:{}",
+            sha256::digest(b"missing precompiled".as_slice())
+        );
+
+        // a precompiled module that is gone is precompiled again
+        assert_eq!(client.read_precompiled(&digest).await.unwrap(), None);
+
+        // but a content store that can't be reached fails the load, rather than recompiling
+        client.content_channel = Endpoint::from_static("http://127.0.0.1:1").connect_lazy();
+        let err = client.read_precompiled(&digest).await.unwrap_err();
+        assert!(matches!(err, ShimError::Containerd(_)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_retry_policy() {
+        let failures = Arc::new(AtomicUsize::new(0));
+        let counted = failures.clone();
+        let policy = RetryPolicy::new(3, Duration::from_millis(10)).with_retryable(move |err| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            matches!(err, ShimError::Containerd(_))
+        });
+        let mut client = AsyncClient::connect("/run/containerd/containerd.sock", "test-ns")
+            .await
+            .unwrap()
+            .with_retry_policy(policy);
+        let digest = format!("sha256:{}", sha256::digest(b"retried".as_slice()));
+
+        // content that isn't found isn't retried
+        let err = client.read_content(&digest).await.unwrap_err();
+        assert!(matches!(err, ShimError::NotFound(_)), "{err}");
+        assert_eq!(failures.swap(0, Ordering::SeqCst), 1);
+
+        // but a content store that can't be reached is, until the policy gives up
+        client.content_channel = Endpoint::from_static("http://127.0.0.1:1").connect_lazy();
+        let err = client.read_content(&digest).await.unwrap_err();
+        assert!(matches!(err, ShimError::Containerd(_)), "{err}");
+        assert_eq!(failures.load(Ordering::SeqCst), 2);
+
+        // and so is the read that streams the content
+        let err = client
+            .copy_content(&digest, &mut Vec::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ShimError::Containerd(_)), "{err}");
+        assert_eq!(failures.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_async_client_futures_are_send() {
+        // the operations of the async client can be spawned, with the `tracing` feature as well,
+        // so this only needs to compile
+        fn spawn_operations(client: &'static AsyncClient) {
+            tokio::spawn(client.load_modules("id", &JitEngine));
+            tokio::spawn(client.export_logs("id", vec![], vec![]));
+            let sink = Box::leak(Box::new(std::io::sink()));
+            tokio::spawn(client.copy_content("sha256:digest", sink));
+            tokio::spawn(client.read_contents(vec![]));
+        }
+        let _ = spawn_operations;
+    }
+
+    #[tokio::test]
+    async fn test_async_client() {
+        // nothing here may start a runtime of its own, as that panics within this one
+        let client = AsyncClient::connect("/run/containerd/containerd.sock", "test-ns")
+            .await
+            .unwrap();
+
+        let label = precompile_label("test", "async");
+        let content = client
+            .save_image_content(
+                b"async".to_vec(),
+                None,
+                "original".to_string(),
+                &label,
+                None,
+            )
+            .await
+            .unwrap();
+        let data = client.read_content(&content.digest).await.unwrap();
+        assert_eq!(data, b"async");
+
+        let mut copied = vec![];
+        let written = client
+            .copy_content(&content.digest, &mut copied)
+            .await
+            .unwrap();
+        assert_eq!(written, 5);
+        assert_eq!(copied, data);
+
+        // the lease of the content is deleted within the runtime
+        let digest = content.release().await;
+        client.delete_content(&digest).await.unwrap();
+        assert!(!client.delete_precompiled_blob(&digest).await.unwrap());
+    }
+
+    #[test]
+    fn test_content_address() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = dir.path().join("containerd.sock");
+        let content_address = dir.path().join("content.sock");
+        std::os::unix::fs::symlink("/run/containerd/containerd.sock", &address).unwrap();
+        std::os::unix::fs::symlink("/run/containerd/containerd.sock", &content_address).unwrap();
+        let content_address = content_address.to_str().unwrap();
+        let client = Client::connect(address.to_str().unwrap(), "test-ns")
+            .unwrap()
+            .with_content_address(content_address)
+            .unwrap();
+
+        let label = precompile_label("test", "content-address");
+        let content = client
+            .save_content(
+                b"content address".to_vec(),
+                "original".to_string(),
+                &label,
+                None,
+            )
+            .unwrap();
+        let lease_id = content.lease.lease_id.clone();
+
+        // the lease can only be deleted through the content store address once the other one is gone
+        std::fs::remove_file(&address).unwrap();
+        let digest = client.release(content);
+        let leases = client
+            .block_on(async {
+                let req = containerd_client::services::v1::ListRequest::default();
+                let req = with_namespace!(req, client.inner.namespace);
+                LeasesClient::new(client.inner.content_channel.clone())
+                    .list(req)
+                    .await
+            })
+            .unwrap()
+            .into_inner()
+            .leases;
+        assert!(!leases.iter().any(|lease| lease.id == lease_id));
+
+        client.delete_content(digest).unwrap();
+    }
+
+    #[test]
+    fn test_stall_timeout() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // a response stream that never yields a message
+            let mut responses = futures::stream::pending::<
+                std::result::Result<WriteContentResponse, tonic::Status>,
+            >();
+            let err = stall_timeout(
+                Duration::from_millis(10),
+                "commit response",
+                responses.try_next(),
+            )
+            .await
+            .expect_err("stalled stream should time out");
+            assert!(
+                matches!(err, ShimError::Timeout { operation, after } if operation.contains("commit response") && after == Duration::from_millis(10))
+            );
+
+            // a full channel that is never drained
+            let (tx, _rx) = mpsc::channel(1);
+            tx.send(WriteContentRequest::default()).await.unwrap();
+            stall_timeout(
+                Duration::from_millis(10),
+                "commit request",
+                tx.send(WriteContentRequest::default()),
+            )
+            .await
+            .expect_err("stalled send should time out");
+        });
+    }
+
+    #[test]
+    fn test_wait_for_target() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let image = |target: Option<Descriptor>| Image {
+                name: "test-wait-for-target".to_string(),
+                target,
+                ..Default::default()
+            };
+            let target = Descriptor {
+                digest: "sha256:pulled".to_string(),
+                ..Default::default()
+            };
+
+            // the target appears once the pull completes
+            let gets = AtomicUsize::new(0);
+            let pulled = wait_for_target(Duration::from_secs(5), || {
+                let pulled = gets.fetch_add(1, Ordering::SeqCst) >= 2;
+                let target = pulled.then(|| target.clone());
+                async move { Ok(image(target)) }
+            })
+            .await
+            .unwrap();
+            assert_eq!(pulled.target, Some(target.clone()));
+            assert_eq!(gets.load(Ordering::SeqCst), 3);
+
+            // an image that never gets a target isn't ready
+            let err = wait_for_target(Duration::from_millis(500), || async { Ok(image(None)) })
+                .await
+                .expect_err("the image has no target");
+            assert!(
+                matches!(err, ShimError::ImageNotReady(msg) if msg.contains("test-wait-for-target"))
+            );
+
+            // an image that doesn't exist isn't waited for
+            let err = wait_for_target(Duration::from_secs(5), || async {
+                Err::<Image, _>(ShimError::NotFound("test-wait-for-target".to_string()))
+            })
+            .await
+            .expect_err("the image doesn't exist");
+            assert!(matches!(err, ShimError::NotFound(_)));
+        });
+    }
+
+    #[test]
+    fn test_save_content_stat_stalled() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns")
+            .unwrap()
+            .with_stat_timeout(Duration::ZERO)
+            .with_retry_policy(RetryPolicy::new(2, Duration::ZERO));
+        let data = b"stalled stat".to_vec();
+        let label = precompile_label("test", "stalled-stat");
+
+        let err = client
+            .save_content(data.clone(), "original".to_string(), &label, None)
+            .expect_err("stat should time out");
+        assert!(matches!(err, ShimError::Timeout { operation, .. } if operation.contains("stat")));
+
+        // the ingest of the stalled stat was aborted, so the write can be retried
+        let client = client.with_stat_timeout(DEFAULT_STAT_TIMEOUT);
+        let returned = client
+            .save_content(data, "original".to_string(), &label, None)
+            .unwrap();
+        client.delete_content(returned.digest.clone()).unwrap();
+    }
+
+    #[test]
+    fn test_save_content_stalled() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns")
+            .unwrap()
+            .with_write_timeout(Duration::ZERO);
+        let data = b"stalled write".to_vec();
+        let label = precompile_label("test", "stalled");
+
+        let err = client
+            .save_content(data.clone(), "original".to_string(), &label, None)
+            .expect_err("write should time out");
+        assert!(matches!(err, ShimError::Timeout { .. }), "{err}");
+
+        // the lease and the ingest were cleaned up, so the write can be retried
+        let client = client.with_write_timeout(DEFAULT_WRITE_TIMEOUT);
+        let returned = client
+            .save_content(data, "original".to_string(), &label, None)
+            .unwrap();
+        client.delete_content(returned.digest.clone()).unwrap();
+    }
+
+    #[test]
+    fn test_delete_precompiled_blob() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        // unreferenced content is deleted
+        let label = precompile_label("test", "unreferenced");
+        let unreferenced = client
+            .save_content(
+                b"unreferenced".to_vec(),
+                "original".to_string(),
+                &label,
+                None,
+            )
+            .unwrap();
+        assert!(client
+            .delete_precompiled_blob(&unreferenced.digest)
+            .unwrap());
+        client
+            .read_content(&unreferenced.digest)
+            .expect_err("content should not exist");
+
+        // content referenced by an image is kept
+        let label = precompile_label("test", "referenced");
+        let referenced = client
+            .save_content(b"referenced".to_vec(), "original".to_string(), &label, None)
+            .unwrap();
+        let image_name = "localhost/test-delete-precompiled:latest";
+        client.create_image(
+            image_name,
+            &referenced.digest,
+            HashMap::from([(label, referenced.digest.clone())]),
+        );
+        assert!(!client.delete_precompiled_blob(&referenced.digest).unwrap());
+        client.read_content(&referenced.digest).unwrap();
+
+        client.delete_image(image_name);
+        assert!(client.delete_precompiled_blob(&referenced.digest).unwrap());
+    }
+
+    #[test]
+    fn test_on_image_removed() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+        let engine = EmptyPrecompileEngine;
+        let label = precompile_label(EmptyPrecompileEngine::name(), "v1");
+
+        let image_name = "localhost/test-on-image-removed:latest";
+        let other_name = "localhost/test-on-image-removed-other:latest";
+        let precompiled = client
+            .save_image_content(
+                b"precompiled-on-image-removed".to_vec(),
+                Some(image_name),
+                "sha256:manifest".to_string(),
+                &label,
+                None,
+            )
+            .unwrap();
+        let labels = HashMap::from([(label, precompiled.digest.clone())]);
+        client.create_image(image_name, &precompiled.digest, labels.clone());
+        client.create_image(other_name, &precompiled.digest, labels);
+
+        // another image with the same precompiled content keeps it
+        client.delete_image(image_name);
+        assert_eq!(client.on_image_removed(image_name, &engine).unwrap(), 0);
+        client.read_content(&precompiled.digest).unwrap();
+
+        // removing the image that references the content as well doesn't wait for the garbage collector
+        client.delete_image(other_name);
+        assert_eq!(client.on_image_removed(image_name, &engine).unwrap(), 1);
+        client
+            .read_content(&precompiled.digest)
+            .expect_err("content should not exist");
+    }
+
+    #[test]
+    fn test_evict_precompiled() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns-evict")
+            .unwrap()
+            .with_max_cache_size(25);
+
+        let mut saved = vec![];
+        for (name, last_used) in [("a", "1"), ("b", "2"), ("c", "3")] {
+            let label = precompile_label("test", &format!("evict-{name}"));
+            let content = client
+                .save_content(
+                    name.repeat(10).into_bytes(),
+                    "original".to_string(),
+                    &label,
+                    None,
+                )
+                .unwrap();
+            let mut info = client.get_info(content.digest.clone()).unwrap();
+            info.labels
+                .insert(LAST_USED_LABEL.to_string(), last_used.to_string());
+            client.update_info(info).unwrap();
+            saved.push(content);
+        }
+
+        // 30 bytes are cached, the two least recently used blobs make room for 10 more
+        assert_eq!(client.evict_precompiled(10).unwrap(), 2);
+        client
+            .read_content(&saved[0].digest)
+            .expect_err("content should be evicted");
+        client
+            .read_content(&saved[1].digest)
+            .expect_err("content should be evicted");
+        assert_eq!(
+            client.read_content(&saved[2].digest).unwrap(),
+            b"cccccccccc"
+        );
+
+        // within budget nothing is evicted
+        assert_eq!(client.evict_precompiled(10).unwrap(), 0);
+
+        client.delete_precompiled_blob(&saved[2].digest).unwrap();
+    }
+
+    #[test]
+    fn test_evict_blob_keeps_concurrent_labels() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        let label = precompile_label("test", "evict-blob");
+        let content = client
+            .save_content(b"evict-blob".to_vec(), "original".to_string(), &label, None)
+            .unwrap();
+        let image_name = "localhost/test-evict-blob:latest";
+        let labels = HashMap::from([(label.clone(), content.digest.clone())]);
+        client.create_image(image_name, &content.digest, labels);
+
+        // another precompile labels the image after the eviction listed the images
+        let mut images = client.block_on(client.inner.list_images(vec![])).unwrap();
+        let other = precompile_label("test", "evict-blob-other");
+        client
+            .block_on(client.inner.set_image_labels(
+                image_name,
+                HashMap::from([(other.clone(), "sha256:other".to_string())]),
+            ))
+            .unwrap();
+
+        assert!(client
+            .block_on(client.inner.evict_blob(&mut images, &content.digest))
+            .unwrap());
+        let labels = client.get_image(image_name).unwrap().labels;
+        assert!(!labels.contains_key(&label), "{labels:?}");
+        assert_eq!(labels.get(&other).unwrap(), "sha256:other");
+
+        client.delete_image(image_name);
+    }
+
+    #[test]
+    fn test_touch_precompiled() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        let label = precompile_label("test", "touch");
+        let content = client
+            .save_content(b"touch".to_vec(), "original".to_string(), &label, None)
+            .unwrap();
+        let mut info = client.get_info(content.digest.clone()).unwrap();
+        info.labels
+            .insert(LAST_USED_LABEL.to_string(), "1".to_string());
+        client.update_info(info).unwrap();
+
+        // a cache hit updates a stale label
+        assert!(client.touch_precompiled(&content.digest).unwrap());
+        let info = client.get_info(content.digest.clone()).unwrap();
+        assert!(last_used(&info) > 1);
+
+        // but not one that was updated recently
+        assert!(!client.touch_precompiled(&content.digest).unwrap());
+
+        client.delete_content(content.digest.clone()).unwrap();
+    }
+
+    // an engine that runs the wasm layers as they are, without precompiling them
+    #[derive(Clone)]
+    struct JitEngine;
+
+    impl Engine for JitEngine {
+        fn name() -> &'static str {
+            "jit"
+        }
+        fn run_wasi(&self, _ctx: &impl RuntimeContext, _stdio: Stdio) -> anyhow::Result<i32> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_load_modules_cached() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        // each content needs its own label, as saving takes a lease on the label
+        let save = |name: &str, data: Vec<u8>| {
+            let label = precompile_label("test", &format!("load-modules-cached-{name}"));
+            client
+                .save_content(data, "original".to_string(), &label, None)
+                .unwrap()
+        };
+        let descriptor = |media_type: MediaType, content: &WriteContent, size: usize| {
+            oci_spec::image::Descriptor::new(media_type, size as i64, content.digest.clone())
+        };
+
+        let config = br#"{"architecture":"wasm","os":"wasip1","author":"load-modules-cached"}"#;
+        // a module with a single empty custom section
+        let layer = b"\0asm\x01\0\0\0\0\x05\x04test";
+        let (config_size, layer_size) = (config.len(), layer.len());
+        let config = save("config", config.to_vec());
+        let layer = save("layer", layer.to_vec());
+        let manifest = oci_spec::image::ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(descriptor(MediaType::ImageConfig, &config, config_size))
+            .layers(vec![descriptor(
+                MediaType::Other(oci::WASM_MODULE_LAYER_MEDIA_TYPE.to_string()),
+                &layer,
+                layer_size,
+            )])
+            .build()
+            .unwrap();
+        let manifest = save("manifest", serde_json::to_vec(&manifest).unwrap());
+
+        let image_name = "localhost/test-load-modules-cached:latest";
+        let container_id = "test-load-modules-cached";
+        client.create_image(image_name, &manifest.digest, HashMap::new());
+        client.create_container(container_id, image_name);
+
+        let (layers, _) = client.load_modules(container_id, &JitEngine).unwrap();
+        assert_eq!(layers.len(), 1);
+
+        // the image isn't read again, so loading works without its manifest
+        let manifest_digest = manifest.digest.clone();
+        drop(manifest);
+        client.delete_content(manifest_digest).unwrap();
+        let (cached, _) = client.load_modules(container_id, &JitEngine).unwrap();
+        assert_eq!(cached[0].layer, layers[0].layer);
+
+        // the cached image is still checked, e.g., by the verifier
+        let verified = Client::connect(path, "test-ns")
+            .unwrap()
+            .with_verifier(|_| anyhow::bail!("rejected"));
+        let err = verified
+            .load_modules(container_id, &JitEngine)
+            .expect_err("the verifier rejects every image");
+        assert!(matches!(err, ShimError::VerificationFailed(_)), "{err}");
+
+        // but it is once the container is deleted
+        forget_modules(JitEngine::name(), container_id);
+        client
+            .load_modules(container_id, &JitEngine)
+            .expect_err("the manifest was deleted");
+
+        client.delete_container(container_id);
+        client.delete_image(image_name);
+        for content in [config, layer] {
+            let digest = content.digest.clone();
+            drop(content);
+            client.delete_content(digest).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_precompile_gc_refs() {
+        let client = AsyncClient::connect("/run/containerd/containerd.sock", "test-ns")
+            .await
+            .unwrap();
+        let data = format!("image content {}", unix_now()).into_bytes();
+        let content = client
+            .save_image_content(data, None, "original".to_string(), "runwasi.io/test", None)
+            .await
+            .unwrap();
+
+        // the precompiles of distinct engines ref their content from the image content at the same time
+        let labels: Vec<_> = (0..8)
+            .map(|i| precompile_label("test", &format!("concurrent-{i}")))
+            .collect();
+        let updates = labels.iter().enumerate().map(|(i, label)| {
+            client.update_info_labels(
+                &content.digest,
+                HashMap::from([(precompile_gc_ref(label), format!("sha256:{i}"))]),
+            )
+        });
+        for updated in futures::future::join_all(updates).await {
+            updated.unwrap();
+        }
+
+        let info = client.get_info(content.digest.clone()).await.unwrap();
+        for (i, label) in labels.iter().enumerate() {
+            assert_eq!(
+                info.labels.get(&precompile_gc_ref(label)),
+                Some(&format!("sha256:{i}")),
+                "{label}"
+            );
+        }
+
+        // a ref is removed without touching the others
+        client
+            .update_info_labels(
+                &content.digest,
+                HashMap::from([(precompile_gc_ref(&labels[0]), String::new())]),
+            )
+            .await
+            .unwrap();
+        let info = client.get_info(content.digest.clone()).await.unwrap();
+        assert!(!info.labels.contains_key(&precompile_gc_ref(&labels[0])));
+        assert!(info.labels.contains_key(&precompile_gc_ref(&labels[1])));
+
+        let digest = content.digest.clone();
+        drop(content);
+        client.delete_content(digest).await.unwrap();
+    }
+
+    #[test]
+    fn test_precompile_gc_ref() {
+        assert_eq!(
+            precompile_gc_ref("runwasi.io/precompiled/wasmtime/17.0.0"),
+            "containerd.io/gc.ref.content.precompile.wasmtime/17.0.0"
+        );
+        assert_eq!(
+            precompile_gc_ref(&precompile_label("wasmtime", "17.0.0/abc")),
+            "containerd.io/gc.ref.content.precompile.wasmtime/17.0.0/abc"
+        );
+    }
+
+    #[test]
+    fn test_modules_cache() {
+        let key = ("test", "test-modules-cache".to_string());
+        let modules = |layer: &[u8]| {
+            let config = oci_spec::image::Descriptor::new(MediaType::ImageConfig, 0, "");
+            let layer = WasmLayer {
+                config,
+                layer: layer.to_vec(),
+                binary_type: None,
+                wasi_version: None,
+            };
+            (Arc::new(vec![layer]), Platform::default())
+        };
+        let manifest = oci_spec::image::ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(oci_spec::image::Descriptor::new(
+                MediaType::ImageConfig,
+                0,
+                "",
+            ))
+            .layers(vec![])
+            .build()
+            .unwrap();
+        let cache = |digest: &str, wasm_features: Vec<WasmFeature>, layer: &[u8]| {
+            let digest = digest.to_string();
+            cache_modules(
+                key.clone(),
+                digest,
+                wasm_features,
+                manifest.clone(),
+                modules(layer),
+            )
+        };
+
+        assert!(cached_modules(&key, "sha256:1", &[]).is_none());
+
+        cache("sha256:1", vec![], b"one");
+        let (_, (layers, _)) = cached_modules(&key, "sha256:1", &[]).unwrap();
+        assert_eq!(layers[0].layer, b"one");
+
+        // the cache is invalidated when the image digest changes
+        assert!(cached_modules(&key, "sha256:2", &[]).is_none());
+        cache("sha256:2", vec![], b"two");
+        assert!(cached_modules(&key, "sha256:1", &[]).is_none());
+        let (_, (layers, _)) = cached_modules(&key, "sha256:2", &[]).unwrap();
+        assert_eq!(layers[0].layer, b"two");
+
+        // or when the wasm features of the container do
+        assert!(cached_modules(&key, "sha256:2", &[WasmFeature::Gc]).is_none());
+    }
+
+    #[derive(Clone)]
+    struct EmptyPrecompileEngine;
+
+    impl Engine for EmptyPrecompileEngine {
+        fn name() -> &'static str {
+            "empty-precompile"
+        }
+        fn run_wasi(&self, _ctx: &impl RuntimeContext, _stdio: Stdio) -> anyhow::Result<i32> {
+            Ok(0)
+        }
+        fn can_precompile(&self) -> Option<String> {
+            Some("v1".to_string())
+        }
+        fn precompile(&self, _layers: &[Vec<u8>]) -> anyhow::Result<Vec<u8>> {
+            Ok(vec![])
+        }
+        fn validate_precompiled(&self, precompiled: &[u8]) -> anyhow::Result<()> {
+            anyhow::ensure!(precompiled.starts_with(b"precompiled"), "not precompiled");
+            Ok(())
+        }
+    }
+
+    // an engine that would run out of memory if it precompiled
+    #[derive(Clone)]
+    struct OutOfMemoryEngine;
+
+    impl Engine for OutOfMemoryEngine {
+        fn name() -> &'static str {
+            "out-of-memory"
+        }
+        fn run_wasi(&self, _ctx: &impl RuntimeContext, _stdio: Stdio) -> anyhow::Result<i32> {
+            Ok(0)
+        }
+        fn can_precompile(&self) -> Option<String> {
+            Some("v1".to_string())
+        }
+        fn precompile(&self, _layers: &[Vec<u8>]) -> anyhow::Result<Vec<u8>> {
+            anyhow::bail!("out of memory")
+        }
+    }
+
+    #[test]
+    fn test_precompile_low_memory() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let mut client = Client::connect(path, "test-ns")
+            .unwrap()
+            .with_min_precompile_memory(1024 * 1024);
+
+        // each content needs its own label, as saving takes a lease on the label
+        let save = |name: &str, data: Vec<u8>| {
+            let label = precompile_label("test", &format!("low-memory-{name}"));
+            client
+                .save_content(data, "original".to_string(), &label, None)
+                .unwrap()
+        };
+        let descriptor = |media_type: MediaType, content: &WriteContent, size: usize| {
+            oci_spec::image::Descriptor::new(media_type, size as i64, content.digest.clone())
+        };
+
+        let config = br#"{"architecture":"wasm","os":"wasip1"}"#.to_vec();
+        // a custom section after the header, as a module with nothing after it is rejected
+        let layer = b"\0asm\x01\0\0\0\0\x02\x01m".to_vec();
+        let (config_size, layer_size) = (config.len(), layer.len());
+        let config = save("config", config);
+        let layer = save("layer", layer);
+        let manifest = oci_spec::image::ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(descriptor(MediaType::ImageConfig, &config, config_size))
+            .layers(vec![descriptor(
+                MediaType::Other(oci::WASM_MODULE_LAYER_MEDIA_TYPE.to_string()),
+                &layer,
+                layer_size,
+            )])
+            .build()
+            .unwrap();
+        let manifest = save("manifest", serde_json::to_vec(&manifest).unwrap());
+
+        let image = Image {
+            name: "localhost/test-low-memory:latest".to_string(),
+            ..Default::default()
+        };
+
+        // below the threshold, the layers are used as they are, without precompiling
+        client.inner.available_memory = || Ok(1024);
+        let (layers, _) = client
+            .load_image_modules(image.clone(), manifest.digest.clone(), &OutOfMemoryEngine)
+            .unwrap();
+        assert_eq!(layers[0].layer, b"\0asm\x01\0\0\0\0\x02\x01m");
+
+        // above it, the image is precompiled
+        client.inner.available_memory = || Ok(u64::MAX);
+        let err = client
+            .load_image_modules(image, manifest.digest.clone(), &OutOfMemoryEngine)
+            .unwrap_err();
+        assert!(err.to_string().contains("out of memory"), "{err}");
+
+        for content in [config, layer, manifest] {
+            let digest = content.digest.clone();
+            drop(content);
+            client.delete_content(digest).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_validate_precompiled() {
+        let engine = EmptyPrecompileEngine;
+
+        let precompiled = engine.precompile(&[b"module".to_vec()]).unwrap();
+        validate_precompiled(&engine, &precompiled).expect_err("empty output should be rejected");
+
+        validate_precompiled(&engine, b"garbage")
+            .expect_err("output the engine can't load should be rejected");
+        validate_precompiled(&engine, b"precompiled module").unwrap();
+    }
+
+    #[test]
+    fn test_load_unsupported_layers() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        // each content needs its own label, as saving takes a lease on the label
+        let save = |name: &str, data: Vec<u8>| {
+            let label = precompile_label("test", &format!("unsupported-layers-{name}"));
+            client
+                .save_content(data, "original".to_string(), &label, None)
+                .unwrap()
+        };
+        let descriptor = |media_type: MediaType, content: &WriteContent, size: usize| {
+            oci_spec::image::Descriptor::new(media_type, size as i64, content.digest.clone())
+        };
+
+        let config = br#"{"architecture":"wasm","os":"wasip1"}"#.to_vec();
+        let layer = b"not wasm".to_vec();
+        let (config_size, layer_size) = (config.len(), layer.len());
+        let config = save("config", config);
+        let layer = save("layer", layer);
+        let manifest = oci_spec::image::ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(descriptor(MediaType::ImageConfig, &config, config_size))
+            .layers(vec![descriptor(
+                MediaType::Other("application/vnd.example.unsupported.v1".to_string()),
+                &layer,
+                layer_size,
+            )])
+            .build()
+            .unwrap();
+        let manifest = save("manifest", serde_json::to_vec(&manifest).unwrap());
+
+        let image = Image {
+            name: "localhost/test-unsupported-layers:latest".to_string(),
+            ..Default::default()
+        };
+        let err = client
+            .load_image_modules(image, manifest.digest.clone(), &EmptyPrecompileEngine)
+            .unwrap_err();
+        assert!(matches!(err, ShimError::NoRunnableContent(_)), "{err}");
+
+        for content in [config, layer, manifest] {
+            let digest = content.digest.clone();
+            drop(content);
+            client.delete_content(digest).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_precompile_insufficient_space() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let mut client = Client::connect(path, "test-ns").unwrap();
+
+        // each content needs its own label, as saving takes a lease on the label
+        let save = |name: &str, data: Vec<u8>| {
+            let label = precompile_label("test", &format!("insufficient-space-{name}"));
+            client
+                .save_content(data, "original".to_string(), &label, None)
+                .unwrap()
+        };
+        let descriptor = |media_type: MediaType, content: &WriteContent, size: usize| {
+            oci_spec::image::Descriptor::new(media_type, size as i64, content.digest.clone())
+        };
+
+        let config = br#"{"architecture":"wasm","os":"wasip1"}"#.to_vec();
+        // a custom section after the header, as a module with nothing after it is rejected
+        let layer = b"\0asm\x01\0\0\0\0\x02\x01a".to_vec();
+        let (config_size, layer_size) = (config.len(), layer.len());
+        let config = save("config", config);
+        let layer = save("layer", layer);
+        let manifest = oci_spec::image::ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(descriptor(MediaType::ImageConfig, &config, config_size))
+            .layers(vec![descriptor(
+                MediaType::Other(oci::WASM_MODULE_LAYER_MEDIA_TYPE.to_string()),
+                &layer,
+                layer_size,
+            )])
+            .build()
+            .unwrap();
+        let manifest = save("manifest", serde_json::to_vec(&manifest).unwrap());
+
+        let image = Image {
+            name: "localhost/test-insufficient-space:latest".to_string(),
+            ..Default::default()
+        };
+
+        // pretend the disk of the content store is full
+        client.inner.available_space = |_| Ok(0);
+        let err = client
+            .load_image_modules(
+                image.clone(),
+                manifest.digest.clone(),
+                &EmptyPrecompileEngine,
+            )
+            .unwrap_err();
+        assert!(matches!(err, ShimError::InsufficientSpace(_)), "{err}");
+
+        // the engine's precompiled output is invalid, so the layers are used as they are
+        client.inner.available_space = |_| Ok(u64::MAX);
+        let (layers, _) = client
+            .load_image_modules(image, manifest.digest.clone(), &EmptyPrecompileEngine)
+            .unwrap();
+        assert_eq!(layers[0].layer, b"\0asm\x01\0\0\0\0\x02\x01a");
+
+        for content in [config, layer, manifest] {
+            let digest = content.digest.clone();
+            drop(content);
+            client.delete_content(digest).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_content_store_root() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let mut client = Client::connect(path, "test-ns").unwrap();
+        // only the content store at the configured root is full
+        client.inner.available_space = |root| {
+            if root == Path::new("/data/containerd/content") {
+                Ok(0)
+            } else {
+                Ok(u64::MAX)
+            }
+        };
+
+        client.inner.check_free_space(1024).unwrap();
+        let client = client.with_content_store_root("/data/containerd/content");
+        let err = client.inner.check_free_space(1024).unwrap_err();
+        assert!(matches!(err, ShimError::InsufficientSpace(_)), "{err}");
+
+        // the free space of a root that can't be found isn't checked
+        let mut client = client.with_content_store_root("/does/not/exist");
+        client.inner.available_space = available_space;
+        client.inner.check_free_space(u64::MAX / 4).unwrap();
+    }
+
+    #[test]
+    fn test_empty_module() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        // each content needs its own label, as saving takes a lease on the label
+        let save = |name: &str, data: Vec<u8>| {
+            let label = precompile_label("test", &format!("empty-module-{name}"));
+            client
+                .save_content(data, "original".to_string(), &label, None)
+                .unwrap()
+        };
+        let descriptor = |media_type: MediaType, content: &WriteContent, size: usize| {
+            oci_spec::image::Descriptor::new(media_type, size as i64, content.digest.clone())
+        };
+
+        let config = br#"{"architecture":"wasm","os":"wasip1","variant":"empty"}"#.to_vec();
+        let config_size = config.len();
+        let config = save("config", config);
+        let layer = save("layer", vec![]);
+        let manifest = oci_spec::image::ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(descriptor(MediaType::ImageConfig, &config, config_size))
+            .layers(vec![descriptor(
+                MediaType::Other(oci::WASM_MODULE_LAYER_MEDIA_TYPE.to_string()),
+                &layer,
+                0,
+            )])
+            .build()
+            .unwrap();
+        let manifest = save("manifest", serde_json::to_vec(&manifest).unwrap());
+
+        let image = Image {
+            name: "localhost/test-empty-module:latest".to_string(),
+            ..Default::default()
+        };
+        let err = client
+            .load_image_modules(image, manifest.digest.clone(), &CountingEngine)
+            .unwrap_err();
+        assert!(
+            matches!(&err, ShimError::InvalidModule { digest, reason } if *digest == layer.digest && reason == "empty module"),
+            "{err}"
+        );
+
+        for content in [config, layer, manifest] {
+            let digest = content.digest.clone();
+            drop(content);
+            client.delete_content(digest).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_module_too_large() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns")
+            .unwrap()
+            .with_max_module_bytes(1024);
+
+        let save = |name: &str, data: Vec<u8>| {
+            let label = precompile_label("test", &format!("module-too-large-{name}"));
+            client
+                .save_content(data, "original".to_string(), &label, None)
+                .unwrap()
+        };
+
+        let config = br#"{"architecture":"wasm","os":"wasip1","variant":"too-large"}"#.to_vec();
+        let config_size = config.len() as i64;
+        let config = save("config", config);
+        let config_descriptor = oci_spec::image::Descriptor::new(
+            MediaType::ImageConfig,
+            config_size,
+            config.digest.clone(),
+        );
+        // the layer isn't in the content store, so reading it would fail with another error
+        let layer = oci_spec::image::Descriptor::new(
+            MediaType::Other(oci::WASM_MODULE_LAYER_MEDIA_TYPE.to_string()),
+            1 << 30,
+            format!("sha256:{}", "0".repeat(64)),
+        );
+        let manifest = oci_spec::image::ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(config_descriptor)
+            .layers(vec![layer])
+            .build()
+            .unwrap();
+        let manifest = save("manifest", serde_json::to_vec(&manifest).unwrap());
+
+        let image = Image {
+            name: "localhost/test-module-too-large:latest".to_string(),
+            ..Default::default()
+        };
+        let err = client
+            .load_image_modules(image, manifest.digest.clone(), &CountingEngine)
+            .unwrap_err();
+        assert!(
+            matches!(err, ShimError::ModuleTooLarge { size, limit } if size == 1 << 30 && limit == 1024),
+            "{err}"
+        );
+
+        for content in [config, manifest] {
+            let digest = content.digest.clone();
+            drop(content);
+            client.delete_content(digest).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_verifier_rejects_image() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns")
+            .unwrap()
+            .with_verifier(|manifest| match manifest.annotations() {
+                Some(annotations) if annotations.contains_key("org.example.signed-by") => Ok(()),
+                _ => anyhow::bail!("missing annotation org.example.signed-by"),
+            });
+
+        // each content needs its own label, as saving takes a lease on the label
+        let save = |name: &str, data: Vec<u8>| {
+            let label = precompile_label("test", &format!("verifier-{name}"));
+            client
+                .save_content(data, "original".to_string(), &label, None)
+                .unwrap()
+        };
+        let descriptor = |media_type: MediaType, content: &WriteContent, size: usize| {
+            oci_spec::image::Descriptor::new(media_type, size as i64, content.digest.clone())
+        };
+
+        let config = br#"{"architecture":"wasm","os":"wasip1","author":"verifier"}"#.to_vec();
+        let layer = b"\0asm\x01\0\0\0verifier".to_vec();
+        let (config_size, layer_size) = (config.len(), layer.len());
+        let config = save("config", config);
+        let layer = save("layer", layer);
+        let manifest = |annotations: Option<HashMap<String, String>>| {
+            let mut manifest = oci_spec::image::ImageManifestBuilder::default()
+                .schema_version(2u32)
+                .config(descriptor(MediaType::ImageConfig, &config, config_size))
+                .layers(vec![descriptor(
+                    MediaType::Other(oci::WASM_MODULE_LAYER_MEDIA_TYPE.to_string()),
+                    &layer,
+                    layer_size,
+                )])
+                .build()
+                .unwrap();
+            manifest.set_annotations(annotations);
+            serde_json::to_vec(&manifest).unwrap()
+        };
+        let unsigned = save("unsigned", manifest(None));
+        let signed = save(
+            "signed",
+            manifest(Some(HashMap::from([(
+                "org.example.signed-by".to_string(),
+                "ops".to_string(),
+            )]))),
+        );
+
+        let image = Image {
+            name: "localhost/test-verifier:latest".to_string(),
+            ..Default::default()
+        };
+
+        let err = client
+            .load_image_modules(
+                image.clone(),
+                unsigned.digest.clone(),
+                &EmptyPrecompileEngine,
+            )
+            .unwrap_err();
+        assert!(matches!(err, ShimError::VerificationFailed(_)), "{err}");
+
+        let (layers, _) = client
+            .load_image_modules(image, signed.digest.clone(), &EmptyPrecompileEngine)
+            .unwrap();
+        assert_eq!(layers[0].layer, b"\0asm\x01\0\0\0verifier");
+
+        for content in [config, layer, unsigned, signed] {
+            let digest = content.digest.clone();
+            drop(content);
+            client.delete_content(digest).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_require_precompile() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        // each content needs its own label, as saving takes a lease on the label
+        let save = |name: &str, data: Vec<u8>| {
+            let label = precompile_label("test", &format!("require-precompile-{name}"));
+            client
+                .save_content(data, "original".to_string(), &label, None)
+                .unwrap()
+        };
+        let descriptor = |media_type: MediaType, content: &WriteContent, size: usize| {
+            oci_spec::image::Descriptor::new(media_type, size as i64, content.digest.clone())
+        };
+
+        let config =
+            br#"{"architecture":"wasm","os":"wasip1","author":"require-precompile"}"#.to_vec();
+        let layer = b"\0asm\x01\0\0\0require-precompile".to_vec();
+        let (config_size, layer_size) = (config.len(), layer.len());
+        let config = save("config", config);
+        let layer = save("layer", layer);
+        let mut manifest = oci_spec::image::ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(descriptor(MediaType::ImageConfig, &config, config_size))
+            .layers(vec![descriptor(
+                MediaType::Other(oci::WASM_MODULE_LAYER_MEDIA_TYPE.to_string()),
+                &layer,
+                layer_size,
+            )])
+            .build()
+            .unwrap();
+        manifest.set_annotations(Some(HashMap::from([(
+            oci::REQUIRE_PRECOMPILE_ANNOTATION.to_string(),
+            "true".to_string(),
+        )])));
+        let manifest = save("manifest", serde_json::to_vec(&manifest).unwrap());
+
+        let image = Image {
+            name: "localhost/test-require-precompile:latest".to_string(),
+            ..Default::default()
+        };
+
+        let err = client
+            .load_image_modules(image.clone(), manifest.digest.clone(), &JitEngine)
+            .unwrap_err();
+        assert!(
+            matches!(&err, ShimError::PrecompileRequired(msg) if msg.contains("jit engine can't precompile")),
+            "{err}"
+        );
+
+        // nor does an engine that can precompile, but not with the wasm features of the container
+        let with_features = Client::connect(path, "test-ns")
+            .unwrap()
+            .with_wasm_features(vec![WasmFeature::Gc]);
+        let err = with_features
+            .load_image_modules(image, manifest.digest.clone(), &EmptyPrecompileEngine)
+            .unwrap_err();
+        assert!(matches!(err, ShimError::PrecompileRequired(_)), "{err}");
+
+        for content in [config, layer, manifest] {
+            let digest = content.digest.clone();
+            drop(content);
+            client.delete_content(digest).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_nondeterministic_compile() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        let manifest = client
+            .save_content(
+                b"nondeterministic-manifest".to_vec(),
+                "original".to_string(),
+                &precompile_label("test", "nondeterministic-manifest"),
+                None,
+            )
+            .unwrap();
+        let image_name = "localhost/test-nondeterministic:latest";
+        client.create_image(image_name, &manifest.digest, HashMap::new());
+
+        let engine = CountingEngine;
+        let label = engine_precompile_label(&engine, "nondeterministic");
+        let store = |precompiled: &[u8], keep_existing: bool| {
+            let image = client.get_image(image_name).unwrap();
+            client
+                .store_precompiled(
+                    image,
+                    &manifest.digest,
+                    label.clone(),
+                    precompiled,
+                    &engine,
+                    keep_existing,
+                )
+                .unwrap()
+        };
+        let label_digest = || {
+            let image = client.get_image(image_name).unwrap();
+            image.labels.get(&label).cloned()
+        };
+
+        let first = store(b"first compile", true);
+        assert_eq!(label_digest(), Some(first.clone()));
+
+        // the same image compiles to different output, and the existing content is kept
+        assert_eq!(store(b"second compile", true), first);
+        assert_eq!(label_digest(), Some(first.clone()));
+
+        // unless the new output should replace it
+        let second = store(b"second compile", false);
+        assert_ne!(second, first);
+        assert_eq!(label_digest(), Some(second.clone()));
+
+        client.delete_image(image_name);
+        client.delete_precompiled_blob(&first).unwrap();
+        client.delete_precompiled_blob(&second).unwrap();
+        let digest = manifest.digest.clone();
+        drop(manifest);
+        client.delete_content(digest).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_precompiled() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns")
+            .unwrap()
+            .with_compressed_precompiled(true);
+
+        let manifest = client
+            .save_content(
+                b"compressed-manifest".to_vec(),
+                "original".to_string(),
+                &precompile_label("test", "compressed-manifest"),
+                None,
+            )
+            .unwrap();
+        let image_name = "localhost/test-compressed:latest";
+        client.create_image(image_name, &manifest.digest, HashMap::new());
+
+        let engine = CountingEngine;
+        let label = engine_precompile_label(&engine, "compressed");
+        let precompiled = b"precompiled ".repeat(1000);
+        let image = client.get_image(image_name).unwrap();
+        let stored_digest = client
+            .store_precompiled(image, &manifest.digest, label, &precompiled, &engine, false)
+            .unwrap();
+
+        // the content is stored compressed, under the digest of the compressed bytes
+        let stored = client.read_content(&stored_digest).unwrap();
+        assert!(stored.starts_with(&ZSTD_MAGIC));
+        assert!(stored.len() < precompiled.len());
+        assert_eq!(
+            stored_digest,
+            format!("sha256:{}", digest(stored.as_slice()))
+        );
+        let info = client.get_info(stored_digest.clone()).unwrap();
+        assert_eq!(
+            info.labels.get(MEDIA_TYPE_LABEL).map(String::as_str),
+            Some("application/vnd.wasm.precompiled.counting+zstd")
+        );
+
+        // and decompressed when it is loaded
+        let loaded = client.read_precompiled(&stored_digest).unwrap();
+        assert_eq!(loaded.as_deref(), Some(precompiled.as_slice()));
+        // content that was stored uncompressed is loaded as is
+        assert_eq!(
+            decompress_precompiled(b"precompiled".to_vec()).unwrap(),
+            b"precompiled"
+        );
+
+        client.delete_image(image_name);
+        client.delete_precompiled_blob(&stored_digest).unwrap();
+        let digest = manifest.digest.clone();
+        drop(manifest);
+        client.delete_content(digest).unwrap();
+    }
+
+    #[test]
+    fn test_precompile_status() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        let manifest = client
+            .save_content(
+                b"precompile-status-manifest".to_vec(),
+                "original".to_string(),
+                &precompile_label("test", "precompile-status-manifest"),
+                None,
+            )
+            .unwrap();
+
+        let engine = CountingEngine;
+        let label = engine_precompile_label(&engine, "v1");
+        let missing = format!("sha256:{}", "0".repeat(64));
+        let images = [
+            // the manifest stands in for the precompiled content, as it is in the content store
+            ("present", Some((label.clone(), manifest.digest.clone()))),
+            ("missing-content", Some((label.clone(), missing))),
+            (
+                "other-version",
+                Some((
+                    engine_precompile_label(&engine, "v0"),
+                    manifest.digest.clone(),
+                )),
+            ),
+            ("not-precompiled", None),
+        ];
+        for (name, label) in &images {
+            let name = format!("localhost/test-precompile-status-{name}:latest");
+            client.create_image(&name, &manifest.digest, label.clone().into_iter().collect());
+        }
+
+        let mut references: Vec<_> = images
+            .iter()
+            .map(|(name, _)| format!("localhost/test-precompile-status-{name}:latest"))
+            .collect();
+        references.push("localhost/test-precompile-status-unknown:latest".to_string());
+        let status = client.precompile_status(&references, &engine).unwrap();
+        let states: Vec<_> = status.into_iter().map(|(_, state)| state).collect();
+        assert_eq!(
+            states,
+            vec![
+                PrecompileState::Present {
+                    digest: manifest.digest.clone()
+                },
+                PrecompileState::Stale,
+                PrecompileState::Stale,
+                PrecompileState::Absent,
+                PrecompileState::Absent,
+            ]
+        );
+
+        for reference in &references[..images.len()] {
+            client.delete_image(reference);
+        }
+        let digest = manifest.digest.clone();
+        drop(manifest);
+        client.delete_content(digest).unwrap();
+    }
+
+    #[test]
+    fn test_export_logs() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        let logs = client
+            .export_logs(
+                "test-export-logs",
+                b"test-export-logs stdout".to_vec(),
+                b"test-export-logs stderr".to_vec(),
+            )
+            .unwrap();
+
+        for (digest, stream, expected) in [
+            (&logs.stdout, "stdout", "test-export-logs stdout"),
+            (&logs.stderr, "stderr", "test-export-logs stderr"),
+        ] {
+            let mut content = vec![];
+            client.copy_content(digest, &mut content).unwrap();
+            assert_eq!(content, expected.as_bytes());
+
+            let info = client.get_info(digest.clone()).unwrap();
+            assert_eq!(
+                info.labels.get(&format!("runwasi.io/logs.{stream}")),
+                Some(&"test-export-logs".to_string())
+            );
+            assert!(info.labels.contains_key("containerd.io/gc.root"));
+        }
+
+        // the same output of another container is labeled with the id of that container
+        let other = client
+            .export_logs(
+                "test-export-logs-other",
+                b"test-export-logs stdout".to_vec(),
+                vec![],
+            )
+            .unwrap();
+        assert_eq!(other.stdout, logs.stdout);
+        let info = client.get_info(other.stdout.clone()).unwrap();
+        assert_eq!(
+            info.labels.get("runwasi.io/logs.stdout"),
+            Some(&"test-export-logs-other".to_string())
+        );
+
+        for digest in [logs.stdout, logs.stderr, other.stderr] {
+            client.delete_content(digest).unwrap();
+        }
+    }
+
+    // an engine whose precompiled output is different every time
+    #[derive(Clone)]
+    struct CountingEngine;
+
+    static PRECOMPILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    impl Engine for CountingEngine {
+        fn name() -> &'static str {
+            "counting"
+        }
+        fn run_wasi(&self, _ctx: &impl RuntimeContext, _stdio: Stdio) -> anyhow::Result<i32> {
+            Ok(0)
+        }
+        fn can_precompile(&self) -> Option<String> {
+            Some("v1".to_string())
+        }
+        fn precompile(&self, _layers: &[Vec<u8>]) -> anyhow::Result<Vec<u8>> {
+            let count = PRECOMPILE_COUNT.fetch_add(1, Ordering::SeqCst);
+            Ok(format!("precompiled {count}").into_bytes())
+        }
+    }
+
+    #[test]
+    fn test_force_precompile() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        // each content needs its own label, as saving takes a lease on the label
+        let save = |name: &str, data: Vec<u8>| {
+            let label = precompile_label("test", &format!("force-precompile-{name}"));
+            client
+                .save_content(data, "original".to_string(), &label, None)
+                .unwrap()
+        };
+        let descriptor = |media_type: MediaType, content: &WriteContent, size: usize| {
+            oci_spec::image::Descriptor::new(media_type, size as i64, content.digest.clone())
+        };
+
+        let config = br#"{"architecture":"wasm","os":"wasip1"}"#.to_vec();
+        // a custom section after the header, as a module with nothing after it is rejected
+        let layer = b"\0asm\x01\0\0\0\0\x02\x01b".to_vec();
+        let (config_size, layer_size) = (config.len(), layer.len());
+        let config = save("config", config);
+        let layer = save("layer", layer);
+        let manifest = oci_spec::image::ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(descriptor(MediaType::ImageConfig, &config, config_size))
+            .layers(vec![descriptor(
+                MediaType::Other(oci::WASM_MODULE_LAYER_MEDIA_TYPE.to_string()),
+                &layer,
+                layer_size,
+            )])
+            .build()
+            .unwrap();
+        let manifest = save("manifest", serde_json::to_vec(&manifest).unwrap());
+
+        let image_name = "localhost/test-force-precompile:latest";
+        client.create_image(image_name, &manifest.digest, HashMap::new());
+
+        // the image still has the gc ref that all the engines shared before
+        let mut info = client.get_info(manifest.digest.clone()).unwrap();
+        info.labels
+            .insert(PRECOMPILE_GC_REF.to_string(), config.digest.clone());
+        client.update_info(info).unwrap();
+
+        let engine = CountingEngine;
+        let label = engine_precompile_label(&engine, "v1");
+        let old_digest = client.force_precompile(image_name, &engine).unwrap();
+        let new_digest = client.force_precompile(image_name, &engine).unwrap();
+        assert_ne!(old_digest, new_digest);
+
+        let image = client.get_image(image_name).unwrap();
+        assert_eq!(image.labels.get(&label), Some(&new_digest));
+        // the precompiled content can be mapped back to the image by digest and by name
+        let precompiled_content = client.get_info(new_digest.clone()).unwrap();
+        assert_eq!(
+            precompiled_content.labels.get(&label),
+            Some(&manifest.digest)
+        );
+        assert_eq!(
+            precompiled_content
+                .labels
+                .get(IMAGE_REFERENCE_LABEL)
+                .map(String::as_str),
+            Some(image_name)
+        );
+        let image_content = client.get_info(manifest.digest.clone()).unwrap();
+        assert_eq!(
+            image_content.labels.get(&precompile_gc_ref(&label)),
+            Some(&new_digest)
+        );
+        assert!(!image_content.labels.contains_key(PRECOMPILE_GC_REF));
+
+        // nothing refers to the old content anymore, so it can be removed
+        let gc_ref = precompile_gc_ref(&label);
+        let filter = format!("labels.\"{gc_ref}\"==\"{old_digest}\"");
+        assert!(client.list_content(vec![filter]).unwrap().is_empty());
+        assert!(client.delete_precompiled_blob(&old_digest).unwrap());
+
+        client.delete_image(image_name);
+        client.delete_precompiled_blob(&new_digest).unwrap();
+        for content in [config, layer, manifest] {
+            let digest = content.digest.clone();
+            drop(content);
+            client.delete_content(digest).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_precompile_cache_dir() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let client = Client::connect(path, "test-ns")
+            .unwrap()
+            .with_precompile_cache_dir(dir.path().join("precompiled"))
+            .unwrap();
+
+        // each content needs its own label, as saving takes a lease on the label
+        let save = |name: &str, data: Vec<u8>| {
+            let label = precompile_label("test", &format!("cache-dir-{name}"));
+            client
+                .save_content(data, "original".to_string(), &label, None)
+                .unwrap()
+        };
+        let descriptor = |media_type: MediaType, content: &WriteContent, size: usize| {
+            oci_spec::image::Descriptor::new(media_type, size as i64, content.digest.clone())
+        };
+
+        let config = br#"{"architecture":"wasm","os":"wasip1"}"#.to_vec();
+        // a custom section after the header, as a module with nothing after it is rejected
+        let layer = b"\0asm\x01\0\0\0\0\x02\x01d".to_vec();
+        let (config_size, layer_size) = (config.len(), layer.len());
+        let config = save("config", config);
+        let layer = save("layer", layer);
+        let manifest = oci_spec::image::ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(descriptor(MediaType::ImageConfig, &config, config_size))
+            .layers(vec![descriptor(
+                MediaType::Other(oci::WASM_MODULE_LAYER_MEDIA_TYPE.to_string()),
+                &layer,
+                layer_size,
+            )])
+            .build()
+            .unwrap();
+        let manifest = save("manifest", serde_json::to_vec(&manifest).unwrap());
+
+        let image = Image {
+            name: "localhost/test-cache-dir:latest".to_string(),
+            ..Default::default()
+        };
+        let load = || {
+            let (layers, _) = client
+                .load_image_modules(image.clone(), manifest.digest.clone(), &CountingEngine)
+                .unwrap();
+            layers[0].layer.clone()
+        };
+
+        // the engine precompiles a different module every time,
+        // so the second load can only return the first module by reading it from the directory
+        let precompiled = load();
+        assert!(precompiled.starts_with(b"precompiled "));
+        assert_eq!(load(), precompiled);
+        let files = std::fs::read_dir(dir.path().join("precompiled"))
+            .unwrap()
+            .count();
+        assert_eq!(files, 1);
+
+        for content in [config, layer, manifest] {
+            let digest = content.digest.clone();
+            drop(content);
+            client.delete_content(digest).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_image_labels() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        let image_name = "localhost/test-image-labels:latest";
+        let target = format!("sha256:{}", sha256::digest(image_name));
+        client.create_image(
+            image_name,
+            &target,
+            HashMap::from([
+                ("org.example/build".to_string(), "42".to_string()),
+                ("org.example/branch".to_string(), "main".to_string()),
+            ]),
+        );
+
+        let labels = client.image_labels(image_name).unwrap();
+        assert_eq!(labels.get("org.example/build").unwrap(), "42");
+
+        // the other labels are kept, and an empty value removes a label
+        let labels = client
+            .set_image_labels(
+                image_name,
+                HashMap::from([
+                    ("org.example/commit".to_string(), "abc123".to_string()),
+                    ("org.example/build".to_string(), "".to_string()),
+                ]),
+            )
+            .unwrap();
+        assert_eq!(
+            labels,
+            HashMap::from([
+                ("org.example/commit".to_string(), "abc123".to_string()),
+                ("org.example/branch".to_string(), "main".to_string()),
+            ])
+        );
+        assert_eq!(client.image_labels(image_name).unwrap(), labels);
+
+        client.delete_image(image_name);
+    }
+
+    #[test]
+    fn test_image_config() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        // each content needs its own label, as saving takes a lease on the label
+        let save = |name: &str, data: Vec<u8>| {
+            let label = precompile_label("test", &format!("image-config-{name}"));
+            client
+                .save_content(data, "original".to_string(), &label, None)
+                .unwrap()
+        };
+
+        let config = br#"{
+            "created": "2024-01-01T00:00:00Z",
+            "architecture": "wasm",
+            "os": "wasip1",
+            "config": {
+                "Env": ["PATH=/", "GREETING=hello"],
+                "Entrypoint": ["/app.wasm"],
+                "Cmd": ["--verbose"],
+                "Labels": {"org.opencontainers.image.source": "https://example.com/app"},
+                "StopSignal": "SIGINT"
+            },
+            "rootfs": {"type": "layers", "diff_ids": ["sha256:0000000000000000000000000000000000000000000000000000000000000000"]},
+            "history": [{"created_by": "wasm-to-oci"}]
+        }"#
+        .to_vec();
+        let config_size = config.len();
+        let config = save("config", config);
+        let manifest = oci_spec::image::ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(oci_spec::image::Descriptor::new(
+                MediaType::ImageConfig,
+                config_size as i64,
+                config.digest.clone(),
+            ))
+            .layers(vec![])
+            .build()
+            .unwrap();
+        let manifest = save("manifest", serde_json::to_vec(&manifest).unwrap());
+
+        let image_name = "localhost/test-image-config:latest";
+        client.create_image(image_name, &manifest.digest, HashMap::new());
+
+        let image_config = client.image_config(image_name).unwrap();
+        assert_eq!(*image_config.architecture(), Arch::Wasm);
+        assert_eq!(
+            *image_config.os(),
+            oci_spec::image::Os::Other("wasip1".to_string())
+        );
+        let runtime_config = image_config.config().as_ref().unwrap();
+        assert_eq!(
+            runtime_config.entrypoint(),
+            &Some(vec!["/app.wasm".to_string()])
+        );
+        assert_eq!(runtime_config.cmd(), &Some(vec!["--verbose".to_string()]));
+        assert_eq!(
+            runtime_config.env(),
+            &Some(vec!["PATH=/".to_string(), "GREETING=hello".to_string()])
+        );
+        assert_eq!(
+            runtime_config.labels(),
+            &Some(HashMap::from([(
+                "org.opencontainers.image.source".to_string(),
+                "https://example.com/app".to_string()
+            )]))
+        );
+        assert_eq!(runtime_config.stop_signal(), &Some("SIGINT".to_string()));
+        assert_eq!(image_config.history().len(), 1);
+
+        // the tag of the image can be left out
+        assert_eq!(
+            client.image_config("localhost/test-image-config").unwrap(),
+            image_config
+        );
+
+        client.delete_image(image_name);
+        for content in [config, manifest] {
+            let digest = content.digest.clone();
+            drop(content);
+            client.delete_content(digest).unwrap();
+        }
+    }
+
+    // an engine that takes a while to precompile, and counts how often it does
+    #[derive(Clone)]
+    struct SlowEngine;
+
+    static SLOW_PRECOMPILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    impl Engine for SlowEngine {
+        fn name() -> &'static str {
+            "slow"
+        }
+        fn run_wasi(&self, _ctx: &impl RuntimeContext, _stdio: Stdio) -> anyhow::Result<i32> {
+            Ok(0)
+        }
+        fn can_precompile(&self) -> Option<String> {
+            Some("v1".to_string())
+        }
+        fn precompile(&self, _layers: &[Vec<u8>]) -> anyhow::Result<Vec<u8>> {
+            std::thread::sleep(Duration::from_secs(1));
+            SLOW_PRECOMPILE_COUNT.fetch_add(1, Ordering::SeqCst);
+            Ok(b"precompiled slowly".to_vec())
+        }
+    }
+
+    #[test]
+    fn test_concurrent_precompile() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        // each content needs its own label, as saving takes a lease on the label
+        let save = |name: &str, data: Vec<u8>| {
+            let label = precompile_label("test", &format!("concurrent-precompile-{name}"));
+            client
+                .save_content(data, "original".to_string(), &label, None)
+                .unwrap()
+        };
+        let descriptor = |media_type: MediaType, content: &WriteContent, size: usize| {
+            oci_spec::image::Descriptor::new(media_type, size as i64, content.digest.clone())
+        };
+
+        let config = br#"{"architecture":"wasm","os":"wasip1","author":"concurrent"}"#.to_vec();
+        let layer = b"\0asm\x01\0\0\0concurrent".to_vec();
+        let (config_size, layer_size) = (config.len(), layer.len());
+        let config = save("config", config);
+        let layer = save("layer", layer);
+        let manifest = oci_spec::image::ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(descriptor(MediaType::ImageConfig, &config, config_size))
+            .layers(vec![descriptor(
+                MediaType::Other(oci::WASM_MODULE_LAYER_MEDIA_TYPE.to_string()),
+                &layer,
+                layer_size,
+            )])
+            .build()
+            .unwrap();
+        let manifest = save("manifest", serde_json::to_vec(&manifest).unwrap());
+
+        let image_name = "localhost/test-concurrent-precompile:latest";
+        client.create_image(image_name, &manifest.digest, HashMap::new());
+        let image = client.get_image(image_name).unwrap();
+        let image_digest = manifest.digest.clone();
+
+        // every container of the image misses the cache at the same time
+        let modules: Vec<_> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    s.spawn(|| {
+                        let client = Client::connect(path, "test-ns").unwrap();
+                        client
+                            .load_image_modules(image.clone(), image_digest.clone(), &SlowEngine)
+                            .unwrap()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(SLOW_PRECOMPILE_COUNT.load(Ordering::SeqCst), 1);
+        for (layers, _) in modules {
+            assert_eq!(layers[0].layer, b"precompiled slowly");
+        }
+
+        // a container that looked up the image before the precompile finished
+        // starts after it, and uses the module that was precompiled
+        let (layers, _) = client
+            .load_image_modules(image.clone(), image_digest.clone(), &SlowEngine)
+            .unwrap();
+        assert_eq!(SLOW_PRECOMPILE_COUNT.load(Ordering::SeqCst), 1);
+        assert_eq!(layers[0].layer, b"precompiled slowly");
+
+        let label = engine_precompile_label(&SlowEngine, "v1");
+        let image = client.get_image(image_name).unwrap();
+        let precompiled = image.labels.get(&label).unwrap().clone();
+        client.delete_image(image_name);
+        client.delete_precompiled_blob(precompiled).unwrap();
+        for content in [config, layer, manifest] {
+            let digest = content.digest.clone();
+            drop(content);
+            client.delete_content(digest).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_read_contents() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, "test-ns").unwrap();
+
+        let layers: Vec<_> = (0..16)
+            .map(|i| format!("layer {i}").repeat(64 * 1024).into_bytes())
+            .collect();
+        let contents: Vec<_> = layers
+            .iter()
+            .enumerate()
+            .map(|(i, layer)| {
+                let label = precompile_label("test", &format!("read-contents-{i}"));
+                client
+                    .save_content(layer.clone(), "original".to_string(), &label, None)
+                    .unwrap()
+            })
+            .collect();
+        let digests: Vec<_> = contents.iter().map(|c| c.digest.clone()).collect();
+
+        // the layers are returned in order, whether they are read one at a time or concurrently
+        let client = client.with_read_concurrency(1);
+        assert_eq!(client.read_contents(digests.clone()).unwrap(), layers);
+        let client = client.with_read_concurrency(8);
+        assert_eq!(client.read_contents(digests.clone()).unwrap(), layers);
+
+        // a missing layer fails the whole read
+        let mut missing = digests.clone();
+        missing.insert(4, format!("sha256:{}", digest("missing")));
+        client
+            .read_contents(missing)
+            .expect_err("reading a missing layer should fail");
+
+        for digest in digests {
+            client.delete_content(digest).unwrap();
+        }
     }
 }
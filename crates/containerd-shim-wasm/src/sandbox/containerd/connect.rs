@@ -0,0 +1,106 @@
+#![cfg(unix)]
+
+use containerd_client::tonic::transport::{
+    Certificate, Channel, ClientTlsConfig, Endpoint, Identity,
+};
+
+use crate::sandbox::error::{Error as ShimError, Result};
+use crate::sandbox::instance_utils::ContainerdTlsOptions;
+
+/// Connects to containerd at `address`.
+///
+/// Addresses of the form `tcp://host:port` are reached over TCP, with TLS when `tls` is set.
+/// Any other address is the path of the unix socket of containerd.
+pub(crate) async fn connect(address: &str, tls: Option<&ContainerdTlsOptions>) -> Result<Channel> {
+    let Some(host) = address.strip_prefix("tcp://") else {
+        if tls.is_some() {
+            return Err(ShimError::InvalidArgument(format!(
+                "TLS is only supported for tcp:// addresses of containerd, not {address:?}"
+            )));
+        }
+        return containerd_client::connect(address)
+            .await
+            .map_err(|err| ShimError::Containerd(err.to_string()));
+    };
+
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let mut endpoint = Endpoint::from_shared(format!("{scheme}://{host}")).map_err(|err| {
+        ShimError::InvalidArgument(format!("invalid containerd address {address:?}: {err}"))
+    })?;
+    if let Some(tls) = tls {
+        endpoint = endpoint
+            .tls_config(client_tls_config(tls)?)
+            .map_err(|err| ShimError::InvalidArgument(format!("invalid TLS settings: {err}")))?;
+    }
+    endpoint
+        .connect()
+        .await
+        .map_err(|err| ShimError::Containerd(format!("failed to connect to {address}: {err}")))
+}
+
+fn client_tls_config(tls: &ContainerdTlsOptions) -> Result<ClientTlsConfig> {
+    let read = |path: &std::path::Path| {
+        std::fs::read(path).map_err(|err| {
+            ShimError::InvalidArgument(format!("failed to read TLS file {path:?}: {err}"))
+        })
+    };
+
+    let mut config = ClientTlsConfig::new();
+    if let Some(ca_file) = &tls.ca_file {
+        config = config.ca_certificate(Certificate::from_pem(read(ca_file)?));
+    }
+    match (&tls.cert_file, &tls.key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            config = config.identity(Identity::from_pem(read(cert_file)?, read(key_file)?));
+        }
+        (None, None) => {}
+        _ => {
+            return Err(ShimError::InvalidArgument(
+                "a TLS client certificate and its key must be set together".to_string(),
+            ))
+        }
+    }
+    if let Some(domain_name) = &tls.domain_name {
+        config = config.domain_name(domain_name);
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect_tcp() {
+        // the connection is established before anything is sent to containerd
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            socket
+        });
+
+        connect(&format!("tcp://127.0.0.1:{port}"), None)
+            .await
+            .unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_tls_needs_tcp() {
+        let tls = ContainerdTlsOptions::default();
+        let err = connect("/run/containerd/containerd.sock", Some(&tls))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ShimError::InvalidArgument(_)), "{err}");
+
+        let tls = ContainerdTlsOptions {
+            cert_file: Some("client.pem".into()),
+            ..Default::default()
+        };
+        let err = connect("tcp://127.0.0.1:1", Some(&tls)).await.unwrap_err();
+        assert!(matches!(err, ShimError::InvalidArgument(_)), "{err}");
+    }
+}
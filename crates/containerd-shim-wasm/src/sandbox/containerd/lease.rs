@@ -1,9 +1,14 @@
 #![cfg(unix)]
 
 use containerd_client::services::v1::leases_client::LeasesClient;
+use containerd_client::services::v1::DeleteRequest;
+use containerd_client::tonic::transport::Channel;
 use containerd_client::{tonic, with_namespace};
+use tokio::runtime::Handle;
 use tonic::Request;
 
+use crate::sandbox::error::{Error, Result};
+
 // Adds lease info to grpc header
 // https://github.com/containerd/containerd/blob/8459273f806e068e1a6bacfaf1355bbbad738d5e/docs/garbage-collection.md#using-grpc
 #[macro_export]
@@ -22,42 +27,51 @@ macro_rules! with_lease {
 pub(crate) struct LeaseGuard {
     pub(crate) lease_id: String,
     pub(crate) namespace: String,
-    pub(crate) address: String,
+    // the channel of the content store, which the lease was created through
+    pub(crate) channel: Channel,
+    // the runtime of the client, which deletes the lease when the guard is dropped without a release
+    pub(crate) handle: Handle,
+    pub(crate) released: bool,
+}
+
+impl LeaseGuard {
+    /// Deletes the lease, so that the content written under it can be garbage collected
+    /// unless something else references it.
+    pub(crate) async fn release(mut self) -> Result<()> {
+        self.released = true;
+        delete_lease(self.channel.clone(), &self.namespace, self.lease_id.clone()).await
+    }
 }
 
-// Provides a best effort for dropping a lease of the content.  If the lease cannot be dropped, it will log a warning
+// Provides a best effort for dropping a lease that wasn't released, e.g., when the future that holds it is cancelled.
+// The callers release the lease on their error paths, as the runtime of the blocking client only runs the task
+// that deletes it here on its next call. Blocking a thread of the runtime here could deadlock it instead.
+// If the runtime is gone before the task runs, the lease expires on its own.
 impl Drop for LeaseGuard {
     fn drop(&mut self) {
-        let id = self.lease_id.clone();
-        let rt = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-
-        let client = rt.block_on(containerd_client::connect(self.address.clone()));
-
-        let channel = match client {
-            Ok(channel) => channel,
-            Err(e) => {
-                log::error!(
-                    "failed to connect to containerd: {}. lease may not be deleted",
-                    e
-                );
-                return;
-            }
-        };
-
-        let mut client = LeasesClient::new(channel);
-
-        rt.block_on(async {
-            let req = containerd_client::services::v1::DeleteRequest { id, sync: false };
-            let req = with_namespace!(req, self.namespace);
-            let result = client.delete(req).await;
-
-            match result {
-                Ok(_) => log::debug!("removed lease"),
-                Err(e) => log::error!("failed to remove lease: {}", e),
+        if self.released {
+            return;
+        }
+        let (channel, namespace, id) = (
+            self.channel.clone(),
+            self.namespace.clone(),
+            self.lease_id.clone(),
+        );
+        self.handle.spawn(async move {
+            if let Err(e) = delete_lease(channel, &namespace, id).await {
+                log::error!("{e}");
             }
         });
     }
 }
+
+async fn delete_lease(channel: Channel, namespace: &str, id: String) -> Result<()> {
+    let req = DeleteRequest { id, sync: false };
+    let req = with_namespace!(req, namespace);
+    LeasesClient::new(channel)
+        .delete(req)
+        .await
+        .map_err(|e| Error::Containerd(format!("failed to remove lease: {e}")))?;
+    log::debug!("removed lease");
+    Ok(())
+}
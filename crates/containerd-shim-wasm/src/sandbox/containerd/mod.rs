@@ -1,6 +1,12 @@
 #![cfg(unix)]
 
+mod cache_dir;
 mod client;
+mod connect;
 mod lease;
+mod retry;
+mod trace;
 
-pub(crate) use client::Client;
+pub(crate) use client::{engine_precompile_label, forget_modules};
+pub use client::{AsyncClient, Client, ExportedLogs, ImageVerifier, PrecompileState};
+pub use retry::RetryPolicy;
@@ -0,0 +1,301 @@
+//! The calls of the client to containerd can fail transiently, e.g., while containerd restarts.
+//! A [`RetryPolicy`] set with [`AsyncClient::with_retry_policy`](super::AsyncClient::with_retry_policy)
+//! retries the content reads and writes, the leases and the image operations of the client alike,
+//! rather than each of them retrying in its own way.
+//!
+//! An operation that retries more than the policy does, e.g., the reads of content that isn't found yet,
+//! runs a single policy that retries both, see [`RetryPolicy::also_retrying`], rather than nesting
+//! a loop of its own around the policy, which would multiply the attempts.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::sandbox::error::{Error as ShimError, Result};
+
+type Retryable = dyn Fn(&ShimError) -> bool + Send + Sync;
+
+/// How many times the client runs an operation that fails, and how long it waits in between.
+///
+/// The delay before each retry is twice the one before it, starting at the base delay and up to the max delay,
+/// and varies randomly by up to the jitter of it.
+/// By default, the policy runs each operation once, without retries.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Option<Duration>,
+    jitter: f64,
+    retryable: Arc<Retryable>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("base_delay", &self.base_delay)
+            .field("max_delay", &self.max_delay)
+            .field("jitter", &self.jitter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that runs each operation once, without retries.
+    pub fn none() -> Self {
+        Self::new(1, Duration::ZERO)
+    }
+
+    /// A policy that runs each operation up to `max_attempts` times, waiting `base_delay` before the first retry.
+    /// Failures of the calls to containerd and timeouts are retried, see [`RetryPolicy::with_retryable`].
+    pub fn new(max_attempts: u32, base_delay: Duration) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+            max_delay: None,
+            jitter: 0.0,
+            retryable: Arc::new(is_transient),
+        }
+    }
+
+    /// Caps the delay before a retry at `max_delay`, rather than doubling it without bound.
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = Some(max_delay);
+        self
+    }
+
+    /// Varies each delay randomly by up to `jitter` of it, between 0 and 1,
+    /// so that the clients that failed at the same time don't all retry at the same time.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets which errors are retried, rather than returned right away.
+    pub fn with_retryable(
+        mut self,
+        retryable: impl Fn(&ShimError) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.retryable = Arc::new(retryable);
+        self
+    }
+
+    /// Returns how many times an operation is run at most.
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Returns a policy that retries the errors of `retryable` as well as the ones of this policy,
+    /// with at least `max_attempts` attempts and `base_delay` before the first retry.
+    pub(crate) fn also_retrying(
+        &self,
+        max_attempts: u32,
+        base_delay: Duration,
+        retryable: impl Fn(&ShimError) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        let retryable_before = self.retryable.clone();
+        Self {
+            max_attempts: self.max_attempts.max(max_attempts),
+            base_delay: self.base_delay.max(base_delay),
+            max_delay: self.max_delay,
+            jitter: self.jitter,
+            retryable: Arc::new(move |err| retryable_before(err) || retryable(err)),
+        }
+    }
+
+    // Returns how long to wait before the `retry`th retry, starting at 1.
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << retry.saturating_sub(1).min(16));
+        let delay = match self.max_delay {
+            Some(max_delay) => delay.min(max_delay),
+            None => delay,
+        };
+        if self.jitter == 0.0 {
+            return delay;
+        }
+        // the keys of each `RandomState` are different, so the hash of nothing is a random number
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        delay.mul_f64(1.0 + self.jitter * (2.0 * random - 1.0))
+    }
+
+    /// Runs `attempt` until it succeeds, fails with an error that isn't retryable,
+    /// or was run `max_attempts` times, in which case its last error is returned.
+    pub(crate) async fn run<T, Fut>(
+        &self,
+        operation: &str,
+        mut attempt: impl FnMut() -> Fut,
+    ) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut retry = 0;
+        loop {
+            match attempt().await {
+                Err(err) if retry + 1 < self.max_attempts && (self.retryable)(&err) => {
+                    retry += 1;
+                    let delay = self.delay(retry);
+                    log::warn!(
+                        "{operation} failed, retrying in {delay:?} ({retry}/{}): {err}",
+                        self.max_attempts - 1
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+// Failures of the calls to containerd, rather than its answers, e.g., that the content isn't found.
+fn is_transient(err: &ShimError) -> bool {
+    matches!(err, ShimError::Containerd(_) | ShimError::Timeout { .. })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    fn transient() -> ShimError {
+        ShimError::Containerd("status: Unavailable".to_string())
+    }
+
+    #[test]
+    fn test_retries_transient_error() {
+        let rt = Runtime::new().unwrap();
+        let policy = RetryPolicy::new(3, Duration::from_millis(10));
+        let attempts = AtomicU32::new(0);
+        let value = rt
+            .block_on(policy.run("read", || {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    match attempt {
+                        0 => Err(transient()),
+                        n => Ok(n),
+                    }
+                }
+            }))
+            .unwrap();
+        assert_eq!(value, 1);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_gives_up() {
+        let rt = Runtime::new().unwrap();
+
+        // after the last attempt
+        let policy = RetryPolicy::new(3, Duration::from_millis(10));
+        let attempts = AtomicU32::new(0);
+        let err = rt
+            .block_on(policy.run("read", || {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async { Err::<(), _>(transient()) }
+            }))
+            .unwrap_err();
+        assert!(matches!(err, ShimError::Containerd(_)), "{err}");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // right away, for an error that isn't retryable
+        let attempts = AtomicU32::new(0);
+        let err = rt
+            .block_on(policy.run("read", || {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async { Err::<(), _>(ShimError::NotFound("sha256:missing".to_string())) }
+            }))
+            .unwrap_err();
+        assert!(matches!(err, ShimError::NotFound(_)), "{err}");
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        // and by default, without retries
+        let attempts = AtomicU32::new(0);
+        rt.block_on(RetryPolicy::default().run("read", || {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            async { Err::<(), _>(transient()) }
+        }))
+        .unwrap_err();
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_retryable() {
+        let rt = Runtime::new().unwrap();
+        let policy = RetryPolicy::new(2, Duration::ZERO)
+            .with_retryable(|err| matches!(err, ShimError::NotFound(_)));
+        let attempts = AtomicU32::new(0);
+        rt.block_on(policy.run("read", || {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            async { Err::<(), _>(ShimError::NotFound("sha256:missing".to_string())) }
+        }))
+        .unwrap_err();
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_also_retrying() {
+        let rt = Runtime::new().unwrap();
+        let not_found = || ShimError::NotFound("sha256:missing".to_string());
+        let policy = RetryPolicy::new(3, Duration::ZERO).also_retrying(4, Duration::ZERO, |err| {
+            matches!(err, ShimError::NotFound(_))
+        });
+        assert_eq!(policy.max_attempts(), 4);
+
+        // the errors of both policies share the attempts, rather than each getting its own
+        let attempts = AtomicU32::new(0);
+        rt.block_on(policy.run("read", || {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match attempt % 2 {
+                    0 => Err::<(), _>(transient()),
+                    _ => Err(not_found()),
+                }
+            }
+        }))
+        .unwrap_err();
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+
+        // other errors are still returned right away
+        let attempts = AtomicU32::new(0);
+        rt.block_on(policy.run("read", || {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            async { Err::<(), _>(ShimError::InvalidArgument("invalid".to_string())) }
+        }))
+        .unwrap_err();
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(100));
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+
+        let capped = policy.clone().with_max_delay(Duration::from_millis(250));
+        assert_eq!(capped.delay(2), Duration::from_millis(200));
+        assert_eq!(capped.delay(3), Duration::from_millis(250));
+
+        let policy = policy.with_jitter(0.5);
+        for _ in 0..100 {
+            let delay = policy.delay(2);
+            assert!(
+                (Duration::from_millis(100)..=Duration::from_millis(300)).contains(&delay),
+                "{delay:?}"
+            );
+        }
+    }
+}
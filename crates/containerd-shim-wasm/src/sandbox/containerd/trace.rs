@@ -0,0 +1,91 @@
+#![cfg(unix)]
+
+use std::future::Future;
+use std::time::Instant;
+
+// Creates a `TimedSpan` for an operation of the image load path.
+// Fields are only recorded with the `tracing` feature, and use their `Display` value.
+#[cfg(feature = "tracing")]
+macro_rules! timed_span {
+    ($name:literal $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::sandbox::containerd::trace::TimedSpan::new(
+            $name,
+            tracing::info_span!(
+                $name,
+                $($key = %$value,)*
+                elapsed_ms = tracing::field::Empty
+            ),
+        )
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! timed_span {
+    ($name:literal $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::sandbox::containerd::trace::TimedSpan::new($name)
+    };
+}
+
+pub(crate) use timed_span;
+
+// Measures how long an operation takes, from when it is created until the operation completes.
+// With the `tracing` feature, the operation runs in a `tracing` span that records the duration,
+// so that a tracing subscriber can show where the time to load an image is spent.
+// Without it, the duration is only logged.
+//
+// The span is only entered while the operation is polled, see `TimedSpan::instrument`, rather than held
+// entered across awaits, which would make the futures of the client `!Send` and nest the spans of other tasks.
+pub(crate) struct TimedSpan {
+    name: &'static str,
+    start: Instant,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl TimedSpan {
+    #[cfg(feature = "tracing")]
+    pub(crate) fn new(name: &'static str, span: tracing::Span) -> Self {
+        Self {
+            name,
+            start: Instant::now(),
+            span,
+        }
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            start: Instant::now(),
+        }
+    }
+
+    // Runs `fut` in the span, and records how long it took once it completes.
+    pub(crate) async fn instrument<F: Future>(self, fut: F) -> F::Output {
+        #[cfg(feature = "tracing")]
+        let output = tracing::Instrument::instrument(fut, self.span.clone()).await;
+        #[cfg(not(feature = "tracing"))]
+        let output = fut.await;
+        drop(self);
+        output
+    }
+
+    // Runs `f` in the span, and records how long it took.
+    pub(crate) fn in_scope<T>(self, f: impl FnOnce() -> T) -> T {
+        #[cfg(feature = "tracing")]
+        let output = self.span.in_scope(f);
+        #[cfg(not(feature = "tracing"))]
+        let output = f();
+        drop(self);
+        output
+    }
+}
+
+impl Drop for TimedSpan {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        #[cfg(feature = "tracing")]
+        self.span.record("elapsed_ms", elapsed.as_millis() as u64);
+        log::debug!("{} took {:?}", self.name, elapsed);
+    }
+}
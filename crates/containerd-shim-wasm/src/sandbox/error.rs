@@ -1,6 +1,8 @@
 //! Error types used by shims
 //! This handles converting to the appropriate ttrpc error codes
 
+use std::time::Duration;
+
 use anyhow::Error as AnyError;
 use containerd_shim::Error as ShimError;
 use oci_spec::OciSpecError;
@@ -35,6 +37,54 @@ pub enum Error {
     /// The operation was rejected because the system is not in a state required for the operation's
     #[error("{0}")]
     FailedPrecondition(String),
+    /// The image targets wasm, but has nothing that the runtime can run
+    #[error("no runnable content: {0}")]
+    NoRunnableContent(String),
+    /// A limit on some resource, e.g., the number of instances, has been reached
+    #[error("resource exhausted: {0}")]
+    ResourceExhausted(String),
+    /// The export that the container runs has a signature that the shim can't call,
+    /// e.g., it takes parameters
+    #[error("unsupported export {name:?} with signature {signature}: only exports without parameters or results can be run")]
+    UnsupportedExport { name: String, signature: String },
+    /// The module or component doesn't export the function that the entrypoint of the container names
+    #[error("start function {name:?} not found: the available exports are {available:?}")]
+    StartFunctionNotFound {
+        name: String,
+        available: Vec<String>,
+    },
+    /// A wasm layer of the image can't be a module or a component that runs, e.g., because it is empty
+    #[error("invalid module {digest}: {reason}")]
+    InvalidModule { digest: String, reason: String },
+    /// A wasm layer of the image is larger than the shim is configured to load
+    #[error("module of {size} bytes is larger than the limit of {limit} bytes")]
+    ModuleTooLarge { size: u64, limit: u64 },
+    /// The image of the container was rejected by the verifier of the shim,
+    /// e.g., because it isn't signed or lacks a required annotation
+    #[error("verification failed: {0}")]
+    VerificationFailed(String),
+    /// The module requires wasm features that the engine doesn't support on this host
+    #[error("unsupported feature: {0}")]
+    UnsupportedFeature(String),
+    /// The image of the container must run precompiled, but the engine can't precompile it,
+    /// see [`REQUIRE_PRECOMPILE_ANNOTATION`](crate::sandbox::REQUIRE_PRECOMPILE_ANNOTATION)
+    #[error("precompile required: {0}")]
+    PrecompileRequired(String),
+    /// The image of the container has no target yet, e.g., because it is still being pulled
+    #[error("image not ready: {0}")]
+    ImageNotReady(String),
+    /// An operation didn't complete in time, e.g., a content write to containerd that stopped making progress
+    #[error("{operation} timed out after {after:?}")]
+    Timeout { operation: String, after: Duration },
+    /// There isn't enough free space on disk for the operation
+    #[error("insufficient space: {0}")]
+    InsufficientSpace(String),
+    /// The container can't be deleted, e.g., because it is still running
+    #[error("container {id} can't be deleted: {reason}")]
+    DeleteFailed { id: String, reason: DeleteFailure },
+    /// The entrypoint of the container is a native executable, and the shim doesn't fall back to running those
+    #[error("not a wasm module: {0}")]
+    NotWasm(String),
     /// Error while parsing JSON
     #[error("{0}")]
     Json(#[from] serde_json::Error),
@@ -50,6 +100,20 @@ pub enum Error {
     Containerd(String),
 }
 
+/// Why a container can't be deleted, see [`Error::DeleteFailed`].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum DeleteFailure {
+    /// The container is still running, and the shim isn't set to kill it on delete
+    #[error("it is still running, kill it first or set the force_delete option")]
+    Running,
+    /// The state of the container is still in use, e.g., by a mount
+    #[error("its state is busy: {0}")]
+    Busy(String),
+    /// The shim isn't allowed to remove the state of the container
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+}
+
 pub type Result<T, E = Error> = ::std::result::Result<T, E>;
 
 impl From<Error> for ttrpc::Error {
@@ -76,6 +140,53 @@ impl From<Error> for ttrpc::Error {
             Error::FailedPrecondition(ref s) => {
                 ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::FAILED_PRECONDITION, s))
             }
+            Error::NoRunnableContent(ref s) => {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::FAILED_PRECONDITION, s))
+            }
+            Error::NotWasm(ref s) => {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::FAILED_PRECONDITION, s))
+            }
+            Error::UnsupportedFeature(ref s) => {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::FAILED_PRECONDITION, s))
+            }
+            Error::PrecompileRequired(ref s) => {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::FAILED_PRECONDITION, s))
+            }
+            Error::ImageNotReady(ref s) => {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::UNAVAILABLE, s))
+            }
+            Error::ResourceExhausted(ref s) => {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::RESOURCE_EXHAUSTED, s))
+            }
+            Error::InsufficientSpace(ref s) => {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::RESOURCE_EXHAUSTED, s))
+            }
+            Error::InvalidModule { .. } => ttrpc::Error::RpcStatus(ttrpc::get_status(
+                ttrpc::Code::INVALID_ARGUMENT,
+                e.to_string(),
+            )),
+            Error::StartFunctionNotFound { .. } => ttrpc::Error::RpcStatus(ttrpc::get_status(
+                ttrpc::Code::INVALID_ARGUMENT,
+                e.to_string(),
+            )),
+            Error::Timeout { .. } => ttrpc::Error::RpcStatus(ttrpc::get_status(
+                ttrpc::Code::DEADLINE_EXCEEDED,
+                e.to_string(),
+            )),
+            Error::ModuleTooLarge { .. } => ttrpc::Error::RpcStatus(ttrpc::get_status(
+                ttrpc::Code::RESOURCE_EXHAUSTED,
+                e.to_string(),
+            )),
+            Error::DeleteFailed { ref reason, .. } => {
+                let code = match reason {
+                    DeleteFailure::PermissionDenied(_) => ttrpc::Code::PERMISSION_DENIED,
+                    _ => ttrpc::Code::FAILED_PRECONDITION,
+                };
+                ttrpc::Error::RpcStatus(ttrpc::get_status(code, e.to_string()))
+            }
+            Error::VerificationFailed(ref s) => {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::PERMISSION_DENIED, s))
+            }
             Error::Oci(ref _s) => {
                 ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::UNKNOWN, e.to_string()))
             }
@@ -141,6 +252,29 @@ mod tests {
             _ => panic!("unexpected error"),
         }
 
+        let e = Error::ResourceExhausted("resource exhausted".to_string());
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::RESOURCE_EXHAUSTED);
+                assert_eq!(s.message, "resource exhausted");
+            }
+            _ => panic!("unexpected error"),
+        }
+
+        let e = Error::Timeout {
+            operation: "content write".to_string(),
+            after: Duration::from_secs(1),
+        };
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::DEADLINE_EXCEEDED);
+                assert_eq!(s.message, "content write timed out after 1s");
+            }
+            _ => panic!("unexpected error"),
+        }
+
         let e = Error::Shim(ShimError::InvalidArgument("invalid argument".to_string()));
         let t: ttrpc::Error = e.into();
         match t {
@@ -1,12 +1,21 @@
 //! Abstractions for running/managing a wasm/wasi instance.
 
+use std::collections::HashMap;
+use std::io::Read;
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use chrono::{DateTime, Utc};
+use oci_spec::runtime::Spec;
 
 use super::error::Error;
+use super::instance_utils::read_options;
+use super::oci::ResolvedImage;
 use super::sync::WaitableCell;
+use crate::container::{Capabilities, WasmBinaryType};
 use crate::sys::signals::*;
 
 /// Generic options builder for creating a wasm instance.
@@ -28,8 +37,23 @@ pub struct InstanceConfig<Engine: Send + Sync + Clone> {
     namespace: String,
     // /// GRPC address back to main containerd
     containerd_address: String,
+    /// Annotations from the OCI spec of the bundle.
+    annotations: HashMap<String, String>,
+    /// Optional wasm module to run, instead of the one in the image of the container.
+    module: Option<Arc<[u8]>>,
+    /// Optional layers of the image of the container, resolved before, instead of loading them from containerd.
+    resolved_image: Option<Arc<ResolvedImage>>,
 }
 
+/// The namespace used when the bundle doesn't specify one.
+const DEFAULT_NAMESPACE: &str = "default";
+
+/// The address of containerd used when building a config from a bundle.
+#[cfg(unix)]
+pub(crate) const DEFAULT_CONTAINERD_ADDRESS: &str = "/run/containerd/containerd.sock";
+#[cfg(windows)]
+pub(crate) const DEFAULT_CONTAINERD_ADDRESS: &str = r"\\.\pipe\containerd-containerd";
+
 impl<Engine: Send + Sync + Clone> InstanceConfig<Engine> {
     pub fn new(
         engine: Engine,
@@ -46,6 +70,9 @@ impl<Engine: Send + Sync + Clone> InstanceConfig<Engine> {
             stdout: PathBuf::default(),
             stderr: PathBuf::default(),
             bundle: PathBuf::default(),
+            annotations: HashMap::default(),
+            module: None,
+            resolved_image: None,
         }
     }
 
@@ -93,6 +120,12 @@ impl<Engine: Send + Sync + Clone> InstanceConfig<Engine> {
         &self.bundle
     }
 
+    /// set the wasm engine for the instance
+    pub fn set_engine(&mut self, engine: Engine) -> &mut Self {
+        self.engine = engine;
+        self
+    }
+
     /// get the wasm engine for the instance
     pub fn get_engine(&self) -> Engine {
         self.engine.clone()
@@ -107,6 +140,193 @@ impl<Engine: Send + Sync + Clone> InstanceConfig<Engine> {
     pub fn get_containerd_address(&self) -> String {
         self.containerd_address.clone()
     }
+
+    /// set the annotations for the instance
+    pub fn set_annotations(&mut self, annotations: HashMap<String, String>) -> &mut Self {
+        self.annotations = annotations;
+        self
+    }
+
+    /// get the annotations for the instance
+    pub fn get_annotations(&self) -> &HashMap<String, String> {
+        &self.annotations
+    }
+
+    /// get a copy of the config that uses a different engine
+    pub fn with_engine<E: Send + Sync + Clone>(&self, engine: E) -> InstanceConfig<E> {
+        InstanceConfig {
+            engine,
+            stdin: self.stdin.clone(),
+            stdout: self.stdout.clone(),
+            stderr: self.stderr.clone(),
+            bundle: self.bundle.clone(),
+            namespace: self.namespace.clone(),
+            containerd_address: self.containerd_address.clone(),
+            annotations: self.annotations.clone(),
+            module: self.module.clone(),
+            resolved_image: self.resolved_image.clone(),
+        }
+    }
+
+    /// read the wasm module to run from `reader`, e.g., a pipe, instead of from the layers of the image
+    ///
+    /// Only the module doesn't come from the image: the container still needs a bundle,
+    /// and is still created with libcontainer.
+    /// At most `max_size` bytes are read, so that a bad writer can't exhaust the memory of the shim.
+    /// The module must be a wasm module or component.
+    pub fn set_module_from_reader(
+        &mut self,
+        reader: impl Read,
+        max_size: u64,
+    ) -> Result<&mut Self, Error> {
+        let mut module = vec![];
+        reader
+            .take(max_size.saturating_add(1))
+            .read_to_end(&mut module)?;
+        if module.len() as u64 > max_size {
+            return Err(Error::ResourceExhausted(format!(
+                "wasm module is larger than the limit of {max_size} bytes"
+            )));
+        }
+        if WasmBinaryType::from_bytes(&module).is_none() {
+            return Err(Error::InvalidArgument(
+                "input is not a wasm module or component".to_string(),
+            ));
+        }
+        self.module = Some(module.into());
+        Ok(self)
+    }
+
+    /// get the wasm module to run, if it was read from a reader rather than the image
+    pub fn get_module(&self) -> Option<&[u8]> {
+        self.module.as_deref()
+    }
+
+    /// run the layers of an image resolved before, e.g., kept in an external store, instead of loading them from containerd
+    pub fn set_resolved_image(&mut self, resolved_image: ResolvedImage) -> &mut Self {
+        self.resolved_image = Some(Arc::new(resolved_image));
+        self
+    }
+
+    /// get the layers of the image to run, if they were resolved before
+    pub fn get_resolved_image(&self) -> Option<&ResolvedImage> {
+        self.resolved_image.as_deref()
+    }
+}
+
+impl<Engine: Default + Send + Sync + Clone> InstanceConfig<Engine> {
+    /// Creates a config for the OCI bundle at the given path, using the default engine.
+    ///
+    /// The bundle must contain a readable `config.json`, whose annotations are added to the config.
+    /// The namespace is read from the bundle's `options.json`, and defaults to `default`.
+    /// The `stdin`, `stdout` and `stderr` files in the bundle are used for stdio, if they exist.
+    pub fn from_bundle(bundle: impl AsRef<Path>) -> Result<Self, Error> {
+        let bundle = bundle.as_ref();
+        let spec = Spec::load(bundle.join("config.json")).map_err(|err| {
+            Error::InvalidArgument(format!("invalid bundle {}: {err}", bundle.display()))
+        })?;
+        let namespace = read_options(bundle)?
+            .namespace
+            .unwrap_or_else(|| DEFAULT_NAMESPACE.to_string());
+
+        let mut cfg = Self::new(Engine::default(), namespace, DEFAULT_CONTAINERD_ADDRESS);
+        cfg.set_bundle(bundle)
+            .set_annotations(spec.annotations().clone().unwrap_or_default());
+
+        let stdin = bundle.join("stdin");
+        if stdin.exists() {
+            cfg.set_stdin(stdin);
+        }
+        let stdout = bundle.join("stdout");
+        if stdout.exists() {
+            cfg.set_stdout(stdout);
+        }
+        let stderr = bundle.join("stderr");
+        if stderr.exists() {
+            cfg.set_stderr(stderr);
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// The reason a guest trapped.
+/// Engines can attach this as context to the error returned from `run_wasi`
+/// so that the shim can report a typed exit status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum TrapReason {
+    /// The guest exhausted its wasm stack.
+    #[error("stack overflow")]
+    StackOverflow,
+    /// The guest trapped for any other reason.
+    #[error("wasm trap")]
+    Other,
+}
+
+/// The typed exit status of an instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The guest exited with the given exit code.
+    Exited(u32),
+    /// The guest was terminated by the given signal.
+    Signaled(i32),
+    /// The guest was aborted by a trap.
+    Trapped(TrapReason),
+    /// The guest was killed by the OOM-killer for exceeding the memory limit of its cgroup.
+    OutOfMemory,
+}
+
+impl ExitStatus {
+    /// Returns whether the guest succeeded, i.e., exited with one of the `success` exit codes.
+    /// A guest that was terminated by a signal, trapped or ran out of memory never succeeded.
+    pub fn is_success(&self, success: &SuccessExitCodes) -> bool {
+        match self {
+            ExitStatus::Exited(code) => success.contains(*code),
+            _ => false,
+        }
+    }
+}
+
+/// The exit codes with which a guest succeeds, for applications that exit with other codes than 0 on success,
+/// see [`SUCCESS_EXIT_CODES_ANNOTATION`](crate::sandbox::SUCCESS_EXIT_CODES_ANNOTATION).
+///
+/// They are parsed from a comma separated list of codes and ranges of codes, e.g., `0,2,64-78`.
+/// By default only 0 is a success.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuccessExitCodes(Vec<RangeInclusive<u32>>);
+
+impl Default for SuccessExitCodes {
+    fn default() -> Self {
+        Self(vec![0..=0])
+    }
+}
+
+impl SuccessExitCodes {
+    /// Returns whether `code` is a success.
+    pub fn contains(&self, code: u32) -> bool {
+        self.0.iter().any(|range| range.contains(&code))
+    }
+}
+
+impl FromStr for SuccessExitCodes {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidArgument(format!("invalid success exit codes {s:?}"));
+        let parse = |code: &str| code.trim().parse::<u32>().map_err(|_| invalid());
+        let mut ranges = vec![];
+        for range in s.split(',') {
+            let (first, last) = match range.split_once('-') {
+                Some((first, last)) => (parse(first)?, parse(last)?),
+                None => (parse(range)?, parse(range)?),
+            };
+            if first > last {
+                return Err(invalid());
+            }
+            ranges.push(first..=last);
+        }
+        Ok(Self(ranges))
+    }
 }
 
 /// Represents a WASI module(s).
@@ -130,6 +350,24 @@ pub trait Instance: 'static {
     /// Send a signal to the instance
     fn kill(&self, signal: u32) -> Result<(), Error>;
 
+    /// Returns the signal that [`Instance::stop`] sends to the instance first.
+    /// The default is SIGTERM.
+    fn stop_signal(&self) -> u32 {
+        SIGTERM as u32
+    }
+
+    /// Stops the instance gracefully: sends it its stop signal,
+    /// and kills it with SIGKILL if it hasn't exited within `timeout`.
+    fn stop(&self, timeout: Duration) -> Result<(), Error> {
+        let signal = self.stop_signal();
+        self.kill(signal)?;
+        if signal != SIGKILL as u32 && self.wait_timeout(timeout).is_none() {
+            log::info!("instance didn't exit {timeout:?} after signal {signal}, sending SIGKILL");
+            self.kill(SIGKILL as u32)?;
+        }
+        Ok(())
+    }
+
     /// Delete any reference to the instance
     /// This is called after the instance has exited.
     fn delete(&self) -> Result<(), Error>;
@@ -144,6 +382,72 @@ pub trait Instance: 'static {
     /// Returns None if the timeout is reached before the instance has finished.
     /// This is a blocking call.
     fn wait_timeout(&self, t: impl Into<Option<Duration>>) -> Option<(u32, DateTime<Utc>)>;
+
+    /// Returns true if the instance can run the sandbox ("pause") container of a CRI pod.
+    /// The sandbox container then holds the namespaces, e.g., network and IPC, that the
+    /// other containers of the pod join.
+    /// When this returns false, the sandbox container isn't run, and the containers of the pod
+    /// share the namespaces of the shim instead.  This is the default.
+    fn supports_pod_sandbox() -> bool
+    where
+        Self: Sized,
+    {
+        false
+    }
+
+    /// Returns the names of the engines that can run the instances.
+    /// A container selects one of them with the `runwasi.io/engine` annotation.
+    /// The default implementation returns no names.
+    fn engine_names() -> Vec<&'static str>
+    where
+        Self: Sized,
+    {
+        vec![]
+    }
+
+    /// Returns the version of the engine running the instances, if any.
+    /// This is reported by the shim's `--version` flag.
+    fn engine_version() -> Option<String>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// Returns what `engine` supports, or None if the instances don't run an engine.  This is the default.
+    /// This is reported by the shim's `capabilities` subcommand.
+    fn capabilities(_engine: &Self::Engine) -> Option<Capabilities>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// Returns the digest of the image manifest the instance was created from,
+    /// or None if it didn't come from an OCI image.  This is the default.
+    fn image_digest(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the typed exit status of the instance, or None if it hasn't exited yet.
+    /// The default implementation derives it from the exit code.
+    fn exit_status(&self) -> Option<ExitStatus> {
+        self.wait_timeout(Duration::ZERO)
+            .map(|(code, _)| ExitStatus::Exited(code))
+    }
+
+    /// Returns the exit codes with which the guest succeeds.
+    /// The default is only 0.
+    fn success_exit_codes(&self) -> SuccessExitCodes {
+        SuccessExitCodes::default()
+    }
+
+    /// Returns whether the instance succeeded, per its [`Instance::success_exit_codes`],
+    /// or None if it hasn't exited yet.
+    fn exit_succeeded(&self) -> Option<bool> {
+        let exit_status = self.exit_status()?;
+        Some(exit_status.is_success(&self.success_exit_codes()))
+    }
 }
 
 /// This is used for the "pause" container with cri and is a no-op instance implementation.
@@ -184,6 +488,61 @@ impl Instance for Nop {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use oci_spec::runtime::SpecBuilder;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_config_from_bundle() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let bundle = dir.path();
+        SpecBuilder::default()
+            .annotations(HashMap::from([("key".to_string(), "value".to_string())]))
+            .build()?
+            .save(bundle.join("config.json"))?;
+        write(bundle.join("options.json"), r#"{"namespace": "test-ns"}"#)?;
+        write(bundle.join("stdout"), "")?;
+
+        let cfg = InstanceConfig::<()>::from_bundle(bundle)?;
+        assert_eq!(cfg.get_bundle(), bundle);
+        assert_eq!(cfg.get_namespace(), "test-ns");
+        assert_eq!(cfg.get_stdout(), bundle.join("stdout"));
+        assert_eq!(cfg.get_stdin(), Path::new(""));
+        assert_eq!(cfg.get_stderr(), Path::new(""));
+        assert_eq!(
+            cfg.get_annotations(),
+            &HashMap::from([("key".to_string(), "value".to_string())])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_from_bundle_defaults() -> Result<(), Error> {
+        let dir = tempdir()?;
+        SpecBuilder::default()
+            .build()?
+            .save(dir.path().join("config.json"))?;
+
+        let cfg = InstanceConfig::<()>::from_bundle(dir.path())?;
+        assert_eq!(cfg.get_namespace(), DEFAULT_NAMESPACE);
+        assert!(cfg.get_annotations().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_from_bundle_without_spec() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let result = InstanceConfig::<()>::from_bundle(dir.path());
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod noptests {
     use std::time::Duration;
@@ -229,4 +588,40 @@ mod noptests {
         nop.delete()?;
         Ok(())
     }
+
+    #[test]
+    fn test_success_exit_codes() -> Result<(), Error> {
+        let default = SuccessExitCodes::default();
+        assert!(ExitStatus::Exited(0).is_success(&default));
+        assert!(!ExitStatus::Exited(2).is_success(&default));
+
+        let codes: SuccessExitCodes = "0, 2,64-78".parse()?;
+        for code in [0, 2, 64, 70, 78] {
+            assert!(ExitStatus::Exited(code).is_success(&codes), "{code}");
+        }
+        for code in [1, 3, 63, 79] {
+            assert!(!ExitStatus::Exited(code).is_success(&codes), "{code}");
+        }
+        assert!(!ExitStatus::Signaled(9).is_success(&codes));
+        assert!(!ExitStatus::OutOfMemory.is_success(&codes));
+
+        for invalid in ["", "a", "0,", "3-1", "-1", "0-"] {
+            let err = invalid.parse::<SuccessExitCodes>().unwrap_err();
+            assert!(matches!(err, Error::InvalidArgument(_)), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_nop_exit_succeeded() -> Result<(), Error> {
+        let nop = Nop::new("".to_string(), None)?;
+        assert_eq!(nop.exit_succeeded(), None);
+        nop.kill(SIGKILL as u32)?;
+        assert_eq!(nop.exit_succeeded(), Some(false));
+
+        let nop = Nop::new("".to_string(), None)?;
+        nop.kill(SIGTERM as u32)?;
+        assert_eq!(nop.exit_succeeded(), Some(true));
+        Ok(())
+    }
 }
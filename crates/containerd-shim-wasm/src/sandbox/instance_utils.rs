@@ -1,4 +1,5 @@
 //! Common utilities for the containerd shims.
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
@@ -30,9 +31,199 @@ pub fn instance_exists<P: AsRef<Path>>(root_path: P, container_id: &str) -> Resu
     Ok(instance_root.exists())
 }
 
-#[derive(Serialize, Deserialize)]
-struct Options {
-    root: Option<PathBuf>,
+/// The runtime options that containerd passes to the shim in the `options.json` file of the bundle.
+///
+/// Every field is optional, and fields that the shim doesn't know about are ignored.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShimOptions {
+    /// The directory with the state of the containers.
+    /// Defaults to `/run/containerd/<engine name>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
+    /// The containerd namespace of the containers.
+    /// Defaults to `default`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// The maximum total size in bytes of the precompiled modules cached in the content store.
+    /// By default the cache isn't capped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_precompiled_cache_size: Option<u64>,
+    /// A directory of the host to keep the precompiled modules in, rather than the content store,
+    /// e.g., on a faster or larger disk. It must not be writable by other users than the shim.
+    /// By default precompiled modules are kept in the content store.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub precompile_cache_dir: Option<PathBuf>,
+    /// The maximum size in bytes of each wasm layer of an image.
+    /// Containers of images with larger layers fail to start, without the layers being read.
+    /// Unlimited by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_module_bytes: Option<u64>,
+    /// How many layers of the image are read from the content store at the same time.
+    /// Defaults to 4.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_read_concurrency: Option<usize>,
+    /// How many times a read of content that isn't found is retried, for setups where content that was
+    /// just committed can take a moment to be visible.
+    /// Defaults to 0, so that the reads of missing content fail right away.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_read_retries: Option<u32>,
+    /// How many milliseconds the first retry of a read of content that isn't found waits,
+    /// each next retry waiting twice as long.
+    /// Defaults to 100.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_read_retry_delay_ms: Option<u64>,
+    /// How many times the size of the layers must be free in the content store before precompiling.
+    /// Defaults to 2.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub precompile_space_factor: Option<f64>,
+    /// The directory of the content store of containerd, whose free space is checked before precompiling.
+    /// Set it when containerd doesn't run with its default root.
+    /// Defaults to `/var/lib/containerd/io.containerd.content.v1.content`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_store_root: Option<PathBuf>,
+    /// The minimum memory in bytes that must be available to precompile an image.
+    /// When less is available, the modules of the image run from its wasm layers, without being precompiled.
+    /// By default images are always precompiled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_precompile_memory: Option<u64>,
+    /// How many seconds to wait for the precompile of an image that another container of the image started,
+    /// before precompiling the image again.
+    /// Defaults to 300.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub precompile_wait_seconds: Option<u64>,
+    /// How many seconds the container may take to be built, e.g., to set up its namespaces,
+    /// before its creation fails and its partial state is removed.
+    /// Defaults to 60.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_timeout_seconds: Option<u64>,
+    /// How many seconds to wait for the image of a container to have a target,
+    /// e.g., when the container is created while the image is still being pulled.
+    /// Defaults to 5.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_target_wait_seconds: Option<u64>,
+    /// Whether to keep the precompiled content of an image when precompiling it again produces different output,
+    /// rather than replacing it.
+    /// Defaults to false.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_existing_precompiled: Option<bool>,
+    /// Whether to compress the precompiled content that is saved to the content store with zstd,
+    /// trading the CPU to decompress it when it is loaded for disk.
+    /// Off by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compress_precompiled: Option<bool>,
+    /// When set, the annotations of a container with this prefix, e.g., `wasm.env/`,
+    /// are passed to the guest as environment variables with the prefix removed.
+    /// Off by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotation_env_prefix: Option<String>,
+    /// Whether to write the modules that a container runs, including precompiled ones,
+    /// to `<root>/<namespace>/.debug/<container id>/` for post-mortem debugging.
+    /// The files are removed when the container is deleted.
+    /// Off by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debug_modules: Option<bool>,
+    /// Whether to write the output of a container to the content store of containerd when the container is deleted,
+    /// labeled with `runwasi.io/logs.stdout` and `runwasi.io/logs.stderr` set to the id of the container.
+    /// The output is kept in `<root>/<namespace>/.logs/<container id>/` while the container runs.
+    /// Off by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub export_logs: Option<bool>,
+    /// Whether to apply the hints in the `runwasi` custom section of the modules, e.g., their default fuel,
+    /// unless the spec of the container overrides them, see [`FUEL_ANNOTATION`](crate::container::FUEL_ANNOTATION).
+    /// The hints are read from the wasm binaries, so they aren't applied to the precompiled modules of an image.
+    /// Off by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module_hints: Option<bool>,
+    /// Whether to connect the guest to a pseudo terminal when the spec of the container sets `process.terminal`,
+    /// for interactive tools that expect one. Its input is read from stdin, and its output written to stdout.
+    /// Containers that ask for a terminal are rejected otherwise.
+    /// Off by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub terminal: Option<bool>,
+    /// What happens to the output of the guest once the reader of its stdout or stderr is closed.
+    /// Defaults to [`ClosedOutput::Error`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closed_output: Option<ClosedOutput>,
+    /// Whether to run containers whose entrypoint is a native executable rather than a wasm module,
+    /// with the linux executor. When off, such containers fail with [`Error::NotWasm`](crate::sandbox::Error::NotWasm),
+    /// except for the sandbox container of a pod, which runs the native pause executable.
+    /// On by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub native_fallback: Option<bool>,
+    /// Whether deleting a container that is still running kills it first,
+    /// rather than failing with [`DeleteFailure::Running`](crate::sandbox::DeleteFailure::Running).
+    /// Off by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub force_delete: Option<bool>,
+    /// How many seconds after a container exits the shim deletes it, when containerd hasn't deleted it by then,
+    /// e.g., because the orchestrator is slow to reap it. The exit status of the container is kept,
+    /// so that containerd can still query and delete it.
+    /// Containers that haven't exited are never deleted. Off by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reap_exited_after_seconds: Option<u64>,
+    /// The default annotations of the containers of each containerd namespace, e.g., a lower `runwasi.io/fuel`
+    /// for the containers of one tenant of the node. The annotations of a container take precedence over them.
+    /// None by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace_annotations: Option<HashMap<String, HashMap<String, String>>>,
+    /// Whether to warm the precompiled modules of an image with [`Engine::warm`](crate::container::Engine::warm)
+    /// when a container is created, so that the first run of the modules doesn't have to load them.
+    /// The containers that run the same precompiled module share it in memory.
+    /// Off by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warm_precompiled: Option<bool>,
+    /// The address of the content store of containerd, and of the leases that protect its content,
+    /// when they are served by another endpoint than the rest of containerd.
+    /// Either the path of a unix socket, or `tcp://host:port`.
+    /// Defaults to the address of containerd.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub containerd_content_address: Option<String>,
+    /// The TLS settings to connect to containerd, when the shim reaches it at a `tcp://` address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub containerd_tls: Option<ContainerdTlsOptions>,
+}
+
+/// What happens to the output of the guest once the reader of its stdout or stderr is closed,
+/// e.g., because a log collector stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClosedOutput {
+    /// The writes of the guest fail with `EPIPE`, which it can handle.
+    /// `SIGPIPE` is ignored, so that it doesn't kill the guest.
+    Error,
+    /// The writes of the guest succeed, and the rest of the output is discarded.
+    Discard,
+}
+
+/// The TLS settings to connect to containerd over TCP.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContainerdTlsOptions {
+    /// A PEM file with the certificate authority that signed the certificate of containerd.
+    /// Defaults to the root certificates of the host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<PathBuf>,
+    /// A PEM file with the client certificate of the shim, if containerd authenticates its clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert_file: Option<PathBuf>,
+    /// A PEM file with the key of the client certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+    /// The name that the certificate of containerd is checked against.
+    /// Defaults to the host of the address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain_name: Option<String>,
+}
+
+/// Reads the `options.json` file of the bundle, if there is one.
+pub fn read_options(bundle: impl AsRef<Path>) -> Result<ShimOptions, Error> {
+    let path = bundle.as_ref().join("options.json");
+    let file = match File::open(&path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(ShimOptions::default()),
+        Err(e) => return Err(e.into()),
+    };
+    serde_json::from_reader(file)
+        .map_err(|err| Error::InvalidArgument(format!("malformed options in {path:?}: {err}")))
 }
 
 pub fn determine_rootdir(
@@ -40,12 +231,7 @@ pub fn determine_rootdir(
     namespace: &str,
     rootdir: impl AsRef<Path>,
 ) -> Result<PathBuf, Error> {
-    let file = match File::open(bundle.as_ref().join("options.json")) {
-        Ok(f) => f,
-        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(rootdir.as_ref().join(namespace)),
-        Err(e) => return Err(e.into()),
-    };
-    let path = serde_json::from_reader::<_, Options>(file)?
+    let path = read_options(bundle)?
         .root
         .unwrap_or_else(|| rootdir.as_ref().to_owned())
         .join(namespace);
@@ -76,8 +262,9 @@ mod tests {
         let namespace = "test_namespace";
         let dir = tempdir()?;
         let rootdir = dir.path().join("runwasi");
-        let opts = Options {
+        let opts = ShimOptions {
             root: Some(rootdir.clone()),
+            ..Default::default()
         };
         std::fs::write(
             dir.path().join("options.json"),
@@ -100,6 +287,64 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_read_options() -> Result<(), Error> {
+        let dir = tempdir()?;
+        // options.json files written by containerd have fields that the shim doesn't use
+        std::fs::write(
+            dir.path().join("options.json"),
+            r#"{
+                "root": "/run/runwasi",
+                "namespace": "k8s.io",
+                "max_precompiled_cache_size": 1048576,
+                "containerd_tls": {"ca_file": "/etc/containerd/ca.pem"},
+                "closed_output": "discard",
+                "namespace_annotations": {"tenant-a": {"runwasi.io/fuel": "1000000"}},
+                "binary_name": "runc",
+                "systemd_cgroup": true
+            }"#,
+        )?;
+        let options = read_options(dir.path())?;
+        assert_eq!(
+            options,
+            ShimOptions {
+                root: Some(PathBuf::from("/run/runwasi")),
+                namespace: Some("k8s.io".to_string()),
+                max_precompiled_cache_size: Some(1048576),
+                closed_output: Some(ClosedOutput::Discard),
+                namespace_annotations: Some(HashMap::from([(
+                    "tenant-a".to_string(),
+                    HashMap::from([("runwasi.io/fuel".to_string(), "1000000".to_string())]),
+                )])),
+                containerd_tls: Some(ContainerdTlsOptions {
+                    ca_file: Some(PathBuf::from("/etc/containerd/ca.pem")),
+                    ..Default::default()
+                }),
+                // the fields that aren't in the file
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_invalid_options() -> Result<(), Error> {
+        let dir = tempdir()?;
+        for options in [
+            r#"{"root": 42}"#,
+            r#"{"max_precompiled_cache_size": -1}"#,
+            "{",
+        ] {
+            std::fs::write(dir.path().join("options.json"), options)?;
+            let err = read_options(dir.path()).unwrap_err();
+            assert!(
+                matches!(&err, Error::InvalidArgument(msg) if msg.contains("options.json")),
+                "{options}: {err}"
+            );
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -3,19 +3,24 @@
 use crate::services::sandbox;
 
 pub mod cli;
+pub mod containerd;
 pub mod error;
 pub mod instance;
 pub mod instance_utils;
 pub mod manager;
+pub mod select;
 pub mod shim;
 pub mod stdio;
 pub mod sync;
 
-pub use error::{Error, Result};
-pub use instance::{Instance, InstanceConfig};
+pub use error::{DeleteFailure, Error, Result};
+pub use instance::{ExitStatus, Instance, InstanceConfig, SuccessExitCodes, TrapReason};
 pub use manager::{Sandbox as SandboxService, Service as ManagerService};
+pub use oci::{
+    ResolvedImage, WasmLayer, REQUIRE_PRECOMPILE_ANNOTATION, SUCCESS_EXIT_CODES_ANNOTATION,
+};
+pub use select::{SelectInstance, ENGINE_ANNOTATION};
 pub use shim::Cli as ShimCli;
 pub use stdio::Stdio;
 
-pub(crate) mod containerd;
 pub(crate) mod oci;
@@ -4,17 +4,323 @@ use std::collections::HashMap;
 use std::io::{ErrorKind, Write};
 #[cfg(unix)]
 use std::os::unix::process::CommandExt;
+use std::path::{Component, PathBuf};
 use std::process;
 
 use anyhow::Context;
-use oci_spec::image::Descriptor;
+use oci_spec::image::{Arch, Descriptor, MediaType, Platform};
+use oci_spec::runtime::Spec;
+use serde::{Deserialize, Serialize};
 
-use super::error::Result;
+use super::error::{Error, Result};
+use super::instance::SuccessExitCodes;
+use crate::container::{WasiVersion, WasmBinaryType};
 
-#[derive(Clone, Debug)]
+/// The media type of OCI layers with a wasm component.
+/// For backwards compatibility, layers of this type can also contain a wasm module.
+pub const WASM_COMPONENT_LAYER_MEDIA_TYPE: &str =
+    "application/vnd.bytecodealliance.wasm.component.layer.v0+wasm";
+
+/// The media type of OCI layers with a wasm module.
+pub const WASM_MODULE_LAYER_MEDIA_TYPE: &str =
+    "application/vnd.bytecodealliance.wasm.module.layer.v0+wasm";
+
+/// Annotation on a layer descriptor to set the WASI version the layer targets,
+/// either `preview1` or `preview2`.
+/// When not set, the version is inferred from whether the layer is a module or a component.
+pub const WASI_VERSION_ANNOTATION: &str = "runwasi.io/wasi-version";
+
+/// Annotation on the manifest of an image to name the function that its containers run, e.g., `main`,
+/// when their runtime spec doesn't name one in its entrypoint.
+/// It takes precedence over the function that the entrypoint of the image config names.
+pub const ENTRYPOINT_ANNOTATION: &str = "runwasi.io/entrypoint";
+
+/// The media type of OCI layers with a data file for the guest, e.g., a model.
+/// The content of the layer is made available at the path in the [`ASSET_PATH_ANNOTATION`]
+/// of the layer descriptor.
+pub const ASSET_LAYER_MEDIA_TYPE: &str = "application/vnd.runwasi.asset.layer.v1";
+
+/// Annotation on the manifest of an image that must only run precompiled, e.g., because its modules are too large
+/// to compile when a container starts. When set to `true`, the containers of the image fail to start on engines
+/// that can't precompile it, with [`Error::PrecompileRequired`], rather than running the wasm layers.
+pub const REQUIRE_PRECOMPILE_ANNOTATION: &str = "runwasi.io/require-precompile";
+
+/// Annotation on an asset layer descriptor with the absolute path of the asset in the container.
+pub const ASSET_PATH_ANNOTATION: &str = "runwasi.io/asset-path";
+
+/// A layer with a data file for the guest.
+/// Only the descriptor is kept, the content is read from the content store when it's needed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssetLayer {
+    pub digest: String,
+    /// The path of the asset, relative to the root of the container.
+    pub path: PathBuf,
+}
+
+impl AssetLayer {
+    /// Returns the asset in the layer, or None if the layer is not an asset layer.
+    pub fn from_descriptor(descriptor: &Descriptor) -> Result<Option<Self>> {
+        if descriptor.media_type().to_string() != ASSET_LAYER_MEDIA_TYPE {
+            return Ok(None);
+        }
+        let path = descriptor
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(ASSET_PATH_ANNOTATION))
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!(
+                    "asset layer {} has no {ASSET_PATH_ANNOTATION} annotation",
+                    descriptor.digest()
+                ))
+            })?;
+        let path = PathBuf::from(path);
+        // the asset must not end up outside of the rootfs
+        if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+            return Err(Error::InvalidArgument(format!(
+                "asset layer {}: invalid path {path:?}",
+                descriptor.digest()
+            )));
+        }
+        Ok(Some(Self {
+            digest: descriptor.digest().clone(),
+            path: path.components().skip(1).collect(),
+        }))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WasmLayer {
     pub config: Descriptor,
+    #[serde(with = "layer_bytes")]
     pub layer: Vec<u8>,
+    /// Whether the layer is a wasm module or a component.
+    /// This is None for layers that are not wasm, e.g., runtime configuration, and for precompiled layers.
+    pub binary_type: Option<WasmBinaryType>,
+    /// The version of WASI the layer targets.
+    /// This is None whenever `binary_type` is None.
+    pub wasi_version: Option<WasiVersion>,
+}
+
+impl WasmLayer {
+    /// Creates a layer for a wasm module or component that doesn't come from an image.
+    pub fn from_module(module: Vec<u8>) -> Self {
+        let binary_type = WasmBinaryType::from_bytes(&module);
+        Self {
+            config: Descriptor::new(MediaType::ImageConfig, 0, ""),
+            binary_type,
+            wasi_version: binary_type.map(WasiVersion::default_for),
+            layer: module,
+        }
+    }
+
+    /// Classifies a layer from the image as a wasm module or a component based on its media type.
+    pub fn classify(media_type: &MediaType, layer: &[u8]) -> Option<WasmBinaryType> {
+        match media_type.to_string().as_str() {
+            WASM_MODULE_LAYER_MEDIA_TYPE => Some(WasmBinaryType::Module),
+            // images built before modules had their own media type use this one for both
+            WASM_COMPONENT_LAYER_MEDIA_TYPE => match WasmBinaryType::from_bytes(layer) {
+                Some(WasmBinaryType::Module) => Some(WasmBinaryType::Module),
+                _ => Some(WasmBinaryType::Component),
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the WASI version that a layer of the given type targets.
+    /// This is the version in the [`WASI_VERSION_ANNOTATION`] of the layer descriptor if any,
+    /// and otherwise the default version for the type of the layer.
+    pub fn wasi_version(
+        descriptor: &Descriptor,
+        binary_type: Option<WasmBinaryType>,
+    ) -> Result<Option<WasiVersion>> {
+        let Some(binary_type) = binary_type else {
+            return Ok(None);
+        };
+        let annotation = descriptor
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(WASI_VERSION_ANNOTATION));
+        let wasi_version = match annotation {
+            Some(version) => version.parse().map_err(|err| {
+                Error::InvalidArgument(format!("layer {}: {err}", descriptor.digest()))
+            })?,
+            None => WasiVersion::default_for(binary_type),
+        };
+        if binary_type == WasmBinaryType::Component && wasi_version == WasiVersion::Preview1 {
+            return Err(Error::InvalidArgument(format!(
+                "layer {}: a component can't target WASI preview 1",
+                descriptor.digest()
+            )));
+        }
+        Ok(Some(wasi_version))
+    }
+}
+
+/// The layers and the platform of an image, as the `load_modules` of the containerd client resolves them,
+/// including the precompiled modules of the engine.
+///
+/// They can be kept in an external store, e.g., keyed by the digest of the image,
+/// and set on the config of later containers of the image with [`InstanceConfig::set_resolved_image`](crate::sandbox::InstanceConfig::set_resolved_image),
+/// so that they run without reading the image from containerd.
+/// The bytes of the layers are serialized as base64 strings by human readable formats, e.g., JSON,
+/// and as raw bytes by binary formats.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ResolvedImage {
+    pub layers: Vec<WasmLayer>,
+    pub platform: Platform,
+}
+
+impl From<(Vec<WasmLayer>, Platform)> for ResolvedImage {
+    fn from((layers, platform): (Vec<WasmLayer>, Platform)) -> Self {
+        Self { layers, platform }
+    }
+}
+
+// (De)serializes the bytes of a layer as base64 in human readable formats, and as raw bytes otherwise,
+// rather than as a sequence of numbers.
+mod layer_bytes {
+    use std::fmt;
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine as _;
+    use serde::de::{Error, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(BytesVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a base64 string or bytes")
+        }
+
+        fn visit_str<E: Error>(self, v: &str) -> Result<Vec<u8>, E> {
+            STANDARD.decode(v).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(v)
+        }
+
+        // for binary formats without a representation of bytes
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+/// Returns whether `arch` is an architecture of wasm images, either `wasm`,
+/// or `wasm32` and `wasm64`, which tell 32-bit images from 64-bit images.
+pub(crate) fn is_wasm_arch(arch: &Arch) -> bool {
+    match arch {
+        Arch::Wasm => true,
+        Arch::Other(arch) => arch == "wasm32" || arch == "wasm64",
+        _ => false,
+    }
+}
+
+/// Returns whether `arch` is the architecture of 64-bit wasm images, whose modules use the memory64 proposal.
+pub(crate) fn is_wasm64(arch: &Arch) -> bool {
+    matches!(arch, Arch::Other(arch) if arch == "wasm64")
+}
+
+/// Annotation on the runtime spec of a container to cap the number of instances of its image
+/// that can run in the shim at the same time.
+pub const MAX_INSTANCES_PER_IMAGE_ANNOTATION: &str = "runwasi.io/max-instances-per-image";
+
+/// Returns the value of the [`MAX_INSTANCES_PER_IMAGE_ANNOTATION`] in the spec, if any.
+pub(crate) fn max_instances_per_image(spec: &Spec) -> Result<Option<usize>> {
+    let Some(value) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(MAX_INSTANCES_PER_IMAGE_ANNOTATION))
+    else {
+        return Ok(None);
+    };
+    let max: usize = value.parse().map_err(|err| {
+        Error::InvalidArgument(format!(
+            "invalid {MAX_INSTANCES_PER_IMAGE_ANNOTATION} annotation {value:?}: {err}"
+        ))
+    })?;
+    Ok(Some(max))
+}
+
+/// Annotation on the runtime spec of a container with the exit codes with which its guest succeeds,
+/// e.g., `0,2` or `0-3`, see [`SuccessExitCodes`].
+pub const SUCCESS_EXIT_CODES_ANNOTATION: &str = "runwasi.io/success-exit-codes";
+
+/// Returns the exit codes in the [`SUCCESS_EXIT_CODES_ANNOTATION`] of the spec, or only 0 if it has none.
+#[cfg_attr(windows, allow(dead_code))]
+pub(crate) fn success_exit_codes(spec: &Spec) -> Result<SuccessExitCodes> {
+    match spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(SUCCESS_EXIT_CODES_ANNOTATION))
+    {
+        Some(codes) => codes.parse(),
+        None => Ok(SuccessExitCodes::default()),
+    }
+}
+
+/// Returns the annotations of the spec with the given prefix as environment variables,
+/// with the prefix removed from their names, e.g., `wasm.env/FOO=bar` becomes `FOO=bar`.
+pub(crate) fn annotation_env(spec: &Spec, prefix: &str) -> Vec<(String, String)> {
+    let Some(annotations) = spec.annotations() else {
+        return vec![];
+    };
+    let mut env: Vec<_> = annotations
+        .iter()
+        .filter_map(|(key, value)| {
+            let name = key.strip_prefix(prefix)?;
+            if name.is_empty() || name.contains('=') {
+                log::warn!("ignoring annotation {key:?}, it isn't a valid environment variable");
+                return None;
+            }
+            Some((name.to_string(), value.clone()))
+        })
+        .collect();
+    env.sort();
+    env
+}
+
+/// Adds the `defaults` to the annotations of the spec, keeping the annotations that the spec already has.
+#[cfg_attr(windows, allow(dead_code))]
+pub(crate) fn apply_default_annotations(spec: &mut Spec, defaults: &HashMap<String, String>) {
+    if defaults.is_empty() {
+        return;
+    }
+    let mut annotations = spec.annotations().clone().unwrap_or_default();
+    for (key, value) in defaults {
+        annotations
+            .entry(key.clone())
+            .or_insert_with(|| value.clone());
+    }
+    spec.set_annotations(Some(annotations));
 }
 
 fn parse_env(envs: &[String]) -> HashMap<String, String> {
@@ -101,3 +407,214 @@ pub(crate) fn setup_prestart_hooks(hooks: &Option<oci_spec::runtime::Hooks>) ->
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::image::{DescriptorBuilder, ImageManifestBuilder};
+
+    use super::*;
+
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0";
+    const COMPONENT: &[u8] = b"\0asm\x0d\0\x01\0";
+
+    #[test]
+    fn test_resolved_image_round_trip() -> anyhow::Result<()> {
+        let precompiled = WasmLayer {
+            config: Descriptor::new(MediaType::ImageConfig, 42, "sha256:config"),
+            layer: b"precompiled".to_vec(),
+            binary_type: None,
+            wasi_version: None,
+        };
+        let resolved = ResolvedImage::from((
+            vec![WasmLayer::from_module(MODULE.to_vec()), precompiled],
+            Platform::default(),
+        ));
+
+        let json = serde_json::to_string(&resolved)?;
+        // the bytes are a base64 string, rather than an array of numbers
+        assert!(json.contains(r#""layer":"AGFzbQEAAAA=""#), "{json}");
+
+        let deserialized: ResolvedImage = serde_json::from_str(&json)?;
+        assert_eq!(deserialized.platform, resolved.platform);
+        assert_eq!(deserialized.layers.len(), 2);
+        for (layer, original) in deserialized.layers.iter().zip(&resolved.layers) {
+            assert_eq!(layer.config, original.config);
+            assert_eq!(layer.layer, original.layer);
+            assert_eq!(layer.binary_type, original.binary_type);
+            assert_eq!(layer.wasi_version, original.wasi_version);
+        }
+        assert_eq!(
+            deserialized.layers[0].binary_type,
+            Some(WasmBinaryType::Module)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_classify_image_with_module_and_component() -> anyhow::Result<()> {
+        let descriptor = |media_type: &str, layer: &[u8]| {
+            DescriptorBuilder::default()
+                .media_type(media_type)
+                .size(layer.len() as i64)
+                .digest(format!("sha256:{}", sha256::digest(layer)))
+                .build()
+        };
+
+        let layers = [
+            (WASM_MODULE_LAYER_MEDIA_TYPE, MODULE),
+            (WASM_COMPONENT_LAYER_MEDIA_TYPE, COMPONENT),
+            (
+                "application/vnd.example.runtime.config.v1+toml",
+                b"".as_slice(),
+            ),
+        ];
+        let manifest = ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(descriptor(
+                "application/vnd.oci.image.config.v1+json",
+                b"{}",
+            )?)
+            .layers(
+                layers
+                    .iter()
+                    .map(|(media_type, layer)| descriptor(media_type, layer))
+                    .collect::<std::result::Result<Vec<_>, _>>()?,
+            )
+            .build()?;
+
+        let binary_types: Vec<_> = manifest
+            .layers()
+            .iter()
+            .zip(layers)
+            .map(|(config, (_, layer))| WasmLayer::classify(config.media_type(), layer))
+            .collect();
+
+        assert_eq!(
+            binary_types,
+            [
+                Some(WasmBinaryType::Module),
+                Some(WasmBinaryType::Component),
+                None
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_classify_module_in_component_layer() {
+        let media_type = MediaType::Other(WASM_COMPONENT_LAYER_MEDIA_TYPE.to_string());
+        assert_eq!(
+            WasmLayer::classify(&media_type, MODULE),
+            Some(WasmBinaryType::Module)
+        );
+    }
+
+    fn annotated_layer(layer: &[u8], wasi_version: Option<&str>) -> anyhow::Result<Descriptor> {
+        let annotations = wasi_version
+            .map(|v| HashMap::from([(WASI_VERSION_ANNOTATION.to_string(), v.to_string())]))
+            .unwrap_or_default();
+        Ok(DescriptorBuilder::default()
+            .media_type(WASM_COMPONENT_LAYER_MEDIA_TYPE)
+            .size(layer.len() as i64)
+            .digest(format!("sha256:{}", sha256::digest(layer)))
+            .annotations(annotations)
+            .build()?)
+    }
+
+    #[test]
+    fn test_wasi_version_per_layer() -> anyhow::Result<()> {
+        let layers = [
+            (annotated_layer(MODULE, None)?, MODULE),
+            (annotated_layer(COMPONENT, None)?, COMPONENT),
+            (annotated_layer(MODULE, Some("preview2"))?, MODULE),
+        ];
+
+        let wasi_versions = layers
+            .iter()
+            .map(|(descriptor, layer)| {
+                let binary_type = WasmLayer::classify(descriptor.media_type(), layer);
+                WasmLayer::wasi_version(descriptor, binary_type)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        assert_eq!(
+            wasi_versions,
+            [
+                Some(WasiVersion::Preview1),
+                Some(WasiVersion::Preview2),
+                Some(WasiVersion::Preview2)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_wasi_version_invalid() -> anyhow::Result<()> {
+        let descriptor = annotated_layer(COMPONENT, Some("preview1"))?;
+        let res = WasmLayer::wasi_version(&descriptor, Some(WasmBinaryType::Component));
+        assert!(matches!(res, Err(Error::InvalidArgument(_))));
+
+        let descriptor = annotated_layer(MODULE, Some("preview3"))?;
+        let res = WasmLayer::wasi_version(&descriptor, Some(WasmBinaryType::Module));
+        assert!(matches!(res, Err(Error::InvalidArgument(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_asset_layer() -> anyhow::Result<()> {
+        let descriptor = |media_type: &str, path: Option<&str>| {
+            let annotations = path
+                .map(|p| HashMap::from([(ASSET_PATH_ANNOTATION.to_string(), p.to_string())]))
+                .unwrap_or_default();
+            DescriptorBuilder::default()
+                .media_type(media_type)
+                .size(5)
+                .digest("sha256:1234")
+                .annotations(annotations)
+                .build()
+        };
+
+        let asset = AssetLayer::from_descriptor(&descriptor(
+            ASSET_LAYER_MEDIA_TYPE,
+            Some("/models/model.bin"),
+        )?)?;
+        assert_eq!(
+            asset,
+            Some(AssetLayer {
+                digest: "sha256:1234".to_string(),
+                path: PathBuf::from("models/model.bin"),
+            })
+        );
+
+        let asset = AssetLayer::from_descriptor(&descriptor(
+            WASM_MODULE_LAYER_MEDIA_TYPE,
+            Some("/models/model.bin"),
+        )?)?;
+        assert_eq!(asset, None);
+
+        for path in [
+            None,
+            Some("models/model.bin"),
+            Some("/models/../../etc/passwd"),
+        ] {
+            let res = AssetLayer::from_descriptor(&descriptor(ASSET_LAYER_MEDIA_TYPE, path)?);
+            assert!(matches!(res, Err(Error::InvalidArgument(_))), "{path:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wasm_arch() {
+        for arch in ["wasm", "wasm32", "wasm64"] {
+            assert!(is_wasm_arch(&Arch::from(arch)), "{arch}");
+        }
+        for arch in ["amd64", "wasm128", ""] {
+            assert!(!is_wasm_arch(&Arch::from(arch)), "{arch}");
+        }
+
+        assert!(is_wasm64(&Arch::from("wasm64")));
+        assert!(!is_wasm64(&Arch::from("wasm32")));
+        assert!(!is_wasm64(&Arch::Wasm));
+    }
+}
@@ -0,0 +1,243 @@
+//! Selection between the engines of a shim that embeds more than one.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use super::instance::{ExitStatus, SuccessExitCodes};
+use super::{Error, Instance, InstanceConfig, Result};
+use crate::container::Capabilities;
+
+/// Annotation on the runtime spec of a container with the name of the engine to run it with,
+/// for shims that embed more than one engine.
+/// By default the container runs with the first engine of the shim.
+pub const ENGINE_ANNOTATION: &str = "runwasi.io/engine";
+
+/// An instance that runs with either of two engines, selected by the `runwasi.io/engine`
+/// annotation of the container.
+///
+/// Containers without the annotation run with `A`, the default engine.
+/// Nest selectors to embed more than two engines, e.g., `SelectInstance<A, SelectInstance<B, C>>`.
+pub enum SelectInstance<A: Instance, B: Instance> {
+    First(A),
+    Second(B),
+}
+
+impl<A: Instance, B: Instance> Instance for SelectInstance<A, B> {
+    type Engine = (A::Engine, B::Engine);
+
+    fn new(id: String, cfg: Option<&InstanceConfig<Self::Engine>>) -> Result<Self> {
+        let Some(cfg) = cfg else {
+            return Ok(Self::First(A::new(id, None)?));
+        };
+        let (first, second) = cfg.get_engine();
+        match cfg.get_annotations().get(ENGINE_ANNOTATION) {
+            None => Ok(Self::First(A::new(id, Some(&cfg.with_engine(first)))?)),
+            Some(name) if A::engine_names().contains(&name.as_str()) => {
+                Ok(Self::First(A::new(id, Some(&cfg.with_engine(first)))?))
+            }
+            Some(name) if B::engine_names().contains(&name.as_str()) => {
+                Ok(Self::Second(B::new(id, Some(&cfg.with_engine(second)))?))
+            }
+            Some(name) => Err(Error::InvalidArgument(format!(
+                "unknown engine {name:?} in {ENGINE_ANNOTATION} annotation, expected one of {:?}",
+                Self::engine_names()
+            ))),
+        }
+    }
+
+    fn start(&self) -> Result<u32> {
+        match self {
+            Self::First(i) => i.start(),
+            Self::Second(i) => i.start(),
+        }
+    }
+
+    fn kill(&self, signal: u32) -> Result<()> {
+        match self {
+            Self::First(i) => i.kill(signal),
+            Self::Second(i) => i.kill(signal),
+        }
+    }
+
+    fn stop_signal(&self) -> u32 {
+        match self {
+            Self::First(i) => i.stop_signal(),
+            Self::Second(i) => i.stop_signal(),
+        }
+    }
+
+    fn stop(&self, timeout: Duration) -> Result<()> {
+        match self {
+            Self::First(i) => i.stop(timeout),
+            Self::Second(i) => i.stop(timeout),
+        }
+    }
+
+    fn delete(&self) -> Result<()> {
+        match self {
+            Self::First(i) => i.delete(),
+            Self::Second(i) => i.delete(),
+        }
+    }
+
+    fn wait(&self) -> (u32, DateTime<Utc>) {
+        match self {
+            Self::First(i) => i.wait(),
+            Self::Second(i) => i.wait(),
+        }
+    }
+
+    fn wait_timeout(&self, t: impl Into<Option<Duration>>) -> Option<(u32, DateTime<Utc>)> {
+        match self {
+            Self::First(i) => i.wait_timeout(t),
+            Self::Second(i) => i.wait_timeout(t),
+        }
+    }
+
+    // the sandbox container has no annotations, so it runs with the default engine
+    fn supports_pod_sandbox() -> bool {
+        A::supports_pod_sandbox()
+    }
+
+    fn engine_names() -> Vec<&'static str> {
+        let mut names = A::engine_names();
+        names.extend(B::engine_names());
+        names
+    }
+
+    fn engine_version() -> Option<String> {
+        match (A::engine_version(), B::engine_version()) {
+            (Some(first), Some(second)) => Some(format!("{first}, {second}")),
+            (first, second) => first.or(second),
+        }
+    }
+
+    // the capabilities are those of a single engine, so they are the ones of the default engine
+    fn capabilities((first, second): &Self::Engine) -> Option<Capabilities> {
+        A::capabilities(first).or_else(|| B::capabilities(second))
+    }
+
+    fn image_digest(&self) -> Option<String> {
+        match self {
+            Self::First(i) => i.image_digest(),
+            Self::Second(i) => i.image_digest(),
+        }
+    }
+
+    fn exit_status(&self) -> Option<ExitStatus> {
+        match self {
+            Self::First(i) => i.exit_status(),
+            Self::Second(i) => i.exit_status(),
+        }
+    }
+
+    fn success_exit_codes(&self) -> SuccessExitCodes {
+        match self {
+            Self::First(i) => i.success_exit_codes(),
+            Self::Second(i) => i.success_exit_codes(),
+        }
+    }
+
+    fn exit_succeeded(&self) -> Option<bool> {
+        match self {
+            Self::First(i) => i.exit_succeeded(),
+            Self::Second(i) => i.exit_succeeded(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    // an instance that does nothing, for an engine named after `NAME`
+    struct NamedInstance<const NAME: char>;
+
+    impl<const NAME: char> Instance for NamedInstance<NAME> {
+        type Engine = ();
+
+        fn new(_id: String, _cfg: Option<&InstanceConfig<Self::Engine>>) -> Result<Self> {
+            Ok(Self)
+        }
+        fn start(&self) -> Result<u32> {
+            Ok(0)
+        }
+        fn kill(&self, _signal: u32) -> Result<()> {
+            Ok(())
+        }
+        // a signal of its own for each engine
+        fn stop_signal(&self) -> u32 {
+            NAME as u32
+        }
+        fn delete(&self) -> Result<()> {
+            Ok(())
+        }
+        fn wait_timeout(&self, _t: impl Into<Option<Duration>>) -> Option<(u32, DateTime<Utc>)> {
+            None
+        }
+        // only the engine `a` succeeds, whatever its exit status
+        fn exit_succeeded(&self) -> Option<bool> {
+            Some(NAME == 'a')
+        }
+        fn engine_names() -> Vec<&'static str> {
+            match NAME {
+                'a' => vec!["a"],
+                'b' => vec!["b"],
+                _ => vec!["c"],
+            }
+        }
+    }
+
+    type Selector =
+        SelectInstance<NamedInstance<'a'>, SelectInstance<NamedInstance<'b'>, NamedInstance<'c'>>>;
+
+    fn select(engine: Option<&str>) -> Result<Selector> {
+        let mut cfg = InstanceConfig::new(((), ((), ())), "test-ns", "/dev/null");
+        let annotations = engine
+            .map(|name| HashMap::from([(ENGINE_ANNOTATION.to_string(), name.to_string())]))
+            .unwrap_or_default();
+        cfg.set_annotations(annotations);
+        Selector::new("test".to_string(), Some(&cfg))
+    }
+
+    #[test]
+    fn test_select_engine() -> Result<()> {
+        assert_eq!(Selector::engine_names(), vec!["a", "b", "c"]);
+
+        assert!(matches!(select(None)?, SelectInstance::First(_)));
+        assert!(matches!(select(Some("a"))?, SelectInstance::First(_)));
+        assert!(matches!(
+            select(Some("b"))?,
+            SelectInstance::Second(SelectInstance::First(_))
+        ));
+        assert!(matches!(
+            select(Some("c"))?,
+            SelectInstance::Second(SelectInstance::Second(_))
+        ));
+
+        let Err(err) = select(Some("d")) else {
+            panic!("an unknown engine should be rejected");
+        };
+        assert!(matches!(err, Error::InvalidArgument(_)), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_stop_signal() -> Result<()> {
+        assert_eq!(select(None)?.stop_signal(), 'a' as u32);
+        assert_eq!(select(Some("b"))?.stop_signal(), 'b' as u32);
+        assert_eq!(select(Some("c"))?.stop_signal(), 'c' as u32);
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_exit_succeeded() -> Result<()> {
+        assert_eq!(select(None)?.exit_succeeded(), Some(true));
+        assert_eq!(select(Some("b"))?.exit_succeeded(), Some(false));
+        assert_eq!(select(Some("c"))?.exit_succeeded(), Some(false));
+        Ok(())
+    }
+}
@@ -12,6 +12,8 @@ use shim::Flags;
 use crate::sandbox::instance::Instance;
 use crate::sandbox::shim::events::{RemoteEventSender, ToTimestamp};
 use crate::sandbox::shim::local::Local;
+#[cfg(unix)]
+use crate::sandbox::shim::logger;
 use crate::sys::networking::setup_namespaces;
 
 /// Cli implements the containerd-shim cli interface using `Local<T>` as the task service.
@@ -30,7 +32,18 @@ where
 {
     type T = Local<I>;
 
-    fn new(_runtime_id: &str, args: &Flags, _config: &mut shim::Config) -> Self {
+    #[cfg_attr(windows, allow(unused_variables))]
+    fn new(_runtime_id: &str, args: &Flags, config: &mut shim::Config) -> Self {
+        // the shim serves the tasks of its containers unless it is started with an action,
+        // and logs their records at the level of their annotation then
+        #[cfg(unix)]
+        if args.action.is_empty() && !config.no_setup_logger {
+            match logger::init(args.debug) {
+                Ok(()) => config.no_setup_logger = true,
+                Err(err) => eprintln!("failed to set up the logger of the shim: {err}"),
+            }
+        }
+
         Cli {
             engine: Default::default(),
             namespace: args.namespace.to_string(),
@@ -2,6 +2,7 @@ use std::sync::{Arc, OnceLock, RwLock};
 use std::time::Duration;
 
 use chrono::{DateTime, Utc};
+use containerd_shim::protos::types::task::Status;
 
 use crate::sandbox::instance::Nop;
 use crate::sandbox::shim::instance_option::InstanceOption;
@@ -12,6 +13,7 @@ pub(super) struct InstanceData<T: Instance> {
     pub instance: InstanceOption<T>,
     cfg: InstanceConfig<T::Engine>,
     pid: OnceLock<u32>,
+    started_at: OnceLock<DateTime<Utc>>,
     state: Arc<RwLock<TaskState>>,
 }
 
@@ -23,6 +25,7 @@ impl<T: Instance> InstanceData<T> {
             instance,
             cfg,
             pid: OnceLock::default(),
+            started_at: OnceLock::default(),
             state: Arc::new(RwLock::new(TaskState::Created)),
         })
     }
@@ -34,6 +37,7 @@ impl<T: Instance> InstanceData<T> {
             instance,
             cfg,
             pid: OnceLock::default(),
+            started_at: OnceLock::default(),
             state: Arc::new(RwLock::new(TaskState::Created)),
         })
     }
@@ -42,10 +46,24 @@ impl<T: Instance> InstanceData<T> {
         self.pid.get().copied()
     }
 
+    pub fn started_at(&self) -> Option<DateTime<Utc>> {
+        self.started_at.get().copied()
+    }
+
     pub fn config(&self) -> &InstanceConfig<T::Engine> {
         &self.cfg
     }
 
+    pub fn status(&self) -> Status {
+        if self.pid().is_none() {
+            Status::CREATED
+        } else if self.wait_timeout(Duration::ZERO).is_none() {
+            Status::RUNNING
+        } else {
+            Status::STOPPED
+        }
+    }
+
     pub fn start(&self) -> Result<u32> {
         let mut s = self.state.write().unwrap();
         s.start()?;
@@ -57,6 +75,7 @@ impl<T: Instance> InstanceData<T> {
         let _ = match res {
             Ok(pid) => {
                 let _ = self.pid.set(pid);
+                let _ = self.started_at.set(Utc::now());
                 s.started()
             }
             Err(_) => s.stop(),
@@ -2,7 +2,8 @@ use std::time::Duration;
 
 use chrono::{DateTime, Utc};
 
-use crate::sandbox::instance::Nop;
+use crate::container::Capabilities;
+use crate::sandbox::instance::{ExitStatus, Nop, SuccessExitCodes};
 use crate::sandbox::{Instance, InstanceConfig, Result};
 
 pub(super) enum InstanceOption<I: Instance> {
@@ -11,11 +12,11 @@ pub(super) enum InstanceOption<I: Instance> {
 }
 
 impl<I: Instance> Instance for InstanceOption<I> {
-    type Engine = ();
+    type Engine = I::Engine;
 
-    fn new(_id: String, _cfg: Option<&InstanceConfig<Self::Engine>>) -> Result<Self> {
-        // this is never called
-        unimplemented!();
+    // the Nop instance of the sandbox container is created on its own, see `InstanceData::new_base`
+    fn new(id: String, cfg: Option<&InstanceConfig<Self::Engine>>) -> Result<Self> {
+        Ok(Self::Instance(I::new(id, cfg)?))
     }
 
     fn start(&self) -> Result<u32> {
@@ -32,6 +33,20 @@ impl<I: Instance> Instance for InstanceOption<I> {
         }
     }
 
+    fn stop_signal(&self) -> u32 {
+        match self {
+            Self::Instance(i) => i.stop_signal(),
+            Self::Nop(i) => i.stop_signal(),
+        }
+    }
+
+    fn stop(&self, timeout: Duration) -> Result<()> {
+        match self {
+            Self::Instance(i) => i.stop(timeout),
+            Self::Nop(i) => i.stop(timeout),
+        }
+    }
+
     fn delete(&self) -> Result<()> {
         match self {
             Self::Instance(i) => i.delete(),
@@ -39,10 +54,61 @@ impl<I: Instance> Instance for InstanceOption<I> {
         }
     }
 
+    fn wait(&self) -> (u32, DateTime<Utc>) {
+        match self {
+            Self::Instance(i) => i.wait(),
+            Self::Nop(i) => i.wait(),
+        }
+    }
+
     fn wait_timeout(&self, t: impl Into<Option<Duration>>) -> Option<(u32, DateTime<Utc>)> {
         match self {
             Self::Instance(i) => i.wait_timeout(t),
             Self::Nop(i) => i.wait_timeout(t),
         }
     }
+
+    fn supports_pod_sandbox() -> bool {
+        I::supports_pod_sandbox()
+    }
+
+    fn engine_names() -> Vec<&'static str> {
+        I::engine_names()
+    }
+
+    fn engine_version() -> Option<String> {
+        I::engine_version()
+    }
+
+    fn capabilities(engine: &Self::Engine) -> Option<Capabilities> {
+        I::capabilities(engine)
+    }
+
+    fn image_digest(&self) -> Option<String> {
+        match self {
+            Self::Instance(i) => i.image_digest(),
+            Self::Nop(i) => i.image_digest(),
+        }
+    }
+
+    fn exit_status(&self) -> Option<ExitStatus> {
+        match self {
+            Self::Instance(i) => i.exit_status(),
+            Self::Nop(i) => i.exit_status(),
+        }
+    }
+
+    fn success_exit_codes(&self) -> SuccessExitCodes {
+        match self {
+            Self::Instance(i) => i.success_exit_codes(),
+            Self::Nop(i) => i.success_exit_codes(),
+        }
+    }
+
+    fn exit_succeeded(&self) -> Option<bool> {
+        match self {
+            Self::Instance(i) => i.exit_succeeded(),
+            Self::Nop(i) => i.exit_succeeded(),
+        }
+    }
 }
@@ -2,11 +2,12 @@ use std::collections::HashMap;
 use std::fs::create_dir_all;
 use std::ops::Not;
 use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time::Duration;
 
 use anyhow::Context as AnyhowContext;
+use chrono::{DateTime, Utc};
 use containerd_shim::api::{
     ConnectRequest, ConnectResponse, CreateTaskRequest, CreateTaskResponse, DeleteRequest, Empty,
     KillRequest, ShutdownRequest, StartRequest, StartResponse, StateRequest, StateResponse,
@@ -23,8 +24,10 @@ use log::debug;
 use oci_spec::runtime::Spec;
 
 use crate::sandbox::instance::{Instance, InstanceConfig};
+use crate::sandbox::instance_utils::read_options;
 use crate::sandbox::shim::events::{EventSender, RemoteEventSender, ToTimestamp};
 use crate::sandbox::shim::instance_data::InstanceData;
+use crate::sandbox::shim::logger;
 use crate::sandbox::{oci, Error, Result, SandboxService};
 use crate::sys::metrics::get_metrics;
 
@@ -32,12 +35,43 @@ use crate::sys::metrics::get_metrics;
 mod tests;
 
 type LocalInstances<T> = RwLock<HashMap<String, Arc<InstanceData<T>>>>;
+type ReapedInstances = Mutex<HashMap<String, ReapedInstance>>;
+
+/// An instance that the shim deleted after it exited, because containerd didn't delete it in time,
+/// see [`ShimOptions::reap_exited_after_seconds`](crate::sandbox::instance_utils::ShimOptions::reap_exited_after_seconds).
+/// It is kept until containerd deletes it, so that its state can still be queried.
+#[derive(Debug, Clone)]
+struct ReapedInstance {
+    pid: u32,
+    exit_status: u32,
+    exited_at: DateTime<Utc>,
+    bundle: String,
+    stdin: String,
+    stdout: String,
+    stderr: String,
+}
+
+/// A summary of an instance tracked by the shim.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceSummary {
+    pub id: String,
+    pub status: Status,
+    /// The digest of the image manifest, if the instance was created from an OCI image.
+    pub image_digest: Option<String>,
+    /// When the instance was started, or None if it hasn't been started.
+    pub started_at: Option<DateTime<Utc>>,
+}
 
 /// Local implements the Task service for a containerd shim.
 /// It defers all task operations to the `Instance` implementation.
 pub struct Local<T: Instance + Send + Sync, E: EventSender = RemoteEventSender> {
     pub engine: T::Engine,
-    pub(super) instances: LocalInstances<T>,
+    pub(super) instances: Arc<LocalInstances<T>>,
+    reaped: Arc<ReapedInstances>,
+    // serializes starting instances, so that concurrent starts can't go over the per-image cap
+    start_lock: Mutex<()>,
+    // how long a second of `reap_exited_after_seconds` lasts, which tests shorten
+    reap_tick: Duration,
     events: E,
     exit: Arc<ExitSignal>,
     namespace: String,
@@ -53,12 +87,15 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         namespace: impl AsRef<str>,
         containerd_address: impl AsRef<str>,
     ) -> Self {
-        let instances = RwLock::default();
+        let instances = Arc::default();
         let namespace = namespace.as_ref().to_string();
         let containerd_address = containerd_address.as_ref().to_string();
         Self {
             engine,
             instances,
+            reaped: Arc::default(),
+            start_lock: Mutex::default(),
+            reap_tick: Duration::from_secs(1),
             events,
             exit,
             namespace,
@@ -71,6 +108,11 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         instance.ok_or_else(|| Error::NotFound(id.to_string()))
     }
 
+    // Returns the instance that the shim deleted after it exited, if containerd hasn't deleted it since.
+    fn get_reaped(&self, id: &str) -> Option<ReapedInstance> {
+        self.reaped.lock().unwrap().get(id).cloned()
+    }
+
     fn has_instance(&self, id: &str) -> bool {
         self.instances.read().unwrap().contains_key(id)
     }
@@ -79,6 +121,47 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         self.instances.read().unwrap().is_empty()
     }
 
+    /// Lists the instances that have been created and not yet deleted, sorted by id.
+    pub fn list_instances(&self) -> Vec<InstanceSummary> {
+        let mut instances: Vec<_> = self
+            .instances
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, i)| InstanceSummary {
+                id: id.clone(),
+                status: i.status(),
+                image_digest: i.instance.image_digest(),
+                started_at: i.started_at(),
+            })
+            .collect();
+        instances.sort_by(|a, b| a.id.cmp(&b.id));
+        instances
+    }
+
+    // Fails if starting the instance would go over the cap on running instances of its image.
+    fn check_image_instances(&self, id: &str, i: &InstanceData<T>) -> Result<()> {
+        let spec = Spec::load(i.config().get_bundle().join("config.json"))?;
+        let (Some(max), Some(digest)) = (
+            oci::max_instances_per_image(&spec)?,
+            i.instance.image_digest(),
+        ) else {
+            return Ok(());
+        };
+        let running = self
+            .list_instances()
+            .into_iter()
+            .filter(|s| s.id != id && s.status == Status::RUNNING)
+            .filter(|s| s.image_digest.as_ref() == Some(&digest))
+            .count();
+        if running >= max {
+            return Err(Error::ResourceExhausted(format!(
+                "image {digest} already has {running} running instances, the maximum is {max}"
+            )));
+        }
+        Ok(())
+    }
+
     fn instance_config(&self) -> InstanceConfig<T::Engine> {
         InstanceConfig::new(
             self.engine.clone(),
@@ -88,6 +171,69 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
     }
 }
 
+// Deletes an instance that exited, unless containerd deleted it first, or created another instance with its id,
+// and keeps its exit status for containerd to query.
+fn reap_instance<T: Instance>(
+    instances: &LocalInstances<T>,
+    reaped: &ReapedInstances,
+    events: &impl EventSender,
+    id: &str,
+    i: &Arc<InstanceData<T>>,
+) {
+    // The instance is moved to the reaped instances under the lock, so that containerd can't delete it
+    // at the same time, and can query its exit status while it is deleted.
+    // It is deleted without the lock, so that the other tasks don't wait for its teardown.
+    let pid = i.pid().unwrap_or_default();
+    let (exit_status, exited_at) = {
+        let mut instances = instances.write().unwrap();
+        if !instances
+            .get(id)
+            .is_some_and(|current| Arc::ptr_eq(current, i))
+        {
+            return;
+        }
+        let Some((exit_status, exited_at)) = i.wait_timeout(Duration::ZERO) else {
+            return;
+        };
+        instances.remove(id);
+        let cfg = i.config();
+        reaped.lock().unwrap().insert(
+            id.to_string(),
+            ReapedInstance {
+                pid,
+                exit_status,
+                exited_at,
+                bundle: cfg.get_bundle().to_string_lossy().to_string(),
+                stdin: cfg.get_stdin().to_string_lossy().to_string(),
+                stdout: cfg.get_stdout().to_string_lossy().to_string(),
+                stderr: cfg.get_stderr().to_string_lossy().to_string(),
+            },
+        );
+        (exit_status, exited_at)
+    };
+
+    if let Err(err) = i.delete() {
+        log::warn!("failed to reap exited instance {id}: {err}");
+        // the instance is tracked again, so that containerd can still delete it,
+        // unless containerd deleted the reaped instance, or created another one with its id, in the meantime
+        let mut instances = instances.write().unwrap();
+        if reaped.lock().unwrap().remove(id).is_some() {
+            instances.entry(id.to_string()).or_insert_with(|| i.clone());
+        }
+        return;
+    }
+    logger::clear_level(id);
+
+    events.send(TaskDelete {
+        container_id: id.to_string(),
+        pid,
+        exit_status,
+        exited_at: Some(exited_at.to_timestamp()).into(),
+        ..Default::default()
+    });
+    log::info!("reaped instance {id}, which exited at {exited_at}");
+}
+
 fn is_cri_container(spec: &Spec) -> bool {
     spec.annotations()
         .as_ref()
@@ -97,10 +243,13 @@ fn is_cri_container(spec: &Spec) -> bool {
 // These are the same functions as in Task, but without the TtrcpContext, which is useful for testing
 impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
     fn task_create(&self, req: CreateTaskRequest) -> Result<CreateTaskResponse> {
+        let _scope = logger::scope(req.id());
         if !req.checkpoint().is_empty() || !req.parent_checkpoint().is_empty() {
             return Err(ShimError::Unimplemented("checkpoint is not supported".to_string()).into());
         }
 
+        // the instance connects the guest to a terminal on unix, if the shim is configured to
+        #[cfg(not(unix))]
         if req.terminal {
             return Err(Error::InvalidArgument(
                 "terminal is not supported".to_string(),
@@ -147,19 +296,31 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             .set_stdout(&req.stdout)
             .set_stderr(&req.stderr);
 
+        // the records of the container are logged at its level from its creation until it is deleted
+        if let Some(level) = logger::log_level(&spec)? {
+            logger::set_level(req.id(), level);
+        }
+
         // Check if this is a cri container
-        let instance = if self.is_empty() && is_cri_container(&spec) {
-            // If it is cri, then this is the "pause" container, which we don't need to deal with.
-            // TODO: maybe we can just go ahead and execute the actual container with runc?
-            InstanceData::new_base(req.id(), cfg)?
+        let instance = if self.is_empty() && is_cri_container(&spec) && !T::supports_pod_sandbox() {
+            // If it is cri, then this is the "pause" container.
+            // The instance can't run it, so the containers of the pod share the namespaces of the shim.
+            InstanceData::new_base(req.id(), cfg)
         } else {
-            InstanceData::new_instance(req.id(), cfg)?
+            // Otherwise the "pause" container is run like any other container, so that it holds
+            // the namespaces of the pod, which the other containers join through their runtime spec.
+            InstanceData::new_instance(req.id(), cfg)
         };
+        let instance = instance.map_err(|err| {
+            logger::clear_level(req.id());
+            err
+        })?;
 
         self.instances
             .write()
             .unwrap()
             .insert(req.id().to_string(), Arc::new(instance));
+        self.reaped.lock().unwrap().remove(req.id());
 
         self.events.send(TaskCreate {
             container_id: req.id,
@@ -192,8 +353,19 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             return Err(ShimError::Unimplemented("exec is not supported".to_string()).into());
         }
 
+        let _scope = logger::scope(req.id());
         let i = self.get_instance(req.id())?;
-        let pid = i.start()?;
+        let reap_after = read_options(i.config().get_bundle())?
+            .reap_exited_after_seconds
+            .map(|seconds| {
+                self.reap_tick
+                    .saturating_mul(seconds.try_into().unwrap_or(u32::MAX))
+            });
+        let pid = {
+            let _guard = self.start_lock.lock().unwrap();
+            self.check_image_instances(req.id(), &i)?;
+            i.start()?
+        };
 
         self.events.send(TaskStart {
             container_id: req.id().into(),
@@ -202,21 +374,28 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         });
 
         let events = self.events.clone();
+        let instances = self.instances.clone();
+        let reaped = self.reaped.clone();
 
         let id = req.id().to_string();
 
         thread::Builder::new()
             .name(format!("{id}-wait"))
             .spawn(move || {
+                let _scope = logger::scope(&id);
                 let (exit_code, timestamp) = i.wait();
                 events.send(TaskExit {
                     container_id: id.clone(),
                     exit_status: exit_code,
                     exited_at: Some(timestamp.to_timestamp()).into(),
                     pid,
-                    id,
+                    id: id.clone(),
                     ..Default::default()
                 });
+                if let Some(reap_after) = reap_after {
+                    thread::sleep(reap_after);
+                    reap_instance(&instances, &reaped, &events, &id, &i);
+                }
             })
             .context("could not spawn thread to wait exit")
             .map_err(Error::from)?;
@@ -233,6 +412,7 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         if !req.exec_id().is_empty() {
             return Err(Error::InvalidArgument("exec is not supported".to_string()));
         }
+        let _scope = logger::scope(req.id());
         self.get_instance(req.id())?.kill(req.signal())?;
         Ok(Empty::new())
     }
@@ -242,9 +422,23 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             return Err(Error::InvalidArgument("exec is not supported".to_string()));
         }
 
-        let i = self.get_instance(req.id())?;
+        let _scope = logger::scope(req.id());
+        let i = match self.get_instance(req.id()) {
+            Ok(i) => i,
+            Err(err) => {
+                // the shim deleted the instance already, and sent its delete event then
+                let reaped = self.reaped.lock().unwrap().remove(req.id()).ok_or(err)?;
+                return Ok(DeleteResponse {
+                    pid: reaped.pid,
+                    exit_status: reaped.exit_status,
+                    exited_at: Some(reaped.exited_at.to_timestamp()).into(),
+                    ..Default::default()
+                });
+            }
+        };
 
         i.delete()?;
+        logger::clear_level(req.id());
 
         let pid = i.pid().unwrap_or_default();
         let (exit_code, timestamp) = i.wait_timeout(Duration::ZERO).unzip();
@@ -273,7 +467,17 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             return Err(Error::InvalidArgument("exec is not supported".to_string()));
         }
 
-        let i = self.get_instance(req.id())?;
+        let i = match self.get_instance(req.id()) {
+            Ok(i) => i,
+            Err(err) => {
+                let reaped = self.get_reaped(req.id()).ok_or(err)?;
+                return Ok(WaitResponse {
+                    exit_status: reaped.exit_status,
+                    exited_at: Some(reaped.exited_at.to_timestamp()).into(),
+                    ..Default::default()
+                });
+            }
+        };
         let (exit_code, timestamp) = i.wait();
 
         Ok(WaitResponse {
@@ -288,18 +492,27 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             return Err(Error::InvalidArgument("exec is not supported".to_string()));
         }
 
-        let i = self.get_instance(req.id())?;
+        let i = match self.get_instance(req.id()) {
+            Ok(i) => i,
+            Err(err) => {
+                let reaped = self.get_reaped(req.id()).ok_or(err)?;
+                return Ok(StateResponse {
+                    bundle: reaped.bundle,
+                    stdin: reaped.stdin,
+                    stdout: reaped.stdout,
+                    stderr: reaped.stderr,
+                    pid: reaped.pid,
+                    exit_status: reaped.exit_status,
+                    exited_at: Some(reaped.exited_at.to_timestamp()).into(),
+                    status: Status::STOPPED.into(),
+                    ..Default::default()
+                });
+            }
+        };
         let pid = i.pid();
         let (exit_code, timestamp) = i.wait_timeout(Duration::ZERO).unzip();
         let timestamp = timestamp.map(ToTimestamp::to_timestamp);
-
-        let status = if pid.is_none() {
-            Status::CREATED
-        } else if exit_code.is_none() {
-            Status::RUNNING
-        } else {
-            Status::STOPPED
-        };
+        let status = i.status();
 
         Ok(StateResponse {
             bundle: i.config().get_bundle().to_string_lossy().to_string(),
@@ -391,6 +604,8 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         debug!("shutdown");
         if self.is_empty() {
             self.exit.signal();
+        } else {
+            debug!("instances still present: {:?}", self.list_instances());
         }
         Ok(Empty::new())
     }
@@ -1,5 +1,6 @@
 use std::fs::{create_dir, File};
 use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
 
@@ -39,7 +40,10 @@ impl<T: Instance + Send + Sync, E: EventSender> Drop for LocalWithDescrutor<T, E
             .unwrap()
             .iter()
             .for_each(|(_, v)| {
-                let _ = v.kill(9);
+                // a killed instance can only be deleted once it has exited
+                if v.kill(9).is_ok() {
+                    v.wait();
+                }
                 v.delete().unwrap();
             });
     }
@@ -276,6 +280,82 @@ fn test_cri_task() -> Result<()> {
     Ok(())
 }
 
+// A no-op instance that can run the sandbox container of a pod, unlike `Nop`.
+struct PodSandboxNop(Nop);
+
+impl Instance for PodSandboxNop {
+    type Engine = ();
+    fn new(id: String, cfg: Option<&InstanceConfig<Self::Engine>>) -> Result<Self> {
+        Ok(Self(Nop::new(id, cfg)?))
+    }
+    fn start(&self) -> Result<u32> {
+        self.0.start()
+    }
+    fn kill(&self, signal: u32) -> Result<()> {
+        self.0.kill(signal)
+    }
+    fn delete(&self) -> Result<()> {
+        self.0.delete()
+    }
+    fn wait_timeout(&self, t: impl Into<Option<Duration>>) -> Option<(u32, DateTime<Utc>)> {
+        self.0.wait_timeout(t)
+    }
+    fn supports_pod_sandbox() -> bool {
+        true
+    }
+}
+
+#[test]
+fn test_cri_task_with_pod_sandbox() -> Result<()> {
+    // An instance that supports it runs the sandbox container, so that it holds the namespaces of the pod.
+    let (etx, _erx) = channel();
+    let local = Arc::new(Local::<PodSandboxNop, _>::new(
+        (),
+        etx,
+        Arc::new(ExitSignal::default()),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDescrutor::new(local.clone());
+
+    let sandbox_id = "test-cri-task-with-pod-sandbox".to_string();
+    let temp = tempdir().unwrap();
+    create_bundle(
+        temp.path(),
+        Some(with_cri_sandbox(None, sandbox_id.clone())),
+    )?;
+    local.task_create(CreateTaskRequest {
+        id: "testbase".to_string(),
+        bundle: temp.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    })?;
+    let i = local.get_instance("testbase")?;
+    assert!(matches!(i.instance, InstanceOption::Instance(_)));
+
+    // the other containers of the pod, and the containers outside of any pod, are run as usual
+    let temp2 = tempdir().unwrap();
+    create_bundle(temp2.path(), Some(with_cri_sandbox(None, sandbox_id)))?;
+    local.task_create(CreateTaskRequest {
+        id: "testinstance".to_string(),
+        bundle: temp2.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    })?;
+    let i = local.get_instance("testinstance")?;
+    assert!(matches!(i.instance, InstanceOption::Instance(_)));
+
+    let temp3 = tempdir().unwrap();
+    create_bundle(temp3.path(), None)?;
+    local.task_create(CreateTaskRequest {
+        id: "testnonsandbox".to_string(),
+        bundle: temp3.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    })?;
+    let i = local.get_instance("testnonsandbox")?;
+    assert!(matches!(i.instance, InstanceOption::Instance(_)));
+
+    Ok(())
+}
+
 #[test]
 fn test_task_lifecycle() -> Result<()> {
     let (etx, _erx) = channel(); // TODO: check events
@@ -392,3 +472,364 @@ fn test_task_lifecycle() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_list_instances() -> Result<()> {
+    let (etx, _erx) = channel();
+    let local = Arc::new(Local::<Nop, _>::new(
+        (),
+        etx,
+        Arc::new(ExitSignal::default()),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDescrutor::new(local.clone());
+
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    create_bundle(dir, None)?;
+
+    assert_eq!(local.list_instances(), vec![]);
+
+    for id in ["test-b", "test-a"] {
+        local.task_create(CreateTaskRequest {
+            id: id.to_string(),
+            bundle: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        })?;
+    }
+
+    let summaries = local.list_instances();
+    let ids: Vec<_> = summaries.iter().map(|s| s.id.as_str()).collect();
+    assert_eq!(ids, ["test-a", "test-b"]);
+    assert!(summaries.iter().all(|s| s.status == Status::CREATED));
+    assert!(summaries.iter().all(|s| s.started_at.is_none()));
+    assert!(summaries.iter().all(|s| s.image_digest.is_none()));
+
+    local.task_start(StartRequest {
+        id: "test-a".to_string(),
+        ..Default::default()
+    })?;
+
+    let summaries = local.list_instances();
+    assert_eq!(summaries[0].status, Status::RUNNING);
+    assert!(summaries[0].started_at.is_some());
+    assert_eq!(summaries[1].status, Status::CREATED);
+
+    local.task_kill(KillRequest {
+        id: "test-a".to_string(),
+        signal: 9,
+        ..Default::default()
+    })?;
+    assert_eq!(local.list_instances()[0].status, Status::STOPPED);
+
+    for id in ["test-a", "test-b"] {
+        local.task_delete(DeleteRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })?;
+    }
+
+    assert_eq!(local.list_instances(), vec![]);
+
+    Ok(())
+}
+
+#[test]
+fn test_reap_exited_instances() -> Result<()> {
+    let (etx, erx) = channel();
+    let mut local = Local::<Nop, _>::new(
+        (),
+        etx,
+        Arc::new(ExitSignal::default()),
+        "test_namespace",
+        "/test/address",
+    );
+    local.reap_tick = REAP_TICK;
+    let local = Arc::new(local);
+    let mut _wrapped = LocalWithDescrutor::new(local.clone());
+
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    create_bundle(dir, None)?;
+    std::fs::write(
+        dir.join("options.json"),
+        r#"{"reap_exited_after_seconds": 1}"#,
+    )?;
+
+    for id in ["exited", "running"] {
+        local.task_create(CreateTaskRequest {
+            id: id.to_string(),
+            bundle: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        })?;
+        local.task_start(StartRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })?;
+    }
+    local.task_kill(KillRequest {
+        id: "exited".to_string(),
+        signal: 9,
+        ..Default::default()
+    })?;
+
+    // the exited instance is deleted once the timeout passes
+    loop {
+        let (topic, _) = erx.recv_timeout(Duration::from_secs(5)).unwrap();
+        if topic.ends_with("/delete") {
+            break;
+        }
+    }
+    let ids: Vec<_> = local.list_instances().into_iter().map(|s| s.id).collect();
+    assert_eq!(ids, ["running"]);
+    assert_eq!(local.list_instances()[0].status, Status::RUNNING);
+
+    // and its exit status can still be queried
+    let state = local.task_state(StateRequest {
+        id: "exited".to_string(),
+        ..Default::default()
+    })?;
+    assert_eq!(state.status(), Status::STOPPED);
+    assert_eq!(state.exit_status, 137);
+    assert_eq!(state.bundle, dir.to_str().unwrap());
+    let wait = local.task_wait(WaitRequest {
+        id: "exited".to_string(),
+        ..Default::default()
+    })?;
+    assert_eq!(wait.exit_status, 137);
+
+    let deleted = local.task_delete(DeleteRequest {
+        id: "exited".to_string(),
+        ..Default::default()
+    })?;
+    assert_eq!(deleted.exit_status, 137);
+    match local
+        .task_state(StateRequest {
+            id: "exited".to_string(),
+            ..Default::default()
+        })
+        .unwrap_err()
+    {
+        Error::NotFound(_) => {}
+        e => return Err(e),
+    }
+
+    // the running instance is never reaped
+    thread::sleep(REAP_TICK * 3);
+    assert_eq!(local.list_instances()[0].status, Status::RUNNING);
+
+    Ok(())
+}
+
+// the time of a second of `reap_exited_after_seconds` in the tests
+const REAP_TICK: Duration = Duration::from_millis(10);
+
+// held by the tests to block the deletes of `SlowDeleteNop`
+static DELETE_GATE: Mutex<()> = Mutex::new(());
+
+// A no-op instance whose delete waits for `DELETE_GATE`, like the teardown of a real container takes a while.
+struct SlowDeleteNop(Nop);
+
+impl Instance for SlowDeleteNop {
+    type Engine = ();
+    fn new(id: String, cfg: Option<&InstanceConfig<Self::Engine>>) -> Result<Self> {
+        Ok(Self(Nop::new(id, cfg)?))
+    }
+    fn start(&self) -> Result<u32> {
+        self.0.start()
+    }
+    fn kill(&self, signal: u32) -> Result<()> {
+        self.0.kill(signal)
+    }
+    fn delete(&self) -> Result<()> {
+        let _gate = DELETE_GATE.lock().unwrap();
+        self.0.delete()
+    }
+    fn wait_timeout(&self, t: impl Into<Option<Duration>>) -> Option<(u32, DateTime<Utc>)> {
+        self.0.wait_timeout(t)
+    }
+}
+
+#[test]
+fn test_reap_without_blocking_tasks() -> Result<()> {
+    let (etx, erx) = channel();
+    let mut local = Local::<SlowDeleteNop, _>::new(
+        (),
+        etx,
+        Arc::new(ExitSignal::default()),
+        "test_namespace",
+        "/test/address",
+    );
+    local.reap_tick = REAP_TICK;
+    let local = Arc::new(local);
+
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    create_bundle(dir, None)?;
+    std::fs::write(
+        dir.join("options.json"),
+        r#"{"reap_exited_after_seconds": 1}"#,
+    )?;
+    local.task_create(CreateTaskRequest {
+        id: "exited".to_string(),
+        bundle: dir.to_str().unwrap().to_string(),
+        ..Default::default()
+    })?;
+
+    let gate = DELETE_GATE.lock().unwrap();
+    local.task_start(StartRequest {
+        id: "exited".to_string(),
+        ..Default::default()
+    })?;
+    local.task_kill(KillRequest {
+        id: "exited".to_string(),
+        signal: 9,
+        ..Default::default()
+    })?;
+
+    // while the reaper deletes the instance, the tasks still run, and see it as stopped
+    while !local.list_instances().is_empty() {
+        thread::sleep(REAP_TICK);
+    }
+    let (tx, rx) = channel();
+    let ll = local.clone();
+    thread::spawn(move || {
+        let state = ll.task_state(StateRequest {
+            id: "exited".to_string(),
+            ..Default::default()
+        });
+        tx.send(state).unwrap();
+    });
+    let state = rx.recv_timeout(Duration::from_secs(5)).unwrap()?;
+    assert_eq!(state.status(), Status::STOPPED);
+    assert_eq!(state.exit_status, 137);
+
+    drop(gate);
+    loop {
+        let (topic, _) = erx.recv_timeout(Duration::from_secs(5)).unwrap();
+        if topic.ends_with("/delete") {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// A no-op instance that reports the image digest written in its bundle.
+struct ImageInstance {
+    nop: Nop,
+    image_digest: String,
+}
+
+impl Instance for ImageInstance {
+    type Engine = ();
+
+    fn new(id: String, cfg: Option<&InstanceConfig<()>>) -> Result<Self> {
+        let bundle = cfg.context("missing configuration")?.get_bundle();
+        let image_digest = std::fs::read_to_string(bundle.join("image-digest"))?;
+        Ok(Self {
+            nop: Nop::new(id, None)?,
+            image_digest,
+        })
+    }
+
+    fn start(&self) -> Result<u32> {
+        self.nop.start()
+    }
+
+    fn kill(&self, signal: u32) -> Result<()> {
+        self.nop.kill(signal)
+    }
+
+    fn delete(&self) -> Result<()> {
+        self.nop.delete()
+    }
+
+    fn wait_timeout(&self, t: impl Into<Option<Duration>>) -> Option<(u32, DateTime<Utc>)> {
+        self.nop.wait_timeout(t)
+    }
+
+    fn image_digest(&self) -> Option<String> {
+        Some(self.image_digest.clone())
+    }
+}
+
+#[test]
+fn test_max_instances_per_image() -> Result<()> {
+    let (etx, _erx) = channel();
+    let local = Arc::new(Local::<ImageInstance, _>::new(
+        (),
+        etx,
+        Arc::new(ExitSignal::default()),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDescrutor::new(local.clone());
+
+    let mut spec = Spec::default();
+    spec.set_annotations(Some(HashMap::from([(
+        oci::MAX_INSTANCES_PER_IMAGE_ANNOTATION.to_string(),
+        "2".to_string(),
+    )])));
+
+    let mut bundles = vec![];
+    let mut create = |id: &str, image_digest: &str| -> Result<()> {
+        let temp = tempdir()?;
+        create_bundle(temp.path(), Some(spec.clone()))?;
+        std::fs::write(temp.path().join("image-digest"), image_digest)?;
+        local.task_create(CreateTaskRequest {
+            id: id.to_string(),
+            bundle: temp.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })?;
+        bundles.push(temp);
+        Ok(())
+    };
+    create("a-1", "sha256:a")?;
+    create("a-2", "sha256:a")?;
+    create("a-3", "sha256:a")?;
+    create("b-1", "sha256:b")?;
+
+    let start = |id: &str| {
+        local.task_start(StartRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })
+    };
+    start("a-1")?;
+    start("a-2")?;
+    match start("a-3").unwrap_err() {
+        Error::ResourceExhausted(_) => {}
+        e => return Err(e),
+    }
+
+    // instances of other images are unaffected
+    start("b-1")?;
+
+    // once an instance of the image exits, another one can start
+    local.task_kill(KillRequest {
+        id: "a-1".to_string(),
+        signal: 9,
+        ..Default::default()
+    })?;
+    start("a-3")?;
+
+    for id in ["a-1", "a-2", "a-3", "b-1"] {
+        let _ = local.task_kill(KillRequest {
+            id: id.to_string(),
+            signal: 9,
+            ..Default::default()
+        });
+        local.task_wait(WaitRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })?;
+        local.task_delete(DeleteRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,239 @@
+//! The logger of the shim, which logs the records of a container at the level of its [`LOG_LEVEL_ANNOTATION`],
+//! so that one container can be debugged without raising the log level of the whole shim.
+//!
+//! A record belongs to the container whose task the thread that logs it is handling, see [`scope`].
+//! Records that don't belong to a container with a level are logged at the level of the shim.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Mutex, OnceLock, RwLock};
+
+use chrono::{SecondsFormat, Utc};
+use log::{LevelFilter, Log, Metadata, Record};
+use oci_spec::runtime::Spec;
+
+use crate::sandbox::Error;
+
+/// Annotation that sets the log level of a container, e.g., `debug`, both in the shim and in the process of the container,
+/// to debug one container without raising the log level of the whole shim.
+pub const LOG_LEVEL_ANNOTATION: &str = "runwasi.io/log-level";
+
+// the levels of the containers that set one
+static LEVELS: RwLock<BTreeMap<String, LevelFilter>> = RwLock::new(BTreeMap::new());
+
+// the level of the shim, once its logger is installed with `init`
+static SHIM_LEVEL: OnceLock<LevelFilter> = OnceLock::new();
+
+thread_local! {
+    // the container whose task the thread is handling
+    static CONTAINER: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Parses the level of the [`LOG_LEVEL_ANNOTATION`], e.g., `debug` or `TRACE`.
+pub(crate) fn log_level(spec: &Spec) -> Result<Option<LevelFilter>, Error> {
+    let Some(level) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(LOG_LEVEL_ANNOTATION))
+    else {
+        return Ok(None);
+    };
+    level.parse().map(Some).map_err(|_| {
+        Error::InvalidArgument(format!(
+            "invalid log level {level:?} in {LOG_LEVEL_ANNOTATION} annotation"
+        ))
+    })
+}
+
+/// Logs the records of the container at `level`, until its level is cleared.
+pub(crate) fn set_level(id: &str, level: LevelFilter) {
+    LEVELS.write().unwrap().insert(id.to_string(), level);
+    update_max_level();
+}
+
+/// Logs the records of the container at the level of the shim again, e.g., once it is deleted.
+pub(crate) fn clear_level(id: &str) {
+    LEVELS.write().unwrap().remove(id);
+    update_max_level();
+}
+
+// the `log` macros drop the records above the max level before they reach the logger,
+// so it is raised to the level of the most verbose container
+fn update_max_level() {
+    if let Some(shim_level) = SHIM_LEVEL.get() {
+        let levels = LEVELS.read().unwrap();
+        let level = levels.values().copied().max().unwrap_or(*shim_level);
+        log::set_max_level(level.max(*shim_level));
+    }
+}
+
+/// Marks the records that the thread logs as records of the container, until the scope is dropped.
+pub(crate) fn scope(id: &str) -> Scope {
+    let previous = CONTAINER.with(|container| container.replace(Some(id.to_string())));
+    Scope { previous }
+}
+
+pub(crate) struct Scope {
+    previous: Option<String>,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CONTAINER.with(|container| *container.borrow_mut() = self.previous.take());
+    }
+}
+
+// the level of the container whose task the thread is handling, if it set one
+fn container_level() -> Option<LevelFilter> {
+    CONTAINER.with(|container| {
+        let container = container.borrow();
+        LEVELS.read().unwrap().get(container.as_ref()?).copied()
+    })
+}
+
+/// Filters the records of `inner` with the level of the container they belong to,
+/// and with `level` if they don't belong to a container with a level.
+pub(crate) struct ContainerFilter<L> {
+    inner: L,
+    level: LevelFilter,
+}
+
+impl<L: Log> ContainerFilter<L> {
+    pub(crate) fn new(inner: L, level: LevelFilter) -> Self {
+        Self { inner, level }
+    }
+}
+
+impl<L: Log> Log for ContainerFilter<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= container_level().unwrap_or(self.level)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// Writes the records to the `log` fifo that containerd reads the logs of the shim from, in the format of logrus.
+struct FifoLogger {
+    file: Mutex<File>,
+}
+
+impl Log for FifoLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        // containerd may be restarting, and not reading the fifo, which the shim doesn't fail for
+        let _ = writeln!(
+            self.file.lock().unwrap(),
+            "time=\"{}\" level={} msg=\"{}\"\n",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true),
+            record.level().as_str().to_lowercase(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().sync_all();
+    }
+}
+
+/// Installs the logger of the shim, in place of the one that `containerd_shim` installs,
+/// logging at the debug level if `debug` is set, and at the info level otherwise.
+/// Must be called from the bundle of the shim, where containerd creates the `log` fifo.
+pub(crate) fn init(debug: bool) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).open("log")?;
+    let level = match debug {
+        true => LevelFilter::Debug,
+        false => LevelFilter::Info,
+    };
+    let logger = ContainerFilter::new(
+        FifoLogger {
+            file: Mutex::new(file),
+        },
+        level,
+    );
+    log::set_boxed_logger(Box::new(logger)).map_err(io::Error::other)?;
+    let _ = SHIM_LEVEL.set(level);
+    update_max_level();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use log::Level;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct CapturingLogger(Arc<Mutex<Vec<String>>>);
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &Record) {
+            let line = format!("{} {}", record.level(), record.args());
+            self.0.lock().unwrap().push(line);
+        }
+        fn flush(&self) {}
+    }
+
+    fn log(logger: &impl Log, level: Level, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .args(format_args!("{message}"))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn test_container_level() {
+        let captured = CapturingLogger::default();
+        let logger = ContainerFilter::new(captured.clone(), LevelFilter::Info);
+        set_level("test-logger-debugged", LevelFilter::Debug);
+
+        for id in ["test-logger-debugged", "test-logger-other"] {
+            let _scope = scope(id);
+            log(&logger, Level::Debug, id);
+            log(&logger, Level::Info, id);
+        }
+        // the records that don't belong to a container are logged at the level of the shim
+        log(&logger, Level::Debug, "shim");
+
+        clear_level("test-logger-debugged");
+        let _scope = scope("test-logger-debugged");
+        log(&logger, Level::Debug, "deleted");
+
+        assert_eq!(
+            *captured.0.lock().unwrap(),
+            [
+                "DEBUG test-logger-debugged",
+                "INFO test-logger-debugged",
+                "INFO test-logger-other",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_scope() {
+        let _outer = scope("test-logger-outer");
+        drop(scope("test-logger-inner"));
+        CONTAINER.with(|container| {
+            assert_eq!(container.borrow().as_deref(), Some("test-logger-outer"));
+        });
+    }
+}
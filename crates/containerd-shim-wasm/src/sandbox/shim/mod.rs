@@ -7,6 +7,7 @@ mod events;
 mod instance_data;
 mod instance_option;
 mod local;
+pub(crate) mod logger;
 mod task_state;
 
 pub use cli::Cli;
@@ -1,8 +1,10 @@
 use std::io::ErrorKind::NotFound;
-use std::io::{Error, Result};
+use std::io::{Error, ErrorKind, Result};
 use std::path::Path;
 use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
+use super::sync::WaitableCell;
 use super::InstanceConfig;
 use crate::sys::stdio::*;
 
@@ -15,6 +17,11 @@ pub struct Stdio {
 
 static INITIAL_STDIO: OnceLock<Stdio> = OnceLock::new();
 
+/// Annotation on the runtime spec of a container to convert the CRLF line endings in the output
+/// of the guest to LF, when set to `true`.
+/// By default the output is captured byte for byte.
+pub const NORMALIZE_LINE_ENDINGS_ANNOTATION: &str = "runwasi.io/normalize-line-endings";
+
 impl Stdio {
     pub fn redirect(self) -> Result<()> {
         self.stdin.redirect()?;
@@ -34,8 +41,8 @@ impl Stdio {
     pub fn init_from_cfg(cfg: &InstanceConfig<impl Send + Sync + Clone>) -> Result<Self> {
         Ok(Self {
             stdin: StdioStream::try_from_path(cfg.get_stdin())?,
-            stdout: StdioStream::try_from_path(cfg.get_stdout())?,
-            stderr: StdioStream::try_from_path(cfg.get_stderr())?,
+            stdout: StdioStream::try_from_output_path(cfg.get_stdout())?,
+            stderr: StdioStream::try_from_output_path(cfg.get_stderr())?,
         })
     }
 
@@ -50,6 +57,138 @@ impl Stdio {
     pub fn guard(self) -> impl Drop {
         StdioGuard(self)
     }
+
+    /// Converts the CRLF line endings written to stdout and stderr to LF.
+    /// The returned [`OutputCopies`] tells when all the output has been converted.
+    #[cfg(unix)]
+    pub fn normalize_line_endings(self) -> Result<(Self, OutputCopies)> {
+        let (stdout, stdout_done) = self.stdout.normalize_line_endings()?;
+        let (stderr, stderr_done) = self.stderr.normalize_line_endings()?;
+        let stdio = Self {
+            stdin: self.stdin,
+            stdout,
+            stderr,
+        };
+        Ok((stdio, OutputCopies(vec![stdout_done, stderr_done])))
+    }
+
+    /// Copies the output written to stdout and stderr to `stdout_copy` and `stderr_copy`,
+    /// on top of writing it to the streams.
+    /// The returned [`OutputCopies`] tells when all the output has been copied.
+    #[cfg(unix)]
+    pub fn tee_output(
+        self,
+        stdout_copy: std::fs::File,
+        stderr_copy: std::fs::File,
+    ) -> Result<(Self, OutputCopies)> {
+        let (stdout, stdout_done) = self.stdout.tee(stdout_copy)?;
+        let (stderr, stderr_done) = self.stderr.tee(stderr_copy)?;
+        let stdio = Self {
+            stdin: self.stdin,
+            stdout,
+            stderr,
+        };
+        Ok((stdio, OutputCopies(vec![stdout_done, stderr_done])))
+    }
+
+    /// Discards the output that the guest writes to stdout and stderr once their readers are closed,
+    /// rather than failing its writes.
+    /// The returned [`OutputCopies`] tells when all the output has been copied, or discarded.
+    #[cfg(unix)]
+    pub fn discard_closed_output(self) -> Result<(Self, OutputCopies)> {
+        let (stdout, stdout_done) = self.stdout.discard_closed_output()?;
+        let (stderr, stderr_done) = self.stderr.discard_closed_output()?;
+        let stdio = Self {
+            stdin: self.stdin,
+            stdout,
+            stderr,
+        };
+        Ok((stdio, OutputCopies(vec![stdout_done, stderr_done])))
+    }
+
+    /// Connects the guest to a new pseudo terminal, the way containerd expects the stdio of a container
+    /// with `process.terminal` set: the input of the terminal is read from stdin, and its output,
+    /// which includes what the guest writes to stderr, is written to stdout.
+    /// The returned [`OutputCopies`] tells when all the output of the terminal has been copied.
+    #[cfg(unix)]
+    pub fn with_terminal(self) -> Result<(Self, OutputCopies)> {
+        use std::os::fd::AsRawFd;
+
+        let pty = nix::pty::openpty(None, None)?;
+        let master = dup_file(pty.master.as_raw_fd())?;
+        let slave = || StdioOwnedFd::try_from(dup_file(pty.slave.as_raw_fd())?);
+
+        if let Some(fd) = self.stdin.0.as_raw_fd() {
+            let mut input = dup_file(fd)?;
+            let mut terminal = master.try_clone()?;
+            std::thread::Builder::new()
+                .name("stdio-terminal-input".to_string())
+                .spawn(move || {
+                    if let Err(err) = std::io::copy(&mut input, &mut terminal) {
+                        log::warn!("failed to copy input to the terminal: {err}");
+                    }
+                })?;
+        }
+
+        let done = WaitableCell::new();
+        let output = self.stdout.0.as_raw_fd().map(dup_file).transpose()?;
+        let done_tx = done.clone();
+        std::thread::Builder::new()
+            .name("stdio-terminal-output".to_string())
+            .spawn(move || {
+                let _guard = done_tx.set_guard_with(|| ());
+                let mut terminal = TerminalReader(master);
+                let copied = match output {
+                    Some(mut output) => std::io::copy(&mut terminal, &mut output),
+                    None => std::io::copy(&mut terminal, &mut std::io::sink()),
+                };
+                if let Err(err) = copied {
+                    log::warn!("failed to copy the output of the terminal: {err}");
+                }
+            })?;
+
+        let stdio = Self {
+            stdin: StdioStream(Arc::new(slave()?)),
+            stdout: StdioStream(Arc::new(slave()?)),
+            stderr: StdioStream(Arc::new(slave()?)),
+        };
+        Ok((stdio, OutputCopies(vec![done])))
+    }
+}
+
+// Reads the master end of a terminal.
+// Once every process closed the slave end, reading the master end fails with EIO rather than returning EOF.
+#[cfg(unix)]
+struct TerminalReader(std::fs::File);
+
+#[cfg(unix)]
+impl std::io::Read for TerminalReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match std::io::Read::read(&mut self.0, buf) {
+            Err(err) if err.raw_os_error() == Some(libc::EIO) => Ok(0),
+            read => read,
+        }
+    }
+}
+
+/// The threads copying the output of the guest to the stdio streams.
+pub struct OutputCopies(Vec<WaitableCell<()>>);
+
+impl OutputCopies {
+    /// Waits for the copies to finish, i.e., for the guest output to be closed,
+    /// for up to `timeout` in total.
+    pub fn wait_timeout(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        for done in &self.0 {
+            done.wait_timeout(deadline.saturating_duration_since(Instant::now()));
+        }
+    }
+
+    /// Returns the copies of both `self` and `other`.
+    pub fn join(mut self, other: Self) -> Self {
+        self.0.extend(other.0);
+        self
+    }
 }
 
 struct StdioGuard(Stdio);
@@ -70,8 +209,14 @@ impl<const FD: StdioRawFd> StdioStream<FD> {
             // to make sure the streams stay open
             INITIAL_STDIO.get_or_init(Stdio::init_from_std);
 
-            if unsafe { libc::dup2(fd, FD) } == -1 {
-                return Err(Error::last_os_error());
+            // The guest writes straight to the redirected fd, there is no copy loop in between.
+            // The dup2 itself can be interrupted by a signal though, in which case we retry it,
+            // rather than leaving the stream pointing at the shim's stdio and losing the guest output.
+            while unsafe { libc::dup2(fd, FD) } == -1 {
+                let err = Error::last_os_error();
+                if err.kind() != ErrorKind::Interrupted {
+                    return Err(err);
+                }
             }
         }
         Ok(())
@@ -91,13 +236,95 @@ impl<const FD: StdioRawFd> StdioStream<FD> {
 }
 
 impl<const FD: StdioRawFd> StdioStream<FD> {
+    /// Returns a stream that converts CRLF line endings to LF before writing them to this stream.
+    /// The conversion runs in a thread of the current process, until the returned stream is closed
+    /// by every process that has it.
+    #[cfg(unix)]
+    fn normalize_line_endings(self) -> Result<(Self, WaitableCell<()>)> {
+        let done = WaitableCell::new();
+        let Some(fd) = self.0.as_raw_fd() else {
+            let _ = done.set(());
+            return Ok((self, done));
+        };
+        let output = dup_file(fd)?;
+        let (input, stream) = pipe()?;
+        let done_tx = done.clone();
+        std::thread::Builder::new()
+            .name(format!("stdio-{FD}-normalize"))
+            .spawn(move || {
+                let _guard = done_tx.set_guard_with(|| ());
+                if let Err(err) = copy_normalized(input, output) {
+                    log::warn!("failed to copy normalized output: {err}");
+                }
+            })?;
+        Ok((Self(Arc::new(stream)), done))
+    }
+
+    /// Returns a stream that writes to this stream and to `copy`.
+    /// The stream doesn't need to be set up, in which case the output is only written to `copy`.
+    /// The copy runs in a thread of the current process, until the returned stream is closed
+    /// by every process that has it.
+    #[cfg(unix)]
+    fn tee(self, copy: std::fs::File) -> Result<(Self, WaitableCell<()>)> {
+        let done = WaitableCell::new();
+        let output = self.0.as_raw_fd().map(dup_file).transpose()?;
+        let (input, stream) = pipe()?;
+        let done_tx = done.clone();
+        std::thread::Builder::new()
+            .name(format!("stdio-{FD}-tee"))
+            .spawn(move || {
+                let _guard = done_tx.set_guard_with(|| ());
+                if let Err(err) = copy_tee(input, output, copy) {
+                    log::warn!("failed to copy output: {err}");
+                }
+            })?;
+        Ok((Self(Arc::new(stream)), done))
+    }
+
+    /// Returns a stream that writes to this stream until it is closed, and then discards what is written to it.
+    /// The copy runs in a thread of the current process, until the returned stream is closed
+    /// by every process that has it.
+    #[cfg(unix)]
+    fn discard_closed_output(self) -> Result<(Self, WaitableCell<()>)> {
+        let done = WaitableCell::new();
+        let Some(fd) = self.0.as_raw_fd() else {
+            let _ = done.set(());
+            return Ok((self, done));
+        };
+        let output = dup_file(fd)?;
+        let (input, stream) = pipe()?;
+        let done_tx = done.clone();
+        std::thread::Builder::new()
+            .name(format!("stdio-{FD}-discard"))
+            .spawn(move || {
+                let _guard = done_tx.set_guard_with(|| ());
+                if let Err(err) = copy_until_closed(input, output) {
+                    log::warn!("failed to copy output: {err}");
+                }
+            })?;
+        Ok((Self(Arc::new(stream)), done))
+    }
+
     fn try_from_path(path: impl AsRef<Path>) -> Result<Self> {
-        let path = path.as_ref();
+        Self::open(path.as_ref(), false)
+    }
+
+    // Like `try_from_path`, but writes to a fifo without holding it open for reading,
+    // see `StdioOwnedFd::try_from_output_path`.
+    fn try_from_output_path(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open(path.as_ref(), true)
+    }
+
+    fn open(path: &Path, output: bool) -> Result<Self> {
         if path.as_os_str().is_empty() {
             return Ok(Self(Arc::default()));
         }
 
-        let fd = match StdioOwnedFd::try_from_path(path) {
+        let opened = match output {
+            true => StdioOwnedFd::try_from_output_path(path),
+            false => StdioOwnedFd::try_from_path(path),
+        };
+        let fd = match opened {
             Err(err) if err.kind() == NotFound => Default::default(),
             Err(err) => return Err(err),
             Ok(fd) => fd,
@@ -107,6 +334,106 @@ impl<const FD: StdioRawFd> StdioStream<FD> {
     }
 }
 
+// Copies `reader` to `writer`, replacing every CRLF with LF.
+// A CR at the end of a read is held back until the next read shows whether it starts a CRLF.
+#[cfg(unix)]
+fn copy_normalized(mut reader: impl std::io::Read, mut writer: impl std::io::Write) -> Result<()> {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut out = Vec::with_capacity(buf.len() + 1);
+    let mut pending_cr = false;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        out.clear();
+        for &b in &buf[..n] {
+            if pending_cr && b != b'\n' {
+                out.push(b'\r');
+            }
+            pending_cr = b == b'\r';
+            if !pending_cr {
+                out.push(b);
+            }
+        }
+        writer.write_all(&out)?;
+    }
+    if pending_cr {
+        writer.write_all(b"\r")?;
+    }
+    writer.flush()
+}
+
+// Copies `reader` to `writer`, if there is one, and to `copy`.
+// A failure to write the copy is logged once, and doesn't keep the output from reaching `writer`.
+#[cfg(unix)]
+fn copy_tee(
+    mut reader: impl std::io::Read,
+    mut writer: Option<impl std::io::Write>,
+    mut copy: impl std::io::Write,
+) -> Result<()> {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut copying = true;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if let Some(writer) = &mut writer {
+            writer.write_all(&buf[..n])?;
+        }
+        if copying {
+            if let Err(err) = copy.write_all(&buf[..n]) {
+                log::warn!("failed to write the copy of the output, the rest isn't copied: {err}");
+                copying = false;
+            }
+        }
+    }
+    if copying {
+        copy.flush()?;
+    }
+    match &mut writer {
+        Some(writer) => writer.flush(),
+        None => Ok(()),
+    }
+}
+
+// Copies `reader` to `writer` until `writer` is closed, and then reads the rest of `reader` without writing it.
+#[cfg(unix)]
+fn copy_until_closed(
+    mut reader: impl std::io::Read,
+    mut writer: impl std::io::Write,
+) -> Result<()> {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut open = true;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if !open {
+            continue;
+        }
+        match writer.write_all(&buf[..n]) {
+            Err(err) if err.kind() == ErrorKind::BrokenPipe => {
+                log::info!("the reader of the output was closed, discarding the rest");
+                open = false;
+            }
+            written => written?,
+        }
+    }
+    match open {
+        true => writer.flush(),
+        false => Ok(()),
+    }
+}
+
 pub type Stdin = StdioStream<STDIN_FILENO>;
 pub type Stdout = StdioStream<STDOUT_FILENO>;
 pub type Stderr = StdioStream<STDERR_FILENO>;
@@ -139,8 +466,166 @@ mod test {
         drop(temp);
 
         // a valid path should not fail
-        let s = Stdout::try_from_path(path)?;
+        let s = Stdout::try_from_path(&path)?;
+        assert!(s.0.take().as_raw_fd().is_some());
+        let s = Stdout::try_from_output_path(path)?;
         assert!(s.0.take().as_raw_fd().is_some());
         Ok(())
     }
+
+    #[cfg(unix)]
+    fn write_to(stream: &Stdout, data: &[u8]) -> Result<()> {
+        use std::io::Write;
+
+        let fd = stream.0.as_raw_fd().expect("the stream is open");
+        dup_file(fd)?.write_all(data)
+    }
+
+    #[cfg(unix)]
+    fn open_reader(path: &Path) -> Result<File> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_fifo_with_reader() -> anyhow::Result<()> {
+        use std::io::Read;
+
+        let dir = tempdir()?;
+        let path = dir.path().join("stdout");
+        nix::unistd::mkfifo(&path, nix::sys::stat::Mode::S_IRWXU)?;
+        let mut reader = open_reader(&path)?;
+
+        let s = Stdout::try_from_output_path(&path)?;
+        write_to(&s, b"hello")?;
+        drop(s);
+
+        // the reader sees the end of the output, as the stream didn't hold the fifo open for reading
+        let mut output = vec![];
+        reader.read_to_end(&mut output)?;
+        assert_eq!(output, b"hello");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_fifo_without_reader() -> anyhow::Result<()> {
+        use std::io::Read;
+
+        let dir = tempdir()?;
+        let path = dir.path().join("stdout");
+        nix::unistd::mkfifo(&path, nix::sys::stat::Mode::S_IRWXU)?;
+
+        // the fifo has no reader, but the output is kept until one attaches
+        let s = Stdout::try_from_output_path(&path)?;
+        write_to(&s, b"hello")?;
+
+        let mut reader = open_reader(&path)?;
+        let mut output = [0; 5];
+        reader.read_exact(&mut output)?;
+        assert_eq!(&output, b"hello");
+        Ok(())
+    }
+
+    // Returns the input one byte at a time, so that every CRLF is split across reads.
+    #[cfg(unix)]
+    struct ByteReader<'a>(&'a [u8]);
+
+    #[cfg(unix)]
+    impl std::io::Read for ByteReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let Some((first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = *first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_tee() -> anyhow::Result<()> {
+        let input = b"hello\r\nworld\n";
+
+        let mut output = vec![];
+        let mut copy = vec![];
+        copy_tee(ByteReader(input), Some(&mut output), &mut copy)?;
+        assert_eq!(output, input);
+        assert_eq!(copy, input);
+
+        let mut copy = vec![];
+        copy_tee(&input[..], None::<Vec<u8>>, &mut copy)?;
+        assert_eq!(copy, input);
+
+        Ok(())
+    }
+
+    // Accepts `remaining` bytes, and then fails like a pipe without a reader.
+    #[cfg(unix)]
+    struct ClosingWriter {
+        written: Vec<u8>,
+        remaining: usize,
+    }
+
+    #[cfg(unix)]
+    impl std::io::Write for ClosingWriter {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            if self.remaining == 0 {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            let n = buf.len().min(self.remaining);
+            self.written.extend_from_slice(&buf[..n]);
+            self.remaining -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_until_closed() -> anyhow::Result<()> {
+        let input = b"hello\nworld\n";
+
+        let mut reader = ByteReader(input);
+        let mut writer = ClosingWriter {
+            written: vec![],
+            remaining: 6,
+        };
+        copy_until_closed(&mut reader, &mut writer)?;
+        assert_eq!(writer.written, b"hello\n");
+        // the output after the reader closed was still read, so that the guest doesn't block on it
+        assert!(reader.0.is_empty());
+
+        let mut output = vec![];
+        copy_until_closed(&input[..], &mut output)?;
+        assert_eq!(output, input);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_normalized() -> anyhow::Result<()> {
+        let input = b"hello\r\nworld\r\n\rcarriage\r\r\nreturn\r";
+        let expected = b"hello\nworld\n\rcarriage\r\nreturn\r";
+
+        let mut output = vec![];
+        copy_normalized(&input[..], &mut output)?;
+        assert_eq!(output, expected);
+
+        let mut output = vec![];
+        copy_normalized(ByteReader(input), &mut output)?;
+        assert_eq!(output, expected);
+
+        Ok(())
+    }
 }
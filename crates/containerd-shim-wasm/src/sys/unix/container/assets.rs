@@ -0,0 +1,385 @@
+//! Asset layers are data files for the guest, e.g., models, that are shipped as layers of the image.
+//!
+//! Rather than reading every asset when the container is created, assets are mounted lazily:
+//! a named pipe (FIFO) is created at the path of each asset in the rootfs, and the content
+//! is only read from the content store when the guest first opens the pipe.
+//! The content is then materialized to a regular file that replaces the pipe, so that later opens
+//! don't read it again, and it is streamed to the guest through the pipe.
+//! Assets that the guest never opens are never read.
+//!
+//! When a pipe can't be created, e.g., because the filesystem of the rootfs doesn't support them,
+//! the asset is materialized when the container is created instead.
+//!
+//! The rootfs comes from the image, so the directories on the path of an asset may be symlinks that point
+//! outside of it, e.g., at a directory of the host. Every path is resolved one component at a time, relative
+//! to the rootfs, and symlinks are never followed, so that an image can't write anywhere else on the host.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{copy, ErrorKind, Seek, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Component, Path};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{open, openat, renameat, OFlag};
+use nix::sys::stat::{fchmod, mkdirat, Mode};
+use nix::unistd::{mkfifoat, unlinkat, UnlinkatFlags};
+
+use crate::sandbox::containerd;
+use crate::sandbox::instance_utils::ContainerdTlsOptions;
+use crate::sandbox::oci::AssetLayer;
+
+/// Reads the content of asset layers.
+pub(crate) trait AssetReader: Send + Sync + 'static {
+    fn copy_to(&self, asset: &AssetLayer, file: &mut File) -> Result<u64>;
+}
+
+/// Reads the content of asset layers from the containerd content store.
+pub(crate) struct ContainerdAssetReader {
+    pub address: String,
+    pub namespace: String,
+    pub tls: Option<ContainerdTlsOptions>,
+}
+
+impl AssetReader for ContainerdAssetReader {
+    fn copy_to(&self, asset: &AssetLayer, file: &mut File) -> Result<u64> {
+        // each asset is read from its own thread, which needs its own client
+        let client = containerd::Client::connect_with_tls(
+            self.address.as_str(),
+            &self.namespace,
+            self.tls.clone(),
+        )?;
+        Ok(client.copy_content(&asset.digest, file)?)
+    }
+}
+
+/// The assets mounted in the rootfs of a container.
+/// Dropping it stops waiting for the guest to open the lazy assets.
+pub(crate) struct Assets {
+    // the directory and the name of each pipe
+    pipes: Vec<(OwnedFd, OsString)>,
+    closed: Arc<AtomicBool>,
+}
+
+/// Mounts the assets in `rootfs`, lazily when possible.
+pub(crate) fn mount_assets(
+    rootfs: &Path,
+    assets: Vec<AssetLayer>,
+    reader: Arc<dyn AssetReader>,
+) -> Result<Assets> {
+    let closed = Arc::new(AtomicBool::new(false));
+    let mut pipes = vec![];
+    if assets.is_empty() {
+        return Ok(Assets { pipes, closed });
+    }
+    let rootfs = owned_fd(
+        open(
+            rootfs,
+            OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .with_context(|| format!("failed to open rootfs {rootfs:?}"))?,
+    );
+    for asset in assets {
+        let name = asset
+            .path
+            .file_name()
+            .with_context(|| format!("asset path {:?} has no file name", asset.path))?
+            .to_os_string();
+        let parent = asset.path.parent().unwrap_or(Path::new(""));
+        let dir = create_dirs(&rootfs, parent)
+            .with_context(|| format!("failed to mount asset at {:?}", asset.path))?;
+        // a symlink at the path of the asset is removed, rather than followed
+        match unlinkat(
+            Some(dir.as_raw_fd()),
+            name.as_os_str(),
+            UnlinkatFlags::NoRemoveDir,
+        ) {
+            Ok(()) | Err(Errno::ENOENT) => {}
+            Err(err) => log::warn!("could not remove {:?}: {err}", asset.path),
+        }
+
+        match mkfifoat(
+            Some(dir.as_raw_fd()),
+            name.as_os_str(),
+            Mode::from_bits_truncate(0o444),
+        ) {
+            Ok(()) => {
+                log::info!("mounting asset {} lazily at {:?}", asset.digest, asset.path);
+                let (pipe_dir, reader, closed) = (dir.try_clone()?, reader.clone(), closed.clone());
+                let pipe_name = name.clone();
+                thread::Builder::new()
+                    .name(format!("asset-{}", asset.digest))
+                    .spawn(move || {
+                        if let Err(err) =
+                            serve(&pipe_dir, &pipe_name, &asset, reader.as_ref(), &closed)
+                        {
+                            log::error!("failed to serve asset {}: {err:#}", asset.digest);
+                        }
+                    })?;
+                pipes.push((dir, name));
+            }
+            Err(err) => {
+                log::info!(
+                    "could not mount asset {} lazily: {err}, materializing it at {:?}",
+                    asset.digest,
+                    asset.path
+                );
+                materialize(&dir, &name, &asset, reader.as_ref())
+                    .with_context(|| format!("failed to materialize asset at {:?}", asset.path))?;
+            }
+        }
+    }
+    Ok(Assets { pipes, closed })
+}
+
+// Waits for the guest to open the pipe, then materializes the asset in place of the pipe,
+// and streams it to the guest.
+fn serve(
+    dir: &OwnedFd,
+    name: &OsStr,
+    asset: &AssetLayer,
+    reader: &dyn AssetReader,
+    closed: &AtomicBool,
+) -> Result<()> {
+    // this blocks until the pipe is opened for reading
+    let flags = OFlag::O_WRONLY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    let mut guest = File::from(owned_fd(openat(
+        dir.as_raw_fd(),
+        name,
+        flags,
+        Mode::empty(),
+    )?));
+    if closed.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    // the guest may have replaced the pipe in the meantime
+    if !guest.metadata()?.file_type().is_fifo() {
+        bail!("asset {:?} is not a pipe anymore", asset.path);
+    }
+
+    log::info!("guest opened asset {:?}", asset.path);
+    let mut file = materialize(dir, name, asset, reader)?;
+    file.rewind()?;
+
+    match copy(&mut file, &mut guest) {
+        Ok(_) => Ok(()),
+        // the guest closed the asset before reading all of it
+        Err(err) if err.kind() == ErrorKind::BrokenPipe => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Writes the asset to a temporary file in `dir`, and renames it to `name`,
+// so that the path of the asset is never a partially written file.
+// The rename replaces a pipe or a symlink at the path of the asset, rather than writing to it.
+// Returns the materialized file.
+fn materialize(
+    dir: &OwnedFd,
+    name: &OsStr,
+    asset: &AssetLayer,
+    reader: &dyn AssetReader,
+) -> Result<File> {
+    let tmp = format!(".{}.partial", name.to_string_lossy());
+    match unlinkat(
+        Some(dir.as_raw_fd()),
+        tmp.as_str(),
+        UnlinkatFlags::NoRemoveDir,
+    ) {
+        Ok(()) | Err(Errno::ENOENT) => {}
+        Err(err) => return Err(err.into()),
+    }
+    let flags =
+        OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    let fd = openat(
+        dir.as_raw_fd(),
+        tmp.as_str(),
+        flags,
+        Mode::from_bits_truncate(0o644),
+    )?;
+    let mut file = File::from(owned_fd(fd));
+
+    let size = reader
+        .copy_to(asset, &mut file)
+        .with_context(|| format!("failed to read asset {}", asset.digest))?;
+    file.flush()?;
+    fchmod(file.as_raw_fd(), Mode::from_bits_truncate(0o444))?;
+    renameat(
+        Some(dir.as_raw_fd()),
+        tmp.as_str(),
+        Some(dir.as_raw_fd()),
+        name,
+    )?;
+
+    log::info!("materialized asset {} ({size} bytes)", asset.digest);
+    Ok(file)
+}
+
+impl Drop for Assets {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        for (dir, name) in &self.pipes {
+            // opening the pipes that are still waiting for the guest wakes up their threads,
+            // which then see that the assets are closed
+            let flags = OFlag::O_RDONLY | OFlag::O_NONBLOCK | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+            if let Ok(fd) = openat(dir.as_raw_fd(), name.as_os_str(), flags, Mode::empty()) {
+                drop(owned_fd(fd));
+            }
+        }
+    }
+}
+
+// Opens the directory at `path` in `rootfs`, creating the directories that don't exist.
+// Fails if any component of the path is a symlink, or isn't a directory.
+fn create_dirs(rootfs: &OwnedFd, path: &Path) -> Result<OwnedFd> {
+    let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    let mut dir = rootfs.try_clone()?;
+    for component in path.components() {
+        let name = match component {
+            Component::Normal(name) => name,
+            Component::CurDir => continue,
+            _ => bail!("invalid component {component:?} in asset path"),
+        };
+        match mkdirat(dir.as_raw_fd(), name, Mode::from_bits_truncate(0o755)) {
+            Ok(()) | Err(Errno::EEXIST) => {}
+            Err(err) => return Err(err.into()),
+        }
+        dir = match openat(dir.as_raw_fd(), name, flags, Mode::empty()) {
+            Ok(fd) => owned_fd(fd),
+            Err(Errno::ELOOP | Errno::ENOTDIR) => {
+                bail!("{name:?} in asset path is a symlink or not a directory")
+            }
+            Err(err) => return Err(err.into()),
+        };
+    }
+    Ok(dir)
+}
+
+fn owned_fd(fd: std::os::fd::RawFd) -> OwnedFd {
+    // SAFETY: the fd was just opened, and nothing else owns it
+    unsafe { OwnedFd::from_raw_fd(fd) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir, metadata, read_to_string, write};
+    use std::os::unix::fs::{symlink, FileTypeExt, PermissionsExt};
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeReader {
+        reads: Mutex<Vec<String>>,
+    }
+
+    impl AssetReader for FakeReader {
+        fn copy_to(&self, asset: &AssetLayer, file: &mut File) -> Result<u64> {
+            self.reads.lock().unwrap().push(asset.digest.clone());
+            file.write_all(asset.digest.as_bytes())?;
+            Ok(asset.digest.len() as u64)
+        }
+    }
+
+    fn asset(digest: &str, path: &str) -> AssetLayer {
+        AssetLayer {
+            digest: digest.to_string(),
+            path: PathBuf::from(path),
+        }
+    }
+
+    #[test]
+    fn test_mount_assets() -> Result<()> {
+        let rootfs = tempdir()?;
+        let reader = Arc::new(FakeReader::default());
+        let assets = vec![
+            asset("sha256:model", "models/large/model.bin"),
+            asset("sha256:config", "config.json"),
+        ];
+        write(rootfs.path().join("config.json"), "replaced")?;
+
+        let mounted = mount_assets(rootfs.path(), assets, reader.clone())?;
+        assert!(reader.reads.lock().unwrap().is_empty());
+
+        let model = rootfs.path().join("models/large/model.bin");
+        assert_eq!(read_to_string(&model)?, "sha256:model");
+        let config = rootfs.path().join("config.json");
+        assert_eq!(read_to_string(config)?, "sha256:config");
+
+        // the assets are regular files from now on, and aren't read again
+        assert!(metadata(&model)?.is_file());
+        assert_eq!(metadata(&model)?.permissions().mode() & 0o777, 0o444);
+        assert_eq!(read_to_string(&model)?, "sha256:model");
+
+        drop(mounted);
+        assert_eq!(
+            *reader.reads.lock().unwrap(),
+            ["sha256:model", "sha256:config"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unused_asset_is_not_read() -> Result<()> {
+        let rootfs = tempdir()?;
+        let reader = Arc::new(FakeReader::default());
+        let assets = vec![
+            asset("sha256:used", "models/used.bin"),
+            asset("sha256:unused", "models/unused.bin"),
+        ];
+
+        let mounted = mount_assets(rootfs.path(), assets, reader.clone())?;
+        let used = rootfs.path().join("models/used.bin");
+        assert_eq!(read_to_string(used)?, "sha256:used");
+
+        drop(mounted);
+        assert_eq!(*reader.reads.lock().unwrap(), ["sha256:used"]);
+        let unused = rootfs.path().join("models/unused.bin");
+        assert!(metadata(unused)?.file_type().is_fifo());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_assets_symlinks() -> Result<()> {
+        let dir = tempdir()?;
+        let host = dir.path().join("host");
+        create_dir(&host)?;
+        write(host.join("file"), "host")?;
+        let rootfs = dir.path().join("rootfs");
+        create_dir(&rootfs)?;
+
+        // a directory on the path of the asset that links outside of the rootfs is rejected
+        symlink(&host, rootfs.join("models"))?;
+        mount_assets(
+            &rootfs,
+            vec![asset("sha256:model", "models/file")],
+            Arc::new(FakeReader::default()),
+        )
+        .err()
+        .expect("the asset should not be written through a symlink");
+        assert_eq!(read_to_string(host.join("file"))?, "host");
+
+        // a symlink at the path of the asset is replaced, rather than written through
+        symlink(host.join("file"), rootfs.join("file"))?;
+        let _mounted = mount_assets(
+            &rootfs,
+            vec![asset("sha256:file", "file")],
+            Arc::new(FakeReader::default()),
+        )?;
+        assert_eq!(read_to_string(host.join("file"))?, "host");
+        assert_eq!(read_to_string(rootfs.join("file"))?, "sha256:file");
+        assert!(metadata(rootfs.join("file"))?.is_file());
+
+        Ok(())
+    }
+}
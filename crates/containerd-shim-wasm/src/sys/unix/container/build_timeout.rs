@@ -0,0 +1,171 @@
+//! Libcontainer can block while it builds a container, e.g., on the setup of its namespaces, with no timeout.
+//! When the build doesn't complete in time, the processes that it forked are killed, so that the build
+//! fails and the creation of the container fails with `Error::Timeout` rather than hanging the shim.
+//!
+//! The build runs on the calling thread rather than a thread of its own, as the init process of the container
+//! is killed with its parent-death signal once the thread that forked it exits.
+
+use std::fs::{read_link, read_to_string, remove_dir_all};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+use crate::sandbox::Error;
+
+/// Runs `build` for up to `timeout`.
+///
+/// When it times out, the processes that it forked are killed, and once it returns,
+/// what it built is cleaned up with `abandon` and its partial state in `state_dir` is removed.
+/// The state is only removed after the build returns, as the build may write to it until then.
+pub(crate) fn build_with_timeout<T>(
+    timeout: Duration,
+    state_dir: &Path,
+    build: impl FnOnce() -> Result<T, Error>,
+    abandon: impl FnOnce(T),
+) -> Result<T, Error> {
+    // the processes that the build forks are the children of this thread
+    let task = Path::new("/proc").join(read_link("/proc/thread-self")?);
+    let (done, done_rx) = channel::<()>();
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let watchdog = thread::spawn({
+        let timed_out = timed_out.clone();
+        move || {
+            if let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(timeout) {
+                timed_out.store(true, Ordering::SeqCst);
+                log::error!(
+                    "the build of the container didn't complete within {timeout:?}, killing it"
+                );
+                kill_children(&task);
+            }
+        }
+    });
+
+    let result = build();
+    let _ = done.send(());
+    let _ = watchdog.join();
+    if !timed_out.load(Ordering::SeqCst) {
+        return result;
+    }
+
+    if let Ok(built) = result {
+        log::warn!("the build of the container completed after it timed out, cleaning it up");
+        abandon(built);
+    }
+    match remove_dir_all(state_dir) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            log::warn!("failed to remove the partial state in {state_dir:?}: {err}")
+        }
+        _ => {}
+    }
+    Err(Error::Timeout {
+        operation: "building the container".to_string(),
+        after: timeout,
+    })
+}
+
+// Kills the processes that the thread of `task` forked, along with their descendants.
+fn kill_children(task: &Path) {
+    let children = match read_to_string(task.join("children")) {
+        Ok(children) => children,
+        Err(err) => {
+            log::warn!("failed to list the processes of the build: {err}");
+            return;
+        }
+    };
+    for pid in children
+        .split_whitespace()
+        .filter_map(|pid| pid.parse().ok())
+    {
+        // the descendants first, so that they aren't reparented before they are found
+        kill_children(&Path::new("/proc").join(format!("{pid}/task/{pid}")));
+        if let Err(err) = kill(Pid::from_raw(pid), Signal::SIGKILL) {
+            log::warn!("failed to kill process {pid} of the build: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+    use std::process::Command;
+    use std::time::Instant;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_build_with_timeout() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let state_dir = dir.path().join("container");
+
+        let built = build_with_timeout(
+            Duration::from_secs(5),
+            &state_dir,
+            || Ok(42),
+            |_| panic!("a build that completes in time isn't abandoned"),
+        )?;
+        assert_eq!(built, 42);
+
+        // a slow build leaves partial state behind, until it times out
+        create_dir_all(&state_dir)?;
+        let mut abandoned = None;
+        let err = build_with_timeout(
+            Duration::from_millis(50),
+            &state_dir,
+            || {
+                thread::sleep(Duration::from_millis(500));
+                Ok(42)
+            },
+            |built| abandoned = Some(built),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, Error::Timeout { after, .. } if after == Duration::from_millis(50)),
+            "{err}"
+        );
+        assert!(!state_dir.exists());
+        // what the build produced once it completed is cleaned up
+        assert_eq!(abandoned, Some(42));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_with_timeout_kills_build() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let state_dir = dir.path().join("container");
+        create_dir_all(&state_dir)?;
+
+        // a build that is wedged on a process that it forked, and writes its state once the process is gone
+        let start = Instant::now();
+        let mut abandoned = None;
+        let err = build_with_timeout(
+            Duration::from_millis(50),
+            &state_dir,
+            || {
+                let status = Command::new("sleep").arg("30").status()?;
+                assert!(!status.success(), "the process of the build wasn't killed");
+                write(state_dir.join("state.json"), "{}")?;
+                Ok(42)
+            },
+            |built| abandoned = Some(built),
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::Timeout { .. }), "{err}");
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        // the state is removed after the build wrote it, rather than while it was still running
+        assert!(!state_dir.exists());
+        assert_eq!(abandoned, Some(42));
+
+        Ok(())
+    }
+}
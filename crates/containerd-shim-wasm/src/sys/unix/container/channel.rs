@@ -0,0 +1,56 @@
+use std::fs::File;
+use std::io::{Error, Read, Result, Write};
+use std::marker::PhantomData;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::sync::Arc;
+
+/// A message that fits in a single byte, sent by the container process to the shim.
+pub(crate) trait Message: Copy + std::fmt::Debug {
+    fn to_byte(self) -> u8;
+    fn from_byte(byte: u8) -> Option<Self>;
+}
+
+/// The write end of a pipe used by the container process to report to the shim.
+/// The container process is forked from the shim, so it inherits the pipe.
+pub(crate) struct Sender<T>(Arc<File>, PhantomData<T>);
+
+/// The read end of the pipe, kept by the shim.
+pub(crate) struct Receiver<T>(File, PhantomData<T>);
+
+pub(crate) fn channel<T: Message>() -> Result<(Sender<T>, Receiver<T>)> {
+    let mut fds = [-1; 2];
+    // The pipe is non-blocking so that reading never stalls if another process
+    // forked by the shim holds a copy of the write end.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    let (rx, tx) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    Ok((
+        Sender(Arc::new(File::from(tx)), PhantomData),
+        Receiver(File::from(rx), PhantomData),
+    ))
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), PhantomData)
+    }
+}
+
+impl<T: Message> Sender<T> {
+    pub fn send(&self, msg: T) {
+        if let Err(err) = (&*self.0).write_all(&[msg.to_byte()]) {
+            log::warn!("failed to report {msg:?} to the shim: {err}");
+        }
+    }
+}
+
+impl<T: Message> Receiver<T> {
+    pub fn try_recv(&self) -> Option<T> {
+        let mut byte = [0u8];
+        match (&self.0).read(&mut byte) {
+            Ok(1) => T::from_byte(byte[0]),
+            _ => None,
+        }
+    }
+}
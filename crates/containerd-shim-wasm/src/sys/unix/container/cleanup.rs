@@ -0,0 +1,94 @@
+use crate::sandbox::Error;
+
+/// The steps to release the resources of a container, run in order on a best-effort basis.
+///
+/// A step that fails doesn't stop the steps after it, so that one failure doesn't leak
+/// every other resource of the container. The errors are reported together at the end.
+pub(crate) struct Cleanup<'a> {
+    id: &'a str,
+    errors: Vec<(&'static str, Error)>,
+}
+
+impl<'a> Cleanup<'a> {
+    pub fn new(id: &'a str) -> Self {
+        Self { id, errors: vec![] }
+    }
+
+    /// Runs the step `name`, and records its error if it fails.
+    pub fn step(mut self, name: &'static str, f: impl FnOnce() -> Result<(), Error>) -> Self {
+        if let Err(err) = f() {
+            log::error!("cleanup of container {}: {name} failed: {err}", self.id);
+            self.errors.push((name, err));
+        }
+        self
+    }
+
+    /// Returns the error of the step that failed, or a combined error if more than one failed.
+    pub fn finish(mut self) -> Result<(), Error> {
+        match self.errors.len() {
+            0 => Ok(()),
+            // a single error keeps its kind, so that it maps to the right ttrpc code
+            1 => Err(self.errors.remove(0).1),
+            _ => {
+                let errors: Vec<_> = self
+                    .errors
+                    .iter()
+                    .map(|(name, err)| format!("{name}: {err}"))
+                    .collect();
+                Err(Error::Others(format!(
+                    "cleanup of container {} failed: {}",
+                    self.id,
+                    errors.join("; ")
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[test]
+    fn test_cleanup_runs_every_step() {
+        let ran = RefCell::new(vec![]);
+        let step = |name: &'static str, fail: bool| {
+            let ran = &ran;
+            move || {
+                ran.borrow_mut().push(name);
+                if fail {
+                    return Err(Error::FailedPrecondition(format!("{name} is stuck")));
+                }
+                Ok(())
+            }
+        };
+
+        let res = Cleanup::new("test")
+            .step("first", step("first", false))
+            .step("second", step("second", true))
+            .step("third", step("third", false))
+            .finish();
+        assert_eq!(*ran.borrow(), vec!["first", "second", "third"]);
+        let err = res.unwrap_err();
+        assert!(matches!(err, Error::FailedPrecondition(_)), "{err}");
+
+        ran.borrow_mut().clear();
+        let err = Cleanup::new("test")
+            .step("first", step("first", true))
+            .step("second", step("second", false))
+            .step("third", step("third", true))
+            .finish()
+            .unwrap_err();
+        assert_eq!(*ran.borrow(), vec!["first", "second", "third"]);
+        let Error::Others(msg) = err else {
+            panic!("expected a combined error, got {err}");
+        };
+        assert!(msg.contains("first: first is stuck"), "{msg}");
+        assert!(msg.contains("third: third is stuck"), "{msg}");
+        assert!(!msg.contains("second"), "{msg}");
+
+        assert!(Cleanup::new("test").step("ok", || Ok(())).finish().is_ok());
+    }
+}
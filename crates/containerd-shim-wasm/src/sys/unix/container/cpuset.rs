@@ -0,0 +1,147 @@
+//! Latency sensitive guests can be pinned to some CPUs of the host, with the [`CPUSET_ANNOTATION`] annotation,
+//! e.g., `0-3,6`. The process of the container is restricted to those CPUs before the guest runs.
+//!
+//! The `linux.resources.cpu.cpus` of the spec is left to the cgroup of the container, which libcontainer sets up.
+
+use anyhow::Context;
+use nix::sched::{sched_getaffinity, sched_setaffinity, CpuSet};
+use nix::unistd::Pid;
+use oci_spec::runtime::Spec;
+
+use crate::sandbox::Error;
+
+/// Annotation with the CPUs that the guest runs on, in the list format of cpusets, e.g., `0-3,6`.
+pub const CPUSET_ANNOTATION: &str = "runwasi.io/cpuset";
+
+/// Returns the CPUs that the annotation of the spec pins the guest to, or None if it doesn't pin it.
+/// Fails if the CPUs aren't available to the shim.
+pub(crate) fn requested_cpus(spec: &Spec) -> Result<Option<Vec<usize>>, Error> {
+    let Some(cpuset) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(CPUSET_ANNOTATION))
+        .map(|s| s.trim())
+    else {
+        return Ok(None);
+    };
+    if cpuset.is_empty() {
+        return Ok(None);
+    }
+
+    let cpus = parse_cpuset(cpuset)
+        .ok_or_else(|| Error::InvalidArgument(format!("invalid cpuset {cpuset:?}")))?;
+    let available = sched_getaffinity(Pid::from_raw(0))?;
+    for cpu in &cpus {
+        if !available.is_set(*cpu).unwrap_or(false) {
+            return Err(Error::InvalidArgument(format!(
+                "cpuset {cpuset:?} includes CPU {cpu}, which isn't available"
+            )));
+        }
+    }
+    Ok(Some(cpus))
+}
+
+fn parse_cpuset(cpuset: &str) -> Option<Vec<usize>> {
+    let mut cpus = vec![];
+    for range in cpuset.split(',').map(str::trim) {
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (
+                first.trim().parse::<usize>().ok()?,
+                last.trim().parse::<usize>().ok()?,
+            ),
+            None => {
+                let cpu = range.parse::<usize>().ok()?;
+                (cpu, cpu)
+            }
+        };
+        if first > last || last >= CpuSet::count() {
+            return None;
+        }
+        cpus.extend(first..=last);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Some(cpus)
+}
+
+/// Restricts the current process to `cpus`.
+/// This must be called from inside the container.
+pub(crate) fn pin_to_cpus(cpus: &[usize]) -> anyhow::Result<()> {
+    let mut cpuset = CpuSet::new();
+    for cpu in cpus {
+        cpuset.set(*cpu)?;
+    }
+    sched_setaffinity(Pid::from_raw(0), &cpuset)
+        .with_context(|| format!("failed to pin the guest to CPUs {cpus:?}"))?;
+    log::info!("pinned the guest to CPUs {cpus:?}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use oci_spec::runtime::{LinuxBuilder, LinuxCpuBuilder, LinuxResourcesBuilder, SpecBuilder};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_cpuset() {
+        assert_eq!(parse_cpuset("0"), Some(vec![0]));
+        assert_eq!(parse_cpuset("0-3, 6,2"), Some(vec![0, 1, 2, 3, 6]));
+        for invalid in ["", "a", "3-1", "0-", "-1", "0,,1"] {
+            assert_eq!(parse_cpuset(invalid), None, "{invalid}");
+        }
+        assert_eq!(parse_cpuset(&CpuSet::count().to_string()), None);
+    }
+
+    #[test]
+    fn test_requested_cpus() {
+        let spec = |annotation: Option<&str>, cpus: Option<&str>| {
+            let mut spec = SpecBuilder::default();
+            if let Some(annotation) = annotation {
+                spec = spec.annotations(HashMap::from([(
+                    CPUSET_ANNOTATION.to_string(),
+                    annotation.to_string(),
+                )]));
+            }
+            if let Some(cpus) = cpus {
+                let cpu = LinuxCpuBuilder::default().cpus(cpus).build().unwrap();
+                let resources = LinuxResourcesBuilder::default().cpu(cpu).build().unwrap();
+                let linux = LinuxBuilder::default()
+                    .resources(resources)
+                    .build()
+                    .unwrap();
+                spec = spec.linux(linux);
+            }
+            spec.build().unwrap()
+        };
+
+        assert_eq!(requested_cpus(&Spec::default()).unwrap(), None);
+        // CPU 0 is there on any host, but may not be available to the tests
+        if sched_getaffinity(Pid::from_raw(0))
+            .unwrap()
+            .is_set(0)
+            .unwrap()
+        {
+            assert_eq!(
+                requested_cpus(&spec(Some("0"), None)).unwrap(),
+                Some(vec![0])
+            );
+        }
+
+        // the cpus of the spec are left to the cgroup, even when the shim can't use them
+        let unavailable = (CpuSet::count() - 1).to_string();
+        assert_eq!(
+            requested_cpus(&spec(None, Some(&unavailable))).unwrap(),
+            None
+        );
+        assert_eq!(requested_cpus(&spec(None, Some("invalid"))).unwrap(), None);
+
+        let err = requested_cpus(&spec(Some("invalid"), Some("0"))).unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)), "{err}");
+
+        let err = requested_cpus(&spec(Some(&unavailable), None)).unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)), "{err}");
+    }
+}
@@ -0,0 +1,84 @@
+//! The modules that a container runs can be written to the host for post-mortem debugging,
+//! e.g., to analyze a crash with the exact bytes that the engine ran.
+//!
+//! The files are written under `<rootdir>/.debug/<container id>/`, next to the state of the containers,
+//! and are read-only. They are removed when the container is deleted.
+//! Container ids start with a letter or a digit, so the directory never clashes with the state of a container.
+
+use std::fs::{create_dir_all, remove_dir_all, set_permissions, write, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::sandbox::oci::WasmLayer;
+
+/// Returns the directory with the modules of the container.
+pub(crate) fn debug_modules_dir(rootdir: impl AsRef<Path>, id: &str) -> PathBuf {
+    rootdir.as_ref().join(".debug").join(id)
+}
+
+/// Writes every layer to `dir`, in the order in which they are run.
+/// Wasm modules and components get a `.wasm` extension, and precompiled layers a `.precompiled` one.
+pub(crate) fn write_debug_modules(dir: &Path, modules: &[WasmLayer]) -> Result<()> {
+    // the files of an earlier container with the same id are read-only, so they can't be overwritten
+    remove_debug_modules(dir)?;
+    create_dir_all(dir).with_context(|| format!("failed to create {dir:?}"))?;
+    for (index, module) in modules.iter().enumerate() {
+        let extension = match module.binary_type {
+            Some(_) => "wasm",
+            None => "precompiled",
+        };
+        let path = dir.join(format!("layer-{index}.{extension}"));
+        write(&path, &module.layer).with_context(|| format!("failed to write {path:?}"))?;
+        set_permissions(&path, Permissions::from_mode(0o444))?;
+    }
+    log::info!("wrote {} modules to {dir:?}", modules.len());
+    Ok(())
+}
+
+/// Removes the modules of the container, if there are any.
+pub(crate) fn remove_debug_modules(dir: &Path) -> Result<()> {
+    match remove_dir_all(dir) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("failed to remove {dir:?}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_write_debug_modules() -> Result<()> {
+        let rootdir = tempdir()?;
+        let dir = debug_modules_dir(rootdir.path(), "test");
+        let module = WasmLayer::from_module(b"\0asm\x01\0\0\0".to_vec());
+        let precompiled = WasmLayer {
+            binary_type: None,
+            wasi_version: None,
+            layer: b"precompiled".to_vec(),
+            ..module.clone()
+        };
+
+        write_debug_modules(&dir, &[module, precompiled])?;
+        assert_eq!(std::fs::read(dir.join("layer-0.wasm"))?, b"\0asm\x01\0\0\0");
+        assert_eq!(
+            std::fs::read(dir.join("layer-1.precompiled"))?,
+            b"precompiled"
+        );
+        let permissions = std::fs::metadata(dir.join("layer-0.wasm"))?.permissions();
+        assert!(permissions.readonly());
+
+        remove_debug_modules(&dir)?;
+        assert!(!dir.exists());
+        // there is nothing left to remove
+        remove_debug_modules(&dir)?;
+        Ok(())
+    }
+}
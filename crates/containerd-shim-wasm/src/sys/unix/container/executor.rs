@@ -1,20 +1,34 @@
 use std::cell::OnceCell;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use std::os::unix::prelude::PermissionsExt;
 use std::path::PathBuf;
+use std::sync::Arc;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use libcontainer::workload::default::DefaultExecutor;
 use libcontainer::workload::{
     Executor as LibcontainerExecutor, ExecutorError as LibcontainerExecutorError,
     ExecutorValidationError,
 };
+use log::LevelFilter;
+use nix::sys::signal::{signal, SigHandler, Signal};
+use nix::unistd::{getegid, geteuid};
 use oci_spec::image::Platform;
 use oci_spec::runtime::Spec;
 
-use crate::container::{Engine, PathResolve, RuntimeContext, Source, Stdio, WasiContext};
-use crate::sandbox::oci::WasmLayer;
+use crate::container::{
+    apply_module_hints, check_start_function, set_start_function, Engine, PathResolve,
+    RuntimeContext, Source, Stdio, TrapReason, WasiContext,
+};
+use crate::sandbox::oci::{apply_default_annotations, WasmLayer};
+use crate::sys::container::channel::{channel, Message, Receiver, Sender};
+use crate::sys::container::cpuset::pin_to_cpus;
+use crate::sys::container::guest_signals::forward_guest_signals;
+use crate::sys::container::interrupt::{interrupt_on_stop, interrupted_by};
+use crate::sys::container::name_resolution::configure_name_resolution;
+use crate::sys::container::trap::TrapSender;
 
 #[derive(Clone)]
 enum InnerExecutor {
@@ -23,17 +37,64 @@ enum InnerExecutor {
     CantHandle,
 }
 
+/// The executor that ran a container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutorKind {
+    /// The container ran a wasm module with the engine.
+    Wasm,
+    /// The container ran a native linux executable, as a fallback.
+    Linux,
+}
+
+impl Message for ExecutorKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            ExecutorKind::Wasm => 1,
+            ExecutorKind::Linux => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(ExecutorKind::Wasm),
+            2 => Some(ExecutorKind::Linux),
+            _ => None,
+        }
+    }
+}
+
+/// Used by the container process to report which executor handles the container.
+pub(crate) type ExecutorKindSender = Sender<ExecutorKind>;
+
+/// Kept by the shim to read which executor handled the container.
+pub(crate) type ExecutorKindReceiver = Receiver<ExecutorKind>;
+
+pub(crate) fn executor_kind_channel() -> std::io::Result<(ExecutorKindSender, ExecutorKindReceiver)>
+{
+    channel()
+}
+
 #[derive(Clone)]
 pub(crate) struct Executor<E: Engine> {
     engine: E,
     stdio: Stdio,
     inner: OnceCell<InnerExecutor>,
-    wasm_layers: Vec<WasmLayer>,
+    wasm_layers: Arc<Vec<WasmLayer>>,
     platform: Platform,
+    trap_sender: TrapSender,
+    kind_sender: ExecutorKindSender,
+    env: Vec<(String, String)>,
+    cpus: Option<Vec<usize>>,
+    start_function: Option<String>,
+    module_hints: bool,
+    native_fallback: bool,
+    log_level: Option<LevelFilter>,
+    default_annotations: HashMap<String, String>,
 }
 
 impl<E: Engine> LibcontainerExecutor for Executor<E> {
     fn validate(&self, spec: &Spec) -> Result<(), ExecutorValidationError> {
+        let spec = &self.with_defaults(spec);
         // We can handle linux container. We delegate wasm container to the engine.
         match self.inner(spec) {
             InnerExecutor::CantHandle => Err(ExecutorValidationError::CantHandle(E::name())),
@@ -42,21 +103,67 @@ impl<E: Engine> LibcontainerExecutor for Executor<E> {
     }
 
     fn exec(&self, spec: &Spec) -> Result<(), LibcontainerExecutorError> {
+        // libcontainer reads the spec from the bundle, without the defaults that the shim applied to it
+        let spec = &self.with_defaults(spec);
+        if let Some(level) = self.log_level {
+            // the container runs in its own process, so other containers keep the level of the shim
+            log::set_max_level(level);
+            log::debug!("logging container at level {level}");
+        }
         // If it looks like a linux container, run it as a linux container.
         // Otherwise, run it as a wasm container
         match self.inner(spec) {
             InnerExecutor::CantHandle => Err(LibcontainerExecutorError::CantHandle(E::name())),
             InnerExecutor::Linux => {
                 log::info!("executing linux container");
+                self.kind_sender.send(ExecutorKind::Linux);
                 self.stdio.take().redirect().unwrap();
                 DefaultExecutor {}.exec(spec)
             }
             InnerExecutor::Wasm => {
+                log::info!("executing wasm container");
+                self.kind_sender.send(ExecutorKind::Wasm);
+                if let Some(cpus) = &self.cpus {
+                    pin_to_cpus(cpus)
+                        .map_err(|err| LibcontainerExecutorError::Other(err.to_string()))?;
+                }
+                // a closed output fails the writes of the guest, rather than killing it
+                ignore_sigpipe()
+                    .map_err(|err| LibcontainerExecutorError::Other(err.to_string()))?;
+                configure_name_resolution(spec)
+                    .map_err(|err| LibcontainerExecutorError::Other(err.to_string()))?;
+                // while the process is still single threaded, as setting the environment races with reading it
+                for (key, value) in &self.env {
+                    // the environment of the container takes precedence over annotations
+                    if std::env::var_os(key).is_none() {
+                        std::env::set_var(key, value);
+                    }
+                }
+                // before the signals of the guest are forwarded, as that spawns a thread
+                interrupt_on_stop(spec, self.engine.clone())
+                    .map_err(|err| LibcontainerExecutorError::Other(err.to_string()))?;
+                forward_guest_signals(spec)
+                    .map_err(|err| LibcontainerExecutorError::Other(err.to_string()))?;
+                check_user(spec)
+                    .map_err(|err| LibcontainerExecutorError::Other(err.to_string()))?;
+                // a module in the rootfs can only be read once inside the container
+                let spec = &self.resolved(spec);
+                check_start_function(&self.ctx(spec))
+                    .map_err(|err| LibcontainerExecutorError::Other(err.to_string()))?;
+
+                let spec = &self.transform_args(spec);
                 log::info!("calling start function");
                 match self.engine.run_wasi(&self.ctx(spec), self.stdio.take()) {
                     Ok(code) => std::process::exit(code),
                     Err(err) => {
                         log::info!("error running start function: {err}");
+                        // the guest was stopped, rather than failing on its own
+                        if let Some(signal) = interrupted_by() {
+                            std::process::exit(128 + signal);
+                        }
+                        if let Some(reason) = err.downcast_ref::<TrapReason>() {
+                            self.trap_sender.send(*reason);
+                        }
                         std::process::exit(137)
                     }
                 };
@@ -66,16 +173,116 @@ impl<E: Engine> LibcontainerExecutor for Executor<E> {
 }
 
 impl<E: Engine> Executor<E> {
-    pub fn new(engine: E, stdio: Stdio, wasm_layers: Vec<WasmLayer>, platform: Platform) -> Self {
+    pub fn new(
+        engine: E,
+        stdio: Stdio,
+        wasm_layers: Arc<Vec<WasmLayer>>,
+        platform: Platform,
+        trap_sender: TrapSender,
+        kind_sender: ExecutorKindSender,
+    ) -> Self {
         Self {
             engine,
             stdio,
             inner: Default::default(),
             wasm_layers,
             platform,
+            trap_sender,
+            kind_sender,
+            env: vec![],
+            cpus: None,
+            start_function: None,
+            module_hints: false,
+            native_fallback: true,
+            log_level: None,
+            default_annotations: HashMap::new(),
         }
     }
 
+    /// Adds variables to the environment of the guest, unless the container already sets them.
+    pub fn with_env(mut self, env: Vec<(String, String)>) -> Self {
+        self.env = env;
+        self
+    }
+
+    /// Pins the guest to `cpus`, rather than letting it run on any CPU of the container.
+    pub fn with_cpus(mut self, cpus: Option<Vec<usize>>) -> Self {
+        self.cpus = cpus;
+        self
+    }
+
+    /// Runs `start_function` rather than the function that the entrypoint of the spec names,
+    /// e.g., the one that the manifest of the image names.
+    pub fn with_start_function(mut self, start_function: Option<String>) -> Self {
+        self.start_function = start_function;
+        self
+    }
+
+    /// Applies the hints in the `runwasi` custom section of the module as defaults of the spec.
+    pub fn with_module_hints(mut self, module_hints: bool) -> Self {
+        self.module_hints = module_hints;
+        self
+    }
+
+    /// Runs native linux executables with the linux executor, rather than refusing them.
+    pub fn with_native_fallback(mut self, native_fallback: bool) -> Self {
+        self.native_fallback = native_fallback;
+        self
+    }
+
+    /// Sets the log level of the container process, rather than keeping the one of the shim.
+    pub fn with_log_level(mut self, log_level: Option<LevelFilter>) -> Self {
+        self.log_level = log_level;
+        self
+    }
+
+    /// Adds annotations to the spec of the container, unless it already sets them,
+    /// e.g., the default annotations of its namespace.
+    pub fn with_default_annotations(
+        mut self,
+        default_annotations: HashMap<String, String>,
+    ) -> Self {
+        self.default_annotations = default_annotations;
+        self
+    }
+
+    // Returns the spec with the default annotations applied, see `with_default_annotations`.
+    fn with_defaults(&self, spec: &Spec) -> Spec {
+        let mut spec = spec.clone();
+        apply_default_annotations(&mut spec, &self.default_annotations);
+        spec
+    }
+
+    // Returns the spec with the start function and the hints of the module applied,
+    // see `with_start_function` and `with_module_hints`.
+    fn resolved(&self, spec: &Spec) -> Spec {
+        let mut spec = spec.clone();
+        if let Some(func) = &self.start_function {
+            set_start_function(&mut spec, func);
+        }
+        if self.module_hints {
+            spec = apply_module_hints(&self.ctx(&spec));
+        }
+        spec
+    }
+
+    // Rewrites the arguments of the guest with `Engine::transform_args`, keeping the entrypoint.
+    fn transform_args(&self, spec: &Spec) -> Spec {
+        let mut spec = spec.clone();
+        let Some(mut process) = spec.process().clone() else {
+            return spec;
+        };
+        let args = process.args().clone().unwrap_or_default();
+        if let Some((entrypoint, guest_args)) = args.split_first() {
+            let mut transformed = vec![entrypoint.clone()];
+            transformed.extend(self.engine.transform_args(guest_args.to_vec()));
+            log::debug!("transformed the arguments of the guest to {transformed:?}");
+            process.set_args(Some(transformed));
+            spec.set_process(Some(process));
+        }
+        spec
+    }
+
     fn ctx<'a>(&'a self, spec: &'a Spec) -> WasiContext<'a> {
         let wasm_layers = &self.wasm_layers;
         let platform = &self.platform;
@@ -88,7 +295,9 @@ impl<E: Engine> Executor<E> {
 
     fn inner(&self, spec: &Spec) -> &InnerExecutor {
         self.inner.get_or_init(|| {
-            if is_linux_container(&self.ctx(spec)).is_ok() {
+            // the sandbox container of a pod runs the native pause executable, whatever the fallback is
+            let native_fallback = self.native_fallback || is_sandbox_container(spec);
+            if native_fallback && is_linux_container(&self.ctx(spec)).is_ok() {
                 InnerExecutor::Linux
             } else if self.engine.can_handle(&self.ctx(spec)).is_ok() {
                 InnerExecutor::Wasm
@@ -99,6 +308,38 @@ impl<E: Engine> Executor<E> {
     }
 }
 
+fn ignore_sigpipe() -> Result<()> {
+    unsafe { signal(Signal::SIGPIPE, SigHandler::SigIgn) }?;
+    Ok(())
+}
+
+// libcontainer switches to the uid / gid in `process.user` before calling the executor.
+// Make sure that happened, so that the guest never runs with more privileges than requested.
+fn check_user(spec: &Spec) -> Result<()> {
+    let Some(process) = spec.process() else {
+        return Ok(());
+    };
+    let user = process.user();
+    let (uid, gid) = (geteuid().as_raw(), getegid().as_raw());
+    ensure!(
+        uid == user.uid() && gid == user.gid(),
+        "running as {uid}:{gid}, but the spec requests {}:{}",
+        user.uid(),
+        user.gid()
+    );
+    log::info!("running guest as {uid}:{gid}");
+    Ok(())
+}
+
+/// Returns whether the spec is the one of the sandbox container of a pod, which CRI creates
+/// to hold the namespaces of the pod, rather than of a container of the pod.
+pub(crate) fn is_sandbox_container(spec: &Spec) -> bool {
+    spec.annotations()
+        .as_ref()
+        .and_then(|annotations| annotations.get("io.kubernetes.cri.container-type"))
+        .is_some_and(|typ| typ == "sandbox")
+}
+
 fn is_linux_container(ctx: &impl RuntimeContext) -> Result<()> {
     if let Source::Oci(_) = ctx.entrypoint().source {
         bail!("the entry point contains wasm layers")
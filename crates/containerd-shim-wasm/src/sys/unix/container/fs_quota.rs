@@ -0,0 +1,209 @@
+//! The guest can write to any writable directory of its container, and so fill the disk of the host.
+//! The [`FS_QUOTA_ANNOTATION`] annotation caps the size of directories of the container:
+//! each one is added to the mounts of the spec as a tmpfs of that size, which libcontainer mounts
+//! when it creates the container, so that writes past the quota fail with `ENOSPC` in the guest,
+//! and nothing the guest writes there reaches the disk of the host.
+//!
+//! The tmpfs starts empty, and hides what the rootfs, or another mount of the spec, has in the directory.
+//! What the guest writes to it is held in memory, and counts against the memory limit of the container,
+//! so the quotas of a container should fit in its memory limit.
+
+use std::path::{Path, PathBuf};
+
+use oci_spec::runtime::{Mount, MountBuilder, Spec};
+
+use crate::sandbox::Error;
+
+/// Annotation with a comma separated list of directories of the container and their size quota,
+/// in bytes or with a `Ki`, `Mi` or `Gi` suffix, e.g. `/data=64Mi,/tmp=512Ki`.
+pub const FS_QUOTA_ANNOTATION: &str = "runwasi.io/fs-quota";
+
+/// A directory of the container and the size it is limited to.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct FsQuota {
+    destination: PathBuf,
+    size: u64,
+}
+
+/// Adds a tmpfs of the size of its quota to the mounts of the spec for every quota that the spec requests,
+/// in place of any mount at the same directory.
+pub(crate) fn mount_fs_quotas(spec: &mut Spec) -> Result<(), Error> {
+    let fs_quotas = fs_quotas(spec)?;
+    if fs_quotas.is_empty() {
+        return Ok(());
+    }
+    let mut mounts = spec.mounts().clone().unwrap_or_default();
+    for quota in fs_quotas {
+        mounts.retain(|m| m.destination() != &quota.destination);
+        // after the other mounts, so that it isn't hidden by a mount of a parent directory
+        mounts.push(quota.tmpfs()?);
+        log::info!("limiting {:?} to {} bytes", quota.destination, quota.size);
+    }
+    spec.set_mounts(Some(mounts));
+    Ok(())
+}
+
+// the size quotas that the spec requests
+fn fs_quotas(spec: &Spec) -> Result<Vec<FsQuota>, Error> {
+    let Some(quotas) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(FS_QUOTA_ANNOTATION))
+    else {
+        return Ok(vec![]);
+    };
+    let invalid = |quota: &str, reason: &str| {
+        Error::InvalidArgument(format!(
+            "invalid quota {quota:?} in {FS_QUOTA_ANNOTATION} annotation: {reason}"
+        ))
+    };
+
+    let mut fs_quotas = vec![];
+    for quota in quotas.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (destination, size) = quota
+            .split_once('=')
+            .ok_or_else(|| invalid(quota, "expected <directory>=<size>"))?;
+        let destination = Path::new(destination.trim());
+        // the rootfs itself can't be replaced by a tmpfs
+        if !destination.is_absolute() || destination.parent().is_none() {
+            return Err(invalid(
+                quota,
+                "the directory must be an absolute path other than /",
+            ));
+        }
+        // a tmpfs with a size of 0 has no limit
+        let size = parse_size(size.trim())
+            .filter(|size| *size > 0)
+            .ok_or_else(|| invalid(quota, "the size must be a positive number of bytes"))?;
+        fs_quotas.push(FsQuota {
+            destination: destination.to_path_buf(),
+            size,
+        });
+    }
+    Ok(fs_quotas)
+}
+
+fn parse_size(size: &str) -> Option<u64> {
+    let (number, unit) = match size.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => size.split_at(i),
+        None => (size, ""),
+    };
+    let unit: u64 = match unit {
+        "" => 1,
+        "Ki" => 1 << 10,
+        "Mi" => 1 << 20,
+        "Gi" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+impl FsQuota {
+    fn tmpfs(&self) -> anyhow::Result<Mount> {
+        // writable by any user, as the guest may not run as root
+        let options = [
+            "nosuid".to_string(),
+            "nodev".to_string(),
+            "mode=1777".to_string(),
+            format!("size={}", self.size),
+        ];
+        Ok(MountBuilder::default()
+            .destination(&self.destination)
+            .typ("tmpfs")
+            .source("tmpfs")
+            .options(options.to_vec())
+            .build()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use oci_spec::runtime::SpecBuilder;
+
+    use super::*;
+
+    fn spec(quotas: &str) -> Spec {
+        SpecBuilder::default()
+            .annotations(HashMap::from([(
+                FS_QUOTA_ANNOTATION.to_string(),
+                quotas.to_string(),
+            )]))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_fs_quotas() {
+        assert_eq!(fs_quotas(&Spec::default()).unwrap(), vec![]);
+
+        assert_eq!(
+            fs_quotas(&spec("/data=64Mi, /tmp=512Ki,/cache=4096")).unwrap(),
+            vec![
+                FsQuota {
+                    destination: PathBuf::from("/data"),
+                    size: 64 << 20,
+                },
+                FsQuota {
+                    destination: PathBuf::from("/tmp"),
+                    size: 512 << 10,
+                },
+                FsQuota {
+                    destination: PathBuf::from("/cache"),
+                    size: 4096,
+                },
+            ]
+        );
+
+        for invalid in [
+            "/data",
+            "data=1Mi",
+            "/=1Mi",
+            "/data=0",
+            "/data=1MB",
+            "/data=-1",
+        ] {
+            let err = fs_quotas(&spec(invalid)).unwrap_err();
+            assert!(matches!(err, Error::InvalidArgument(_)), "{invalid}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_mount_fs_quotas() -> anyhow::Result<()> {
+        let mut without = Spec::default();
+        mount_fs_quotas(&mut without)?;
+        assert_eq!(without, Spec::default());
+
+        let bind = MountBuilder::default()
+            .destination("/etc/hosts")
+            .typ("bind")
+            .source("/etc/hosts")
+            .build()?;
+        let mut spec = spec("/tmp=512Ki");
+        spec.set_mounts(Some(vec![
+            bind.clone(),
+            MountBuilder::default()
+                .destination("/tmp")
+                .typ("tmpfs")
+                .source("tmpfs")
+                .build()?,
+        ]));
+        mount_fs_quotas(&mut spec)?;
+
+        // the quota replaces the mount at its directory, and leaves the others alone
+        let mounts = spec.mounts().as_ref().unwrap();
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[0], bind);
+        let quota = &mounts[1];
+        assert_eq!(quota.destination(), Path::new("/tmp"));
+        assert_eq!(quota.typ().as_deref(), Some("tmpfs"));
+        assert!(quota
+            .options()
+            .as_ref()
+            .unwrap()
+            .contains(&"size=524288".to_string()));
+
+        Ok(())
+    }
+}
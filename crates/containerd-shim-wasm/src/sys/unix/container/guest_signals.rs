@@ -0,0 +1,182 @@
+//! WASI has no signals, so a guest can't handle the signals sent to its container.
+//! Instead, the container process catches the signals listed in the [`GUEST_SIGNALS_ANNOTATION`]
+//! annotation, and appends their names, one per line, to the [`GUEST_SIGNALS_FILE`] file.
+//! A cooperating guest can watch that file to react to, e.g., `SIGHUP` or `SIGUSR1`.
+//!
+//! The file is created by the shim in the bundle of the container, and bind mounted into the container,
+//! so that it works with a read-only rootfs and with a guest that doesn't run as root.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+
+use anyhow::{Context, Result};
+use nix::sys::signal::{SigSet, Signal};
+use nix::unistd::{chown, Gid, Uid};
+use oci_spec::runtime::{MountBuilder, Spec};
+
+/// Annotation with a comma separated list of the signals to forward to the guest, e.g. `SIGHUP,SIGUSR1`.
+pub const GUEST_SIGNALS_ANNOTATION: &str = "runwasi.io/guest-signals";
+
+/// File in the container where the signals forwarded to the guest are written.
+pub const GUEST_SIGNALS_FILE: &str = "/run/runwasi/signals";
+
+// the file of the bundle that is mounted at `GUEST_SIGNALS_FILE`
+const SIGNALS_FILE: &str = "runwasi-signals";
+
+pub(crate) fn guest_signals(spec: &Spec) -> Result<SigSet> {
+    let mut signals = SigSet::empty();
+    let Some(names) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(GUEST_SIGNALS_ANNOTATION))
+    else {
+        return Ok(signals);
+    };
+    for name in names.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let signal = Signal::from_str(name).with_context(|| {
+            format!("invalid signal {name:?} in {GUEST_SIGNALS_ANNOTATION} annotation")
+        })?;
+        signals.add(signal);
+    }
+    Ok(signals)
+}
+
+// Whether the set has no signals.
+// Sets can't be compared with `SigSet::empty()`, as it leaves the bytes of `sigset_t` past the signals
+// of the platform uninitialized.
+pub(crate) fn is_empty(signals: &SigSet) -> bool {
+    signals.iter().next().is_none()
+}
+
+/// Creates the file of the signals forwarded to the guest in `bundle`, if the spec requests any,
+/// and adds a bind mount of it at [`GUEST_SIGNALS_FILE`] to the spec.
+/// The file is owned by the user of the process of the container, which writes to it.
+pub(crate) fn mount_guest_signals(spec: &mut Spec, bundle: &Path) -> Result<Option<PathBuf>> {
+    if is_empty(&guest_signals(spec)?) {
+        return Ok(None);
+    }
+
+    let path = bundle.join(SIGNALS_FILE);
+    File::create(&path).with_context(|| format!("failed to create {path:?}"))?;
+    if let Some(user) = spec.process().as_ref().map(|p| p.user()) {
+        chown(
+            &path,
+            Some(Uid::from_raw(user.uid())),
+            Some(Gid::from_raw(user.gid())),
+        )?;
+    }
+
+    let mut mounts = spec.mounts().clone().unwrap_or_default();
+    mounts.push(
+        MountBuilder::default()
+            .destination(GUEST_SIGNALS_FILE)
+            .typ("bind")
+            .source(&path)
+            .options(
+                ["rbind", "nosuid", "nodev", "noexec"]
+                    .map(String::from)
+                    .to_vec(),
+            )
+            .build()?,
+    );
+    spec.set_mounts(Some(mounts));
+    Ok(Some(path))
+}
+
+/// Starts forwarding the signals requested by the spec to the guest.
+/// This must be called from inside the container, before the engine spawns any thread.
+pub(crate) fn forward_guest_signals(spec: &Spec) -> Result<()> {
+    let signals = guest_signals(spec)?;
+    if is_empty(&signals) {
+        return Ok(());
+    }
+
+    // the file is mounted by libcontainer, see `mount_guest_signals`
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(GUEST_SIGNALS_FILE)
+        .with_context(|| format!("failed to open {GUEST_SIGNALS_FILE}"))?;
+
+    // Block the signals in this thread, so that every thread spawned later also blocks them,
+    // and they are only received by the thread below.
+    signals.thread_block()?;
+    thread::spawn(move || loop {
+        match signals.wait() {
+            Ok(signal) => {
+                log::info!("forwarding {signal} to the guest");
+                if let Err(err) = writeln!(file, "{signal}") {
+                    log::warn!("failed to forward {signal} to the guest: {err}");
+                }
+            }
+            Err(err) => {
+                log::error!("failed to wait for guest signals: {err}");
+                return;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use oci_spec::runtime::SpecBuilder;
+
+    use super::*;
+
+    fn spec_with_signals(signals: &str) -> Result<Spec> {
+        Ok(SpecBuilder::default()
+            .annotations(HashMap::from([(
+                GUEST_SIGNALS_ANNOTATION.to_string(),
+                signals.to_string(),
+            )]))
+            .build()?)
+    }
+
+    #[test]
+    fn test_guest_signals() -> Result<()> {
+        let signals = guest_signals(&spec_with_signals("SIGHUP, SIGUSR1")?)?;
+        assert!(signals.contains(Signal::SIGHUP));
+        assert!(signals.contains(Signal::SIGUSR1));
+        assert!(!signals.contains(Signal::SIGTERM));
+
+        let signals = guest_signals(&SpecBuilder::default().build()?)?;
+        assert!(is_empty(&signals));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_guest_signals() -> Result<()> {
+        let bundle = tempfile::tempdir()?;
+
+        let mut spec = SpecBuilder::default().build()?;
+        assert_eq!(mount_guest_signals(&mut spec, bundle.path())?, None);
+
+        let mut spec = spec_with_signals("SIGUSR1")?;
+        let mounts = spec.mounts().clone().unwrap_or_default();
+        let path = mount_guest_signals(&mut spec, bundle.path())?.unwrap();
+        assert!(path.starts_with(bundle.path()));
+        assert!(path.is_file());
+
+        // the file is written on the host, and only mounted into the container
+        let added = spec.mounts().as_ref().unwrap().last().unwrap().clone();
+        assert_eq!(spec.mounts().as_ref().unwrap().len(), mounts.len() + 1);
+        assert_eq!(added.destination(), Path::new(GUEST_SIGNALS_FILE));
+        assert_eq!(added.source().as_deref(), Some(path.as_path()));
+        assert_eq!(added.typ().as_deref(), Some("bind"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_guest_signals() -> Result<()> {
+        guest_signals(&spec_with_signals("SIGHUP,SIGNOPE")?).expect_err("SIGNOPE is not a signal");
+        Ok(())
+    }
+}
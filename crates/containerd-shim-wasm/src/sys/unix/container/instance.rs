@@ -1,7 +1,9 @@
-use std::marker::PhantomData;
+use std::fs::File;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use chrono::{DateTime, Utc};
@@ -10,59 +12,814 @@ use libcontainer::container::Container;
 use libcontainer::signal::Signal;
 use libcontainer::syscall::syscall::SyscallType;
 use nix::errno::Errno;
+use nix::sys::signal::Signal as NixSignal;
 use nix::sys::wait::{waitid, Id as WaitID, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 use oci_spec::image::Platform;
+use oci_spec::runtime::Spec;
 
-use crate::container::Engine;
-use crate::sandbox::instance_utils::{determine_rootdir, get_instance_root, instance_exists};
+use crate::container::{
+    apply_module_hints, check_start_function, check_wasm_features, enabled_wasm_features,
+    manifest_start_function, order_layers, parse_entrypoint, platform_wasm_features,
+    reorders_layers, set_start_function, Capabilities, Engine, WasiContext, WasmBinaryType,
+    WasmFeature, ENTRYPOINT_ANNOTATION, FUEL_ANNOTATION, INTERRUPT_TIMEOUT_ANNOTATION,
+};
+use crate::sandbox::instance_utils::{
+    determine_rootdir, get_instance_root, instance_exists, read_options, ClosedOutput, ShimOptions,
+};
+use crate::sandbox::oci::{
+    annotation_env, apply_default_annotations, success_exit_codes, AssetLayer, WasmLayer,
+};
+use crate::sandbox::shim::logger;
+use crate::sandbox::stdio::{OutputCopies, NORMALIZE_LINE_ENDINGS_ANNOTATION};
 use crate::sandbox::sync::WaitableCell;
 use crate::sandbox::{
-    containerd, Error as SandboxError, Instance as SandboxInstance, InstanceConfig, Stdio,
+    containerd, DeleteFailure, Error as SandboxError, ExitStatus, Instance as SandboxInstance,
+    InstanceConfig, Stdio, SuccessExitCodes,
 };
-use crate::sys::container::executor::Executor;
+use crate::sys::container::assets::{mount_assets, Assets, ContainerdAssetReader};
+use crate::sys::container::build_timeout::build_with_timeout;
+use crate::sys::container::cleanup::Cleanup;
+use crate::sys::container::cpuset::requested_cpus;
+use crate::sys::container::debug_modules::{
+    debug_modules_dir, remove_debug_modules, write_debug_modules,
+};
+use crate::sys::container::executor::{
+    executor_kind_channel, is_sandbox_container, Executor, ExecutorKind, ExecutorKindReceiver,
+};
+use crate::sys::container::fs_quota::mount_fs_quotas;
+use crate::sys::container::guest_signals::mount_guest_signals;
+use crate::sys::container::logs::{capture_logs, logs_dir, remove_logs, LogExport};
+use crate::sys::container::metrics::InstanceMetrics;
+use crate::sys::container::oom::OomCounter;
+use crate::sys::container::secrets::{mount_secrets, remove_secrets};
+use crate::sys::container::timings::StartupTimings;
+use crate::sys::container::trap::{trap_channel, TrapReceiver};
+use crate::sys::signals::{SIGKILL, SIGTERM};
+
+/// Annotation that overrides the signal a container is stopped with, e.g., `SIGINT`,
+/// instead of the `StopSignal` of its image.
+pub const STOP_SIGNAL_ANNOTATION: &str = "runwasi.io/stop-signal";
 
 static DEFAULT_CONTAINER_ROOT_DIR: &str = "/run/containerd";
+const OUTPUT_COPY_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_BUILD_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_READ_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// what the container runs, from the image in containerd
+struct LoadedImage {
+    modules: Arc<Vec<WasmLayer>>,
+    platform: Platform,
+    image_digest: Option<String>,
+    stop_signal: Option<String>,
+    assets: Vec<AssetLayer>,
+    // the function that the manifest names, and the entrypoint of the image config, see `manifest_start_function`
+    manifest_function: Option<String>,
+    config_entrypoint: Option<Vec<String>>,
+}
+
+// check if container is OCI image with wasm layers and attempt to read the module
+fn load_image<E: Engine>(
+    id: &str,
+    cfg: &InstanceConfig<E>,
+    options: &ShimOptions,
+    spec: &Spec,
+    engine: &E,
+    wasm_features: &[WasmFeature],
+    timings: &mut StartupTimings,
+) -> Result<LoadedImage, SandboxError> {
+    let namespace = cfg.get_namespace();
+    let mut client = containerd::Client::connect_with_tls(
+        cfg.get_containerd_address().as_str(),
+        namespace,
+        options.containerd_tls.clone(),
+    )?;
+    if let Some(content_address) = &options.containerd_content_address {
+        client = client.with_content_address(content_address)?;
+    }
+    client = client
+        .with_wasm_features(wasm_features.to_vec())
+        .with_precompile(runs_precompiled(spec))
+        .with_module_hints(options.module_hints == Some(true));
+    if let Some(max_cache_size) = options.max_precompiled_cache_size {
+        client = client.with_max_cache_size(max_cache_size);
+    }
+    if let Some(precompile_cache_dir) = &options.precompile_cache_dir {
+        client = client.with_precompile_cache_dir(precompile_cache_dir)?;
+    }
+    if let Some(max_module_bytes) = options.max_module_bytes {
+        client = client.with_max_module_bytes(max_module_bytes);
+    }
+    if let Some(read_concurrency) = options.content_read_concurrency {
+        client = client.with_read_concurrency(read_concurrency);
+    }
+    if let Some(read_retries) = options.content_read_retries {
+        let delay = options
+            .content_read_retry_delay_ms
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_READ_RETRY_DELAY);
+        client = client.with_read_retries(read_retries, delay);
+    }
+    if let Some(space_factor) = options.precompile_space_factor {
+        client = client.with_space_factor(space_factor);
+    }
+    if let Some(content_store_root) = &options.content_store_root {
+        client = client.with_content_store_root(content_store_root);
+    }
+    if let Some(min_precompile_memory) = options.min_precompile_memory {
+        client = client.with_min_precompile_memory(min_precompile_memory);
+    }
+    if let Some(precompile_wait) = options.precompile_wait_seconds {
+        client = client.with_precompile_wait(Duration::from_secs(precompile_wait));
+    }
+    if let Some(image_target_wait) = options.image_target_wait_seconds {
+        client = client.with_image_target_wait(Duration::from_secs(image_target_wait));
+    }
+    if let Some(keep_existing_precompiled) = options.keep_existing_precompiled {
+        client = client.with_keep_existing_precompiled(keep_existing_precompiled);
+    }
+    if let Some(compress_precompiled) = options.compress_precompiled {
+        client = client.with_compressed_precompiled(compress_precompiled);
+    }
+    let verifier = engine.clone();
+    client = client.with_verifier(move |manifest| verifier.verify_image(manifest));
+    let (modules, platform) = match client.load_modules(id, engine) {
+        Ok(modules) => modules,
+        // the image can't run on this runtime, e.g. it requires unsupported features
+        Err(err @ SandboxError::FailedPrecondition(_)) => return Err(err),
+        // the image targets wasm, but there is nothing in it to run
+        Err(err @ SandboxError::NoRunnableContent(_)) => return Err(err),
+        // precompiling would fill the disk of the content store
+        Err(err @ SandboxError::InsufficientSpace(_)) => return Err(err),
+        // the engine can't run the modules on this host
+        Err(err @ SandboxError::UnsupportedFeature(_)) => return Err(err),
+        // the image must run precompiled, and the engine can't precompile it
+        Err(err @ SandboxError::PrecompileRequired(_)) => return Err(err),
+        // a module of the image is empty or truncated
+        Err(err @ SandboxError::InvalidModule { .. }) => return Err(err),
+        // a module of the image is larger than the shim may load
+        Err(err @ SandboxError::ModuleTooLarge { .. }) => return Err(err),
+        // the image is still being pulled
+        Err(err @ SandboxError::ImageNotReady(_)) => return Err(err),
+        // the image was rejected by the engine, and must not run
+        Err(err @ SandboxError::VerificationFailed(_)) => return Err(err),
+        // containerd stopped making progress, the container can be created again later
+        Err(err @ SandboxError::Timeout { .. }) => return Err(err),
+        Err(e) => {
+            log::warn!("Error obtaining wasm layers for container {id}.  Will attempt to use files inside container image. Error: {e}");
+            (Arc::default(), Platform::default())
+        }
+    };
+    let load_timings = client.load_timings();
+    timings.content_loaded = load_timings.content_loaded;
+    timings.precompiled = load_timings.precompiled;
+    let image_digest = client.image_digest(id).ok();
+    let stop_signal = client.stop_signal(id).unwrap_or_else(|err| {
+        log::debug!("no stop signal for container {id}: {err}");
+        None
+    });
+
+    // asset layers are only read from the content store if the guest uses them
+    let assets = client.load_assets(id).unwrap_or_else(|err| {
+        log::debug!("no asset layers for container {id}: {err}");
+        vec![]
+    });
+
+    let manifest_function = match client.manifest_annotations(id) {
+        Ok(mut annotations) => annotations.remove(ENTRYPOINT_ANNOTATION),
+        Err(err) => {
+            log::debug!("no manifest annotations for container {id}: {err}");
+            None
+        }
+    };
+    // the entrypoint of the config only matters to tell whether the runtime overrode it
+    let config_entrypoint = match manifest_function {
+        Some(_) => client.config_entrypoint(id).unwrap_or_else(|err| {
+            log::debug!("no entrypoint in the image config of container {id}: {err}");
+            None
+        }),
+        None => None,
+    };
+
+    Ok(LoadedImage {
+        modules,
+        platform,
+        image_digest,
+        stop_signal,
+        assets,
+        manifest_function,
+        config_entrypoint,
+    })
+}
+
+// Whether the modules of the container can run precompiled.
+// The engine meters the fuel of a container that sets a limit, and makes the guest of a container that sets
+// an interrupt timeout interruptible, while precompiled modules are compiled without either.
+// The layers of a container that orders them are precompiled in the order of the manifest.
+fn runs_precompiled(spec: &Spec) -> bool {
+    let annotation = |key: &str| spec.annotations().as_ref().and_then(|a| a.get(key));
+    annotation(FUEL_ANNOTATION).is_none()
+        && annotation(INTERRUPT_TIMEOUT_ANNOTATION).is_none()
+        && !reorders_layers(spec)
+}
+
+// Warms the precompiled modules with the engine, so that the guest doesn't have to load them when it starts.
+// The container process is forked from the shim, and shares the state of the engine from this point.
+// Failing to warm a module only makes the container start slower, so it doesn't fail the container.
+// The modules are warmed with the digest that their layer is described by, see `Source::digest`.
+// Returns the modules that were warmed, which are released if the container fails to be created.
+fn warm_precompiled<E: Engine>(engine: &E, modules: &[WasmLayer]) -> WarmedModules<E> {
+    let precompiled = modules
+        .iter()
+        .filter(|module| WasmBinaryType::from_bytes(&module.layer).is_none());
+    let mut warmed = WarmedModules {
+        engine: engine.clone(),
+        digests: vec![],
+    };
+    for module in precompiled {
+        let digest = module.config.digest().clone();
+        log::info!("warming precompiled module {digest}");
+        match engine.warm(&digest, &module.layer) {
+            Ok(()) => warmed.digests.push(digest),
+            Err(err) => log::warn!("failed to warm precompiled module {digest}: {err}"),
+        }
+    }
+    warmed
+}
+
+// The precompiled modules that a container warmed with the engine.
+// They are released when the guard is dropped, unless the container keeps them with `keep`,
+// so that a container that fails to be created doesn't keep them from being evicted.
+struct WarmedModules<E: Engine> {
+    engine: E,
+    digests: Vec<String>,
+}
+
+impl<E: Engine> WarmedModules<E> {
+    // Keeps the modules for the container, which releases them when it is deleted.
+    fn keep(mut self) -> Vec<String> {
+        std::mem::take(&mut self.digests)
+    }
+}
+
+impl<E: Engine> Drop for WarmedModules<E> {
+    fn drop(&mut self) {
+        for digest in &self.digests {
+            self.engine.release(digest);
+        }
+    }
+}
+
+// The modules that the containerd client cached for a container, see `containerd::Client::load_modules`.
+// They are dropped from the cache when the guard is dropped, unless the container is created with `keep`,
+// as only a container that is deleted drops them otherwise.
+struct CachedModules {
+    engine_name: &'static str,
+    id: String,
+    kept: bool,
+}
+
+impl CachedModules {
+    fn new(engine_name: &'static str, id: &str) -> Self {
+        Self {
+            engine_name,
+            id: id.to_string(),
+            kept: false,
+        }
+    }
+
+    // Keeps the modules cached for the container, which drops them when it is deleted.
+    fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for CachedModules {
+    fn drop(&mut self) {
+        if !self.kept {
+            containerd::forget_modules(self.engine_name, &self.id);
+        }
+    }
+}
+
+// Parses a signal the way the image config and docker write it, e.g., `SIGTERM`, `TERM` or `15`.
+fn parse_signal(signal: &str) -> Result<u32, SandboxError> {
+    let invalid = || SandboxError::InvalidArgument(format!("invalid stop signal {signal:?}"));
+    if let Ok(number) = signal.parse::<i32>() {
+        return NixSignal::try_from(number)
+            .map(|s| s as u32)
+            .map_err(|_| invalid());
+    }
+    let name = signal.to_uppercase();
+    let name = match name.starts_with("SIG") {
+        true => name,
+        false => format!("SIG{name}"),
+    };
+    name.parse::<NixSignal>()
+        .map(|s| s as u32)
+        .map_err(|_| invalid())
+}
+
+// Fails with `NotWasm` if the entrypoint of the container is a native executable in the rootfs,
+// i.e., something that the linux executor would run.
+// The entrypoint is resolved the way it is inside the container, with the `PATH` of the spec.
+fn check_not_native(spec: &Spec, rootfs: &Path) -> Result<(), SandboxError> {
+    let Some(process) = spec.process() else {
+        return Ok(());
+    };
+    let Some(arg0) = process.args().as_ref().and_then(|args| args.first()) else {
+        return Ok(());
+    };
+    let path = Path::new(parse_entrypoint(arg0).0);
+    let in_rootfs = |path: &Path| rootfs.join(path.strip_prefix("/").unwrap_or(path));
+    let candidates = if path.components().count() > 1 {
+        vec![in_rootfs(&process.cwd().join(path))]
+    } else {
+        let paths = process
+            .env()
+            .iter()
+            .flatten()
+            .find_map(|var| var.strip_prefix("PATH="))
+            .unwrap_or_default();
+        std::env::split_paths(paths)
+            .map(|dir| in_rootfs(&dir.join(path)))
+            .collect()
+    };
+
+    for candidate in candidates {
+        let mut buffer = [0; 4];
+        let read = File::open(&candidate).and_then(|mut file| file.read_exact(&mut buffer));
+        if read.is_err() {
+            continue;
+        }
+        // the ELF magic number or a shebang, as the linux executor checks
+        if matches!(buffer, [0x7f, 0x45, 0x4c, 0x46] | [0x23, 0x21, ..]) {
+            return Err(SandboxError::NotWasm(format!(
+                "the entrypoint {arg0:?} is a native executable, and the native fallback is disabled"
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Fails with `InvalidArgument` if the spec misses a section that a container can't run without,
+// so that a partial spec is refused before anything is set up for the container.
+fn check_spec(spec: &Spec) -> Result<(), SandboxError> {
+    if spec.process().is_none() {
+        return Err(SandboxError::InvalidArgument(
+            "the runtime spec has no process".to_string(),
+        ));
+    }
+    if spec.root().is_none() {
+        return Err(SandboxError::InvalidArgument(
+            "the runtime spec has no root".to_string(),
+        ));
+    }
+    Ok(())
+}
 
 pub struct Instance<E: Engine> {
     exit_code: WaitableCell<(u32, DateTime<Utc>)>,
+    exit_status: Arc<OnceLock<ExitStatus>>,
+    trap_receiver: Arc<TrapReceiver>,
+    executor_kind: OnceLock<ExecutorKind>,
+    kind_receiver: ExecutorKindReceiver,
     rootdir: PathBuf,
     id: String,
-    _phantom: PhantomData<E>,
+    image_digest: Option<String>,
+    platform: Platform,
+    stop_signal: u32,
+    success_exit_codes: SuccessExitCodes,
+    output_copies: Option<Arc<OutputCopies>>,
+    debug_modules: Option<PathBuf>,
+    secrets_dir: Option<PathBuf>,
+    signals_file: Option<PathBuf>,
+    log_export: Option<LogExport>,
+    exported_logs: OnceLock<containerd::ExportedLogs>,
+    timings: StartupTimings,
+    started: OnceLock<Instant>,
+    // the pid of the container process, once it is started
+    pid: OnceLock<i32>,
+    force_delete: bool,
+    engine: E,
+    // the digests of the precompiled modules that the container warmed with the engine
+    warmed: Mutex<Vec<String>>,
+    _assets: Assets,
+}
+
+impl<E: Engine> Instance<E> {
+    /// Returns which executor handled the container,
+    /// or None if the container hasn't started running yet.
+    pub fn which_executor(&self) -> Option<ExecutorKind> {
+        if let Some(kind) = self.executor_kind.get() {
+            return Some(*kind);
+        }
+        let kind = self.kind_receiver.try_recv()?;
+        log::info!("container {} is handled by the {kind:?} executor", self.id);
+        Some(*self.executor_kind.get_or_init(|| kind))
+    }
+
+    // kills the processes of the container, and removes its cgroups and state
+    fn delete_container(&self) -> Result<(), SandboxError> {
+        match instance_exists(&self.rootdir, &self.id) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(err) => {
+                log::error!("could not find the container, skipping cleanup: {}", err);
+                return Ok(());
+            }
+        }
+        let container_root = get_instance_root(&self.rootdir, &self.id)?;
+        match Container::load(container_root) {
+            Ok(mut container) => {
+                container.delete(true)?;
+            }
+            Err(err) => {
+                log::error!("could not find the container, skipping cleanup: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    // removes what is left of the state directory of the container,
+    // e.g., when libcontainer couldn't load or delete the container
+    fn remove_container_state(&self) -> Result<(), SandboxError> {
+        if !instance_exists(&self.rootdir, &self.id).unwrap_or(false) {
+            return Ok(());
+        }
+        let container_root = get_instance_root(&self.rootdir, &self.id)?;
+        std::fs::remove_dir_all(&container_root).map_err(|err| {
+            let reason = match Errno::from_i32(err.raw_os_error().unwrap_or_default()) {
+                Errno::EBUSY => DeleteFailure::Busy(format!("{container_root:?}: {err}")),
+                Errno::EACCES | Errno::EPERM => {
+                    DeleteFailure::PermissionDenied(format!("{container_root:?}: {err}"))
+                }
+                _ => return err.into(),
+            };
+            SandboxError::DeleteFailed {
+                id: self.id.clone(),
+                reason,
+            }
+        })
+    }
+
+    // whether the container was started, and hasn't exited yet
+    fn is_running(&self) -> bool {
+        self.started.get().is_some() && self.exit_code.wait_timeout(Duration::ZERO).is_none()
+    }
+
+    /// Returns the platform of the image that the container runs, e.g., `wasip1/wasm`.
+    /// It is the default platform when the container doesn't run the modules of a wasm image.
+    pub fn platform(&self) -> &Platform {
+        &self.platform
+    }
+
+    /// Returns the digests of the output of the container in the content store,
+    /// once it is deleted with [`ShimOptions::export_logs`] set.
+    pub fn exported_logs(&self) -> Option<containerd::ExportedLogs> {
+        self.exported_logs.get().cloned()
+    }
+
+    /// Samples the resources that the container used so far.
+    /// Fails if the container isn't running.
+    pub fn metrics(&self) -> Result<InstanceMetrics, SandboxError> {
+        let (Some(pid), Some(started)) = (self.pid.get(), self.started.get()) else {
+            return Err(SandboxError::FailedPrecondition(format!(
+                "container {} isn't running",
+                self.id
+            )));
+        };
+        if self.exit_code.wait_timeout(Duration::ZERO).is_some() {
+            return Err(SandboxError::FailedPrecondition(format!(
+                "container {} has exited",
+                self.id
+            )));
+        }
+        Ok(InstanceMetrics::sample(*pid, started.elapsed())?)
+    }
+
+    /// Returns when each phase of the startup of the container finished.
+    pub fn startup_timings(&self) -> StartupTimings {
+        StartupTimings {
+            started: self.started.get().copied(),
+            ..self.timings
+        }
+    }
 }
 
 impl<E: Engine> SandboxInstance for Instance<E> {
     type Engine = E;
 
     fn new(id: String, cfg: Option<&InstanceConfig<Self::Engine>>) -> Result<Self, SandboxError> {
+        let mut timings = StartupTimings::new(Instant::now());
         let cfg = cfg.context("missing configuration")?;
         let engine = cfg.get_engine();
         let bundle = cfg.get_bundle().to_path_buf();
         let namespace = cfg.get_namespace();
         let rootdir = Path::new(DEFAULT_CONTAINER_ROOT_DIR).join(E::name());
         let rootdir = determine_rootdir(&bundle, &namespace, rootdir)?;
-        let stdio = Stdio::init_from_cfg(cfg)?;
-
-        // check if container is OCI image with wasm layers and attempt to read the module
-        let (modules, platform) = containerd::Client::connect(cfg.get_containerd_address().as_str(), &namespace)?
-            .load_modules(&id, &engine)
-            .unwrap_or_else(|e| {
-                log::warn!("Error obtaining wasm layers for container {id}.  Will attempt to use files inside container image. Error: {e}");
-                (vec![], Platform::default())
+        let mut spec = Spec::load(bundle.join("config.json"))?;
+        let bundle_mounts = spec.mounts().clone();
+        check_spec(&spec)?;
+        let options = read_options(&bundle)?;
+        // applied before any annotation is read,
+        // so that the defaults of the namespace act as annotations of the container
+        let default_annotations = options
+            .namespace_annotations
+            .as_ref()
+            .and_then(|namespaces| namespaces.get(&namespace))
+            .cloned()
+            .unwrap_or_default();
+        apply_default_annotations(&mut spec, &default_annotations);
+        // the logs and the assets of the container are only written to and read from the content store
+        let content_address = options
+            .containerd_content_address
+            .clone()
+            .unwrap_or_else(|| cfg.get_containerd_address());
+        let mut stdio = Stdio::init_from_cfg(cfg)?;
+        let mut output_copies: Option<OutputCopies> = None;
+        if options.closed_output == Some(ClosedOutput::Discard) {
+            // closest to the streams of containerd, so that the captured logs are kept whole
+            let (discarding, copies) = stdio.discard_closed_output()?;
+            stdio = discarding;
+            output_copies = Some(copies);
+        }
+        let log_export = match options.export_logs {
+            Some(true) => {
+                let dir = logs_dir(&rootdir, &id);
+                let (captured, copies) = capture_logs(&dir, stdio)?;
+                stdio = captured;
+                output_copies = Some(match output_copies.take() {
+                    Some(discarding) => discarding.join(copies),
+                    None => copies,
+                });
+                Some(LogExport {
+                    dir,
+                    address: content_address.clone(),
+                    namespace: namespace.clone(),
+                    tls: options.containerd_tls.clone(),
+                })
+            }
+            _ => None,
+        };
+        let normalize = spec
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(NORMALIZE_LINE_ENDINGS_ANNOTATION));
+        if normalize.is_some_and(|v| v == "true") {
+            // normalized before it is captured, so that the exported logs are normalized too
+            let (normalized, copies) = stdio.normalize_line_endings()?;
+            stdio = normalized;
+            output_copies = Some(match output_copies {
+                Some(captured) => captured.join(copies),
+                None => copies,
             });
+        }
+        let terminal = spec.process().as_ref().and_then(|p| p.terminal());
+        if terminal == Some(true) {
+            if options.terminal != Some(true) {
+                return Err(SandboxError::InvalidArgument(
+                    "terminal is not supported, unless the terminal option of the shim is set"
+                        .to_string(),
+                ));
+            }
+            // set up last, so that the output of the terminal is normalized and captured too
+            let (with_terminal, copies) = stdio.with_terminal()?;
+            stdio = with_terminal;
+            output_copies = Some(match output_copies {
+                Some(others) => others.join(copies),
+                None => copies,
+            });
+        }
+        // the secrets are removed when the guard is dropped, unless the container is created
+        let secrets_dir = mount_secrets(&mut spec, &bundle)?;
+        let signals_file = mount_guest_signals(&mut spec, &bundle)?;
+        mount_fs_quotas(&mut spec)?;
+        let cpus = requested_cpus(&spec)?;
+        let log_level = logger::log_level(&spec)?;
+        let wasm_features = enabled_wasm_features(&engine, &spec)?;
+        let env = match &options.annotation_env_prefix {
+            Some(prefix) => annotation_env(&spec, prefix),
+            None => vec![],
+        };
+
+        // the modules are cached for the container once they are loaded from its image
+        let cached_modules = CachedModules::new(E::name(), &id);
+        let loaded = match (cfg.get_module(), cfg.get_resolved_image()) {
+            // the module was fed to the shim directly, so there is no image to read it from
+            (Some(module), _) => {
+                check_wasm_features(&engine, &wasm_features, [module])?;
+                timings.content_loaded = Some(Instant::now());
+                LoadedImage {
+                    modules: Arc::new(vec![WasmLayer::from_module(module.to_vec())]),
+                    platform: Platform::default(),
+                    image_digest: None,
+                    stop_signal: None,
+                    assets: vec![],
+                    manifest_function: None,
+                    config_entrypoint: None,
+                }
+            }
+            // the layers were resolved before, e.g., by an earlier container of the image
+            (None, Some(resolved)) => {
+                let layers = resolved.layers.iter().map(|l| l.layer.as_slice());
+                let wasm_features = platform_wasm_features(&wasm_features, &resolved.platform);
+                check_wasm_features(&engine, &wasm_features, layers)?;
+                timings.content_loaded = Some(Instant::now());
+                LoadedImage {
+                    modules: Arc::new(resolved.layers.clone()),
+                    platform: resolved.platform.clone(),
+                    image_digest: None,
+                    stop_signal: None,
+                    assets: vec![],
+                    manifest_function: None,
+                    config_entrypoint: None,
+                }
+            }
+            (None, None) => load_image(
+                &id,
+                cfg,
+                &options,
+                &spec,
+                &engine,
+                &wasm_features,
+                &mut timings,
+            )?,
+        };
+        let LoadedImage {
+            modules,
+            platform,
+            image_digest,
+            stop_signal,
+            assets,
+            manifest_function,
+            config_entrypoint,
+        } = loaded;
+        // the executor hands the layers to the engine in this order,
+        // for which the layers that are shared with the modules cache are copied
+        let modules = match reorders_layers(&spec) {
+            true => {
+                let modules = Arc::try_unwrap(modules).unwrap_or_else(|m| m.as_ref().clone());
+                Arc::new(order_layers(&spec, modules)?)
+            }
+            false => modules,
+        };
+        let config_arg0 = config_entrypoint
+            .as_ref()
+            .and_then(|entrypoint| entrypoint.first());
+        let start_function =
+            manifest_start_function(&spec, manifest_function, config_arg0.map(String::as_str));
+        // modules in the rootfs are only checked by the executor, once they can be read
+        if !modules.is_empty() {
+            let mut spec = spec.clone();
+            if let Some(func) = &start_function {
+                set_start_function(&mut spec, func);
+            }
+            let ctx = WasiContext {
+                spec: &spec,
+                wasm_layers: &modules,
+                platform: &platform,
+            };
+            match options.module_hints {
+                // the hints of the module can name the start function
+                Some(true) => check_start_function(&WasiContext {
+                    spec: &apply_module_hints(&ctx),
+                    ..ctx
+                })?,
+                _ => check_start_function(&ctx)?,
+            }
+        }
+        let warmed = match options.warm_precompiled {
+            Some(true) => warm_precompiled(&engine, &modules),
+            _ => WarmedModules {
+                engine: engine.clone(),
+                digests: vec![],
+            },
+        };
+        // the annotation must be valid, but an image with an invalid stop signal can still run
+        let stop_signal = match spec
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(STOP_SIGNAL_ANNOTATION))
+        {
+            Some(signal) => parse_signal(signal)?,
+            None => stop_signal
+                .and_then(|signal| {
+                    parse_signal(&signal)
+                        .map_err(|err| log::warn!("ignoring stop signal of the image: {err}"))
+                        .ok()
+                })
+                .unwrap_or(SIGTERM as u32),
+        };
+        let success_exit_codes = success_exit_codes(&spec)?;
+        let debug_modules = match options.debug_modules {
+            Some(true) => {
+                let dir = debug_modules_dir(&rootdir, &id);
+                write_debug_modules(&dir, &modules)?;
+                Some(dir)
+            }
+            _ => None,
+        };
 
-        ContainerBuilder::new(id.clone(), SyscallType::Linux)
-            .with_executor(Executor::new(engine, stdio, modules, platform))
-            .with_root_path(rootdir.clone())?
-            .as_init(&bundle)
-            .with_systemd(false)
-            .build()?;
+        let rootfs = spec
+            .root()
+            .as_ref()
+            .context("rootfs is not set in runtime spec")?
+            .path();
+        let reader = ContainerdAssetReader {
+            address: content_address,
+            namespace: namespace.clone(),
+            tls: options.containerd_tls.clone(),
+        };
+        // modules in the image are run by the engine, whatever the entrypoint is,
+        // and the sandbox container of a pod always runs the native pause executable
+        if options.native_fallback == Some(false)
+            && modules.is_empty()
+            && !is_sandbox_container(&spec)
+        {
+            check_not_native(&spec, &bundle.join(rootfs))?;
+        }
+        let assets = mount_assets(&bundle.join(rootfs), assets, Arc::new(reader))?;
+
+        let (trap_sender, trap_receiver) = trap_channel()?;
+        let (kind_sender, kind_receiver) = executor_kind_channel()?;
+
+        let executor = Executor::new(
+            engine.clone(),
+            stdio,
+            modules,
+            platform.clone(),
+            trap_sender,
+            kind_sender,
+        )
+        .with_env(env)
+        .with_cpus(cpus)
+        .with_start_function(start_function)
+        .with_module_hints(options.module_hints == Some(true))
+        .with_native_fallback(options.native_fallback != Some(false))
+        .with_log_level(log_level)
+        .with_default_annotations(default_annotations);
+        // libcontainer reads the spec from the bundle, so the mounts that the shim adds must be saved to it
+        if spec.mounts() != &bundle_mounts {
+            spec.save(bundle.join("config.json"))?;
+        }
+        let build_timeout = options
+            .build_timeout_seconds
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_BUILD_TIMEOUT);
+        let mut container = build_with_timeout(
+            build_timeout,
+            &rootdir.join(&id),
+            || {
+                Ok(ContainerBuilder::new(id.clone(), SyscallType::Linux)
+                    .with_executor(executor)
+                    .with_root_path(rootdir.clone())?
+                    .as_init(&bundle)
+                    .with_systemd(false)
+                    .build()?)
+            },
+            |mut container: Container| {
+                if let Err(err) = container.delete(true) {
+                    log::error!("could not delete container {id}: {err}");
+                }
+            },
+        )?;
+
+        if let Err(err) = engine.post_create(&container) {
+            log::error!("post create hook failed for container {id}: {err}");
+            if let Err(err) = container.delete(true) {
+                log::error!("could not delete container {id}: {err}");
+            }
+            return Err(err.into());
+        }
+        timings.created = Some(Instant::now());
+        cached_modules.keep();
 
         Ok(Self {
             id,
             exit_code: WaitableCell::new(),
+            exit_status: Default::default(),
+            trap_receiver: Arc::new(trap_receiver),
+            executor_kind: OnceLock::new(),
+            kind_receiver,
             rootdir,
-            _phantom: Default::default(),
+            image_digest,
+            platform,
+            stop_signal,
+            success_exit_codes,
+            output_copies: output_copies.map(Arc::new),
+            debug_modules,
+            secrets_dir: secrets_dir.map(|dir| dir.keep()),
+            signals_file,
+            log_export,
+            exported_logs: OnceLock::new(),
+            timings,
+            started: OnceLock::new(),
+            pid: OnceLock::new(),
+            force_delete: options.force_delete == Some(true),
+            engine,
+            warmed: Mutex::new(warmed.keep()),
+            _assets: assets,
         })
     }
 
@@ -77,28 +834,58 @@ impl<E: Engine> SandboxInstance for Instance<E> {
         let container_root = get_instance_root(&self.rootdir, &self.id)?;
         let mut container = Container::load(container_root)?;
         let pid = container.pid().context("failed to get pid")?.as_raw();
+        let oom_counter = OomCounter::for_pid(pid);
 
         container.start()?;
+        let _ = self.started.set(Instant::now());
+        let _ = self.pid.set(pid);
+        self.startup_timings().log(&self.id);
 
         let exit_code = self.exit_code.clone();
+        let exit_status = self.exit_status.clone();
+        let trap_receiver = self.trap_receiver.clone();
+        let output_copies = self.output_copies.clone();
+        let (id, success_exit_codes) = (self.id.clone(), self.success_exit_codes.clone());
         thread::spawn(move || {
             // move the exit code guard into this thread
             let _guard = guard;
 
-            let status = match waitid(WaitID::Pid(Pid::from_raw(pid)), WaitPidFlag::WEXITED) {
-                Ok(WaitStatus::Exited(_, status)) => status,
-                Ok(WaitStatus::Signaled(_, sig, _)) => sig as i32,
-                Ok(_) => 0,
-                Err(Errno::ECHILD) => {
-                    log::info!("no child process");
-                    0
-                }
-                Err(e) => {
-                    log::error!("waitpid failed: {e}");
-                    137
-                }
-            } as u32;
-            let _ = exit_code.set((status, Utc::now()));
+            let (status, typed_status) =
+                match waitid(WaitID::Pid(Pid::from_raw(pid)), WaitPidFlag::WEXITED) {
+                    Ok(WaitStatus::Exited(_, status)) => match trap_receiver.try_recv() {
+                        Some(reason) => (status, ExitStatus::Trapped(reason)),
+                        None => (status, ExitStatus::Exited(status as u32)),
+                    },
+                    Ok(WaitStatus::Signaled(_, sig, _))
+                        if sig as i32 == SIGKILL
+                            && oom_counter.as_ref().is_some_and(|c| c.oom_killed()) =>
+                    {
+                        (sig as i32, ExitStatus::OutOfMemory)
+                    }
+                    Ok(WaitStatus::Signaled(_, sig, _)) => {
+                        (sig as i32, ExitStatus::Signaled(sig as i32))
+                    }
+                    Ok(_) => (0, ExitStatus::Exited(0)),
+                    Err(Errno::ECHILD) => {
+                        log::info!("no child process");
+                        (0, ExitStatus::Exited(0))
+                    }
+                    Err(e) => {
+                        log::error!("waitpid failed: {e}");
+                        (137, ExitStatus::Exited(137))
+                    }
+                };
+            // the output may still be on its way to the stdio streams
+            if let Some(output_copies) = output_copies {
+                output_copies.wait_timeout(OUTPUT_COPY_TIMEOUT);
+            }
+            let outcome = match typed_status.is_success(&success_exit_codes) {
+                true => "success",
+                false => "failure",
+            };
+            log::info!("instance {id} exited with {typed_status:?}, a {outcome}");
+            let _ = exit_status.set(typed_status);
+            let _ = exit_code.set((status as u32, Utc::now()));
         });
 
         Ok(pid as u32)
@@ -107,6 +894,7 @@ impl<E: Engine> SandboxInstance for Instance<E> {
     /// Send a signal to the instance
     fn kill(&self, signal: u32) -> Result<(), SandboxError> {
         log::info!("sending signal {signal} to instance: {}", self.id);
+        let all = signal == SIGKILL as u32;
         let signal = Signal::try_from(signal as i32).map_err(|err| {
             SandboxError::InvalidArgument(format!("invalid signal number: {}", err))
         })?;
@@ -114,33 +902,80 @@ impl<E: Engine> SandboxInstance for Instance<E> {
         let mut container = Container::load(container_root)
             .with_context(|| format!("could not load state for container {}", self.id))?;
 
-        container.kill(signal, true)?;
+        // SIGKILL is sent to every process in the container, so that nothing is left behind.
+        // Any other signal is delivered to the init process only, which runs the guest.
+        container.kill(signal, all)?;
 
         Ok(())
     }
 
     /// Delete any reference to the instance
     /// This is called after the instance has exited.
+    ///
+    /// A container that is still running is only deleted with [`ShimOptions::force_delete`],
+    /// otherwise it is left untouched and the delete fails with [`DeleteFailure::Running`].
+    ///
+    /// Every cleanup step is attempted, even if an earlier one fails.
+    /// The processes of the container are killed before its state is removed,
+    /// and the shim forgets about the container last.
     fn delete(&self) -> Result<(), SandboxError> {
         log::info!("deleting instance: {}", self.id);
-        match instance_exists(&self.rootdir, &self.id) {
-            Ok(true) => {}
-            Ok(false) => return Ok(()),
-            Err(err) => {
-                log::error!("could not find the container, skipping cleanup: {}", err);
-                return Ok(());
+        if self.is_running() {
+            if !self.force_delete {
+                return Err(SandboxError::DeleteFailed {
+                    id: self.id.clone(),
+                    reason: DeleteFailure::Running,
+                });
             }
+            log::info!("killing running instance {} before deleting it", self.id);
         }
-        let container_root = get_instance_root(&self.rootdir, &self.id)?;
-        match Container::load(container_root) {
-            Ok(mut container) => {
-                container.delete(true)?;
-            }
-            Err(err) => {
-                log::error!("could not find the container, skipping cleanup: {}", err);
-            }
-        }
-        Ok(())
+        Cleanup::new(&self.id)
+            .step("delete container", || self.delete_container())
+            .step("remove container state", || self.remove_container_state())
+            .step("remove debug modules", || match &self.debug_modules {
+                Some(dir) => Ok(remove_debug_modules(dir)?),
+                None => Ok(()),
+            })
+            .step("remove secrets", || match &self.secrets_dir {
+                Some(dir) if dir.exists() => Ok(remove_secrets(dir)?),
+                _ => Ok(()),
+            })
+            .step("remove guest signals", || match &self.signals_file {
+                Some(file) if file.exists() => Ok(std::fs::remove_file(file)?),
+                _ => Ok(()),
+            })
+            .step("export logs", || {
+                let Some(export) = &self.log_export else {
+                    return Ok(());
+                };
+                if self.exported_logs.get().is_some() {
+                    return Ok(());
+                }
+                match export.export(&self.id) {
+                    Ok(logs) => {
+                        let _ = self.exported_logs.set(logs);
+                        Ok(())
+                    }
+                    Err(err) => {
+                        // the logs are gone with the container either way
+                        if let Err(err) = remove_logs(&export.dir) {
+                            log::warn!("{err}");
+                        }
+                        Err(err.into())
+                    }
+                }
+            })
+            .step("release cached modules", || {
+                containerd::forget_modules(E::name(), &self.id);
+                Ok(())
+            })
+            .step("release warmed modules", || {
+                for digest in std::mem::take(&mut *self.warmed.lock().unwrap()) {
+                    self.engine.release(&digest);
+                }
+                Ok(())
+            })
+            .finish()
     }
 
     /// Waits for the instance to finish and retunrs its exit code
@@ -149,4 +984,44 @@ impl<E: Engine> SandboxInstance for Instance<E> {
     fn wait_timeout(&self, t: impl Into<Option<Duration>>) -> Option<(u32, DateTime<Utc>)> {
         self.exit_code.wait_timeout(t).copied()
     }
+
+    fn engine_names() -> Vec<&'static str> {
+        vec![E::name()]
+    }
+
+    fn engine_version() -> Option<String> {
+        Some(E::version())
+    }
+
+    fn capabilities(engine: &E) -> Option<Capabilities> {
+        Some(Capabilities::of(engine))
+    }
+
+    // The sandbox container runs the native pause binary from its image using the linux executor,
+    // in the namespaces that the runtime spec asks libcontainer to create.
+    fn supports_pod_sandbox() -> bool {
+        true
+    }
+
+    fn image_digest(&self) -> Option<String> {
+        self.image_digest.clone()
+    }
+
+    fn stop_signal(&self) -> u32 {
+        self.stop_signal
+    }
+
+    fn success_exit_codes(&self) -> SuccessExitCodes {
+        self.success_exit_codes.clone()
+    }
+
+    fn exit_status(&self) -> Option<ExitStatus> {
+        let (code, _) = self.wait_timeout(Duration::ZERO)?;
+        Some(
+            self.exit_status
+                .get()
+                .copied()
+                .unwrap_or(ExitStatus::Exited(code)),
+        )
+    }
 }
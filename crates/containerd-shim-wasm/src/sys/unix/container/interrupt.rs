@@ -0,0 +1,112 @@
+//! When a container that sets the [`INTERRUPT_TIMEOUT_ANNOTATION`] is stopped with `SIGTERM` or `SIGINT`,
+//! the guest is interrupted with [`Engine::interrupt`] rather than killed, so that the engine can return
+//! and the output of the guest is flushed.
+//! The container then exits with `128 + signal`, like a process that the signal terminated.
+//! If the engine doesn't return within the timeout, e.g., because it can't interrupt the guest,
+//! the container exits anyway.
+//! Without the annotation, the signals terminate the container as they would any process.
+//!
+//! The signals that the [`GUEST_SIGNALS_ANNOTATION`](super::guest_signals::GUEST_SIGNALS_ANNOTATION)
+//! forwards to the guest are left to the guest.
+
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+
+use anyhow::Result;
+use nix::sys::signal::{SigSet, Signal};
+use oci_spec::runtime::Spec;
+
+use crate::container::{parse_interrupt_timeout, Engine, INTERRUPT_TIMEOUT_ANNOTATION};
+use crate::sys::container::guest_signals::{guest_signals, is_empty};
+
+const INTERRUPT_SIGNALS: [Signal; 2] = [Signal::SIGTERM, Signal::SIGINT];
+
+static INTERRUPTED_BY: AtomicI32 = AtomicI32::new(0);
+
+/// Returns the signal that the guest was interrupted on, if it was.
+pub(crate) fn interrupted_by() -> Option<i32> {
+    match INTERRUPTED_BY.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+fn interrupt_signals(spec: &Spec) -> Result<SigSet> {
+    let forwarded = guest_signals(spec)?;
+    let mut signals = SigSet::empty();
+    for signal in INTERRUPT_SIGNALS {
+        if !forwarded.contains(signal) {
+            signals.add(signal);
+        }
+    }
+    Ok(signals)
+}
+
+/// Starts interrupting the guest when the container is stopped.
+/// This must be called from inside the container, before any other thread is spawned.
+pub(crate) fn interrupt_on_stop(spec: &Spec, engine: impl Engine) -> Result<()> {
+    let Some(timeout) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(INTERRUPT_TIMEOUT_ANNOTATION))
+    else {
+        return Ok(());
+    };
+    let timeout = parse_interrupt_timeout(timeout)?;
+    let signals = interrupt_signals(spec)?;
+    if is_empty(&signals) {
+        return Ok(());
+    }
+
+    // Block the signals in this thread, so that every thread spawned later also blocks them,
+    // and they are only received by the thread below.
+    signals.thread_block()?;
+    thread::spawn(move || {
+        let signal = match signals.wait() {
+            Ok(signal) => signal,
+            Err(err) => {
+                log::error!("failed to wait for stop signals: {err}");
+                return;
+            }
+        };
+        log::info!("interrupting the guest on {signal}");
+        INTERRUPTED_BY.store(signal as i32, Ordering::SeqCst);
+        engine.interrupt();
+
+        thread::sleep(timeout);
+        log::warn!("the guest wasn't interrupted within {timeout:?}, exiting");
+        std::process::exit(128 + signal as i32);
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use oci_spec::runtime::SpecBuilder;
+
+    use super::*;
+    use crate::sys::container::guest_signals::GUEST_SIGNALS_ANNOTATION;
+
+    #[test]
+    fn test_interrupt_signals() -> Result<()> {
+        let signals = interrupt_signals(&SpecBuilder::default().build()?)?;
+        assert!(signals.contains(Signal::SIGTERM));
+        assert!(signals.contains(Signal::SIGINT));
+
+        // the guest handles the signals forwarded to it
+        let spec = SpecBuilder::default()
+            .annotations(HashMap::from([(
+                GUEST_SIGNALS_ANNOTATION.to_string(),
+                "SIGTERM".to_string(),
+            )]))
+            .build()?;
+        let signals = interrupt_signals(&spec)?;
+        assert!(!signals.contains(Signal::SIGTERM));
+        assert!(signals.contains(Signal::SIGINT));
+
+        Ok(())
+    }
+}
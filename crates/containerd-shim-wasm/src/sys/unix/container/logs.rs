@@ -0,0 +1,101 @@
+//! The output of a container can be exported to the content store of containerd when the container is deleted,
+//! e.g., on ephemeral nodes where the logs of the container are lost with the node.
+//!
+//! While the container runs, its output is copied to `<rootdir>/.logs/<container id>/`,
+//! next to the state of the containers, on top of being written to its stdio streams.
+
+use std::fs::{create_dir_all, read, remove_dir_all, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::sandbox::containerd::{Client, ExportedLogs};
+use crate::sandbox::instance_utils::ContainerdTlsOptions;
+use crate::sandbox::stdio::{OutputCopies, Stdio};
+
+/// Where the output of a container is kept, and the containerd it is exported to.
+pub(crate) struct LogExport {
+    pub dir: PathBuf,
+    pub address: String,
+    pub namespace: String,
+    pub tls: Option<ContainerdTlsOptions>,
+}
+
+/// Returns the directory with the output of the container.
+pub(crate) fn logs_dir(rootdir: impl AsRef<Path>, id: &str) -> PathBuf {
+    rootdir.as_ref().join(".logs").join(id)
+}
+
+/// Returns stdio streams that also write the output to `dir`.
+pub(crate) fn capture_logs(dir: &Path, stdio: Stdio) -> Result<(Stdio, OutputCopies)> {
+    // the output of an earlier container with the same id isn't exported twice
+    remove_logs(dir)?;
+    create_dir_all(dir).with_context(|| format!("failed to create {dir:?}"))?;
+    let create = |name: &str| {
+        let path = dir.join(name);
+        File::create(&path).with_context(|| format!("failed to create {path:?}"))
+    };
+    Ok(stdio.tee_output(create("stdout")?, create("stderr")?)?)
+}
+
+impl LogExport {
+    /// Writes the output of container `id` to the content store, and removes it from `dir`.
+    pub(crate) fn export(&self, id: &str) -> Result<ExportedLogs> {
+        let read_log = |name: &str| {
+            let path = self.dir.join(name);
+            read(&path).with_context(|| format!("failed to read {path:?}"))
+        };
+        let stdout = read_log("stdout")?;
+        let stderr = read_log("stderr")?;
+        let client = Client::connect_with_tls(&self.address, &self.namespace, self.tls.clone())?;
+        let logs = client.export_logs(id, stdout, stderr)?;
+        log::info!(
+            "exported the logs of container {id}: stdout {}, stderr {}",
+            logs.stdout,
+            logs.stderr
+        );
+        remove_logs(&self.dir)?;
+        Ok(logs)
+    }
+}
+
+/// Removes the output of the container, if there is any.
+pub(crate) fn remove_logs(dir: &Path) -> Result<()> {
+    match remove_dir_all(dir) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("failed to remove {dir:?}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_capture_logs() -> Result<()> {
+        let rootdir = tempdir()?;
+        let dir = logs_dir(rootdir.path(), "test");
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("stdout"), "output of an earlier container")?;
+
+        let (stdio, copies) = capture_logs(&dir, Stdio::default())?;
+        // the copies finish once nothing can write to the streams anymore
+        drop(stdio);
+        copies.wait_timeout(Duration::from_secs(5));
+        assert_eq!(read(dir.join("stdout"))?, b"");
+        assert_eq!(read(dir.join("stderr"))?, b"");
+
+        remove_logs(&dir)?;
+        assert!(!dir.exists());
+        // there is nothing left to remove
+        remove_logs(&dir)?;
+        Ok(())
+    }
+}
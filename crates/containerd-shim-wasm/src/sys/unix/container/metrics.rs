@@ -0,0 +1,124 @@
+use std::fmt::Write;
+use std::fs::read_to_string;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+/// The resources that a running container used so far, sampled from its process on demand.
+///
+/// The process values are read from `/proc/<pid>/stat` at once, so that they are a consistent snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstanceMetrics {
+    /// The CPU time of the process, in user and kernel mode.
+    pub cpu_time: Duration,
+    /// The resident memory of the process, in bytes.
+    pub memory_bytes: u64,
+    /// The page faults of the process that didn't need to read from disk.
+    pub minor_page_faults: u64,
+    /// The page faults of the process that needed to read from disk.
+    pub major_page_faults: u64,
+    /// How long the container has been running.
+    pub runtime: Duration,
+}
+
+impl InstanceMetrics {
+    /// Samples the metrics of the process `pid`, that has been running for `runtime`.
+    pub(crate) fn sample(pid: i32, runtime: Duration) -> Result<Self> {
+        let path = format!("/proc/{pid}/stat");
+        let stat = read_to_string(&path).with_context(|| format!("failed to read {path}"))?;
+        Self::parse(&stat, runtime).with_context(|| format!("invalid {path}: {stat:?}"))
+    }
+
+    fn parse(stat: &str, runtime: Duration) -> Option<Self> {
+        // the name of the command is in parentheses, and can have spaces or parentheses of its own
+        let (_, fields) = stat.rsplit_once(')')?;
+        let fields: Vec<_> = fields.split_whitespace().collect();
+        // the fields are numbered from 1, and the first two are the pid and the command
+        let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let cpu_ticks = field(14)? + field(15)?;
+        Some(Self {
+            cpu_time: Duration::from_secs_f64(cpu_ticks as f64 / ticks as f64),
+            memory_bytes: field(24)? * page_size,
+            minor_page_faults: field(10)?,
+            major_page_faults: field(12)?,
+            runtime,
+        })
+    }
+
+    /// Formats the metrics in the Prometheus text format, labeled with the id of the container.
+    pub fn to_prometheus(&self, id: &str) -> String {
+        let metrics = [
+            (
+                "runwasi_cpu_seconds_total",
+                "counter",
+                self.cpu_time.as_secs_f64(),
+            ),
+            ("runwasi_memory_bytes", "gauge", self.memory_bytes as f64),
+            (
+                "runwasi_minor_page_faults_total",
+                "counter",
+                self.minor_page_faults as f64,
+            ),
+            (
+                "runwasi_major_page_faults_total",
+                "counter",
+                self.major_page_faults as f64,
+            ),
+            (
+                "runwasi_runtime_seconds",
+                "gauge",
+                self.runtime.as_secs_f64(),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, typ, value) in metrics {
+            let _ = writeln!(text, "# TYPE {name} {typ}");
+            let _ = writeln!(text, "{name}{{container=\"{id}\"}} {value}");
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "42 (wasm (guest)) S 1 42 42 0 -1 4194560 150 0 3 0 200 50 0 0 20 0 1 0 100 1000000 25 18446744073709551615";
+        let metrics = InstanceMetrics::parse(stat, Duration::from_secs(7)).unwrap();
+
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        assert_eq!(
+            metrics,
+            InstanceMetrics {
+                cpu_time: Duration::from_secs_f64(250.0 / ticks as f64),
+                memory_bytes: 25 * page_size,
+                minor_page_faults: 150,
+                major_page_faults: 3,
+                runtime: Duration::from_secs(7),
+            }
+        );
+
+        assert!(InstanceMetrics::parse("42 (truncated) S 1", Duration::ZERO).is_none());
+    }
+
+    #[test]
+    fn test_sample() -> Result<()> {
+        let metrics = InstanceMetrics::sample(std::process::id() as i32, Duration::ZERO)?;
+        assert!(metrics.memory_bytes > 0);
+
+        let text = metrics.to_prometheus("test");
+        assert!(text.contains("# TYPE runwasi_cpu_seconds_total counter\n"));
+        assert!(text.contains(&format!(
+            "runwasi_memory_bytes{{container=\"test\"}} {}\n",
+            metrics.memory_bytes
+        )));
+
+        Ok(())
+    }
+}
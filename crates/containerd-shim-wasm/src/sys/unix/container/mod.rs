@@ -1,2 +1,18 @@
-mod executor;
+mod assets;
+mod build_timeout;
+mod channel;
+mod cleanup;
+pub mod cpuset;
+mod debug_modules;
+pub mod executor;
+pub mod fs_quota;
+pub mod guest_signals;
 pub mod instance;
+mod interrupt;
+mod logs;
+pub mod metrics;
+pub mod name_resolution;
+mod oom;
+pub mod secrets;
+pub mod timings;
+mod trap;
@@ -0,0 +1,144 @@
+//! The engine resolves the names that a guest looks up, e.g., with `wasi:sockets`, from inside the container.
+//! It reads `/etc/hosts` and `/etc/resolv.conf` in the rootfs, which the OCI mounts of the spec can provide,
+//! like for any other container.
+//!
+//! The [`HOSTS_ANNOTATION`] and [`DNS_ANNOTATION`] annotations configure them for containers
+//! that don't mount them, e.g., when the container is run with `ctr`.
+
+use std::fs::{create_dir_all, write, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use oci_spec::runtime::Spec;
+
+/// Annotation with a comma separated list of `name=ip` entries to add to `/etc/hosts`,
+/// e.g. `db.internal=10.0.0.2,cache=10.0.0.3`.
+pub const HOSTS_ANNOTATION: &str = "runwasi.io/hosts";
+
+/// Annotation with a comma separated list of the IP addresses of the nameservers
+/// to write to `/etc/resolv.conf`, e.g. `10.0.0.10,1.1.1.1`.
+pub const DNS_ANNOTATION: &str = "runwasi.io/dns";
+
+fn annotation<'a>(spec: &'a Spec, key: &str) -> impl Iterator<Item = &'a str> {
+    spec.annotations()
+        .as_ref()
+        .and_then(|a| a.get(key))
+        .map(String::as_str)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+}
+
+fn hosts(spec: &Spec) -> Result<Vec<(String, IpAddr)>> {
+    annotation(spec, HOSTS_ANNOTATION)
+        .map(|entry| {
+            let (name, ip) = entry.split_once('=').with_context(|| {
+                format!(
+                    "invalid entry {entry:?} in {HOSTS_ANNOTATION} annotation, expected name=ip"
+                )
+            })?;
+            let ip = ip.trim().parse().with_context(|| {
+                format!("invalid address {ip:?} in {HOSTS_ANNOTATION} annotation")
+            })?;
+            Ok((name.trim().to_string(), ip))
+        })
+        .collect()
+}
+
+fn nameservers(spec: &Spec) -> Result<Vec<IpAddr>> {
+    annotation(spec, DNS_ANNOTATION)
+        .map(|ip| {
+            ip.parse()
+                .with_context(|| format!("invalid address {ip:?} in {DNS_ANNOTATION} annotation"))
+        })
+        .collect()
+}
+
+/// Writes the hosts and nameservers requested by the annotations of the spec.
+/// The hosts are appended to `/etc/hosts`, so that the entries of a mounted hosts file are kept,
+/// and the nameservers replace the content of `/etc/resolv.conf`.
+/// This must be called from inside the container.
+pub(crate) fn configure_name_resolution(spec: &Spec) -> Result<()> {
+    let hosts = hosts(spec)?;
+    let nameservers = nameservers(spec)?;
+    if hosts.is_empty() && nameservers.is_empty() {
+        return Ok(());
+    }
+    create_dir_all("/etc")?;
+
+    if !hosts.is_empty() {
+        let path = Path::new("/etc/hosts");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open {path:?}"))?;
+        for (name, ip) in &hosts {
+            writeln!(file, "{ip}\t{name}").with_context(|| format!("failed to write {path:?}"))?;
+        }
+        log::info!("added {} entries to {path:?}", hosts.len());
+    }
+
+    if !nameservers.is_empty() {
+        let path = Path::new("/etc/resolv.conf");
+        let content: Vec<_> = nameservers
+            .iter()
+            .map(|ip| format!("nameserver {ip}\n"))
+            .collect();
+        write(path, content.concat()).with_context(|| format!("failed to write {path:?}"))?;
+        log::info!("wrote {} nameservers to {path:?}", nameservers.len());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use oci_spec::runtime::SpecBuilder;
+
+    use super::*;
+
+    fn spec_with_annotation(key: &str, value: &str) -> Result<Spec> {
+        Ok(SpecBuilder::default()
+            .annotations(HashMap::from([(key.to_string(), value.to_string())]))
+            .build()?)
+    }
+
+    #[test]
+    fn test_hosts() -> Result<()> {
+        let spec = spec_with_annotation(HOSTS_ANNOTATION, "db.internal=10.0.0.2, cache = ::1")?;
+        assert_eq!(
+            hosts(&spec)?,
+            vec![
+                ("db.internal".to_string(), "10.0.0.2".parse()?),
+                ("cache".to_string(), "::1".parse()?),
+            ]
+        );
+        assert!(nameservers(&spec)?.is_empty());
+
+        hosts(&spec_with_annotation(HOSTS_ANNOTATION, "db.internal")?)
+            .expect_err("an entry needs an address");
+        hosts(&spec_with_annotation(HOSTS_ANNOTATION, "db.internal=nope")?)
+            .expect_err("nope is not an address");
+        Ok(())
+    }
+
+    #[test]
+    fn test_nameservers() -> Result<()> {
+        let spec = spec_with_annotation(DNS_ANNOTATION, "10.0.0.10,1.1.1.1")?;
+        assert_eq!(
+            nameservers(&spec)?,
+            vec!["10.0.0.10".parse::<IpAddr>()?, "1.1.1.1".parse()?]
+        );
+        assert!(hosts(&spec)?.is_empty());
+
+        nameservers(&spec_with_annotation(DNS_ANNOTATION, "dns.google")?)
+            .expect_err("nameservers are addresses");
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+//! Detects whether the cgroup OOM-killer terminated a container.
+//!
+//! The kernel counts the processes it kills for running out of memory in the memory cgroup
+//! of the container, in `memory.events` for cgroup v2, and in `memory.oom_control` for v1.
+//! Sampling that counter when the container starts and again after it exits tells an OOM kill
+//! apart from any other SIGKILL.
+
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+/// The OOM kill counter of the memory cgroup of a process.
+#[derive(Debug)]
+pub(crate) struct OomCounter {
+    path: PathBuf,
+    initial: u64,
+}
+
+impl OomCounter {
+    /// Finds the memory cgroup of the process `pid` and samples its OOM kill counter.
+    /// Returns None if the process doesn't have a memory cgroup that we can read.
+    pub(crate) fn for_pid(pid: i32) -> Option<Self> {
+        let cgroups = read_to_string(format!("/proc/{pid}/cgroup")).ok()?;
+        let path = cgroups.lines().find_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let (id, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+            let path = path.trim_start_matches('/');
+            if id == "0" && controllers.is_empty() {
+                Some(Path::new("/sys/fs/cgroup").join(path).join("memory.events"))
+            } else if controllers.split(',').any(|c| c == "memory") {
+                Some(
+                    Path::new("/sys/fs/cgroup/memory")
+                        .join(path)
+                        .join("memory.oom_control"),
+                )
+            } else {
+                None
+            }
+        })?;
+        let initial = read_oom_kills(&path)?;
+        Some(Self { path, initial })
+    }
+
+    /// Returns true if the OOM-killer killed a process in the cgroup since the counter was sampled.
+    pub(crate) fn oom_killed(&self) -> bool {
+        match read_oom_kills(&self.path) {
+            Some(count) => count > self.initial,
+            None => {
+                log::warn!("could not read OOM kill counter from {:?}", self.path);
+                false
+            }
+        }
+    }
+}
+
+// Both `memory.events` and `memory.oom_control` are lists of `<key> <value>` lines.
+fn read_oom_kills(path: &Path) -> Option<u64> {
+    let content = read_to_string(path).ok()?;
+    content
+        .lines()
+        .find_map(|line| match line.split_once(' ')? {
+            ("oom_kill", count) => count.trim().parse().ok(),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_oom_kills() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+
+        let path = dir.path().join("memory.events");
+        std::fs::write(&path, "low 0\nhigh 0\nmax 12\noom 2\noom_kill 1\n")?;
+        assert_eq!(read_oom_kills(&path), Some(1));
+
+        let path = dir.path().join("memory.oom_control");
+        std::fs::write(&path, "oom_kill_disable 0\nunder_oom 0\noom_kill 3\n")?;
+        assert_eq!(read_oom_kills(&path), Some(3));
+
+        assert_eq!(read_oom_kills(&dir.path().join("missing")), None);
+        Ok(())
+    }
+}
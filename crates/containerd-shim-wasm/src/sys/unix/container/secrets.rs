@@ -0,0 +1,301 @@
+//! Secrets are passed to the guest as files in a read-only mount,
+//! so that their values never appear in the environment or in the logs.
+//!
+//! The [`SECRET_MOUNTS_ANNOTATION`] annotation lists the destinations of the mounts of the spec
+//! that hold secrets. The source of each of these mounts is a directory on the host.
+//! The shim copies the files in that directory to a tmpfs in the bundle of the container, so that
+//! they are never written to disk, and replaces the mount with a read-only bind mount of the copy,
+//! which libcontainer mounts when it creates the container.
+//! The guest never sees the directory on the host, nor can it change the copy.
+
+use std::fs::{copy, create_dir, read_dir, set_permissions, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use nix::errno::Errno;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use oci_spec::runtime::{Mount, MountBuilder, Spec};
+
+use crate::sandbox::Error;
+
+/// Annotation with a comma separated list of the destinations of the mounts that hold secrets,
+/// e.g. `/secrets,/run/tokens`.
+pub const SECRET_MOUNTS_ANNOTATION: &str = "runwasi.io/secret-mounts";
+
+// the directory of the bundle where the tmpfs with the secrets of the container is mounted
+const SECRETS_DIR: &str = "runwasi-secrets";
+
+/// The tmpfs with the secrets of a container.
+///
+/// The tmpfs is removed when the guard is dropped, unless it is kept for the container with [`SecretsDir::keep`],
+/// so that an error while the container is created doesn't leave the secrets behind.
+#[derive(Debug)]
+pub(crate) struct SecretsDir {
+    path: PathBuf,
+    kept: bool,
+}
+
+impl SecretsDir {
+    /// Keeps the tmpfs for the container, which removes it with [`remove_secrets`] when it is deleted.
+    pub fn keep(mut self) -> PathBuf {
+        self.kept = true;
+        self.path.clone()
+    }
+}
+
+impl Drop for SecretsDir {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+        if let Err(err) = remove_secrets(&self.path) {
+            log::error!("{err:#}");
+        }
+    }
+}
+
+/// Unmounts the tmpfs with the secrets of a container, and removes its mount point.
+pub(crate) fn remove_secrets(dir: &Path) -> anyhow::Result<()> {
+    match umount2(dir, MntFlags::MNT_DETACH) {
+        // nothing is mounted there anymore
+        Ok(()) | Err(Errno::EINVAL) => {}
+        Err(err) => return Err(err).with_context(|| format!("failed to unmount {dir:?}")),
+    }
+    std::fs::remove_dir(dir).with_context(|| format!("failed to remove {dir:?}"))
+}
+
+/// Copies the secrets of every secret mount of the spec to a tmpfs in the bundle, and replaces the mounts
+/// with read-only bind mounts of the copies.
+/// Returns the guard of the tmpfs, so that it can be removed with the container.
+pub(crate) fn mount_secrets(spec: &mut Spec, bundle: &Path) -> Result<Option<SecretsDir>, Error> {
+    let Some(destinations) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(SECRET_MOUNTS_ANNOTATION))
+        .cloned()
+    else {
+        return Ok(None);
+    };
+
+    let mut mounts = spec.mounts().clone().unwrap_or_default();
+    let mut sources = vec![];
+    for destination in destinations
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+    {
+        let invalid = |reason: &str| {
+            Error::InvalidArgument(format!(
+                "invalid secret mount {destination:?} in {SECRET_MOUNTS_ANNOTATION} annotation: {reason}"
+            ))
+        };
+        let index = mounts
+            .iter()
+            .position(|m| m.destination() == Path::new(destination))
+            .ok_or_else(|| invalid("the spec has no mount at this destination"))?;
+        let source = mounts[index]
+            .source()
+            .clone()
+            .filter(|source| source.is_absolute() && source.is_dir())
+            .ok_or_else(|| invalid("the source of the mount must be a directory of the host"))?;
+        sources.push((index, source));
+    }
+    if sources.is_empty() {
+        return Ok(None);
+    }
+
+    let secrets_dir = mount_tmpfs(&bundle.join(SECRETS_DIR))?;
+    for (i, (index, source)) in sources.iter().enumerate() {
+        let mount = &mut mounts[*index];
+        let copy_dir = secrets_dir.path.join(i.to_string());
+        let count = copy_secrets(source, &copy_dir)?;
+        log::info!("found {count} secrets for mount {:?}", mount.destination());
+        *mount = read_only_bind(mount.destination(), &copy_dir)?;
+    }
+    spec.set_mounts(Some(mounts));
+    Ok(Some(secrets_dir))
+}
+
+// mounts a tmpfs that only root can read at `dir`, so that the copies of the secrets never touch the disk
+fn mount_tmpfs(dir: &Path) -> anyhow::Result<SecretsDir> {
+    create_dir(dir).with_context(|| format!("failed to create {dir:?}"))?;
+    let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+    if let Err(err) = mount(Some("tmpfs"), dir, Some("tmpfs"), flags, Some("mode=0700")) {
+        let _ = std::fs::remove_dir(dir);
+        return Err(err).with_context(|| format!("failed to mount a tmpfs at {dir:?}"));
+    }
+    Ok(SecretsDir {
+        path: dir.to_path_buf(),
+        kept: false,
+    })
+}
+
+// copies the files of `source` to `dir`, read-only for every user, as the guest may not run as root
+fn copy_secrets(source: &Path, dir: &Path) -> anyhow::Result<usize> {
+    create_dir(dir).with_context(|| format!("failed to create {dir:?}"))?;
+    let mut count = 0;
+    for entry in read_dir(source)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = entry.file_name();
+        let path = dir.join(&name);
+        copy(entry.path(), &path).with_context(|| format!("failed to copy secret {name:?}"))?;
+        set_permissions(&path, Permissions::from_mode(0o444))?;
+        count += 1;
+    }
+    set_permissions(dir, Permissions::from_mode(0o555))?;
+    Ok(count)
+}
+
+fn read_only_bind(destination: &Path, source: &Path) -> anyhow::Result<Mount> {
+    let options = ["rbind", "ro", "nosuid", "nodev", "noexec"];
+    Ok(MountBuilder::default()
+        .destination(destination)
+        .typ("bind")
+        .source(source)
+        .options(options.map(String::from).to_vec())
+        .build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs::{create_dir, metadata, read, write};
+
+    use nix::sys::statfs::{statfs, TMPFS_MAGIC};
+    use oci_spec::runtime::SpecBuilder;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_mount_secrets() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let secrets = dir.path().join("secrets");
+        create_dir(&secrets)?;
+        write(secrets.join("token"), "s3cr3t")?;
+        let bundle = dir.path().join("bundle");
+        create_dir(&bundle)?;
+
+        let tmpfs = MountBuilder::default()
+            .destination("/tmp")
+            .typ("tmpfs")
+            .source("tmpfs")
+            .build()?;
+        let mut spec = SpecBuilder::default()
+            .mounts(vec![
+                tmpfs.clone(),
+                MountBuilder::default()
+                    .destination("/secrets")
+                    .typ("bind")
+                    .source(&secrets)
+                    .build()?,
+            ])
+            .annotations(HashMap::from([(
+                SECRET_MOUNTS_ANNOTATION.to_string(),
+                "/secrets".to_string(),
+            )]))
+            .build()?;
+
+        let guard = mount_secrets(&mut spec, &bundle)?.unwrap();
+        let secrets_dir = guard.path.clone();
+        assert!(secrets_dir.starts_with(&bundle));
+        assert_eq!(statfs(&secrets_dir)?.filesystem_type(), TMPFS_MAGIC);
+        let mode = metadata(&secrets_dir)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // other mounts are left alone, even a tmpfs
+        let mounts = spec.mounts().as_ref().unwrap();
+        assert_eq!(mounts[0], tmpfs);
+
+        let mount = &mounts[1];
+        assert_eq!(mount.destination(), Path::new("/secrets"));
+        assert_eq!(mount.typ().as_deref(), Some("bind"));
+        assert!(mount
+            .options()
+            .as_ref()
+            .unwrap()
+            .contains(&"ro".to_string()));
+        let source = mount.source().as_ref().unwrap();
+        assert!(source.starts_with(&secrets_dir));
+        assert_eq!(read(source.join("token"))?, b"s3cr3t");
+        let mode = metadata(source.join("token"))?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o444);
+
+        // the tmpfs outlives a kept guard, until the container removes it
+        let kept = guard.keep();
+        assert!(kept.join("0").join("token").exists());
+        remove_secrets(&kept)?;
+        assert!(!kept.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_secrets_removed_on_drop() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let secrets = dir.path().join("secrets");
+        create_dir(&secrets)?;
+        write(secrets.join("token"), "s3cr3t")?;
+
+        let mut spec = SpecBuilder::default()
+            .mounts(vec![MountBuilder::default()
+                .destination("/secrets")
+                .typ("bind")
+                .source(&secrets)
+                .build()?])
+            .annotations(HashMap::from([(
+                SECRET_MOUNTS_ANNOTATION.to_string(),
+                "/secrets".to_string(),
+            )]))
+            .build()?;
+
+        // e.g., the container fails to be created after its secrets are mounted
+        let guard = mount_secrets(&mut spec, dir.path())?.unwrap();
+        let secrets_dir = guard.path.clone();
+        drop(guard);
+        assert!(!secrets_dir.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_secrets_invalid() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let spec = |source: &str| {
+            SpecBuilder::default()
+                .mounts(vec![MountBuilder::default()
+                    .destination("/secrets")
+                    .typ("tmpfs")
+                    .source(source)
+                    .build()
+                    .unwrap()])
+                .annotations(HashMap::from([(
+                    SECRET_MOUNTS_ANNOTATION.to_string(),
+                    "/secrets".to_string(),
+                )]))
+                .build()
+                .unwrap()
+        };
+
+        // without the annotation, a mount is never a secret mount
+        let mut without = spec("tmpfs");
+        without.set_annotations(None);
+        assert!(mount_secrets(&mut without, dir.path())?.is_none());
+
+        for source in ["tmpfs", "/does/not/exist"] {
+            let err = mount_secrets(&mut spec(source), dir.path()).unwrap_err();
+            assert!(matches!(err, Error::InvalidArgument(_)), "{source}: {err}");
+        }
+
+        let mut missing = spec("tmpfs");
+        missing.set_mounts(None);
+        let err = mount_secrets(&mut missing, dir.path()).unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)), "{err}");
+
+        Ok(())
+    }
+}
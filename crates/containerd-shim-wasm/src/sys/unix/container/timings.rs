@@ -0,0 +1,62 @@
+use std::time::{Duration, Instant};
+
+/// When each phase of the startup of a container finished,
+/// to show how much of the time to start it goes to loading, precompiling and instantiating the modules.
+///
+/// The phases run in this order, so the timestamps that are set never decrease.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StartupTimings {
+    /// When the shim began creating the instance.
+    pub requested: Instant,
+    /// When the modules were read from the content store, or from the cache of precompiled modules.
+    /// None if the container doesn't run an OCI image with wasm layers.
+    pub content_loaded: Option<Instant>,
+    /// When the modules were precompiled and the output was cached.
+    /// None if the precompiled modules were in the cache already, or the engine doesn't precompile.
+    pub precompiled: Option<Instant>,
+    /// When the container was created, with everything it needs to run.
+    pub created: Option<Instant>,
+    /// When the container process was started to instantiate the modules and run the guest.
+    /// None until the instance is started.
+    pub started: Option<Instant>,
+}
+
+impl StartupTimings {
+    pub(crate) fn new(requested: Instant) -> Self {
+        Self {
+            requested,
+            content_loaded: None,
+            precompiled: None,
+            created: None,
+            started: None,
+        }
+    }
+
+    // the time spent in each phase, from the end of the previous phase that finished
+    fn phases(&self) -> Vec<(&'static str, Duration)> {
+        let mut last = self.requested;
+        [
+            ("content load", self.content_loaded),
+            ("precompile", self.precompiled),
+            ("create", self.created),
+            ("start", self.started),
+        ]
+        .into_iter()
+        .filter_map(|(phase, end)| {
+            let end = end?;
+            let elapsed = end.duration_since(last);
+            last = end;
+            Some((phase, elapsed))
+        })
+        .collect()
+    }
+
+    pub(crate) fn log(&self, id: &str) {
+        let phases: Vec<_> = self
+            .phases()
+            .into_iter()
+            .map(|(phase, elapsed)| format!("{phase} {elapsed:?}"))
+            .collect();
+        log::info!("startup timings of container {id}: {}", phases.join(", "));
+    }
+}
@@ -0,0 +1,30 @@
+use std::io::Result;
+
+use super::channel::{channel, Message, Receiver, Sender};
+use crate::sandbox::TrapReason;
+
+/// Used by the container process to report why the guest trapped.
+pub(crate) type TrapSender = Sender<TrapReason>;
+
+/// Kept by the shim to read the reason after the container exits.
+pub(crate) type TrapReceiver = Receiver<TrapReason>;
+
+pub(crate) fn trap_channel() -> Result<(TrapSender, TrapReceiver)> {
+    channel()
+}
+
+impl Message for TrapReason {
+    fn to_byte(self) -> u8 {
+        match self {
+            TrapReason::StackOverflow => 1,
+            TrapReason::Other => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(TrapReason::StackOverflow),
+            _ => Some(TrapReason::Other),
+        }
+    }
+}
@@ -1,6 +1,7 @@
-use std::fs::OpenOptions;
-use std::io::Result;
-use std::os::fd::{IntoRawFd, OwnedFd, RawFd};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Result};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
 use std::path::Path;
 
 use crossbeam::atomic::AtomicCell;
@@ -8,6 +9,25 @@ pub use libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
 
 pub type StdioRawFd = RawFd;
 
+/// Returns a new file that refers to the same open file as `fd`.
+pub fn dup_file(fd: StdioRawFd) -> Result<File> {
+    let fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Creates a pipe, and returns its read end and its write end.
+pub fn pipe() -> Result<(File, StdioOwnedFd)> {
+    let mut fds = [-1; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    let [read, write] = fds;
+    Ok(unsafe { (File::from_raw_fd(read), StdioOwnedFd::from_raw_fd(write)) })
+}
+
 pub struct StdioOwnedFd(AtomicCell<StdioRawFd>);
 
 impl Drop for StdioOwnedFd {
@@ -48,4 +68,39 @@ impl StdioOwnedFd {
     pub fn try_from_path(path: impl AsRef<Path>) -> Result<Self> {
         Self::try_from(OpenOptions::new().read(true).write(true).open(path)?)
     }
+
+    /// Opens the output at `path`, e.g., the fifo that containerd creates for the output of a container.
+    /// A fifo is opened for writing only, so that its reader sees the end of the output once the guest closes it.
+    /// If it has no reader yet, it is opened for reading too, so that the output is kept in the fifo
+    /// until a reader attaches, rather than failing to open.
+    pub fn try_from_output_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if !std::fs::metadata(path)?.file_type().is_fifo() {
+            return Self::try_from_path(path);
+        }
+        // opening a fifo for writing only blocks until it has a reader, unless it is non-blocking
+        let open = |read| {
+            OpenOptions::new()
+                .read(read)
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(path)
+        };
+        let file = match open(false) {
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => {
+                log::debug!("output fifo {path:?} has no reader yet");
+                open(true)?
+            }
+            result => result?,
+        };
+        // the guest waits for the reader when the fifo is full, rather than failing its writes
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        if flags == -1
+            || unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags & !libc::O_NONBLOCK) }
+                == -1
+        {
+            return Err(Error::last_os_error());
+        }
+        Self::try_from(file)
+    }
 }
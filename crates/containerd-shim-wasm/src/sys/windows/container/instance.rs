@@ -39,4 +39,12 @@ impl<E: Engine> SandboxInstance for Instance<E> {
     fn wait_timeout(&self, _t: impl Into<Option<Duration>>) -> Option<(u32, DateTime<Utc>)> {
         todo!();
     }
+
+    fn engine_names() -> Vec<&'static str> {
+        vec![E::name()]
+    }
+
+    fn engine_version() -> Option<String> {
+        Some(E::version())
+    }
 }
@@ -66,4 +66,9 @@ impl StdioOwnedFd {
         }
         Self::try_from(options.open(path)?)
     }
+
+    /// Opens the output at `path`, which containerd passes as a named pipe, like any other stream.
+    pub fn try_from_output_path(path: impl AsRef<Path>) -> Result<Self> {
+        Self::try_from_path(path)
+    }
 }
@@ -1,19 +1,27 @@
 //! Testing utilities used across different modules
 
-use std::collections::HashMap;
 use std::fs::{self, create_dir, read_to_string, write, File};
+use std::io::Read;
 use std::marker::PhantomData;
 use std::ops::Add;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{bail, Result};
 pub use containerd_shim_wasm_test_modules as modules;
 use oci_spec::image::{self as spec, Arch};
-use oci_spec::runtime::{ProcessBuilder, RootBuilder, SpecBuilder};
+use oci_spec::runtime::{
+    LinuxMemoryBuilder, LinuxNamespaceBuilder, LinuxNamespaceType, MountBuilder, ProcessBuilder,
+    RootBuilder, Spec, SpecBuilder, UserBuilder,
+};
 use oci_tar_builder::{Builder, WASM_LAYER_MEDIA_TYPE};
 
-use crate::sandbox::{Instance, InstanceConfig};
+use crate::container::{Engine, RuntimeContext, WasmFeature, SECRET_MOUNTS_ANNOTATION};
+use crate::sandbox::instance_utils::ShimOptions;
+use crate::sandbox::oci::ResolvedImage;
+use crate::sandbox::{Error, ExitStatus, Instance, InstanceConfig, Stdio};
 use crate::sys::signals::SIGKILL;
 
 const TEST_NAMESPACE: &str = "runwasi-test";
@@ -24,6 +32,12 @@ where
 {
     container_name: String,
     tempdir: tempfile::TempDir,
+    module_reader: Option<(Box<dyn Read>, u64)>,
+    resolved_image: Option<ResolvedImage>,
+    engine: Option<WasiInstance::Engine>,
+    image_stop_signal: Option<String>,
+    image_variant: Option<String>,
+    image_arch: Option<String>,
     _phantom: PhantomData<WasiInstance>,
 }
 
@@ -33,6 +47,7 @@ where
 {
     instance: WasiInstance,
     tempdir: tempfile::TempDir,
+    container_name: String,
 }
 
 impl<WasiInstance: Instance> WasiTestBuilder<WasiInstance>
@@ -53,7 +68,11 @@ where
         create_dir(dir.join("rootfs"))?;
         let rootdir = dir.join("runwasi");
         create_dir(&rootdir)?;
-        let opts = HashMap::from([("root", rootdir)]);
+        let opts = ShimOptions {
+            root: Some(rootdir),
+            namespace: Some(TEST_NAMESPACE.to_string()),
+            ..Default::default()
+        };
         let opts_file = File::create(dir.join("options.json"))?;
         serde_json::to_writer(opts_file, &opts)?;
 
@@ -63,6 +82,12 @@ where
         let builder = Self {
             container_name: "test".to_string(),
             tempdir,
+            module_reader: None,
+            resolved_image: None,
+            engine: None,
+            image_stop_signal: None,
+            image_variant: None,
+            image_arch: None,
             _phantom: Default::default(),
         }
         .with_wasm([0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00])?
@@ -82,21 +107,316 @@ where
             "" => "/hello.wasm".to_string(),
             s => "/hello.wasm#".to_string().add(s),
         };
-        let spec = SpecBuilder::default()
-            .root(RootBuilder::default().path("rootfs").build()?)
-            .process(
-                ProcessBuilder::default()
-                    .cwd("/")
-                    .args([entrypoint])
-                    .build()?,
-            )
-            .build()?;
+        // keep any other settings that were already set on the spec
+        let mut spec = match Spec::load(dir.join("config.json")) {
+            Ok(spec) => spec,
+            Err(_) => SpecBuilder::default()
+                .root(RootBuilder::default().path("rootfs").build()?)
+                .build()?,
+        };
+        let mut process = match spec.process() {
+            Some(process) => process.clone(),
+            None => ProcessBuilder::default().cwd("/").build()?,
+        };
+        process.set_args(Some(vec![entrypoint]));
+        spec.set_process(Some(process));
+
+        spec.save(dir.join("config.json"))?;
+
+        Ok(self)
+    }
+
+    pub fn with_annotation(self, key: impl AsRef<str>, value: impl AsRef<str>) -> Result<Self> {
+        let dir = self.tempdir.path();
+        let (key, value) = (key.as_ref(), value.as_ref());
+
+        log::info!("setting wasi test annotation {key:?} to {value:?}");
+
+        let mut spec = Spec::load(dir.join("config.json"))?;
+        let mut annotations = spec.annotations().clone().unwrap_or_default();
+        annotations.insert(key.to_string(), value.to_string());
+        spec.set_annotations(Some(annotations));
+        spec.save(dir.join("config.json"))?;
+
+        Ok(self)
+    }
+
+    /// Passes the annotations with the given prefix to the guest as environment variables.
+    pub fn with_annotation_env_prefix(self, prefix: impl AsRef<str>) -> Result<Self> {
+        let dir = self.tempdir.path();
+        let prefix = prefix.as_ref();
+
+        log::info!("setting wasi test annotation env prefix to {prefix:?}");
+
+        let mut opts: ShimOptions = serde_json::from_reader(File::open(dir.join("options.json"))?)?;
+        opts.annotation_env_prefix = Some(prefix.to_string());
+        serde_json::to_writer(File::create(dir.join("options.json"))?, &opts)?;
+
+        Ok(self)
+    }
+
+    /// Creates the instance in the containerd namespace `namespace`, rather than the namespace of the tests.
+    pub fn with_namespace(self, namespace: impl AsRef<str>) -> Result<Self> {
+        let dir = self.tempdir.path();
+        let namespace = namespace.as_ref();
+
+        log::info!("setting wasi test namespace to {namespace:?}");
+
+        let mut opts: ShimOptions = serde_json::from_reader(File::open(dir.join("options.json"))?)?;
+        opts.namespace = Some(namespace.to_string());
+        serde_json::to_writer(File::create(dir.join("options.json"))?, &opts)?;
+
+        Ok(self)
+    }
+
+    /// Sets a default annotation of the containers of the containerd namespace `namespace`,
+    /// see [`ShimOptions::namespace_annotations`].
+    pub fn with_namespace_annotation(
+        self,
+        namespace: impl AsRef<str>,
+        key: impl AsRef<str>,
+        value: impl AsRef<str>,
+    ) -> Result<Self> {
+        let dir = self.tempdir.path();
+        let (namespace, key, value) = (namespace.as_ref(), key.as_ref(), value.as_ref());
+
+        log::info!(
+            "setting wasi test default annotation {key:?} of namespace {namespace:?} to {value:?}"
+        );
+
+        let mut opts: ShimOptions = serde_json::from_reader(File::open(dir.join("options.json"))?)?;
+        opts.namespace_annotations
+            .get_or_insert_with(Default::default)
+            .entry(namespace.to_string())
+            .or_default()
+            .insert(key.to_string(), value.to_string());
+        serde_json::to_writer(File::create(dir.join("options.json"))?, &opts)?;
+
+        Ok(self)
+    }
+
+    /// Writes the modules that the instance runs to the host, see [`ShimOptions::debug_modules`].
+    pub fn with_debug_modules(self) -> Result<Self> {
+        let dir = self.tempdir.path();
+
+        log::info!("enabling wasi test debug modules");
+
+        let mut opts: ShimOptions = serde_json::from_reader(File::open(dir.join("options.json"))?)?;
+        opts.debug_modules = Some(true);
+        serde_json::to_writer(File::create(dir.join("options.json"))?, &opts)?;
+
+        Ok(self)
+    }
+
+    /// Compresses the precompiled modules that the instance saves to the content store,
+    /// see [`ShimOptions::compress_precompiled`].
+    pub fn with_compressed_precompiled(self) -> Result<Self> {
+        let dir = self.tempdir.path();
+
+        log::info!("enabling wasi test precompiled compression");
+
+        let mut opts: ShimOptions = serde_json::from_reader(File::open(dir.join("options.json"))?)?;
+        opts.compress_precompiled = Some(true);
+        serde_json::to_writer(File::create(dir.join("options.json"))?, &opts)?;
+
+        Ok(self)
+    }
+
+    /// Writes the output of the instance to the content store when it is deleted,
+    /// see [`ShimOptions::export_logs`].
+    pub fn with_export_logs(self) -> Result<Self> {
+        let dir = self.tempdir.path();
+
+        log::info!("enabling wasi test log export");
+
+        let mut opts: ShimOptions = serde_json::from_reader(File::open(dir.join("options.json"))?)?;
+        opts.export_logs = Some(true);
+        serde_json::to_writer(File::create(dir.join("options.json"))?, &opts)?;
+
+        Ok(self)
+    }
+
+    /// Applies the hints in the `runwasi` custom section of the module, see [`ShimOptions::module_hints`].
+    pub fn with_module_hints(self) -> Result<Self> {
+        let dir = self.tempdir.path();
+
+        log::info!("enabling wasi test module hints");
+
+        let mut opts: ShimOptions = serde_json::from_reader(File::open(dir.join("options.json"))?)?;
+        opts.module_hints = Some(true);
+        serde_json::to_writer(File::create(dir.join("options.json"))?, &opts)?;
+
+        Ok(self)
+    }
+
+    /// Kills the container if it is still running when it is deleted, see [`ShimOptions::force_delete`].
+    pub fn with_force_delete(self) -> Result<Self> {
+        let dir = self.tempdir.path();
+
+        log::info!("enabling wasi test force delete");
+
+        let mut opts: ShimOptions = serde_json::from_reader(File::open(dir.join("options.json"))?)?;
+        opts.force_delete = Some(true);
+        serde_json::to_writer(File::create(dir.join("options.json"))?, &opts)?;
+
+        Ok(self)
+    }
+
+    /// Refuses to run a native entrypoint, see [`ShimOptions::native_fallback`].
+    pub fn without_native_fallback(self) -> Result<Self> {
+        let dir = self.tempdir.path();
+
+        log::info!("disabling wasi test native fallback");
+
+        let mut opts: ShimOptions = serde_json::from_reader(File::open(dir.join("options.json"))?)?;
+        opts.native_fallback = Some(false);
+        serde_json::to_writer(File::create(dir.join("options.json"))?, &opts)?;
+
+        Ok(self)
+    }
+
+    /// Connects the guest to a pseudo terminal, see [`ShimOptions::terminal`].
+    #[cfg(unix)]
+    pub fn with_terminal(self) -> Result<Self> {
+        let dir = self.tempdir.path();
+
+        log::info!("enabling wasi test terminal");
+
+        let mut opts: ShimOptions = serde_json::from_reader(File::open(dir.join("options.json"))?)?;
+        opts.terminal = Some(true);
+        serde_json::to_writer(File::create(dir.join("options.json"))?, &opts)?;
+
+        let mut spec = Spec::load(dir.join("config.json"))?;
+        let mut process = spec.process().clone().unwrap_or_default();
+        process.set_terminal(Some(true));
+        spec.set_process(Some(process));
+        spec.save(dir.join("config.json"))?;
+
+        Ok(self)
+    }
+
+    /// Removes the process of the spec, as a partial spec would.
+    pub fn without_process(self) -> Result<Self> {
+        let dir = self.tempdir.path();
+
+        log::info!("removing wasi test process");
+
+        let mut spec = Spec::load(dir.join("config.json"))?;
+        spec.set_process(None);
+        spec.save(dir.join("config.json"))?;
+
+        Ok(self)
+    }
+
+    /// Runs the guest as the given user and group.
+    /// The rootfs is owned by that user, so that the guest can create files in it.
+    #[cfg(unix)]
+    pub fn with_user(self, uid: u32, gid: u32) -> Result<Self> {
+        let dir = self.tempdir.path();
+
+        log::info!("setting wasi test user to {uid}:{gid}");
+
+        let mut spec = Spec::load(dir.join("config.json"))?;
+        let mut process = spec.process().clone().unwrap_or_default();
+        process.set_user(UserBuilder::default().uid(uid).gid(gid).build()?);
+        spec.set_process(Some(process));
+        spec.save(dir.join("config.json"))?;
+
+        std::os::unix::fs::chown(dir.join("rootfs"), Some(uid), Some(gid))?;
+
+        Ok(self)
+    }
+
+    /// Sets the id of the container, so that a test can run multiple containers at the same time.
+    pub fn with_container_name(mut self, name: impl AsRef<str>) -> Result<Self> {
+        self.container_name = name.as_ref().to_string();
+        Ok(self)
+    }
+
+    /// Joins the namespace at `path`, e.g., `/proc/<pid>/ns/ipc`, rather than creating a new one,
+    /// the way the containers of a pod join the namespaces of its sandbox container.
+    #[cfg(unix)]
+    pub fn with_namespace_path(
+        self,
+        typ: LinuxNamespaceType,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let dir = self.tempdir.path();
+        let path = path.as_ref();
+
+        log::info!("setting wasi test {typ:?} namespace to {path:?}");
+
+        let mut spec = Spec::load(dir.join("config.json"))?;
+        let mut linux = spec.linux().clone().unwrap_or_default();
+        let mut namespaces = linux.namespaces().clone().unwrap_or_default();
+        namespaces.retain(|ns| ns.typ() != typ);
+        namespaces.push(
+            LinuxNamespaceBuilder::default()
+                .typ(typ)
+                .path(path)
+                .build()?,
+        );
+        linux.set_namespaces(Some(namespaces));
+        spec.set_linux(Some(linux));
+        spec.save(dir.join("config.json"))?;
+
+        Ok(self)
+    }
+
+    /// Limits the memory, including swap, that the guest can use to `limit` bytes.
+    #[cfg(unix)]
+    pub fn with_memory_limit(self, limit: i64) -> Result<Self> {
+        let dir = self.tempdir.path();
 
+        log::info!("setting wasi test memory limit to {limit} bytes");
+
+        let mut spec = Spec::load(dir.join("config.json"))?;
+        let mut linux = spec.linux().clone().unwrap_or_default();
+        let mut resources = linux.resources().clone().unwrap_or_default();
+        resources.set_memory(Some(
+            LinuxMemoryBuilder::default()
+                .limit(limit)
+                .swap(limit)
+                .build()?,
+        ));
+        linux.set_resources(Some(resources));
+        spec.set_linux(Some(linux));
         spec.save(dir.join("config.json"))?;
 
         Ok(self)
     }
 
+    /// Adds a secret file that the guest can read from `/secrets/<name>`.
+    pub fn with_secret(self, name: impl AsRef<str>, value: impl AsRef<[u8]>) -> Result<Self> {
+        let dir = self.tempdir.path();
+        let secrets = dir.join("secrets");
+        let name = name.as_ref();
+
+        log::info!("setting wasi test secret {name:?}");
+
+        let mut spec = Spec::load(dir.join("config.json"))?;
+        if !secrets.exists() {
+            create_dir(&secrets)?;
+            let mut mounts = spec.mounts().clone().unwrap_or_default();
+            mounts.push(
+                MountBuilder::default()
+                    .destination("/secrets")
+                    .typ("bind")
+                    .source(&secrets)
+                    .options(vec!["rbind".to_string(), "ro".to_string()])
+                    .build()?,
+            );
+            spec.set_mounts(Some(mounts));
+            let mut annotations = spec.annotations().clone().unwrap_or_default();
+            annotations.insert(SECRET_MOUNTS_ANNOTATION.to_string(), "/secrets".to_string());
+            spec.set_annotations(Some(annotations));
+            spec.save(dir.join("config.json"))?;
+        }
+        write(secrets.join(name), value)?;
+
+        Ok(self)
+    }
+
     pub fn with_wasm(self, wasmbytes: impl AsRef<[u8]>) -> Result<Self> {
         let dir = self.tempdir.path();
 
@@ -111,6 +431,47 @@ where
         Ok(self)
     }
 
+    /// Replaces the wasm module with a native executable, e.g., a script,
+    /// so that the container falls back to running as a linux container.
+    #[cfg(unix)]
+    pub fn with_native(self, executable: impl AsRef<[u8]>) -> Result<Self> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = self.tempdir.path();
+
+        log::info!(
+            "setting wasi test native executable [u8; {}]",
+            executable.as_ref().len()
+        );
+
+        let path = dir.join("rootfs").join("hello.wasm");
+        write(&path, executable)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+
+        Ok(self)
+    }
+
+    /// Feeds the wasm module to the instance through `reader`, e.g., a pipe,
+    /// instead of placing it in the rootfs.  At most `max_size` bytes are read.
+    pub fn with_module_reader(mut self, reader: impl Read + 'static, max_size: u64) -> Self {
+        log::info!("setting wasi test module reader");
+        self.module_reader = Some((Box::new(reader), max_size));
+        self
+    }
+
+    /// Runs the container with `engine`, instead of the default one, e.g., a configured [`TestEngine`].
+    pub fn with_engine(mut self, engine: WasiInstance::Engine) -> Self {
+        self.engine = Some(engine);
+        self
+    }
+
+    /// Runs the layers of `resolved_image`, instead of loading the image from containerd.
+    pub fn with_resolved_image(mut self, resolved_image: ResolvedImage) -> Self {
+        log::info!("setting wasi test resolved image");
+        self.resolved_image = Some(resolved_image);
+        self
+    }
+
     pub fn with_stdin(self, stdin: impl AsRef<[u8]>) -> Result<Self> {
         let dir = self.tempdir.path();
 
@@ -121,6 +482,31 @@ where
         Ok(self)
     }
 
+    /// Sets the `StopSignal` in the config of the image that [`as_oci_image`](Self::as_oci_image) builds.
+    pub fn with_image_stop_signal(mut self, signal: impl AsRef<str>) -> Self {
+        log::info!(
+            "setting wasi test image stop signal to {:?}",
+            signal.as_ref()
+        );
+        self.image_stop_signal = Some(signal.as_ref().to_string());
+        self
+    }
+
+    /// Sets the variant of the platform in the config of the image that [`as_oci_image`](Self::as_oci_image) builds.
+    pub fn with_image_variant(mut self, variant: impl AsRef<str>) -> Self {
+        log::info!("setting wasi test image variant to {:?}", variant.as_ref());
+        self.image_variant = Some(variant.as_ref().to_string());
+        self
+    }
+
+    /// Sets the architecture of the platform in the config of the image that [`as_oci_image`](Self::as_oci_image) builds,
+    /// e.g., `wasm64`, rather than `wasm`.
+    pub fn with_image_arch(mut self, arch: impl AsRef<str>) -> Self {
+        log::info!("setting wasi test image arch to {:?}", arch.as_ref());
+        self.image_arch = Some(arch.as_ref().to_string());
+        self
+    }
+
     pub fn as_oci_image(
         mut self,
         image_name: Option<String>,
@@ -132,15 +518,16 @@ where
         let wasm_path = dir.join("rootfs").join("hello.wasm");
         builder.add_layer_with_media_type(&wasm_path, WASM_LAYER_MEDIA_TYPE.to_string());
 
-        let config = spec::ConfigBuilder::default()
+        let mut config = spec::ConfigBuilder::default()
             .entrypoint(vec!["_start".to_string()])
             .build()
             .unwrap();
+        config.set_stop_signal(self.image_stop_signal.clone());
 
-        let img = spec::ImageConfigurationBuilder::default()
+        let mut img = spec::ImageConfigurationBuilder::default()
             .config(config)
             .os("wasip1")
-            .architecture(Arch::Wasm)
+            .architecture(self.image_arch.as_deref().map_or(Arch::Wasm, Arch::from))
             .rootfs(
                 spec::RootFsBuilder::default()
                     .diff_ids(vec![])
@@ -148,6 +535,7 @@ where
                     .unwrap(),
             )
             .build()?;
+        img.set_variant(self.image_variant.clone());
 
         let image_name = image_name.unwrap_or("localhost/hello:latest".to_string());
         builder.add_config(img, image_name.clone());
@@ -206,18 +594,24 @@ where
 
         log::info!("building wasi test");
 
-        let mut cfg = InstanceConfig::new(
-            WasiInstance::Engine::default(),
-            TEST_NAMESPACE,
-            "/run/containerd/containerd.sock",
-        );
-        cfg.set_bundle(dir)
-            .set_stdout(dir.join("stdout"))
-            .set_stderr(dir.join("stderr"))
-            .set_stdin(dir.join("stdin"));
+        let mut cfg = InstanceConfig::from_bundle(dir)?;
+        if let Some((reader, max_size)) = self.module_reader {
+            cfg.set_module_from_reader(reader, max_size)?;
+        }
+        if let Some(resolved_image) = self.resolved_image {
+            cfg.set_resolved_image(resolved_image);
+        }
+        if let Some(engine) = self.engine {
+            cfg.set_engine(engine);
+        }
 
-        let instance = WasiInstance::new(self.container_name, Some(&cfg))?;
-        Ok(WasiTest { instance, tempdir })
+        let container_name = self.container_name;
+        let instance = WasiInstance::new(container_name.clone(), Some(&cfg))?;
+        Ok(WasiTest {
+            instance,
+            tempdir,
+            container_name,
+        })
     }
 }
 
@@ -245,7 +639,52 @@ where
         Ok(self)
     }
 
+    /// Returns the path of the container rootfs on the host.
+    pub fn rootfs(&self) -> PathBuf {
+        self.tempdir.path().join("rootfs")
+    }
+
+    /// Returns the directory with the modules that the instance runs,
+    /// when it was built [`with_debug_modules`](WasiTestBuilder::with_debug_modules).
+    pub fn debug_modules_dir(&self) -> PathBuf {
+        self.tempdir
+            .path()
+            .join("runwasi")
+            .join(TEST_NAMESPACE)
+            .join(".debug")
+            .join(&self.container_name)
+    }
+
+    /// Returns the content of `digest` in the content store of containerd.
+    #[cfg(unix)]
+    pub fn read_content(&self, digest: &str) -> Result<Vec<u8>> {
+        let client = crate::sandbox::containerd::Client::connect(
+            crate::sandbox::instance::DEFAULT_CONTAINERD_ADDRESS,
+            TEST_NAMESPACE,
+        )?;
+        let mut content = vec![];
+        client.copy_content(digest, &mut content)?;
+        Ok(content)
+    }
+
+    /// Returns what the instance has written to stdout so far.
+    pub fn stdout(&self) -> Result<String> {
+        Ok(read_to_string(self.tempdir.path().join("stdout"))?)
+    }
+
     pub fn wait(&self, timeout: Duration) -> Result<(u32, String, String)> {
+        let (status, _, stdout, stderr) = self.wait_for_exit(timeout)?;
+        Ok((status, stdout, stderr))
+    }
+
+    /// Like [`WasiTest::wait`], but returns the typed exit status of the instance,
+    /// so that tests can tell a guest killed by a signal apart from one that trapped.
+    pub fn wait_exit_status(&self, timeout: Duration) -> Result<(ExitStatus, String, String)> {
+        let (_, exit_status, stdout, stderr) = self.wait_for_exit(timeout)?;
+        Ok((exit_status, stdout, stderr))
+    }
+
+    fn wait_for_exit(&self, timeout: Duration) -> Result<(u32, ExitStatus, String, String)> {
         let dir = self.tempdir.path();
 
         log::info!("waiting wasi test");
@@ -253,18 +692,91 @@ where
             Some(res) => res,
             None => {
                 self.instance.kill(SIGKILL as u32)?;
-                bail!("timeout while waiting for module to finish");
+                return Err(Error::Timeout {
+                    operation: "waiting for the module to finish".to_string(),
+                    after: timeout,
+                }
+                .into());
             }
         };
+        let exit_status = self
+            .instance
+            .exit_status()
+            .unwrap_or(ExitStatus::Exited(status));
 
         let stdout = read_to_string(dir.join("stdout"))?;
         let stderr = read_to_string(dir.join("stderr"))?;
 
         self.instance.delete()?;
 
-        log::info!("wasi test status is {status}");
+        log::info!("wasi test status is {status} ({exit_status:?})");
 
-        Ok((status, stdout, stderr))
+        Ok((status, exit_status, stdout, stderr))
+    }
+}
+
+type RunFn = dyn Fn(&dyn RuntimeContext) -> Result<i32> + Send + Sync;
+
+/// An engine for the tests of the shim, which runs a closure in place of the guest, with the stdio of the container.
+/// By default, the guest exits with `0`.
+#[derive(Clone)]
+pub struct TestEngine {
+    run: Arc<RunFn>,
+    disabled_wasm_features: Vec<WasmFeature>,
+    optional_wasm_features: Vec<WasmFeature>,
+}
+
+impl Default for TestEngine {
+    fn default() -> Self {
+        Self::new(|_| Ok(0))
+    }
+}
+
+impl TestEngine {
+    /// Runs `run` in place of the guest, which exits with the code it returns.
+    pub fn new(run: impl Fn(&dyn RuntimeContext) -> Result<i32> + Send + Sync + 'static) -> Self {
+        Self {
+            run: Arc::new(run),
+            disabled_wasm_features: vec![],
+            optional_wasm_features: vec![],
+        }
+    }
+
+    /// Prints `output` from inside the container, where the closure runs, and exits with `0`.
+    pub fn printing(
+        output: impl Fn(&dyn RuntimeContext) -> Result<String> + Send + Sync + 'static,
+    ) -> Self {
+        Self::new(move |ctx| {
+            print!("{}", output(ctx)?);
+            Ok(0)
+        })
+    }
+
+    /// See [`Engine::disabled_wasm_features`] and [`Engine::optional_wasm_features`].
+    pub fn with_wasm_features(
+        mut self,
+        disabled: Vec<WasmFeature>,
+        optional: Vec<WasmFeature>,
+    ) -> Self {
+        self.disabled_wasm_features = disabled;
+        self.optional_wasm_features = optional;
+        self
+    }
+}
+
+impl Engine for TestEngine {
+    fn name() -> &'static str {
+        "wasi_instance"
+    }
+    fn disabled_wasm_features(&self) -> Vec<WasmFeature> {
+        self.disabled_wasm_features.clone()
+    }
+    fn optional_wasm_features(&self) -> Vec<WasmFeature> {
+        self.optional_wasm_features.clone()
+    }
+    fn run_wasi(&self, ctx: &impl RuntimeContext, stdio: Stdio) -> Result<i32> {
+        stdio.redirect()?;
+        (self.run)(ctx)
     }
 }
 
@@ -274,7 +786,7 @@ pub mod oci_helpers {
 
     use anyhow::{bail, Result};
 
-    use super::TEST_NAMESPACE;
+    use super::{Error, TEST_NAMESPACE};
 
     pub struct OCICleanup {
         pub image_name: String,
@@ -342,7 +854,11 @@ pub mod oci_helpers {
             }
 
             if start.elapsed() > timeout {
-                bail!("timed out waiting for content to be removed");
+                return Err(Error::Timeout {
+                    operation: "waiting for the content to be removed".to_string(),
+                    after: timeout,
+                }
+                .into());
             }
 
             log::trace!("waiting for content to be removed");
@@ -0,0 +1,31 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+// Sets `WASMTIME_VERSION` to the version of wasmtime in the lock file of the workspace,
+// as a dependency doesn't know the resolved versions of the others.
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let lock_file = manifest_dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.exists());
+
+    let version = lock_file.as_ref().and_then(|path| {
+        println!("cargo:rerun-if-changed={}", path.display());
+        wasmtime_version(&fs::read_to_string(path).ok()?)
+    });
+    let version = version.unwrap_or_else(|| {
+        println!("cargo:warning=failed to find the version of wasmtime in Cargo.lock");
+        "unknown".to_string()
+    });
+    println!("cargo:rustc-env=WASMTIME_VERSION={version}");
+}
+
+// the version of the `wasmtime` package, in the `name = "..."` line followed by the `version = "..."` line
+fn wasmtime_version(lock: &str) -> Option<String> {
+    let mut lines = lock.lines();
+    lines.find(|line| line.trim() == r#"name = "wasmtime""#)?;
+    let version = lines.next()?.trim().strip_prefix("version = ")?;
+    Some(version.trim_matches('"').to_string())
+}
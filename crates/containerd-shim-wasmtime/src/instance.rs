@@ -1,26 +1,46 @@
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, OnceLock};
 
 use anyhow::{bail, Context, Result};
 use containerd_shim_wasm::container::{
-    Engine, Entrypoint, Instance, RuntimeContext, Stdio, WasmBinaryType,
+    Engine, Entrypoint, Instance, RuntimeContext, Stdio, TrapReason, WasiVersion, WasmBinaryType,
+    WasmFeature, FUEL_ANNOTATION,
 };
+use containerd_shim_wasm::sandbox::Error as ShimError;
 use wasi_common::I32Exit;
 use wasmtime::component::{self as wasmtime_component, Component, ResourceTable};
-use wasmtime::{Config, Module, Precompiled, Store};
+use wasmtime::{Config, Module, Precompiled, Store, Trap};
 use wasmtime_wasi::preview2::{self as wasi_preview2};
 use wasmtime_wasi::{self as wasi_preview1, Dir};
 
 pub type WasmtimeInstance = Instance<WasmtimeEngine<DefaultConfig>>;
 
+/// Annotation to override the maximum wasm stack size (in bytes) of a container.
+/// When not set, the value from the engine's `Config` is used.
+pub const MAX_WASM_STACK_ANNOTATION: &str = "runwasi.io/max-wasm-stack";
+
 #[derive(Clone)]
 pub struct WasmtimeEngine<T: WasiConfig> {
     engine: wasmtime::Engine,
+    // the engine that runs the guest, which the annotations of the container may have configured
+    running: Arc<OnceLock<wasmtime::Engine>>,
+    // the precompiled modules that `warm` deserialized, by digest, shared by the containers that warmed them
+    warmed: Arc<Mutex<HashMap<String, WarmedModule>>>,
+    // deserializes precompiled modules, which tests replace to count the deserializations
+    pub(crate) deserialize: fn(&wasmtime::Engine, &[u8]) -> Result<Module>,
     config_type: PhantomData<T>,
 }
 
+// A precompiled module that was warmed, with the number of containers that warmed it and aren't deleted yet.
+struct WarmedModule {
+    module: Module,
+    containers: usize,
+}
+
 #[derive(Clone)]
 pub struct DefaultConfig {}
 
@@ -34,6 +54,38 @@ impl WasiConfig for DefaultConfig {
 
 pub trait WasiConfig: Clone + Sync + Send + 'static {
     fn new_config() -> Config;
+
+    /// The wasm features that the `Config` disables, e.g., SIMD on hosts whose CPUs can't run it.
+    /// Modules that require them are rejected with a clear error before the container is created.
+    /// This version of wasmtime can't run the GC and exception handling proposals,
+    /// so they are disabled by default, and can't be enabled for a container.
+    /// Memory64 is disabled by default too, and is enabled for the containers that need it, e.g., of wasm64 images.
+    fn disabled_wasm_features() -> Vec<WasmFeature> {
+        vec![
+            WasmFeature::Gc,
+            WasmFeature::Exceptions,
+            WasmFeature::Memory64,
+        ]
+    }
+
+    /// Rewrites the arguments of the guest, after the entrypoint, see [`Engine::transform_args`].
+    /// The arguments are returned unchanged by default.
+    fn transform_args(args: Vec<String>) -> Vec<String> {
+        args
+    }
+
+    /// Links host functions that the guests targeting `world` import, besides WASI,
+    /// which is always linked.
+    /// This is called before each module or component is instantiated, and links nothing by default.
+    fn link_host_functions(_linker: HostLinker<'_>, _world: WasiVersion) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The linker that a module or a component is about to be instantiated with.
+pub enum HostLinker<'a> {
+    Module(&'a mut wasmtime::Linker<WasiCtx>),
+    Component(&'a mut wasmtime_component::Linker<WasiCtx>),
 }
 
 impl<T: WasiConfig> Default for WasmtimeEngine<T> {
@@ -43,6 +95,9 @@ impl<T: WasiConfig> Default for WasmtimeEngine<T> {
             engine: wasmtime::Engine::new(&config)
                 .context("failed to create wasmtime engine")
                 .unwrap(),
+            running: Arc::default(),
+            warmed: Arc::default(),
+            deserialize,
             config_type: PhantomData,
         }
     }
@@ -53,6 +108,8 @@ pub struct WasiCtx {
     pub(crate) wasi_preview2: wasi_preview2::WasiCtx,
     pub(crate) wasi_preview1: wasi_preview1::WasiCtx,
     pub(crate) resource_table: ResourceTable,
+    // state to run modules that target wasi_preview1 on top of the wasi_preview2 context
+    pub(crate) preview1_adapter: wasi_preview2::preview1::WasiPreview1Adapter,
 }
 
 /// This impl is required to use wasmtime_wasi::preview2::WasiView trait.
@@ -74,11 +131,27 @@ impl wasmtime_wasi::preview2::WasiView for WasiCtx {
     }
 }
 
+/// This impl is required to run modules with the wasi_preview2 context.
+impl wasi_preview2::preview1::WasiPreview1View for WasiCtx {
+    fn adapter(&self) -> &wasi_preview2::preview1::WasiPreview1Adapter {
+        &self.preview1_adapter
+    }
+
+    fn adapter_mut(&mut self) -> &mut wasi_preview2::preview1::WasiPreview1Adapter {
+        &mut self.preview1_adapter
+    }
+}
+
 impl<T: WasiConfig> Engine for WasmtimeEngine<T> {
     fn name() -> &'static str {
         "wasmtime"
     }
 
+    fn version() -> String {
+        // the version of wasmtime rather than of the shim, see build.rs
+        env!("WASMTIME_VERSION").to_string()
+    }
+
     fn run_wasi(&self, ctx: &impl RuntimeContext, stdio: Stdio) -> Result<i32> {
         log::info!("setting up wasi");
         let envs: Vec<_> = std::env::vars().collect();
@@ -91,12 +164,30 @@ impl<T: WasiConfig> Engine for WasmtimeEngine<T> {
 
         stdio.redirect()?;
 
+        let engine = self.configured_for(ctx)?;
+        let _ = self.running.set(engine.engine.clone());
+
         log::info!("building wasi context");
         let wasi_ctx = prepare_wasi_ctx(ctx, envs)?;
-        let store = Store::new(&self.engine, wasi_ctx);
+        let mut store = Store::new(&engine.engine, wasi_ctx);
+        if ctx.interrupt_timeout()?.is_some() {
+            // the guest traps once the epoch is incremented, see `interrupt`
+            store.set_epoch_deadline(1);
+        }
+        if let Some(fuel) = fuel(ctx)? {
+            log::info!("limiting the guest to {fuel} fuel");
+            store.set_fuel(fuel)?;
+        }
 
         let wasm_bytes = &source.as_bytes()?;
-        let status = self.execute(wasm_bytes, store, func)?;
+        let status = engine.execute(
+            wasm_bytes,
+            source.binary_type(),
+            source.wasi_version(),
+            source.digest(),
+            store,
+            func,
+        )?;
 
         let status = status.map(|_| 0).or_else(|err| {
             match err.downcast_ref::<I32Exit>() {
@@ -105,13 +196,24 @@ impl<T: WasiConfig> Engine for WasmtimeEngine<T> {
                 #[cfg(windows)]
                 Some(I32Exit(3..)) => Ok(1),
                 Some(I32Exit(status)) => Ok(*status),
-                _ => Err(err),
+                _ => Err(trap_reason(err)),
             }
         })?;
 
         Ok(status)
     }
 
+    fn transform_args(&self, args: Vec<String>) -> Vec<String> {
+        T::transform_args(args)
+    }
+
+    fn interrupt(&self) {
+        // only the engines of the containers that set a timeout interrupt the guest with epochs
+        if let Some(engine) = self.running.get() {
+            engine.increment_epoch();
+        }
+    }
+
     fn precompile(&self, layers: &[Vec<u8>]) -> Result<Vec<u8>> {
         match layers {
             [layer] => self.engine.precompile_module(layer),
@@ -119,7 +221,66 @@ impl<T: WasiConfig> Engine for WasmtimeEngine<T> {
         }
     }
 
+    fn warm(&self, digest: &str, precompiled: &[u8]) -> Result<()> {
+        let mut warmed = self.warmed.lock().unwrap();
+        if let Some(module) = warmed.get_mut(digest) {
+            module.containers += 1;
+            return Ok(());
+        }
+        match self.engine.detect_precompiled(precompiled) {
+            Some(Precompiled::Module) => {
+                let module = (self.deserialize)(&self.engine, precompiled)?;
+                warmed.insert(
+                    digest.to_string(),
+                    WarmedModule {
+                        module,
+                        containers: 1,
+                    },
+                );
+                Ok(())
+            }
+            Some(Precompiled::Component) => {
+                log::debug!("only precompiled modules are warmed");
+                Ok(())
+            }
+            None => bail!("not a precompiled module"),
+        }
+    }
+
+    fn release(&self, digest: &str) {
+        let mut warmed = self.warmed.lock().unwrap();
+        let Some(module) = warmed.get_mut(digest) else {
+            return;
+        };
+        module.containers -= 1;
+        if module.containers == 0 {
+            log::info!("dropping warmed module {digest}, as no container uses it anymore");
+            warmed.remove(digest);
+        }
+    }
+
+    fn validate_precompiled(&self, precompiled: &[u8]) -> Result<()> {
+        match self.engine.detect_precompiled(precompiled) {
+            Some(_) => Ok(()),
+            None => bail!("not a precompiled module or component"),
+        }
+    }
+
     fn can_precompile(&self) -> Option<String> {
+        Some(Self::version())
+    }
+
+    fn disabled_wasm_features(&self) -> Vec<WasmFeature> {
+        T::disabled_wasm_features()
+    }
+
+    fn optional_wasm_features(&self) -> Vec<WasmFeature> {
+        vec![WasmFeature::Memory64]
+    }
+
+    fn precompile_config_hash(&self) -> Option<String> {
+        // the compatibility hash covers the wasmtime version and every
+        // setting of the engine that affects the compiled artifacts
         let mut hasher = DefaultHasher::new();
         self.engine
             .precompile_compatibility_hash()
@@ -129,19 +290,99 @@ impl<T: WasiConfig> Engine for WasmtimeEngine<T> {
 }
 
 impl<T: std::clone::Clone + Sync + WasiConfig + Send + 'static> WasmtimeEngine<T> {
+    /// Returns an engine with the per-container configuration from the annotations applied.
+    /// If the container doesn't override any setting, this engine is returned.
+    /// Fuel is only metered by the engine when the container sets a limit, as metering slows the guest down,
+    /// so precompiled modules, which are compiled without metering, can't run with a limit.
+    /// Memory64 is enabled for the containers that enable it, and the containers of wasm64 images.
+    /// Epoch interruption is only enabled for the containers that set an interrupt timeout, as checking the epoch
+    /// slows the guest down too, so they can't run precompiled modules either.
+    fn configured_for(&self, ctx: &impl RuntimeContext) -> Result<Self> {
+        let max_wasm_stack = ctx.annotation(MAX_WASM_STACK_ANNOTATION);
+        let fuel = fuel(ctx)?;
+        let memory64 = ctx.wasm_features().contains(&WasmFeature::Memory64);
+        let interruptible = ctx.interrupt_timeout()?.is_some();
+        if max_wasm_stack.is_none() && fuel.is_none() && !memory64 && !interruptible {
+            return Ok(self.clone());
+        }
+
+        let mut config = T::new_config();
+        if let Some(max_wasm_stack) = max_wasm_stack {
+            let max_wasm_stack: usize = max_wasm_stack.parse().with_context(|| {
+                format!("invalid {MAX_WASM_STACK_ANNOTATION} annotation {max_wasm_stack:?}")
+            })?;
+            log::info!("setting max wasm stack to {max_wasm_stack} bytes");
+            config.max_wasm_stack(max_wasm_stack);
+        }
+        if fuel.is_some() {
+            config.consume_fuel(true);
+        }
+        if memory64 {
+            log::info!("enabling memory64");
+            config.wasm_memory64(true);
+        }
+        if interruptible {
+            log::info!("enabling epoch interruption");
+            config.epoch_interruption(true);
+        }
+        Ok(Self {
+            engine: wasmtime::Engine::new(&config)?,
+            running: self.running.clone(),
+            warmed: self.warmed.clone(),
+            deserialize: self.deserialize,
+            config_type: PhantomData,
+        })
+    }
+
+    /// Returns the module that `warm` deserialized from the precompiled content with `digest`,
+    /// or deserializes the precompiled bytes.
+    pub(crate) fn deserialize_module(
+        &self,
+        digest: Option<&str>,
+        precompiled: &[u8],
+    ) -> Result<Module> {
+        if let Some(digest) = digest {
+            let warmed = self.warmed.lock().unwrap();
+            // the annotations of the container can configure another engine than the one the module was warmed with
+            let module = warmed
+                .get(digest)
+                .filter(|warmed| wasmtime::Engine::same(warmed.module.engine(), &self.engine));
+            if let Some(warmed) = module {
+                log::info!("using warmed module {digest}");
+                return Ok(warmed.module.clone());
+            }
+        }
+        (self.deserialize)(&self.engine, precompiled)
+    }
+
     /// Execute a wasm module.
     ///
     /// This function adds wasi_preview1 to the linker and can be utilized
     /// to execute a wasm module that uses wasi_preview1.
+    /// When the module is tagged as targeting wasi_preview2, the wasi_preview1
+    /// imports are implemented on top of the wasi_preview2 context instead.
     fn execute_module(
         &self,
         module: Module,
         mut store: Store<WasiCtx>,
         func: &String,
+        wasi_version: Option<WasiVersion>,
     ) -> Result<std::prelude::v1::Result<(), anyhow::Error>, anyhow::Error> {
         let mut module_linker = wasmtime::Linker::new(&self.engine);
 
-        wasi_preview1::add_to_linker(&mut module_linker, |s: &mut WasiCtx| &mut s.wasi_preview1)?;
+        let world = wasi_version.unwrap_or(WasiVersion::Preview1);
+        match world {
+            WasiVersion::Preview2 => {
+                log::debug!("using wasi_preview2 context for module");
+                wasi_preview2::preview1::add_to_linker_sync(&mut module_linker)?;
+            }
+            WasiVersion::Preview1 => {
+                wasi_preview1::add_to_linker(&mut module_linker, |s: &mut WasiCtx| {
+                    &mut s.wasi_preview1
+                })?;
+            }
+        }
+        T::link_host_functions(HostLinker::Module(&mut module_linker), world)?;
 
         log::info!("instantiating instance");
         let instance: wasmtime::Instance = module_linker.instantiate(&mut store, &module)?;
@@ -171,6 +412,7 @@ impl<T: std::clone::Clone + Sync + WasiConfig + Send + 'static> WasmtimeEngine<T
         let mut linker = wasmtime_component::Linker::new(&self.engine);
 
         wasi_preview2::command::sync::add_to_linker(&mut linker)?;
+        T::link_host_functions(HostLinker::Component(&mut linker), WasiVersion::Preview2)?;
 
         log::info!("instantiating component");
 
@@ -190,9 +432,7 @@ impl<T: std::clone::Clone + Sync + WasiConfig + Send + 'static> WasmtimeEngine<T
             let instance = linker.instantiate(&mut store, &component)?;
 
             log::info!("getting component exported function {func:?}");
-            let start_func = instance.get_func(&mut store, &func).context(format!(
-                "component does not have exported function {func:?}"
-            ))?;
+            let start_func = component_export(&instance, &mut store, &func)?;
 
             log::debug!("running exported function {func:?} {start_func:?}");
             let status = start_func.call(&mut store, &[], &mut []);
@@ -203,14 +443,18 @@ impl<T: std::clone::Clone + Sync + WasiConfig + Send + 'static> WasmtimeEngine<T
     fn execute(
         &self,
         wasm_binary: &[u8],
+        binary_type: Option<WasmBinaryType>,
+        wasi_version: Option<WasiVersion>,
+        digest: Option<&str>,
         store: Store<WasiCtx>,
         func: String,
     ) -> Result<std::prelude::v1::Result<(), anyhow::Error>, anyhow::Error> {
-        match WasmBinaryType::from_bytes(wasm_binary) {
+        // prefer the type the image tagged the layer with, and fall back to inspecting the bytes
+        match binary_type.or_else(|| WasmBinaryType::from_bytes(wasm_binary)) {
             Some(WasmBinaryType::Module) => {
                 log::debug!("loading wasm module");
                 let module = Module::from_binary(&self.engine, wasm_binary)?;
-                self.execute_module(module, store, &func)
+                self.execute_module(module, store, &func, wasi_version)
             }
             Some(WasmBinaryType::Component) => {
                 let component = Component::from_binary(&self.engine, wasm_binary)?;
@@ -219,8 +463,8 @@ impl<T: std::clone::Clone + Sync + WasiConfig + Send + 'static> WasmtimeEngine<T
             None => match &self.engine.detect_precompiled(wasm_binary) {
                 Some(Precompiled::Module) => {
                     log::info!("using precompiled module");
-                    let module = unsafe { Module::deserialize(&self.engine, wasm_binary) }?;
-                    self.execute_module(module, store, &func)
+                    let module = self.deserialize_module(digest, wasm_binary)?;
+                    self.execute_module(module, store, &func, wasi_version)
                 }
                 Some(Precompiled::Component) => {
                     log::info!("using precompiled component");
@@ -235,6 +479,57 @@ impl<T: std::clone::Clone + Sync + WasiConfig + Send + 'static> WasmtimeEngine<T
     }
 }
 
+// Deserializes a precompiled module, which `precompile` compiled, or `validate_precompiled` checked
+// before it was cached.
+fn deserialize(engine: &wasmtime::Engine, precompiled: &[u8]) -> Result<Module> {
+    unsafe { Module::deserialize(engine, precompiled) }
+}
+
+/// Returns the function exported by the component instance with the given name,
+/// or an `UnsupportedExport` error if the shim can't call it, as there are no arguments to pass to it.
+pub(crate) fn component_export<T>(
+    instance: &wasmtime_component::Instance,
+    store: &mut Store<T>,
+    name: &str,
+) -> Result<wasmtime_component::Func> {
+    let func = instance.get_func(&mut *store, name).context(format!(
+        "component does not have exported function {name:?}"
+    ))?;
+
+    let params = func.params(&*store);
+    let results = func.results(&*store);
+    if !params.is_empty() || !results.is_empty() {
+        let params: Vec<_> = params.iter().map(|ty| format!("{ty:?}")).collect();
+        let results: Vec<_> = results.iter().map(|ty| format!("{ty:?}")).collect();
+        return Err(ShimError::UnsupportedExport {
+            name: name.to_string(),
+            signature: format!("func({}) -> ({})", params.join(", "), results.join(", ")),
+        }
+        .into());
+    }
+    Ok(func)
+}
+
+/// Returns the fuel that the guest may consume, from the [`FUEL_ANNOTATION`] of the container.
+fn fuel(ctx: &impl RuntimeContext) -> Result<Option<u64>> {
+    ctx.annotation(FUEL_ANNOTATION)
+        .map(|fuel| {
+            fuel.parse()
+                .with_context(|| format!("invalid {FUEL_ANNOTATION} annotation {fuel:?}"))
+        })
+        .transpose()
+}
+
+/// Attaches the reason of a wasm trap to the error, so that the shim can report it in the exit status.
+fn trap_reason(err: anyhow::Error) -> anyhow::Error {
+    let reason = match err.downcast_ref::<Trap>() {
+        Some(Trap::StackOverflow) => TrapReason::StackOverflow,
+        Some(_) => TrapReason::Other,
+        None => return err,
+    };
+    err.context(reason)
+}
+
 /// Prepare both wasi_preview1 and wasi_preview2 contexts.
 fn prepare_wasi_ctx(
     ctx: &impl RuntimeContext,
@@ -259,6 +554,8 @@ fn prepare_wasi_ctx(
         .args(ctx.args())
         .envs(envs.as_slice())
         .inherit_stdio()
+        // names are resolved with the hosts and resolv.conf files of the container
+        .allow_ip_name_lookup(true)
         .preopened_dir(
             Dir::from_std_file(File::open("/")?),
             dir_perms,
@@ -270,6 +567,7 @@ fn prepare_wasi_ctx(
         wasi_preview1: wasi_preview1_ctx,
         wasi_preview2: wasi_preview2_ctx,
         resource_table: ResourceTable::default(),
+        preview1_adapter: wasi_preview2::preview1::WasiPreview1Adapter::new(),
     };
     Ok(wasi_data)
 }
@@ -1,13 +1,32 @@
-use std::time::Duration;
+use std::fs::{metadata, File};
+use std::io::Write;
+use std::os::fd::FromRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
 
-use containerd_shim_wasm::container::Instance;
+use containerd_shim_wasm::container::{
+    Engine, Instance, TrapReason, WasiVersion, WasmFeature, FS_QUOTA_ANNOTATION, FUEL_ANNOTATION,
+    GUEST_SIGNALS_ANNOTATION, INTERRUPT_TIMEOUT_ANNOTATION, NORMALIZE_LINE_ENDINGS_ANNOTATION,
+    STOP_SIGNAL_ANNOTATION, WASM_FEATURES_ANNOTATION,
+};
+use containerd_shim_wasm::sandbox::{
+    DeleteFailure, Error as ShimError, ExitStatus, Instance as _, ResolvedImage, WasmLayer,
+    SUCCESS_EXIT_CODES_ANNOTATION,
+};
 use containerd_shim_wasm::testing::modules::*;
 use containerd_shim_wasm::testing::{oci_helpers, WasiTest};
+use libc::{SIGKILL, SIGTERM, SIGUSR1};
+use oci_spec::image::{Arch, Os, Platform};
 use serial_test::serial;
-use wasmtime::Config;
+use wasmtime::component::{Component, Linker as ComponentLinker};
+use wasmtime::{Config, Module, OptLevel, Store};
 use WasmtimeTestInstance as WasiInstance;
 
-use crate::instance::{WasiConfig, WasmtimeEngine};
+use crate::instance::{
+    component_export, HostLinker, WasiConfig, WasmtimeEngine, MAX_WASM_STACK_ANNOTATION,
+};
 
 // use test configuration to avoid dead locks when running tests
 // https://github.com/containerd/runwasi/issues/357
@@ -49,6 +68,76 @@ fn test_hello_world() -> anyhow::Result<()> {
     Ok(())
 }
 
+// returns the read and write ends of a new pipe
+fn pipe() -> anyhow::Result<(File, File)> {
+    let mut fds = [-1; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+#[test]
+#[serial]
+fn test_hello_world_from_pipe() -> anyhow::Result<()> {
+    let (reader, mut writer) = pipe()?;
+    let feeder = thread::spawn(move || writer.write_all(HELLO_WORLD.bytes));
+
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_module_reader(reader, 1024 * 1024)
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+    feeder.join().unwrap()?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_hello_world_resolved_image() -> anyhow::Result<()> {
+    let resolved = ResolvedImage::from((
+        vec![WasmLayer::from_module(HELLO_WORLD.bytes.to_vec())],
+        Platform::default(),
+    ));
+    // as if it was kept in an external store, and read back for another container
+    let resolved: ResolvedImage = serde_json::from_str(&serde_json::to_string(&resolved)?)?;
+
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_resolved_image(resolved)
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_module_from_pipe_too_large() -> anyhow::Result<()> {
+    let (reader, mut writer) = pipe()?;
+    // the shim stops reading at the cap, so the rest of the write fails with a broken pipe
+    let feeder = thread::spawn(move || writer.write_all(HELLO_WORLD.bytes));
+
+    let result = WasiTest::<WasiInstance>::builder()?
+        .with_module_reader(reader, 16)
+        .build();
+    let _ = feeder.join().unwrap();
+
+    let Err(err) = result else {
+        panic!("a module over the size cap should be rejected");
+    };
+    assert!(err.to_string().contains("larger than the limit"), "{err}");
+
+    Ok(())
+}
+
 #[test]
 #[serial]
 fn test_hello_world_oci() -> anyhow::Result<()> {
@@ -97,6 +186,105 @@ fn test_hello_world_oci_uses_precompiled() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn test_hello_world_oci_compressed_precompiled() -> anyhow::Result<()> {
+    let (builder, _oci_cleanup1) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_compressed_precompiled()?
+        .as_oci_image(None, Some("c-zstd1".to_string()))?;
+
+    let test = builder.build()?;
+    let (exit_code, stdout, _) = test.start()?.wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    // the precompiled module is stored as a zstd frame
+    let (label, digest) = oci_helpers::get_image_label()?;
+    assert!(label.starts_with("runwasi.io/precompiled/wasmtime/"));
+    let stored = test.read_content(&digest)?;
+    assert!(stored.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]));
+
+    // and runs once it is decompressed
+    let (builder, _oci_cleanup2) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_compressed_precompiled()?
+        .as_oci_image(None, Some("c-zstd2".to_string()))?;
+
+    let test = builder.build()?;
+    let (exit_code, stdout, _) = test.start()?.wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+    assert_eq!(test.instance().startup_timings().precompiled, None);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_startup_timings() -> anyhow::Result<()> {
+    let (builder, _oci_cleanup) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .as_oci_image(None, Some("c-timings".to_string()))?;
+    let test = builder.build()?;
+    assert_eq!(test.instance().startup_timings().started, None);
+
+    let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+
+    let timings = test.instance().startup_timings();
+    assert!(timings.content_loaded.is_some(), "{timings:?}");
+    assert!(timings.created.is_some(), "{timings:?}");
+    assert!(timings.started.is_some(), "{timings:?}");
+
+    // the precompile phase is skipped if a previous test cached the module
+    let phases: Vec<_> = [
+        Some(timings.requested),
+        timings.content_loaded,
+        timings.precompiled,
+        timings.created,
+        timings.started,
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    assert!(phases.windows(2).all(|w| w[0] <= w[1]), "{timings:?}");
+
+    Ok(())
+}
+
+#[test]
+fn test_config_change_invalidates_precompiled() {
+    #[derive(Clone)]
+    struct NoOptConfig {}
+
+    impl WasiConfig for NoOptConfig {
+        fn new_config() -> Config {
+            let mut config = WasiTestConfig::new_config();
+            config.cranelift_opt_level(OptLevel::None);
+            config
+        }
+    }
+
+    let engine = WasmtimeEngine::<WasiTestConfig>::default();
+    let no_opt_engine = WasmtimeEngine::<NoOptConfig>::default();
+
+    // same shim version, but a different configuration
+    assert_eq!(engine.can_precompile(), no_opt_engine.can_precompile());
+    assert_ne!(
+        engine.precompile_config_hash(),
+        no_opt_engine.precompile_config_hash()
+    );
+
+    // the same configuration always produces the same hash
+    assert_eq!(
+        engine.precompile_config_hash(),
+        WasmtimeEngine::<WasiTestConfig>::default().precompile_config_hash()
+    );
+}
+
 #[test]
 #[serial]
 fn test_hello_world_oci_uses_precompiled_when_content_removed() -> anyhow::Result<()> {
@@ -144,6 +332,32 @@ fn test_custom_entrypoint() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn test_missing_start_function() -> anyhow::Result<()> {
+    let (reader, mut writer) = pipe()?;
+    let feeder = thread::spawn(move || writer.write_all(CUSTOM_ENTRYPOINT.bytes));
+
+    let result = WasiTest::<WasiInstance>::builder()?
+        .with_start_fn("bar")?
+        .with_module_reader(reader, 1024 * 1024)
+        .build();
+    feeder.join().unwrap()?;
+
+    let Err(err) = result else {
+        panic!("a start function that the module doesn't export should be rejected");
+    };
+    match err.downcast_ref::<ShimError>() {
+        Some(ShimError::StartFunctionNotFound { name, available }) => {
+            assert_eq!(name, "bar");
+            assert_eq!(available, &["foo"]);
+        }
+        _ => panic!("unexpected error: {err}"),
+    }
+
+    Ok(())
+}
+
 #[test]
 #[serial]
 fn test_unreachable() -> anyhow::Result<()> {
@@ -160,86 +374,1041 @@ fn test_unreachable() -> anyhow::Result<()> {
 
 #[test]
 #[serial]
-fn test_exit_code() -> anyhow::Result<()> {
+fn test_fuel_hint() -> anyhow::Result<()> {
+    // the loop of the module only ends once the fuel that its hints suggest runs out
     let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(EXIT_CODE)?
+        .with_wasm(FUEL_HINT)?
+        .with_module_hints()?
         .build()?
         .start()?
         .wait(Duration::from_secs(10))?;
 
-    assert_eq!(exit_code, 42);
+    assert_eq!(exit_code, 137);
 
     Ok(())
 }
 
 #[test]
 #[serial]
-fn test_seccomp() -> anyhow::Result<()> {
-    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(SECCOMP)?
+fn test_fuel_oci_precompiled() -> anyhow::Result<()> {
+    let (builder, _oci_cleanup1) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .as_oci_image(None, Some("c-fuel1".to_string()))?;
+    let (exit_code, _, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+    let (label, _) = oci_helpers::get_image_label()?;
+    assert!(label.starts_with("runwasi.io/precompiled/wasmtime/"));
+
+    // the image is precompiled, but a container that meters fuel runs the module from the layers
+    let (builder, _oci_cleanup2) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation(FUEL_ANNOTATION, "1000000000")?
+        .as_oci_image(None, Some("c-fuel2".to_string()))?;
+    let (exit_code, stdout, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_fuel_hint_oci() -> anyhow::Result<()> {
+    // the module isn't precompiled, as precompiled modules can't run with the fuel that its hints suggest
+    for container in ["c-fuel-hint1", "c-fuel-hint2"] {
+        let (builder, _oci_cleanup) = WasiTest::<WasiInstance>::builder()?
+            .with_wasm(FUEL_HINT)?
+            .with_module_hints()?
+            .as_oci_image(None, Some(container.to_string()))?;
+        let (exit_code, _, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+        assert_eq!(exit_code, 137);
+    }
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_simd_disabled() -> anyhow::Result<()> {
+    #[derive(Clone)]
+    struct NoSimdConfig {}
+
+    impl WasiConfig for NoSimdConfig {
+        fn new_config() -> Config {
+            let mut config = WasiTestConfig::new_config();
+            config.wasm_relaxed_simd(false);
+            config.wasm_simd(false);
+            config
+        }
+        fn disabled_wasm_features() -> Vec<WasmFeature> {
+            vec![
+                WasmFeature::Simd,
+                WasmFeature::RelaxedSimd,
+                WasmFeature::Gc,
+                WasmFeature::Exceptions,
+            ]
+        }
+    }
+
+    // the module runs on an engine with SIMD
+    let (builder, _oci_cleanup) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(SIMD)?
+        .as_oci_image(None, None)?;
+    let (exit_code, _, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 10);
+
+    // but is rejected when the container is created on an engine without it
+    let (builder, _oci_cleanup) = WasiTest::<Instance<WasmtimeEngine<NoSimdConfig>>>::builder()?
+        .with_wasm(SIMD)?
+        .as_oci_image(None, None)?;
+    let Err(err) = builder.build() else {
+        panic!("a SIMD module should be rejected when SIMD is disabled");
+    };
+    let err = err.downcast::<ShimError>()?;
+    assert!(
+        matches!(&err, ShimError::UnsupportedFeature(msg) if msg.contains("[simd]")),
+        "{err}"
+    );
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_gc_and_exceptions_unsupported() -> anyhow::Result<()> {
+    let resolved = |module: &[u8]| {
+        ResolvedImage::from((
+            vec![WasmLayer::from_module(module.to_vec())],
+            Platform::default(),
+        ))
+    };
+
+    for (module, feature) in [(GC, "[gc]"), (EXCEPTIONS, "[exceptions]")] {
+        let Err(err) = WasiTest::<WasiInstance>::builder()?
+            .with_resolved_image(resolved(module.bytes))
+            .build()
+        else {
+            panic!("a module that requires {feature} should be rejected");
+        };
+        let err = err.downcast::<ShimError>()?;
+        assert!(
+            matches!(&err, ShimError::UnsupportedFeature(msg) if msg.contains(feature)),
+            "{err}"
+        );
+    }
+
+    // and the container can't enable them either
+    let Err(err) = WasiTest::<WasiInstance>::builder()?
+        .with_resolved_image(resolved(GC.bytes))
+        .with_annotation(WASM_FEATURES_ANNOTATION, "gc")?
+        .build()
+    else {
+        panic!("wasmtime can't enable gc");
+    };
+    let err = err.downcast::<ShimError>()?;
+    assert!(
+        matches!(&err, ShimError::UnsupportedFeature(msg) if msg.contains("can't enable")),
+        "{err}"
+    );
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_link_host_functions() -> anyhow::Result<()> {
+    // links `host.answer` for the guests of a single world
+    fn link_answer(
+        linker: HostLinker<'_>,
+        world: WasiVersion,
+        only: WasiVersion,
+    ) -> anyhow::Result<()> {
+        match linker {
+            HostLinker::Module(linker) if world == only => {
+                linker.func_wrap("host", "answer", || 42i32)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    #[derive(Clone)]
+    struct Preview1Config {}
+
+    impl WasiConfig for Preview1Config {
+        fn new_config() -> Config {
+            WasiTestConfig::new_config()
+        }
+        fn link_host_functions(linker: HostLinker<'_>, world: WasiVersion) -> anyhow::Result<()> {
+            link_answer(linker, world, WasiVersion::Preview1)
+        }
+    }
+
+    #[derive(Clone)]
+    struct Preview2Config {}
+
+    impl WasiConfig for Preview2Config {
+        fn new_config() -> Config {
+            WasiTestConfig::new_config()
+        }
+        fn link_host_functions(linker: HostLinker<'_>, world: WasiVersion) -> anyhow::Result<()> {
+            link_answer(linker, world, WasiVersion::Preview2)
+        }
+    }
+
+    // the module targets wasi preview 1, so it can call the host function
+    let (exit_code, _, _) = WasiTest::<Instance<WasmtimeEngine<Preview1Config>>>::builder()?
+        .with_wasm(HOST_IMPORT)?
         .build()?
         .start()?
         .wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 42);
 
-    assert_eq!(exit_code, 0);
-    assert_eq!(stdout.trim(), "current working dir: /");
+    // but can't be instantiated when the host function is only linked for wasi preview 2
+    let (exit_code, _, _) = WasiTest::<Instance<WasmtimeEngine<Preview2Config>>>::builder()?
+        .with_wasm(HOST_IMPORT)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+    assert_ne!(exit_code, 0);
+    assert_ne!(exit_code, 42);
 
     Ok(())
 }
 
 #[test]
 #[serial]
-fn test_has_default_devices() -> anyhow::Result<()> {
+fn test_max_wasm_stack() -> anyhow::Result<()> {
+    // the module recursion fits in the default stack size
     let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(HAS_DEFAULT_DEVICES)?
+        .with_wasm(DEEP_RECURSION)?
         .build()?
         .start()?
         .wait(Duration::from_secs(10))?;
 
     assert_eq!(exit_code, 0);
 
+    // but it overflows a tiny one
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(DEEP_RECURSION)?
+        .with_annotation(MAX_WASM_STACK_ANNOTATION, "4096")?
+        .build()?;
+    let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+
+    assert_ne!(exit_code, 0);
+    assert_eq!(
+        test.instance().exit_status(),
+        Some(ExitStatus::Trapped(TrapReason::StackOverflow))
+    );
+
     Ok(())
 }
 
-// Test that the shim can execute an named exported function
-// that is not the default _start function in a wasm component.
-// The current limitation is that there is no way to pass arguments
-// to the exported function.
-// Issue that tracks this: https://github.com/containerd/runwasi/issues/414
 #[test]
 #[serial]
-fn test_simple_component() -> anyhow::Result<()> {
+fn test_exit_code() -> anyhow::Result<()> {
     let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(SIMPLE_COMPONENT)?
-        .with_start_fn("thunk")?
+        .with_wasm(EXIT_CODE)?
         .build()?
         .start()?
         .wait(Duration::from_secs(10))?;
 
-    assert_eq!(exit_code, 0);
+    assert_eq!(exit_code, 42);
 
     Ok(())
 }
 
-// Test that the shim can execute a wasm component that is
-// compiled with wasip2.
-//
-// This is using the `wasi:cli/command` world to run the component.
-//
-// The wasm component is built and copied over from
-// https://github.com/Mossaka/wasm-component-hello-world. See
-// README.md for how to build the component.
 #[test]
 #[serial]
-fn test_wasip2_component() -> anyhow::Result<()> {
+fn test_success_exit_codes() -> anyhow::Result<()> {
+    // by default only 0 is a success
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(EXIT_CODE)?
+        .build()?;
+    let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 42);
+    assert_eq!(test.instance().exit_succeeded(), Some(false));
+
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(EXIT_CODE)?
+        .with_annotation(SUCCESS_EXIT_CODES_ANNOTATION, "0,40-49")?
+        .build()?;
+    let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 42);
+    assert_eq!(test.instance().exit_succeeded(), Some(true));
+
+    let err = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(EXIT_CODE)?
+        .with_annotation(SUCCESS_EXIT_CODES_ANNOTATION, "42-40")?
+        .build()
+        .err()
+        .expect("invalid exit codes should be rejected");
+    let err = err.downcast::<ShimError>()?;
+    assert!(matches!(err, ShimError::InvalidArgument(_)), "{err}");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_seccomp() -> anyhow::Result<()> {
     let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(COMPONENT_HELLO_WORLD)?
+        .with_wasm(SECCOMP)?
         .build()?
         .start()?
         .wait(Duration::from_secs(10))?;
 
     assert_eq!(exit_code, 0);
-    assert_eq!(stdout, "Hello, world!\n");
+    assert_eq!(stdout.trim(), "current working dir: /");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_secret_mount() -> anyhow::Result<()> {
+    let (exit_code, stdout, stderr) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(READ_SECRET)?
+        .with_secret("token", "s3cr3t-t0k3n")?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "s3cr3t-t0k3n");
+    assert!(!stderr.contains("s3cr3t-t0k3n"));
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_guest_signal() -> anyhow::Result<()> {
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(GUEST_SIGNAL)?
+        .with_annotation(GUEST_SIGNALS_ANNOTATION, "SIGHUP,SIGUSR1")?
+        .build()?;
+    test.start()?;
+
+    // only send the signal once the guest is running, and the shim is forwarding signals
+    let start = Instant::now();
+    while !test.stdout()?.contains("ready") {
+        assert!(start.elapsed() < Duration::from_secs(10), "guest not ready");
+        sleep(Duration::from_millis(10));
+    }
+    test.instance().kill(SIGUSR1 as u32)?;
+
+    let (exit_code, stdout, _) = test.wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "ready\nreceived SIGUSR1\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_platform() -> anyhow::Result<()> {
+    let (builder, _oci_cleanup) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_image_variant("v1")
+        .as_oci_image(None, None)?;
+    let test = builder.build()?;
+
+    let platform = test.instance().platform();
+    assert_eq!(platform.os(), &Os::Other("wasip1".to_string()));
+    assert_eq!(platform.architecture(), &Arch::Wasm);
+    assert_eq!(platform.variant().as_deref(), Some("v1"));
+
+    let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
 
     Ok(())
 }
+
+#[test]
+#[serial]
+fn test_wasm64_image() -> anyhow::Result<()> {
+    // the images of the wasm family of architectures are accepted
+    let (builder, _oci_cleanup) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_image_arch("wasm32")
+        .as_oci_image(None, None)?;
+    let (exit_code, stdout, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    // and the modules of wasm64 images run with memory64
+    let (builder, _oci_cleanup) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(MEMORY64)?
+        .with_image_arch("wasm64")
+        .as_oci_image(None, None)?;
+    let test = builder.build()?;
+    assert_eq!(
+        test.instance().platform().architecture(),
+        &Arch::Other("wasm64".to_string())
+    );
+    let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 64);
+
+    // which is disabled for the other images
+    let (builder, _oci_cleanup) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(MEMORY64)?
+        .as_oci_image(None, None)?;
+    let Err(err) = builder.build() else {
+        panic!("a module that uses memory64 should be rejected in a wasm image");
+    };
+    let err = err.downcast::<ShimError>()?;
+    assert!(
+        matches!(&err, ShimError::UnsupportedFeature(msg) if msg.contains("[memory64]")),
+        "{err}"
+    );
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_interrupt_on_stop() -> anyhow::Result<()> {
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(INTERRUPTIBLE)?
+        .with_annotation(INTERRUPT_TIMEOUT_ANNOTATION, "5")?
+        .build()?;
+    test.start()?;
+
+    let start = Instant::now();
+    while !test.stdout()?.ends_with("ready\n") {
+        assert!(start.elapsed() < Duration::from_secs(10), "guest not ready");
+        sleep(Duration::from_millis(10));
+    }
+    // the guest is interrupted in its loop, rather than waiting for the timeout to exit
+    let stopped = Instant::now();
+    test.instance().stop(Duration::from_secs(10))?;
+    let (exit_status, stdout, _) = test.wait_exit_status(Duration::from_secs(10))?;
+    assert!(stopped.elapsed() < Duration::from_secs(5));
+    assert_eq!(exit_status, ExitStatus::Exited(128 + SIGTERM as u32));
+
+    // everything that the guest wrote before it was interrupted is in the output
+    let expected: String = (0..10_000)
+        .map(|i| format!("line {i:05} written before the interrupt\n"))
+        .chain(["ready\n".to_string()])
+        .collect();
+    assert!(stdout == expected, "captured output doesn't match");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_interrupt_timeout_oci_precompiled() -> anyhow::Result<()> {
+    let (builder, _oci_cleanup1) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .as_oci_image(None, Some("c-interrupt1".to_string()))?;
+    let (exit_code, _, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+    let (label, _) = oci_helpers::get_image_label()?;
+    assert!(label.starts_with("runwasi.io/precompiled/wasmtime/"));
+
+    // the image is precompiled, but an interruptible container runs the module from the layers
+    let (builder, _oci_cleanup2) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation(INTERRUPT_TIMEOUT_ANNOTATION, "5")?
+        .as_oci_image(None, Some("c-interrupt2".to_string()))?;
+    let (exit_code, stdout, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_image_stop_signal() -> anyhow::Result<()> {
+    let (builder, _oci_cleanup) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(GUEST_SIGNAL)?
+        .with_annotation(GUEST_SIGNALS_ANNOTATION, "SIGTERM")?
+        .with_image_stop_signal("SIGTERM")
+        .as_oci_image(None, None)?;
+    let test = builder.build()?;
+    assert_eq!(test.instance().stop_signal(), SIGTERM as u32);
+    test.start()?;
+
+    let start = Instant::now();
+    while !test.stdout()?.contains("ready") {
+        assert!(start.elapsed() < Duration::from_secs(10), "guest not ready");
+        sleep(Duration::from_millis(10));
+    }
+    // the guest exits on its own when it receives SIGTERM, before it would be killed
+    test.instance().stop(Duration::from_secs(10))?;
+
+    let (exit_status, stdout, _) = test.wait_exit_status(Duration::from_secs(10))?;
+    assert_eq!(exit_status, ExitStatus::Exited(0));
+    assert_eq!(stdout, "ready\nreceived SIGTERM\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_stop_signal_annotation() -> anyhow::Result<()> {
+    let (builder, _oci_cleanup) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(INFINITE_LOOP)?
+        .with_annotation(STOP_SIGNAL_ANNOTATION, "USR1")?
+        // caught by the shim, so that it doesn't terminate the guest
+        .with_annotation(GUEST_SIGNALS_ANNOTATION, "SIGUSR1")?
+        .with_image_stop_signal("SIGTERM")
+        .as_oci_image(None, None)?;
+    let test = builder.build()?;
+    assert_eq!(test.instance().stop_signal(), SIGUSR1 as u32);
+
+    // the guest doesn't exit on the stop signal, so it is killed once the timeout passes
+    test.start()?;
+    test.instance().stop(Duration::from_millis(100))?;
+    let (exit_status, _, _) = test.wait_exit_status(Duration::from_secs(10))?;
+    assert_eq!(exit_status, ExitStatus::Signaled(SIGKILL));
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_metrics() -> anyhow::Result<()> {
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(INFINITE_LOOP)?
+        .build()?;
+    test.instance()
+        .metrics()
+        .expect_err("the container isn't running yet");
+    test.start()?;
+
+    // the guest is busy, so its cpu time keeps increasing
+    sleep(Duration::from_millis(500));
+    let first = test.instance().metrics()?;
+    sleep(Duration::from_millis(500));
+    let second = test.instance().metrics()?;
+    assert!(first.cpu_time > Duration::ZERO, "{first:?}");
+    assert!(second.cpu_time > first.cpu_time, "{first:?} {second:?}");
+    assert!(second.runtime > first.runtime);
+    assert!(second.memory_bytes > 0);
+
+    test.instance().kill(SIGKILL as u32)?;
+    test.wait_exit_status(Duration::from_secs(10))?;
+    test.instance()
+        .metrics()
+        .expect_err("the container has exited");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_delete_running() -> anyhow::Result<()> {
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(INFINITE_LOOP)?
+        .build()?;
+    test.start()?;
+
+    let err = test
+        .instance()
+        .delete()
+        .expect_err("a running container shouldn't be deleted without force");
+    match err {
+        ShimError::DeleteFailed {
+            reason: DeleteFailure::Running,
+            ..
+        } => {}
+        _ => panic!("unexpected error: {err}"),
+    }
+    // the container is left running
+    assert!(test.instance().wait_timeout(Duration::ZERO).is_none());
+    test.instance().kill(SIGKILL as u32)?;
+    test.wait_exit_status(Duration::from_secs(10))?;
+
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(INFINITE_LOOP)?
+        .with_force_delete()?
+        .build()?;
+    test.start()?;
+
+    test.delete()?;
+    let (exit_code, _) = test
+        .instance()
+        .wait_timeout(Duration::from_secs(10))
+        .expect("the container was killed");
+    assert_eq!(exit_code, 137);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_kill_long_running() -> anyhow::Result<()> {
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(INFINITE_LOOP)?
+        .build()?;
+    test.start()?;
+
+    test.instance().kill(SIGKILL as u32)?;
+
+    let (exit_status, _, _) = test.wait_exit_status(Duration::from_secs(10))?;
+
+    assert_eq!(exit_status, ExitStatus::Signaled(SIGKILL));
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_debug_modules() -> anyhow::Result<()> {
+    let (reader, mut writer) = pipe()?;
+    let feeder = thread::spawn(move || writer.write_all(INFINITE_LOOP.bytes));
+
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_module_reader(reader, 1024 * 1024)
+        .with_debug_modules()?
+        .build()?;
+    feeder.join().unwrap()?;
+    test.start()?;
+
+    // the module that is running is on the host
+    let module = test.debug_modules_dir().join("layer-0.wasm");
+    assert_eq!(std::fs::read(&module)?, INFINITE_LOOP.bytes);
+    assert!(metadata(&module)?.permissions().readonly());
+
+    test.instance().kill(SIGKILL as u32)?;
+    test.wait_exit_status(Duration::from_secs(10))?;
+    test.delete()?;
+    assert!(!test.debug_modules_dir().exists());
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_export_logs() -> anyhow::Result<()> {
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_export_logs()?
+        .build()?;
+
+    let (exit_code, stdout, _) = test.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+    assert_eq!(test.instance().exported_logs(), None);
+    test.delete()?;
+
+    // the output is still there after the container is gone
+    let logs = test.instance().exported_logs().expect("logs were exported");
+    assert_eq!(test.read_content(&logs.stdout)?, stdout.as_bytes());
+    assert_eq!(test.read_content(&logs.stderr)?, b"");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_out_of_memory() -> anyhow::Result<()> {
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(MEMORY_HOG)?
+        .with_memory_limit(32 * 1024 * 1024)?
+        .build()?;
+
+    let (exit_status, stdout, _) = test.start()?.wait_exit_status(Duration::from_secs(30))?;
+
+    assert_eq!(exit_status, ExitStatus::OutOfMemory);
+    assert_eq!(stdout, "");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_annotation_env() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(PRINT_ENV)?
+        .with_annotation("wasm.env/FOO", "bar")?
+        .with_annotation("other/BAZ", "qux")?
+        .with_annotation_env_prefix("wasm.env/")?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert!(stdout.lines().any(|line| line == "FOO=bar"), "{stdout}");
+    assert!(!stdout.contains("BAZ"), "{stdout}");
+    assert!(!stdout.contains("wasm.env"), "{stdout}");
+
+    // the annotations are ignored unless a prefix is configured
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(PRINT_ENV)?
+        .with_annotation("wasm.env/FOO", "bar")?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert!(!stdout.contains("FOO"), "{stdout}");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_transform_args() -> anyhow::Result<()> {
+    #[derive(Clone)]
+    struct PrependArgConfig {}
+
+    impl WasiConfig for PrependArgConfig {
+        fn new_config() -> Config {
+            WasiTestConfig::new_config()
+        }
+        fn transform_args(mut args: Vec<String>) -> Vec<String> {
+            args.insert(0, "--verbose".to_string());
+            args
+        }
+    }
+
+    let (exit_code, stdout, _) = WasiTest::<Instance<WasmtimeEngine<PrependArgConfig>>>::builder()?
+        .with_wasm(PRINT_ARGS)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    // the entrypoint is kept as the first argument
+    assert_eq!(stdout, "/hello.wasm\n--verbose\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_normalize_line_endings() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(CRLF_OUTPUT)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello\r\nworld\r\n");
+
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(CRLF_OUTPUT)?
+        .with_annotation(NORMALIZE_LINE_ENDINGS_ANNOTATION, "true")?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello\nworld\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_process_user() -> anyhow::Result<()> {
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(CREATE_FILE)?
+        .with_user(1000, 1000)?
+        .build()?;
+
+    let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    let metadata = metadata(test.rootfs().join("created.txt"))?;
+    assert_eq!((metadata.uid(), metadata.gid()), (1000, 1000));
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_fs_quota() -> anyhow::Result<()> {
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(FILL_QUOTA)?
+        .with_annotation(FS_QUOTA_ANNOTATION, "/data=1Mi")?
+        .build()?;
+
+    let (exit_code, stdout, _) = test.start()?.wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0, "{stdout}");
+    assert!(stdout.contains("No space left on device"), "{stdout}");
+    // what the guest wrote stayed in the tmpfs of the container
+    assert!(!test.rootfs().join("data").join("fill").exists());
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_terminal() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(IS_TERMINAL)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "stdin: false, stdout: false\n");
+
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(IS_TERMINAL)?
+        .with_terminal()?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    // the terminal translates the line endings
+    assert_eq!(stdout, "stdin: true, stdout: true\r\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_spec_without_process() -> anyhow::Result<()> {
+    let Err(err) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .without_process()?
+        .build()
+    else {
+        panic!("a spec without a process should be rejected");
+    };
+    let err = err.downcast::<ShimError>()?;
+    assert!(
+        matches!(&err, ShimError::InvalidArgument(msg) if msg.contains("no process")),
+        "{err}"
+    );
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_large_output() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(WRITE_LOTS)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(30))?;
+
+    assert_eq!(exit_code, 0);
+    let expected = (0..200_000)
+        .map(|i| format!("line {i:06} of the output written by the guest\n"))
+        .collect::<Vec<_>>()
+        .concat();
+    assert_eq!(stdout.len(), expected.len());
+    assert!(stdout == expected, "captured output doesn't match");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_has_default_devices() -> anyhow::Result<()> {
+    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HAS_DEFAULT_DEVICES)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+
+    Ok(())
+}
+
+// Test that the shim can execute an named exported function
+// that is not the default _start function in a wasm component.
+// The current limitation is that there is no way to pass arguments
+// to the exported function.
+// Issue that tracks this: https://github.com/containerd/runwasi/issues/414
+#[test]
+#[serial]
+fn test_simple_component() -> anyhow::Result<()> {
+    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(SIMPLE_COMPONENT)?
+        .with_start_fn("thunk")?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+
+    Ok(())
+}
+
+// the digest of the content of a precompiled module, which the shim warms it with
+fn precompiled_digest(precompiled: &[u8]) -> String {
+    format!("sha256:{}", sha256::digest(precompiled))
+}
+
+// How many precompiled modules the engines of `counting_engine` deserialized,
+// so that the tests can tell when a warmed module is used.
+static DESERIALIZED: AtomicUsize = AtomicUsize::new(0);
+
+fn counting_engine() -> WasmtimeEngine<WasiTestConfig> {
+    let mut engine = WasmtimeEngine::default();
+    engine.deserialize = |engine, precompiled| {
+        DESERIALIZED.fetch_add(1, Ordering::SeqCst);
+        unsafe { Module::deserialize(engine, precompiled) }
+    };
+    engine
+}
+
+#[test]
+#[serial]
+fn test_warm() -> anyhow::Result<()> {
+    let engine = counting_engine();
+    let precompiled = engine.precompile(&[HELLO_WORLD.bytes.to_vec()])?;
+    let digest = precompiled_digest(&precompiled);
+    let deserialized = || DESERIALIZED.load(Ordering::SeqCst);
+    let before = deserialized();
+
+    // without warming, the module is deserialized when the container starts
+    engine.deserialize_module(Some(&digest), &precompiled)?;
+    assert_eq!(deserialized(), before + 1);
+
+    // warming deserializes it once, and the container uses that module
+    engine.warm(&digest, &precompiled)?;
+    assert_eq!(deserialized(), before + 2);
+    engine.deserialize_module(Some(&digest), &precompiled)?;
+    assert_eq!(deserialized(), before + 2);
+
+    // only precompiled content can be warmed
+    let digest = precompiled_digest(HELLO_WORLD.bytes);
+    assert!(engine.warm(&digest, HELLO_WORLD.bytes).is_err());
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_share_warmed_module() -> anyhow::Result<()> {
+    let engine = counting_engine();
+    let precompiled = engine.precompile(&[HELLO_WORLD.bytes.to_vec()])?;
+    let digest = precompiled_digest(&precompiled);
+    let deserialized = || DESERIALIZED.load(Ordering::SeqCst);
+    let before = deserialized();
+
+    // two containers of the same image are created at the same time
+    thread::scope(|scope| {
+        let containers: Vec<_> = (0..2)
+            .map(|_| scope.spawn(|| engine.clone().warm(&digest, &precompiled)))
+            .collect();
+        containers
+            .into_iter()
+            .try_for_each(|container| container.join().unwrap())
+    })?;
+    assert_eq!(deserialized(), before + 1);
+
+    // and both run the module that was deserialized once
+    engine.deserialize_module(Some(&digest), &precompiled)?;
+    engine.deserialize_module(Some(&digest), &precompiled)?;
+    assert_eq!(deserialized(), before + 1);
+
+    // the module is kept until neither container uses it
+    engine.release(&digest);
+    engine.deserialize_module(Some(&digest), &precompiled)?;
+    assert_eq!(deserialized(), before + 1);
+
+    engine.release(&digest);
+    engine.deserialize_module(Some(&digest), &precompiled)?;
+    assert_eq!(deserialized(), before + 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_unsupported_component_export() -> anyhow::Result<()> {
+    let engine = wasmtime::Engine::new(&WasiTestConfig::new_config())?;
+    let component = Component::from_binary(&engine, SIMPLE_COMPONENT.bytes)?;
+    let mut store = Store::new(&engine, ());
+    let instance = ComponentLinker::new(&engine).instantiate(&mut store, &component)?;
+
+    component_export(&instance, &mut store, "thunk")?;
+
+    // the shim has no arguments to pass to an export that takes parameters
+    let err = component_export(&instance, &mut store, "add").unwrap_err();
+    match err.downcast_ref::<ShimError>() {
+        Some(ShimError::UnsupportedExport { name, signature }) => {
+            assert_eq!(name, "add");
+            assert_eq!(signature, "func(U32, U32) -> (U32)");
+        }
+        _ => panic!("unexpected error: {err}"),
+    }
+
+    Ok(())
+}
+
+// Test that the shim can execute a wasm component that is
+// compiled with wasip2.
+//
+// This is using the `wasi:cli/command` world to run the component.
+//
+// The wasm component is built and copied over from
+// https://github.com/Mossaka/wasm-component-hello-world. See
+// README.md for how to build the component.
+#[test]
+#[serial]
+fn test_wasip2_component() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(COMPONENT_HELLO_WORLD)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "Hello, world!\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_mixed_wasi_versions() -> anyhow::Result<()> {
+    // a wasi_preview1 module, the same module run on the wasi_preview2 context, and a wasi_preview2 component
+    let module = WasmLayer::from_module(HELLO_WORLD.bytes.to_vec());
+    let mut module_on_preview2 = module.clone();
+    module_on_preview2.wasi_version = Some(WasiVersion::Preview2);
+    let component = WasmLayer::from_module(COMPONENT_HELLO_WORLD.bytes.to_vec());
+
+    let layers = [
+        (module, WasiVersion::Preview1, "hello world\n"),
+        (module_on_preview2, WasiVersion::Preview2, "hello world\n"),
+        (component, WasiVersion::Preview2, "Hello, world!\n"),
+    ];
+    for (layer, wasi_version, expected) in layers {
+        assert_eq!(layer.wasi_version, Some(wasi_version));
+        let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+            .with_resolved_image(ResolvedImage::from((vec![layer], Platform::default())))
+            .build()?
+            .start()?
+            .wait(Duration::from_secs(10))?;
+
+        assert_eq!(exit_code, 0, "{wasi_version:?}");
+        assert_eq!(stdout, expected, "{wasi_version:?}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_engine_version() {
+    let engine = WasmtimeEngine::<WasiTestConfig>::default();
+
+    // the version of wasmtime, e.g. "17.0.1", not the version of the shim
+    let version = WasmtimeEngine::<WasiTestConfig>::version();
+    assert_ne!(version, env!("CARGO_PKG_VERSION"));
+    assert_ne!(version, "unknown");
+
+    assert_eq!(
+        engine.can_precompile(),
+        Some(WasmtimeEngine::<WasiTestConfig>::version())
+    );
+    assert_eq!(
+        WasiInstance::engine_version(),
+        Some(WasmtimeEngine::<WasiTestConfig>::version())
+    );
+}